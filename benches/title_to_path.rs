@@ -0,0 +1,41 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Benchmarks for window title classification, which runs on every tick
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use pomotoshi::task::title_to_path;
+use std::hint::black_box;
+
+/// A spread of titles which hit early, late and no recognizers
+const TITLES: &[&str] = &[
+    "Rocket.Chat - qutebrowser",
+    "Standardize derives on error types by tcharding · Pull Request #1466 · rust-bitcoin/rust-bitcoin - qutebrowser",
+    "[23%] Where in the World: Tenaya and Climate Change - qutebrowser",
+    "Where in the World - Google Chrome - Work",
+    "[mosh] urxvt (camus) - ../check-pr.sh pr/1467/head 1467 (tmux:work-rust-bitcoin/rust-bitcoin)",
+    "server.rs + (~/code/pomotoshi/src) - NVIM",
+    "some window with an unrecognized title",
+];
+
+fn bench_title_to_path(c: &mut Criterion) {
+    for title in TITLES {
+        c.bench_function(&format!("title_to_path: {:.32}", title), |b| {
+            b.iter(|| title_to_path(black_box(title)))
+        });
+    }
+}
+
+criterion_group!(benches, bench_title_to_path);
+criterion_main!(benches);
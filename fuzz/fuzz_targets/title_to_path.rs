@@ -0,0 +1,33 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Fuzzing window title classification and normalization, checking that
+//! neither panics, that paths are never deeper than the deepest recognizer
+//! makes them, and that normalizing twice changes nothing
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pomotoshi::task::{normalize_path, title_to_path};
+
+fuzz_target!(|title: &str| {
+    let mut path = title_to_path(title);
+    assert!(!path.is_empty() && path.len() <= 4);
+    for fold_case in [false, true] {
+        normalize_path(&mut path, fold_case);
+        let mut again = path.clone();
+        normalize_path(&mut again, fold_case);
+        assert_eq!(path, again);
+    }
+});
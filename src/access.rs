@@ -0,0 +1,105 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Access Control
+//!
+//! Restrictions on destructive D-Bus methods, such as cancelling the running
+//! block, so that a buggy script can't do too much damage
+//!
+
+use serde::{Deserialize, Serialize};
+
+/// Configuration for access control
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// If set, only these users may call destructive methods
+    #[serde(default)]
+    allowed_uids: Option<Vec<u32>>,
+    /// If set, destructive methods may only be called through their
+    /// `Confirmed` variants, which take this token as their last argument
+    #[serde(default)]
+    token: Option<String>,
+}
+
+impl Config {
+    /// Whether the caller's user ID needs to be looked up
+    pub fn checks_uid(&self) -> bool {
+        self.allowed_uids.is_some()
+    }
+
+    /// Check whether a destructive call is allowed, given the caller's user
+    /// ID (if known) and the token it gave (if it called a `Confirmed` variant)
+    pub fn check(&self, uid: Option<u32>, token: Option<&str>) -> Result<(), String> {
+        if let Some(ref allowed_uids) = self.allowed_uids {
+            match uid {
+                Some(uid) if allowed_uids.contains(&uid) => {}
+                Some(uid) => return Err(format!("user {} may not call this method", uid)),
+                None => return Err("could not determine the calling user".into()),
+            }
+        }
+        match (self.token.as_deref(), token) {
+            (None, _) => Ok(()),
+            (Some(expected), Some(token)) if secrets_equal(token, expected) => Ok(()),
+            (Some(_), Some(_)) => Err("incorrect confirmation token".into()),
+            (Some(_), None) => Err("a confirmation token is required".into()),
+        }
+    }
+}
+
+/// Compare two secrets, in time independent of where they differ
+pub fn secrets_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let open = Config {
+            allowed_uids: None,
+            token: None,
+        };
+        assert!(open.check(None, None).is_ok());
+
+        let uids = Config {
+            allowed_uids: Some(vec![1000]),
+            token: None,
+        };
+        assert!(uids.check(Some(1000), None).is_ok());
+        assert!(uids.check(Some(1001), None).is_err());
+        assert!(uids.check(None, None).is_err());
+
+        let token = Config {
+            allowed_uids: None,
+            token: Some("sesame".into()),
+        };
+        assert!(token.check(Some(1000), Some("sesame")).is_ok());
+        assert!(token.check(Some(1000), Some("open")).is_err());
+        assert!(token.check(Some(1000), None).is_err());
+
+        let both = Config {
+            allowed_uids: Some(vec![1000]),
+            token: Some("sesame".into()),
+        };
+        assert!(both.check(Some(1000), Some("sesame")).is_ok());
+        assert!(both.check(Some(1001), Some("sesame")).is_err());
+        assert!(both.check(Some(1000), None).is_err());
+    }
+}
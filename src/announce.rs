@@ -0,0 +1,431 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Announcements
+//!
+//! Posting summaries of blocks to a Matrix room, IRC channel or Telegram
+//! chat, for accountability to a remote coworking partner
+//!
+
+use crate::chat::send_json;
+use crate::handle::Handle;
+use crate::history::{self, Block};
+use crate::server::{Event, Transition};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast;
+
+/// How long to allow for posting a single announcement
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of tasks listed in the summary of a completed block
+const TOP_TASKS: usize = 3;
+
+fn default_events() -> Vec<String> {
+    vec![
+        "block_started".into(),
+        "block_completed".into(),
+        "block_cancelled".into(),
+    ]
+}
+fn default_irc_port() -> u16 { 6697 }
+fn default_true() -> bool { true }
+
+/// Where announcements are posted
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Service {
+    /// A Matrix room, e.g. "!abc:example.com", posted to with an access token
+    Matrix {
+        /// The homeserver's URL, e.g. "https://matrix.example.com"
+        homeserver: String,
+        room_id: String,
+        token: String,
+    },
+    /// An IRC channel, which is joined briefly for each announcement
+    Irc {
+        server: String,
+        #[serde(default = "default_irc_port")]
+        port: u16,
+        /// Whether to connect with TLS (using `openssl s_client`)
+        #[serde(default = "default_true")]
+        tls: bool,
+        nick: String,
+        channel: String,
+    },
+    /// A Telegram chat, posted to by a bot
+    Telegram { token: String, chat_id: String },
+}
+
+/// A place to post announcements of blocks
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Channel {
+    #[serde(flatten)]
+    service: Service,
+    /// Names of the transitions to announce: any of `block_started`,
+    /// `block_completed` and `block_cancelled`
+    #[serde(default = "default_events")]
+    events: Vec<String>,
+}
+
+impl Channel {
+    /// Post a message
+    async fn post(&self, text: &str) -> io::Result<()> {
+        match self.service {
+            Service::Matrix {
+                ref homeserver,
+                ref room_id,
+                ref token,
+            } => {
+                // Transaction IDs only need to be unique to stop retries being
+                // posted twice, which we never do
+                let txn_id = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                let url = format!(
+                    "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+                    homeserver.trim_end_matches('/'),
+                    percent_encode(room_id),
+                    txn_id,
+                );
+                let body = json!({ "msgtype": "m.text", "body": text });
+                let headers = [format!("Authorization: Bearer {}", token)];
+                send_json("PUT", &url, &headers, &body).await?;
+            }
+            Service::Telegram {
+                ref token,
+                ref chat_id,
+            } => {
+                // `send_json` keeps the URL, and so the token, off curl's
+                // command line
+                let url = format!("https://api.telegram.org/bot{}/sendMessage", token);
+                let body = json!({ "chat_id": chat_id, "text": text });
+                send_json("POST", &url, &[], &body).await?;
+            }
+            Service::Irc {
+                ref server,
+                port,
+                tls,
+                ref nick,
+                ref channel,
+            } => {
+                if tls {
+                    let mut child = tokio::process::Command::new("openssl")
+                        .arg("s_client")
+                        .arg("-quiet")
+                        .arg("-verify_return_error")
+                        .arg("-connect")
+                        .arg(format!("{}:{}", server, port))
+                        .arg("-servername")
+                        .arg(server)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::null())
+                        .kill_on_drop(true)
+                        .spawn()?;
+                    let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+                        (Some(stdin), Some(stdout)) => (stdin, stdout),
+                        _ => return Err(io::Error::other("openssl has no stdin or stdout")),
+                    };
+                    irc_session(BufReader::new(stdout), stdin, nick, channel, text).await?;
+                } else {
+                    let stream = tokio::net::TcpStream::connect((server.as_str(), port)).await?;
+                    let (reader, writer) = stream.into_split();
+                    irc_session(BufReader::new(reader), writer, nick, channel, text).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A name for the channel, for error messages
+    fn name(&self) -> String {
+        match self.service {
+            Service::Matrix { ref room_id, .. } => format!("Matrix room {}", room_id),
+            Service::Irc {
+                ref server,
+                ref channel,
+                ..
+            } => format!("IRC channel {} on {}", channel, server),
+            Service::Telegram { ref chat_id, .. } => format!("Telegram chat {}", chat_id),
+        }
+    }
+}
+
+/// Register with an IRC server, join a channel, send a message to it and quit
+async fn irc_session<R, W>(
+    mut reader: R,
+    mut writer: W,
+    nick: &str,
+    channel: &str,
+    text: &str,
+) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let register = format!("NICK {}\r\nUSER {} 0 * :Pomotoshi\r\n", nick, nick);
+    writer.write_all(register.as_bytes()).await?;
+    writer.flush().await?;
+
+    // Wait for the welcome message, answering pings
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::Error::other("IRC server closed the connection"));
+        }
+        let message = line.trim_end();
+        if let Some(token) = message.strip_prefix("PING ") {
+            writer
+                .write_all(format!("PONG {}\r\n", token).as_bytes())
+                .await?;
+            writer.flush().await?;
+            continue;
+        }
+        // Skip the prefix, if any, to get the command
+        let mut words = message.split(' ');
+        let command = match words.next() {
+            Some(prefix) if prefix.starts_with(':') => words.next(),
+            command => command,
+        };
+        match command {
+            Some("001") => break,
+            Some("432" | "433" | "436" | "465" | "ERROR") => {
+                return Err(io::Error::other(format!(
+                    "IRC server refused us: {}",
+                    message
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    let mut session = format!("JOIN {}\r\n", channel);
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        session += &format!("PRIVMSG {} :{}\r\n", channel, line);
+    }
+    session += "QUIT\r\n";
+    writer.write_all(session.as_bytes()).await?;
+    writer.flush().await?;
+    // Wait for the server to hang up, so that it has seen everything
+    while reader.read_line(&mut line).await? > 0 {
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Percent-encode everything but unreserved characters, for use in a URL path
+fn percent_encode(s: &str) -> String {
+    let mut ret = String::new();
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            ret.push(byte as char);
+        } else {
+            ret += &format!("%{:02X}", byte);
+        }
+    }
+    ret
+}
+
+/// A length of time, in whole minutes
+fn minutes(s: u64) -> String {
+    format!("{}m", (s + 30) / 60)
+}
+
+/// The announcement of a transition, given the block it concerns, or `None`
+/// if it is not announced
+fn summary(transition: &Transition, block: Option<&Block>) -> Option<String> {
+    let label = match transition.label {
+        Some(ref label) => format!(" ({})", label),
+        None => String::new(),
+    };
+    match transition.event {
+        Event::BlockStarted => Some(format!(
+            "Started a {} block{}.",
+            minutes(transition.duration_s.unwrap_or(0)),
+            label,
+        )),
+        Event::BlockCompleted => {
+            let mut text = format!(
+                "Completed a {} block{}.",
+                minutes(transition.duration_s.unwrap_or(0)),
+                label,
+            );
+            let top: Vec<_> = block
+                .map(|block| block.tasks.leaf_paths())
+                .unwrap_or_default()
+                .into_iter()
+                .take(TOP_TASKS)
+                .map(|(path, time)| format!("{} ({})", path.join(" / "), minutes(time.as_secs())))
+                .collect();
+            if !top.is_empty() {
+                text += &format!(" Top tasks: {}.", top.join(", "));
+            }
+            Some(text)
+        }
+        Event::BlockCancelled => {
+            let elapsed_s = block.map_or(0, |block| block.elapsed.as_secs());
+            Some(format!(
+                "Cancelled a {} block{} after {}.",
+                minutes(transition.duration_s.unwrap_or(0)),
+                label,
+                minutes(elapsed_s),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Post announcements of blocks to the configured channels, forever
+///
+/// The channels are looked up afresh for each transition, so that they can
+/// be changed by reloading the config.
+pub async fn run(handle: Handle, mut events: broadcast::Receiver<Transition>) {
+    loop {
+        let transition = match events.recv().await {
+            Ok(transition) => transition,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let name = transition.event.name();
+        let (channels, block) = {
+            let lock = handle.lock();
+            let channels: Vec<_> = lock
+                .announce()
+                .iter()
+                .filter(|channel| channel.events.iter().any(|event| event == name))
+                .cloned()
+                .collect();
+            (channels, lock.last_block().cloned())
+        };
+        if channels.is_empty() {
+            continue;
+        }
+        // The block is only relevant if it just ended
+        let block = block.filter(|block| block.end_s + 5 >= history::unix_now());
+        let text = match summary(&transition, block.as_ref()) {
+            Some(text) => text,
+            None => continue,
+        };
+        for channel in channels {
+            // Post in the background so that a slow server does not hold up the others
+            let handle = handle.clone();
+            let text = text.clone();
+            tokio::spawn(async move {
+                let result = match tokio::time::timeout(TIMEOUT, channel.post(&text)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::other("timed out")),
+                };
+                if let Err(e) = result {
+                    let message = format!("posting to {}: {}", channel.name(), e);
+                    handle.lock().signal_error(&message);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut transition = Transition {
+            event: Event::BlockStarted,
+            label: Some("review".into()),
+            duration_s: Some(1500),
+            detail: None,
+            preset: None,
+            timestamp: std::time::SystemTime::now(),
+        };
+        assert_eq!(
+            summary(&transition, None).as_deref(),
+            Some("Started a 25m block (review)."),
+        );
+
+        let mut block = Block::start(1500, Some("review".into()));
+        block.record_focus(
+            vec!["rust-bitcoin".into(), "Github".into()],
+            Duration::from_secs(900),
+        );
+        block.record_focus(vec!["vim".into()], Duration::from_secs(300));
+        transition.event = Event::BlockCompleted;
+        assert_eq!(
+            summary(&transition, Some(&block)).as_deref(),
+            Some(
+                "Completed a 25m block (review). Top tasks: Github / rust-bitcoin (15m), vim (5m)."
+            ),
+        );
+
+        block.elapsed = Duration::from_secs(420);
+        transition.event = Event::BlockCancelled;
+        transition.label = None;
+        assert_eq!(
+            summary(&transition, Some(&block)).as_deref(),
+            Some("Cancelled a 25m block after 7m."),
+        );
+
+        transition.event = Event::Halving;
+        assert_eq!(summary(&transition, Some(&block)), None);
+        assert_eq!(percent_encode("!abc:example.com"), "%21abc%3Aexample.com");
+    }
+
+    /// Check that a Matrix homeserver is sent the token and message, if
+    /// `curl` is installed
+    #[tokio::test]
+    async fn test_post_matrix() {
+        if std::process::Command::new("curl")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let channel: Channel = serde_json::from_value(json!({
+            "matrix": {
+                "homeserver": format!("http://{}/", listener.local_addr().unwrap()),
+                "room_id": "!abc:example.com",
+                "token": "secret",
+            },
+        }))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::AsyncReadExt;
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut chunk = [0; 1024];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        channel.post("Started a 25m block.").await.unwrap();
+        let request = server.await.unwrap();
+        let path = "/_matrix/client/v3/rooms/%21abc%3Aexample.com/send/m.room.message/";
+        assert!(request.starts_with(&format!("PUT {}", path)), "{}", request);
+        assert!(request.contains("\r\nAuthorization: Bearer secret\r\n"));
+        assert!(request.ends_with(r#"{"body":"Started a 25m block.","msgtype":"m.text"}"#));
+    }
+}
@@ -0,0 +1,123 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Audio
+//!
+//! Querying PulseAudio (or PipeWire, through its PulseAudio compatibility)
+//! for applications using the microphone, e.g. to be in a call
+//!
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process::Command;
+
+fn default_path() -> Vec<String> { vec!["Calls".into()] }
+
+/// Configuration for treating microphone use as a tracking signal
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Root-first task path under which time is recorded while the
+    /// microphone is in use, with the application using it appended
+    #[serde(default = "default_path")]
+    path: Vec<String>,
+    /// Names of applications whose use of the microphone is ignored, e.g.
+    /// volume meters which record constantly
+    #[serde(default)]
+    ignore: Vec<String>,
+}
+
+impl Config {
+    /// The (reversed) task path to record time against while a set of
+    /// applications are using the microphone, if any of them count
+    pub fn call_path(&self, users: &[String]) -> Option<Vec<String>> {
+        let user = users.iter().find(|user| !self.ignore.contains(user))?;
+        let mut path = self.path.clone();
+        path.push(user.clone());
+        path.reverse();
+        Some(path)
+    }
+}
+
+/// The names of applications currently recording from an input device
+pub fn microphone_users() -> io::Result<Vec<String>> {
+    let output = Command::new("pactl")
+        .arg("list")
+        .arg("source-outputs")
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("running pactl: {}", e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "pactl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(parse_source_outputs(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Extract the application names of streams which are not corked (paused)
+/// from the output of `pactl list source-outputs`
+fn parse_source_outputs(pactl: &str) -> Vec<String> {
+    let mut ret = vec![];
+    for output in pactl.split("Source Output #").skip(1) {
+        let mut corked = false;
+        let mut name = None;
+        for line in output.lines().map(str::trim) {
+            if let Some(value) = line.strip_prefix("Corked:") {
+                corked = value.trim() == "yes";
+            } else if let Some(value) = line.strip_prefix("application.name = ") {
+                name = Some(value.trim_matches('"').to_owned());
+            }
+        }
+        if !corked {
+            ret.push(name.unwrap_or_else(|| "unknown".into()));
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source_outputs() {
+        let pactl = "Source Output #42
+	Driver: PipeWire
+	Corked: no
+	Properties:
+		application.name = \"Firefox\"
+		media.name = \"AudioCallbackDriver\"
+
+Source Output #43
+	Corked: yes
+	Properties:
+		application.name = \"pavucontrol\"
+";
+        assert_eq!(parse_source_outputs(pactl), vec!["Firefox".to_string()]);
+        assert!(parse_source_outputs("").is_empty());
+
+        let config = Config {
+            path: default_path(),
+            ignore: vec!["Firefox".into()],
+        };
+        let users = ["Firefox".to_string(), "Zoom".to_string()];
+        assert_eq!(
+            config.call_path(&users),
+            Some(vec!["Zoom".into(), "Calls".into()])
+        );
+        assert_eq!(config.call_path(&users[..1]), None);
+    }
+}
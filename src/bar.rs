@@ -0,0 +1,57 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Status Bar Socket
+//!
+//! Streaming the rendered status line over a Unix-domain socket, so that any
+//! number of bars (e.g. one xmobar per monitor) can show it, rather than only
+//! the one reading the daemon's stdout.
+//!
+
+use crate::handle::Handle;
+use std::io;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Bind the bar socket to a path, replacing any stale socket left there
+/// (but not one another daemon is listening on)
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    crate::control::bind(path)
+}
+
+/// Accept and serve bars forever
+pub async fn serve(listener: UnixListener, handle: Handle) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_client(stream, handle.clone()));
+        }
+    }
+}
+
+/// Send the status line to a bar, and again whenever it changes, until the
+/// bar hangs up
+async fn serve_client(mut stream: UnixStream, handle: Handle) -> io::Result<()> {
+    let mut line = handle.subscribe_line();
+    loop {
+        let output = format!("{}\n", *line.borrow_and_update());
+        // Nothing has been rendered yet if the daemon is just starting
+        if output != "\n" {
+            stream.write_all(output.as_bytes()).await?;
+        }
+        if line.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
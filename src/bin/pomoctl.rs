@@ -0,0 +1,117 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Pomoctl
+//!
+//! A command-line client for the pomotoshi daemon. If the daemon is not
+//! running, and its D-Bus service file is installed, the bus starts it.
+//!
+
+use pomotoshi::{DBUS_ORG, DBUS_PATH};
+use std::{env, process};
+
+/// Length of blocks started without giving a length
+const DEFAULT_BLOCK_S: u64 = 25 * 60;
+
+const USAGE: &str = "Usage: pomoctl start [SECONDS [LABEL]]
+       pomoctl pause
+       pomoctl cancel [--force | TOKEN]
+       pomoctl status
+       pomoctl report --html FILE
+       pomoctl heatmap [WEEKS]
+       pomoctl import pomobar|timewarrior|csv FILE";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let connection = zbus::Connection::session().await?;
+
+    match args[..] {
+        ["start"] => {
+            call(&connection, "startBlock", &(DEFAULT_BLOCK_S,)).await?;
+        }
+        ["start", time_s] => {
+            call(&connection, "startBlock", &(time_s.parse::<u64>()?,)).await?;
+        }
+        ["start", time_s, label] => {
+            let body = (time_s.parse::<u64>()?, label);
+            call(&connection, "startLabeledBlock", &body).await?;
+        }
+        ["pause"] => {
+            call(&connection, "pauseBlock", &()).await?;
+        }
+        ["cancel"] => {
+            call(&connection, "cancelBlock", &()).await?;
+        }
+        ["cancel", "--force"] => {
+            call(&connection, "forceCancelBlock", &()).await?;
+        }
+        ["cancel", token] => {
+            call(&connection, "cancelBlockConfirmed", &(token,)).await?;
+        }
+        ["status"] => {
+            let reply = call(&connection, "getStatus", &()).await?;
+            let (state, remaining_s, _, label): (String, u64, u64, String) =
+                reply.body().deserialize()?;
+            let mut line = state;
+            if remaining_s > 0 {
+                line += &format!(" {:02}:{:02}", remaining_s / 60, remaining_s % 60);
+            }
+            if !label.is_empty() {
+                line += &format!(" {}", label);
+            }
+            println!("{}", line);
+        }
+        ["report", "--html", path] => {
+            let reply = call(&connection, "htmlReport", &()).await?;
+            let html: String = reply.body().deserialize()?;
+            std::fs::write(path, html)?;
+        }
+        ["heatmap"] => {
+            let reply = call(&connection, "focusHeatmap", &(0u32,)).await?;
+            print!("{}", reply.body().deserialize::<String>()?);
+        }
+        ["heatmap", weeks] => {
+            let reply = call(&connection, "focusHeatmap", &(weeks.parse::<u32>()?,)).await?;
+            print!("{}", reply.body().deserialize::<String>()?);
+        }
+        ["import", format, path] => {
+            // The daemon reads the file, from its own working directory
+            let path = std::fs::canonicalize(path)?;
+            let body = (format, path.to_string_lossy());
+            let reply = call(&connection, "importHistory", &body).await?;
+            println!("{}", reply.body().deserialize::<String>()?);
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+    Ok(())
+}
+
+/// Call one of the daemon's methods
+async fn call<B>(
+    connection: &zbus::Connection,
+    method: &str,
+    body: &B,
+) -> zbus::Result<zbus::Message>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+{
+    connection
+        .call_method(Some(DBUS_ORG), DBUS_PATH, Some(DBUS_ORG), method, body)
+        .await
+}
@@ -0,0 +1,209 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Calendar
+//!
+//! Reading of upcoming events from an iCalendar file or URL, so that blocks
+//! are not started which would run into a meeting
+//!
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process::Command;
+
+fn default_refresh_s() -> u64 { 300 }
+
+/// What to do when a block would overlap an event
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Flash a warning, but start the block anyway
+    #[default]
+    Warn,
+    /// Flash an error and refuse to start the block
+    Refuse,
+}
+
+/// Configuration of the calendar
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Path of an `.ics` file, or an `http(s)` URL (e.g. a CalDAV calendar)
+    source: String,
+    /// How often to re-read the calendar, in seconds
+    #[serde(default = "default_refresh_s")]
+    refresh_s: u64,
+    #[serde(default)]
+    pub action: Action,
+}
+
+/// A timed event
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Event {
+    /// Start time, in seconds since the UNIX epoch
+    pub start_s: u64,
+    /// End time, in seconds since the UNIX epoch
+    pub end_s: u64,
+}
+
+impl Config {
+    /// How often to re-read the calendar, which is at most once a second
+    pub fn refresh_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.refresh_s.max(1))
+    }
+
+    /// Read all timed events from the calendar
+    ///
+    /// This is blocking, since it may read a file or fetch a URL.
+    pub fn load(&self) -> io::Result<Vec<Event>> {
+        let data = if self.source.starts_with("http://") || self.source.starts_with("https://") {
+            let output = Command::new("curl")
+                .arg("--silent")
+                .arg("--fail")
+                .arg("--location")
+                .arg(&self.source)
+                .output()?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "curl exited with {}",
+                    output.status
+                )));
+            }
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        } else {
+            std::fs::read_to_string(&self.source)?
+        };
+        Ok(parse_ics(&data))
+    }
+}
+
+/// Extract timed events from iCalendar data
+///
+/// All-day events are skipped, since they rarely stop anybody working, as
+/// are recurrence rules, which are beyond the scope of this parser.
+fn parse_ics(data: &str) -> Vec<Event> {
+    // Undo line folding, in which long lines are continued with leading whitespace
+    let mut lines: Vec<String> = vec![];
+    for line in data.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(cont), Some(last)) => last.push_str(cont),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    let mut ret = vec![];
+    let mut start = None;
+    let mut end = None;
+    for line in &lines {
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let mut params: Vec<&str> = key.split(';').collect();
+        let name = params.remove(0);
+        match name {
+            "BEGIN" if value == "VEVENT" => {
+                start = None;
+                end = None;
+            }
+            "DTSTART" => start = parse_time(&params, value),
+            "DTEND" => end = parse_time(&params, value),
+            "END" if value == "VEVENT" => {
+                if let (Some(start_s), Some(end_s)) = (start, end) {
+                    ret.push(Event { start_s, end_s });
+                }
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+/// Parse an iCalendar date-time, returning `None` for dates without times
+fn parse_time(params: &[&str], value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.len() < 15 || params.contains(&"VALUE=DATE") {
+        return None;
+    }
+    let (date, time) = value.split_once('T')?;
+    let (year, month, day) = (date.get(0..4)?, date.get(4..6)?, date.get(6..8)?);
+    let (hour, min, sec) = (time.get(0..2)?, time.get(2..4)?, time.get(4..6)?);
+
+    if time.ends_with('Z') {
+        let date = time::Date::from_calendar_date(
+            year.parse().ok()?,
+            time::Month::try_from(month.parse::<u8>().ok()?).ok()?,
+            day.parse().ok()?,
+        )
+        .ok()?;
+        let time =
+            time::Time::from_hms(hour.parse().ok()?, min.parse().ok()?, sec.parse().ok()?).ok()?;
+        let unix = date.with_time(time).assume_utc().unix_timestamp();
+        u64::try_from(unix).ok()
+    } else {
+        // Local times, either floating or with a TZID, are left to `date`
+        let mut command = Command::new("date");
+        if let Some(tzid) = params.iter().find_map(|p| p.strip_prefix("TZID=")) {
+            command.env("TZ", tzid);
+        }
+        let output = command
+            .arg("-d")
+            .arg(format!(
+                "{}-{}-{} {}:{}:{}",
+                year, month, day, hour, min, sec
+            ))
+            .arg("+%s")
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ics() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+                   BEGIN:VEVENT\r\n\
+                   SUMMARY:Standup\r\n\
+                   DTSTART:20220501T120000Z\r\n\
+                   DTEND:20220501T121500Z\r\n\
+                   END:VEVENT\r\n\
+                   BEGIN:VEVENT\r\n\
+                   SUMMARY:Holiday\r\n\
+                   DTSTART;VALUE=DATE:20220502\r\n\
+                   DTEND;VALUE=DATE:20220503\r\n\
+                   END:VEVENT\r\n\
+                   BEGIN:VEVENT\r\n\
+                   SUMMARY:A meeting with a very long\r\n  description\r\n\
+                   DTSTART:20220503T\r\n 090000Z\r\n\
+                   DTEND:20220503T100000Z\r\n\
+                   END:VEVENT\r\n\
+                   END:VCALENDAR\r\n";
+        assert_eq!(
+            parse_ics(ics),
+            vec![
+                Event {
+                    start_s: 1651406400,
+                    end_s: 1651407300,
+                },
+                Event {
+                    start_s: 1651568400,
+                    end_s: 1651572000,
+                },
+            ],
+        );
+    }
+}
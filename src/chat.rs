@@ -0,0 +1,312 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Chat Status
+//!
+//! Setting the user's status in Slack or Rocket.Chat (using `curl`) while
+//! blocks are running, so that colleagues know not to expect a reply
+//!
+
+use crate::handle::Handle;
+use crate::history;
+use crate::server::{Event, Transition};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast;
+
+/// How long to allow for a single request, in seconds
+const TIMEOUT_S: u64 = 10;
+
+fn default_text() -> String { "Focusing until {until}".into() }
+fn default_emoji() -> String { ":tomato:".into() }
+
+/// A chat service
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Service {
+    /// Slack, with a user token having the `users.profile:write` scope
+    Slack {
+        token: String,
+        #[serde(default = "default_emoji")]
+        emoji: String,
+    },
+    /// A Rocket.Chat server, with a personal access token
+    RocketChat {
+        /// The server's URL, e.g. "https://chat.example.com"
+        url: String,
+        user_id: String,
+        token: String,
+    },
+}
+
+/// An account whose status is set during blocks
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Account {
+    #[serde(flatten)]
+    service: Service,
+    /// The status text, in which `{until}` is replaced by the local time the
+    /// block ends, as `HH:MM`
+    #[serde(default = "default_text")]
+    text: String,
+}
+
+impl Account {
+    /// Set the status to say we are focusing until a given time, or clear it
+    /// if there is none
+    async fn set_status(&self, until_s: Option<u64>, until: &str) -> io::Result<()> {
+        let text = match until_s {
+            Some(_) => self.text.replace("{until}", until),
+            None => String::new(),
+        };
+        match self.service {
+            Service::Slack {
+                ref token,
+                ref emoji,
+            } => {
+                let emoji = if until_s.is_some() {
+                    emoji.as_str()
+                } else {
+                    ""
+                };
+                let body = json!({
+                    "profile": {
+                        "status_text": text,
+                        "status_emoji": emoji,
+                        // Slack clears the status itself if we fail to
+                        "status_expiration": until_s.unwrap_or(0),
+                    }
+                });
+                let response = send_json(
+                    "POST",
+                    "https://slack.com/api/users.profile.set",
+                    &[format!("Authorization: Bearer {}", token)],
+                    &body,
+                )
+                .await?;
+                // Slack reports errors in the body rather than the HTTP status
+                let response: serde_json::Value = serde_json::from_str(&response)?;
+                if response["ok"] != true {
+                    return Err(io::Error::other(format!(
+                        "Slack error: {}",
+                        response["error"].as_str().unwrap_or("unknown")
+                    )));
+                }
+            }
+            Service::RocketChat {
+                ref url,
+                ref user_id,
+                ref token,
+            } => {
+                let status = if until_s.is_some() { "busy" } else { "online" };
+                let body = json!({ "message": text, "status": status });
+                send_json(
+                    "POST",
+                    &format!("{}/api/v1/users.setStatus", url.trim_end_matches('/')),
+                    &[
+                        format!("X-Auth-Token: {}", token),
+                        format!("X-User-Id: {}", user_id),
+                    ],
+                    &body,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A name for the account, for error messages
+    fn name(&self) -> &str {
+        match self.service {
+            Service::Slack { .. } => "Slack",
+            Service::RocketChat { ref url, .. } => url,
+        }
+    }
+}
+
+/// Update the status of the configured chat accounts as blocks start and end,
+/// forever
+///
+/// The accounts are looked up afresh for each transition, so that they can be
+/// changed by reloading the config.
+pub async fn run(handle: Handle, mut events: broadcast::Receiver<Transition>) {
+    loop {
+        let transition = match events.recv().await {
+            Ok(transition) => transition,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (accounts, remaining_s) = {
+            let lock = handle.lock();
+            (lock.chat_status().to_vec(), lock.status().remaining_s)
+        };
+        if accounts.is_empty() {
+            continue;
+        }
+        let until_s = match transition.event {
+            Event::BlockStarted => {
+                let started_s = transition
+                    .timestamp
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                Some(started_s + transition.duration_s.unwrap_or(0))
+            }
+            Event::BlockUnpaused => Some(history::unix_now() + remaining_s.unwrap_or(0)),
+            Event::BlockPaused | Event::BlockCancelled | Event::BlockCompleted => None,
+            _ => continue,
+        };
+        let until = match until_s {
+            Some(until_s) => tokio::task::spawn_blocking(move || history::local_clock(until_s))
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            None => String::new(),
+        };
+        // Updates are made in order, so that a slow request can't leave a
+        // status set after the block it was for has ended
+        for account in &accounts {
+            if let Err(e) = account.set_status(until_s, &until).await {
+                let message = format!("setting {} status: {}", account.name(), e);
+                handle.lock().signal_error(&message);
+            }
+        }
+    }
+}
+
+/// Send a JSON body to a URL, with the given HTTP method and extra headers,
+/// returning the response body
+///
+/// The URL, headers and body are passed to `curl` as a config file on its
+/// stdin rather than as arguments, since they may contain tokens, which
+/// other users could read from the process list.
+pub(crate) async fn send_json(
+    method: &str,
+    url: &str,
+    headers: &[String],
+    body: &serde_json::Value,
+) -> io::Result<String> {
+    let content_type = "Content-Type: application/json; charset=utf-8";
+    let body = body.to_string();
+    let mut options = vec![("request", method), ("url", url), ("header", content_type)];
+    options.extend(headers.iter().map(|header| ("header", header.as_str())));
+    options.push(("data-raw", &body));
+    let mut child = tokio::process::Command::new("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--max-time")
+        .arg(TIMEOUT_S.to_string())
+        .arg("--config")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(curl_config(&options).as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A `curl` config file setting the given long options to the given values
+pub(crate) fn curl_config(options: &[(&str, &str)]) -> String {
+    let mut config = String::new();
+    for &(option, value) in options {
+        config.push_str(option);
+        config.push_str(" = \"");
+        for c in value.chars() {
+            match c {
+                '\\' => config.push_str("\\\\"),
+                '"' => config.push_str("\\\""),
+                '\n' => config.push_str("\\n"),
+                '\r' => config.push_str("\\r"),
+                '\t' => config.push_str("\\t"),
+                c => config.push(c),
+            }
+        }
+        config.push_str("\"\n");
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curl_config() {
+        let config = curl_config(&[
+            ("url", "https://example.com/"),
+            ("header", "Authorization: Bearer secret"),
+            ("data-raw", "{\"text\":\"a \\\"quote\\\"\\nand\tmore\"}\r\n"),
+        ]);
+        assert_eq!(
+            config,
+            "url = \"https://example.com/\"\n\
+             header = \"Authorization: Bearer secret\"\n\
+             data-raw = \"{\\\"text\\\":\\\"a \\\\\\\"quote\\\\\\\"\\\\nand\\tmore\\\"}\\r\\n\"\n",
+        );
+    }
+
+    /// Check that `curl` sends what it is given, if it is installed
+    #[tokio::test]
+    async fn test_send_json() {
+        if std::process::Command::new("curl")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/status", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            use tokio::io::AsyncReadExt;
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut chunk = [0; 1024];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let body = json!({ "text": "a \"quote\" and \\ backslash\n" });
+        let headers = ["Authorization: Bearer secret".to_owned()];
+        let response = send_json("PUT", &url, &headers, &body).await.unwrap();
+        assert_eq!(response, "ok");
+        let request = server.await.unwrap();
+        assert!(
+            request.starts_with("PUT /status HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains("\r\nAuthorization: Bearer secret\r\n"));
+        assert!(request.ends_with(&format!("\r\n\r\n{}", body)));
+    }
+}
@@ -0,0 +1,289 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! External Classifier
+//!
+//! Support for classifying window titles with a user-provided command
+//!
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Maximum number of titles to remember responses for
+const CACHE_SIZE: usize = 4096;
+
+/// How long to wait for the command to classify a title, since the server
+/// is locked meanwhile
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to stop asking the command after it fails, so that a broken or
+/// hanging command doesn't hold the server up on every tick
+const FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A user-configured command which maps window titles to task paths
+///
+/// The command is run with `sh -c` and receives titles on stdin, one per
+/// line. For each title it should output one line containing the task path,
+/// root first, with components separated by tabs. An empty line means that
+/// the built-in classification should be used instead. A command which
+/// takes longer than `RESPONSE_TIMEOUT` to respond is killed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct External {
+    /// The shell command to run
+    command: String,
+    /// Whether to start the command once and keep feeding it titles, rather
+    /// than starting it afresh for every title
+    #[serde(default)]
+    coprocess: bool,
+    /// Responses to previously-seen titles
+    #[serde(skip)]
+    cache: HashMap<String, Option<Vec<String>>>,
+    /// The running coprocess, if any
+    #[serde(skip)]
+    child: Option<Coprocess>,
+    /// Until when the command is not asked, after it last failed
+    #[serde(skip)]
+    failed_until: Option<Instant>,
+}
+
+impl Clone for External {
+    /// Clones the configuration and cache, but not any running coprocess
+    fn clone(&self) -> External {
+        External {
+            command: self.command.clone(),
+            coprocess: self.coprocess,
+            cache: self.cache.clone(),
+            child: None,
+            failed_until: self.failed_until,
+        }
+    }
+}
+
+impl External {
+    /// Classify a window title, returning a (reversed) task path
+    ///
+    /// Returns `None` if the command declines to classify the title, or
+    /// cannot be run, in which case the built-in classification should be used.
+    pub fn classify(&mut self, title: &str) -> Option<Vec<String>> {
+        let title = title.trim();
+        if let Some(cached) = self.cache.get(title) {
+            return cached.clone();
+        }
+        let now = crate::clock::now();
+        if self.failed_until.is_some_and(|until| now < until) {
+            return None;
+        }
+
+        let response = if self.coprocess {
+            self.query_coprocess(title)
+        } else {
+            self.query_oneshot(title)
+        };
+        // Failures are not cached, so that the command gets another chance
+        // once it has been left alone for a while
+        let response = match response {
+            Ok(line) => parse_response(&line),
+            Err(_) => {
+                self.failed_until = Some(now + FAILURE_BACKOFF);
+                return None;
+            }
+        };
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.clear();
+        }
+        self.cache.insert(title.to_owned(), response.clone());
+        response
+    }
+
+    /// Run the command once, passing it a single title
+    fn query_oneshot(&self, title: &str) -> io::Result<String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let lines = read_lines(child.stdout.take().expect("stdout is piped"));
+        let result = writeln!(child.stdin.take().expect("stdin is piped"), "{}", title)
+            .and_then(|_| receive(&lines));
+        // The command has either answered or run out of time
+        let _ = child.kill();
+        let _ = child.wait();
+        result
+    }
+
+    /// Pass a title to the coprocess, (re)starting it if necessary
+    fn query_coprocess(&mut self, title: &str) -> io::Result<String> {
+        if self.child.is_none() {
+            self.child = Some(Coprocess::spawn(&self.command)?);
+        }
+        let child = self.child.as_mut().expect("just spawned");
+        let result = child.query(title);
+        if result.is_err() {
+            // Drop (and kill) the coprocess so that it is restarted next time
+            self.child = None;
+        }
+        result
+    }
+}
+
+/// A long-running classifier process
+#[derive(Debug)]
+struct Coprocess {
+    child: Child,
+    stdin: ChildStdin,
+    lines: mpsc::Receiver<io::Result<String>>,
+}
+
+impl Coprocess {
+    /// Start the classifier
+    fn spawn(command: &str) -> io::Result<Coprocess> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        Ok(Coprocess {
+            stdin: child.stdin.take().expect("stdin is piped"),
+            lines: read_lines(child.stdout.take().expect("stdout is piped")),
+            child,
+        })
+    }
+
+    /// Send a title to the classifier and read back its response
+    fn query(&mut self, title: &str) -> io::Result<String> {
+        writeln!(self.stdin, "{}", title)?;
+        self.stdin.flush()?;
+        let line = receive(&self.lines)?;
+        if line.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "classifier exited",
+            ));
+        }
+        Ok(line)
+    }
+}
+
+/// Read lines from a classifier on a thread of its own, so that they can be
+/// waited for with a timeout; the thread exits once the classifier does
+fn read_lines(stdout: ChildStdout) -> mpsc::Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdout = BufReader::new(stdout);
+        loop {
+            let mut line = String::new();
+            let result = stdout.read_line(&mut line);
+            let done = !matches!(result, Ok(n) if n > 0);
+            if sender.send(result.map(|_| line)).is_err() || done {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Wait for the next line from a classifier, which is empty if it exited
+fn receive(lines: &mpsc::Receiver<io::Result<String>>) -> io::Result<String> {
+    match lines.recv_timeout(RESPONSE_TIMEOUT) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Disconnected) => Ok(String::new()),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "classifier did not respond",
+        )),
+    }
+}
+
+impl Drop for Coprocess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Parse a tab-separated, root-first path into a reversed task path
+fn parse_response(line: &str) -> Option<Vec<String>> {
+    let path: Vec<String> = line
+        .trim_end_matches(['\r', '\n'])
+        .split('\t')
+        .filter(|s| !s.is_empty())
+        .rev()
+        .map(String::from)
+        .collect();
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn external(command: &str, coprocess: bool) -> External {
+        serde_json::from_value(serde_json::json!({
+            "command": command,
+            "coprocess": coprocess,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_classify() {
+        let path = Some(vec!["README".to_owned(), "writing".to_owned()]);
+        for coprocess in [false, true] {
+            let mut classifier = external(
+                "while read -r t; do printf 'writing\\tREADME\\n'; done",
+                coprocess,
+            );
+            assert_eq!(classifier.classify("notes.md"), path);
+
+            // A command which hangs is given up on, without caching that
+            let mut classifier = external("sleep 10", coprocess);
+            let start = std::time::Instant::now();
+            assert_eq!(classifier.classify("notes.md"), None);
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert!(classifier.cache.is_empty());
+            assert!(classifier.child.is_none());
+        }
+    }
+
+    #[test]
+    fn test_failure_backoff() {
+        crate::clock::set_manual(std::time::SystemTime::now());
+        let count =
+            std::env::temp_dir().join(format!("pomotoshi-{}-classified", std::process::id()));
+        let _ = std::fs::remove_file(&count);
+        let mut classifier = external(&format!("echo >> '{}'; sleep 10", count.display()), false);
+        let runs = || std::fs::read_to_string(&count).unwrap().lines().count();
+
+        // After timing out, the command is left alone for a while, whatever
+        // the title, and then tried again
+        assert_eq!(classifier.classify("notes.md"), None);
+        assert_eq!(runs(), 1);
+        assert_eq!(classifier.classify("other.md"), None);
+        assert_eq!(runs(), 1);
+        crate::clock::advance(FAILURE_BACKOFF);
+        assert_eq!(classifier.classify("other.md"), None);
+        assert_eq!(runs(), 2);
+        std::fs::remove_file(count).unwrap();
+    }
+}
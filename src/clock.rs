@@ -0,0 +1,197 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Clock
+//!
+//! The source of the current time for the state machine, which can be sped
+//! up for testing, so that color fades, flashes and transitions can be
+//! watched without waiting for a whole block, or started in the past, to
+//! replay a recording.
+//!
+
+#[cfg(test)]
+use std::cell::Cell;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A clock running faster than real time, or from a different starting time
+struct Scaled {
+    /// When the clock was set, in real monotonic time
+    start: Instant,
+    /// The wall-clock time the clock was set to
+    start_system: SystemTime,
+    scale: u32,
+}
+
+impl Scaled {
+    /// How much time has passed on the sped-up clock since it started
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed().saturating_mul(self.scale)
+    }
+}
+
+static SCALED: OnceLock<Scaled> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    /// A clock for tests, which stands still except when advanced, as the
+    /// monotonic and wall-clock times it shows
+    static MANUAL: Cell<Option<(Instant, SystemTime)>> = const { Cell::new(None) };
+}
+
+/// Stop the clock on this thread at the wall-clock time `start`, so that it
+/// only moves when `advance` is called
+#[cfg(test)]
+pub fn set_manual(start: SystemTime) {
+    MANUAL.set(Some((Instant::now(), start)));
+}
+
+/// Move the manual clock on this thread forward
+#[cfg(test)]
+pub fn advance(by: Duration) {
+    let (instant, system) = MANUAL.get().expect("the manual clock has been set");
+    MANUAL.set(Some((instant + by, system + by)));
+}
+
+/// Set the wall-clock time of the manual clock on this thread, leaving the
+/// monotonic time alone, as though the system clock had been changed
+#[cfg(test)]
+pub fn set_wall(to: SystemTime) {
+    let (instant, _) = MANUAL.get().expect("the manual clock has been set");
+    MANUAL.set(Some((instant, to)));
+}
+
+/// Make the clock run `scale` times faster than real time from now on,
+/// starting from the wall-clock time `start` if given
+///
+/// This can only be done once, before anything has read the clock, and
+/// fails if it has already been done.
+pub fn set(scale: u32, start: Option<SystemTime>) -> Result<(), &'static str> {
+    let scaled = Scaled {
+        start: Instant::now(),
+        start_system: start.unwrap_or_else(SystemTime::now),
+        scale,
+    };
+    SCALED
+        .set(scaled)
+        .map_err(|_| "the clock has already been set")
+}
+
+/// Whether the clock has been set, so that it does not show the real time
+pub fn is_simulated() -> bool {
+    SCALED.get().is_some()
+}
+
+/// The current monotonic time
+pub fn now() -> Instant {
+    #[cfg(test)]
+    if let Some((instant, _)) = MANUAL.get() {
+        return instant;
+    }
+    match SCALED.get() {
+        Some(scaled) => scaled.start + scaled.elapsed(),
+        None => Instant::now(),
+    }
+}
+
+/// The current wall-clock time
+pub fn system_now() -> SystemTime {
+    #[cfg(test)]
+    if let Some((_, system)) = MANUAL.get() {
+        return system;
+    }
+    match SCALED.get() {
+        Some(scaled) => scaled.start_system + scaled.elapsed(),
+        None => SystemTime::now(),
+    }
+}
+
+/// The current time, in seconds since the UNIX epoch
+pub fn unix_now() -> u64 {
+    system_now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The first time after `now` at which a countdown to `end_time` shows fewer
+/// whole `unit`s remaining (just after `end_time`, once there are none)
+pub fn next_tick(end_time: Instant, now: Instant, unit: Duration) -> Instant {
+    let remaining = end_time.saturating_duration_since(now);
+    let units = remaining.as_nanos() / unit.as_nanos().max(1);
+    // Units left, as shown, are rounded down, so they drop as soon as the
+    // time remaining is less than a whole number of them
+    let left = unit.saturating_mul(u32::try_from(units).unwrap_or(u32::MAX));
+    (end_time - left).max(now) + Duration::from_millis(1)
+}
+
+/// The real monotonic time at which the clock will show the given time,
+/// which differs from it if the clock has been sped up or set
+pub fn real_time(instant: Instant) -> Instant {
+    let remaining = instant.saturating_duration_since(now());
+    let scale = SCALED.get().map_or(1, |scaled| scaled.scale);
+    Instant::now() + remaining / scale
+}
+
+/// Wait until the given (monotonic) time on the clock
+pub async fn sleep_until(instant: Instant) {
+    tokio::time::sleep_until(real_time(instant).into()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        set_manual(start);
+        let instant = now();
+        assert_eq!(unix_now(), 1_000_000);
+
+        // The manual clock only moves when it is told to
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(now(), instant);
+        // so times on it are reached after the same time in reality
+        let before = Instant::now();
+        let real = real_time(instant + Duration::from_secs(5));
+        assert!(before + Duration::from_secs(5) <= real);
+        assert!(real <= Instant::now() + Duration::from_secs(5));
+        advance(Duration::from_millis(1500));
+        assert_eq!(now(), instant + Duration::from_millis(1500));
+        assert_eq!(unix_now(), 1_000_001);
+
+        // and the wall clock can jump without the monotonic one
+        set_wall(start);
+        assert_eq!(now(), instant + Duration::from_millis(1500));
+        assert_eq!(system_now(), start);
+    }
+
+    #[test]
+    fn test_next_tick() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        // 2.5s left shows as 2, which drops to 1 when 1.999s are left
+        assert_eq!(next_tick(now + ms(2500), now, second), now + ms(501));
+        // On a whole second, it drops straight away
+        assert_eq!(next_tick(now + ms(2000), now, second), now + ms(1));
+        // Once time is up, the tick is just after now
+        assert_eq!(next_tick(now, now, second), now + ms(1));
+        assert_eq!(next_tick(now, now + second, second), now + second + ms(1));
+        // Minutes work the same way
+        let minute = Duration::from_secs(60);
+        assert_eq!(next_tick(now + ms(90_000), now, minute), now + ms(30_001));
+    }
+}
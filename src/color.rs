@@ -17,21 +17,336 @@
 //! Color fading support
 //!
 
+use serde::{Deserialize, Serialize};
+
+/// Parse a color given as `#rgb` or `#rrggbb`
+pub fn parse_hex(s: &str) -> Option<(u8, u8, u8)> {
+    let hex = s.strip_prefix('#')?;
+    if !hex.is_ascii() {
+        return None;
+    }
+    let digit = |i: usize, len: usize| u8::from_str_radix(&hex[i * len..(i + 1) * len], 16).ok();
+    match hex.len() {
+        3 => Some((digit(0, 1)? * 17, digit(1, 1)? * 17, digit(2, 1)? * 17)),
+        6 => Some((digit(0, 2)?, digit(1, 2)?, digit(2, 2)?)),
+        _ => None,
+    }
+}
+
+/// Format a color as `#rrggbb`
+pub fn hex(col: (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", col.0, col.1, col.2)
+}
+
+/// The color space in which a fade is interpolated
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Space {
+    /// Straight line between the RGB values
+    #[default]
+    Rgb,
+    /// Around the shorter way of the hue circle, keeping saturation and value
+    Hsv,
+    /// Around the shorter way of the OKLCH hue circle, which keeps perceived
+    /// lightness even
+    Oklch,
+}
+
+/// How quickly a fade moves away from its initial color
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    /// At a constant rate
+    Linear,
+    /// Quickly at first, then slowing down
+    #[default]
+    Quadratic,
+    /// Very quickly at first, then barely moving near the end
+    Exponential,
+}
+
+impl Easing {
+    /// Map the fraction of the fade which has elapsed to how far between
+    /// the colors we should be
+    fn apply(self, lam: f64) -> f64 {
+        match self {
+            Easing::Linear => lam,
+            Easing::Quadratic => 1.0 - (1.0 - lam).powi(2),
+            Easing::Exponential => (1.0 - (-10.0 * lam).exp2()) / (1.0 - (-10.0f64).exp2()),
+        }
+    }
+}
+
+/// A color used in place of fading while enough of the time remains
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Band {
+    /// Percentage of the time which must remain for this band to apply
+    pub above: u8,
+    pub color: (u8, u8, u8),
+}
+
+/// How to fade between two colors
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Fade {
+    #[serde(default)]
+    pub space: Space,
+    #[serde(default)]
+    pub easing: Easing,
+    /// If nonempty, step between these colors instead of fading
+    #[serde(default)]
+    pub bands: Vec<Band>,
+}
+
+/// Interpolate between two angles in degrees, the shorter way around
+fn lerp_hue(h0: f64, h1: f64, lam: f64) -> f64 {
+    let mut diff = h1 - h0;
+    if diff > 180.0 {
+        diff -= 360.0;
+    } else if diff < -180.0 {
+        diff += 360.0;
+    }
+    (h0 + diff * lam).rem_euclid(360.0)
+}
+
+fn lerp(a: f64, b: f64, lam: f64) -> f64 {
+    a * (1.0 - lam) + b * lam
+}
+
+/// Convert an RGB triple (with components in [0, 1]) to HSV, with hue in degrees
+fn rgb_to_hsv((r, g, b): (f64, f64, f64)) -> (f64, f64, f64) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    (h, s, max)
+}
+
+fn hsv_to_rgb((h, s, v): (f64, f64, f64)) -> (f64, f64, f64) {
+    let c = v * s;
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = v - c;
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    (r + m, g + m, b + m)
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Convert an RGB triple (with components in [0, 1]) to OKLCH, with hue in degrees
+fn rgb_to_oklch((r, g, b): (f64, f64, f64)) -> (f64, f64, f64) {
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    let ok_l = 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s;
+    let ok_a = 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s;
+    let ok_b = 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s;
+    (
+        ok_l,
+        ok_a.hypot(ok_b),
+        ok_b.atan2(ok_a).to_degrees().rem_euclid(360.0),
+    )
+}
+
+fn oklch_to_rgb((ok_l, c, h): (f64, f64, f64)) -> (f64, f64, f64) {
+    let ok_a = c * h.to_radians().cos();
+    let ok_b = c * h.to_radians().sin();
+
+    let l = (ok_l + 0.3963377774 * ok_a + 0.2158037573 * ok_b).powi(3);
+    let m = (ok_l - 0.1055613458 * ok_a - 0.0638541728 * ok_b).powi(3);
+    let s = (ok_l - 0.0894841775 * ok_a - 1.2914855480 * ok_b).powi(3);
+    (
+        linear_to_srgb(4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+        linear_to_srgb(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+        linear_to_srgb(-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s),
+    )
+}
+
+/// Interpolate between two colors, with `lam` going from 0 (the initial
+/// color) to 1 (the final color)
+fn interpolate(
+    initial_col: (u8, u8, u8),
+    final_col: (u8, u8, u8),
+    lam: f64,
+    space: Space,
+) -> (u8, u8, u8) {
+    let unit = |col: (u8, u8, u8)| {
+        (
+            col.0 as f64 / 255.0,
+            col.1 as f64 / 255.0,
+            col.2 as f64 / 255.0,
+        )
+    };
+    let (a, b) = (unit(initial_col), unit(final_col));
+
+    let (r, g, b) = match space {
+        Space::Rgb => (
+            lerp(a.0, b.0, lam),
+            lerp(a.1, b.1, lam),
+            lerp(a.2, b.2, lam),
+        ),
+        Space::Hsv => {
+            let (a, b) = (rgb_to_hsv(a), rgb_to_hsv(b));
+            // The hue of a grey is meaningless, so take the other color's
+            let (ha, hb) = match (a.1 == 0.0, b.1 == 0.0) {
+                (true, false) => (b.0, b.0),
+                (false, true) => (a.0, a.0),
+                _ => (a.0, b.0),
+            };
+            hsv_to_rgb((
+                lerp_hue(ha, hb, lam),
+                lerp(a.1, b.1, lam),
+                lerp(a.2, b.2, lam),
+            ))
+        }
+        Space::Oklch => {
+            let (a, b) = (rgb_to_oklch(a), rgb_to_oklch(b));
+            oklch_to_rgb((
+                lerp(a.0, b.0, lam),
+                lerp(a.1, b.1, lam),
+                lerp_hue(a.2, b.2, lam),
+            ))
+        }
+    };
+    // OKLCH midpoints may fall outside of the RGB gamut, so clamp
+    let byte = |x: f64| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+    (byte(r), byte(g), byte(b))
+}
+
 pub fn fade_between(
     initial_col: (u8, u8, u8),
     final_col: (u8, u8, u8),
     duration: std::time::Duration,
     total_duration: std::time::Duration,
+    fade: &Fade,
 ) -> String {
     let lam = (duration.as_micros() as f64) / (total_duration.as_micros() as f64);
+    if !fade.bands.is_empty() {
+        // Use the highest band we're above, or the initial color if none
+        let percent = lam * 100.0;
+        let band = fade
+            .bands
+            .iter()
+            .filter(|band| percent > band.above as f64)
+            .max_by_key(|band| band.above);
+        return hex(band.map(|band| band.color).unwrap_or(initial_col));
+    }
+    let lam = fade.easing.apply(lam.clamp(0.0, 1.0));
+    hex(interpolate(initial_col, final_col, lam, fade.space))
+}
 
-    let lam = 1.0 - (1.0 - lam).powi(2); // make fade more extreme near the start
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let blend_r = (initial_col.0 as f64) * (1.0 - lam) + (final_col.0 as f64) * lam;
-    let blend_g = (initial_col.1 as f64) * (1.0 - lam) + (final_col.1 as f64) * lam;
-    let blend_b = (initial_col.2 as f64) * (1.0 - lam) + (final_col.2 as f64) * lam;
-    format!(
-        "#{:02x}{:02x}{:02x}",
-        blend_r as u8, blend_g as u8, blend_b as u8
-    )
+    const GREEN: (u8, u8, u8) = (0, 255, 0);
+    const RED: (u8, u8, u8) = (255, 0, 0);
+
+    #[test]
+    fn test_endpoints() {
+        for space in [Space::Rgb, Space::Hsv, Space::Oklch] {
+            for col in [GREEN, RED, (255, 192, 0), (192, 44, 44), (170, 170, 170)] {
+                assert_eq!(interpolate(col, RED, 0.0, space), col, "{:?}", space);
+                assert_eq!(interpolate(GREEN, col, 1.0, space), col, "{:?}", space);
+            }
+        }
+        for easing in [Easing::Linear, Easing::Quadratic, Easing::Exponential] {
+            assert!(easing.apply(0.0).abs() < 1e-12);
+            assert!((easing.apply(1.0) - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_midpoints() {
+        assert_eq!(interpolate(GREEN, RED, 0.5, Space::Rgb), (128, 128, 0));
+        assert_eq!(interpolate(GREEN, RED, 0.5, Space::Hsv), (255, 255, 0));
+        // White takes on blue's hue rather than passing through magenta
+        assert_eq!(
+            interpolate((255, 255, 255), (0, 0, 255), 0.5, Space::Hsv),
+            (128, 128, 255)
+        );
+        // Perceptually, the midpoint is lighter than the RGB one and
+        // shifted towards orange
+        assert_eq!(interpolate(GREEN, RED, 0.5, Space::Oklch), (249, 149, 0));
+
+        assert_eq!(Easing::Linear.apply(0.5), 0.5);
+        assert_eq!(Easing::Quadratic.apply(0.5), 0.75);
+        assert!((Easing::Exponential.apply(0.5) - 0.96970).abs() < 1e-5);
+
+        let fade = Fade {
+            space: Space::Rgb,
+            easing: Easing::Linear,
+            bands: vec![],
+        };
+        let half = std::time::Duration::from_secs(30);
+        let full = std::time::Duration::from_secs(60);
+        assert_eq!(fade_between(GREEN, RED, half, full, &fade), "#808000");
+        assert_eq!(
+            fade_between(GREEN, RED, half, full, &Fade::default()),
+            "#bf4000"
+        );
+    }
+
+    #[test]
+    fn test_bands() {
+        let fade = Fade {
+            bands: vec![
+                Band {
+                    above: 20,
+                    color: (255, 255, 0),
+                },
+                Band {
+                    above: 50,
+                    color: GREEN,
+                },
+            ],
+            ..Fade::default()
+        };
+        let full = std::time::Duration::from_secs(100);
+        let at = |secs| {
+            fade_between(
+                RED,
+                GREEN,
+                std::time::Duration::from_secs(secs),
+                full,
+                &fade,
+            )
+        };
+        assert_eq!(at(100), "#00ff00");
+        assert_eq!(at(51), "#00ff00");
+        assert_eq!(at(50), "#ffff00");
+        assert_eq!(at(21), "#ffff00");
+        assert_eq!(at(20), "#ff0000");
+        assert_eq!(at(0), "#ff0000");
+    }
 }
@@ -0,0 +1,386 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Control Socket
+//!
+//! An alternative to D-Bus for machines without a session bus: a Unix-domain
+//! socket speaking newline-delimited JSON-RPC 2.0. The same API can also be
+//! served over TCP, e.g. to control the timer from another machine.
+//!
+
+use crate::access::secrets_equal;
+use crate::handle::Handle;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use std::{fs, io};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+
+/// JSON-RPC error code for unparseable requests
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code for unknown methods
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code for bad method parameters
+const INVALID_PARAMS: i64 = -32602;
+/// JSON-RPC error code for requests refused by the server
+const REFUSED: i64 = -32000;
+
+/// How long to wait before restarting `openssl s_server` if it fails
+const TLS_RESTART_DELAY: Duration = Duration::from_secs(5);
+/// Script running `openssl s_server` with the given arguments, after the
+/// daemon's PID, which is killed if the daemon exits so that it doesn't hold
+/// on to the port
+const TLS_SCRIPT: &str = r#"
+pid=$1
+shift
+# Background commands get /dev/null as stdin unless it is redirected from
+# another descriptor
+exec 3<&0
+openssl s_server "$@" <&3 3<&- &
+server=$!
+while kill -0 "$pid" 2>/dev/null && kill -0 "$server" 2>/dev/null; do
+    sleep 1
+done
+kill "$server" 2>/dev/null
+wait "$server"
+"#;
+
+/// Configuration for serving the control API over TCP
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TcpConfig {
+    /// Address to listen on, e.g. "0.0.0.0:7879"
+    listen: String,
+    /// Secret which clients must give, by calling `auth`, before anything else
+    token: String,
+    /// Certificate and key to serve TLS with, if any
+    #[serde(default)]
+    tls: Option<Tls>,
+}
+
+/// PEM files for serving TLS (using `openssl s_server`)
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// A JSON-RPC request
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Requests without an id are notifications, which get no response
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+/// Bind a Unix-domain socket, replacing any stale socket file, but failing
+/// if something (e.g. another instance of the daemon) is listening on it
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("something is already listening on {}", path.display()),
+            ))
+        }
+        // Nobody is listening, so the file (if any) is left over from a
+        // daemon which exited without removing it
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Accept and serve clients forever
+pub async fn serve(listener: UnixListener, handle: Handle) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+            let (read, write) = stream.into_split();
+            tokio::spawn(serve_client(read, write, handle.clone(), None, uid));
+        }
+    }
+}
+
+/// Start serving the control API over TCP
+///
+/// Without TLS the address is bound immediately, so this fails if it cannot
+/// be. With TLS, `openssl s_server` is run for each client in turn, so only
+/// one may be connected at a time.
+pub async fn serve_tcp(config: TcpConfig, handle: Handle) -> io::Result<()> {
+    let tls = match config.tls {
+        Some(ref tls) => tls.clone(),
+        None => {
+            let listener = TcpListener::bind(&config.listen).await?;
+            tokio::spawn(async move {
+                loop {
+                    if let Ok((stream, _)) = listener.accept().await {
+                        let (read, write) = stream.into_split();
+                        let token = Some(config.token.clone());
+                        tokio::spawn(serve_client(read, write, handle.clone(), token, None));
+                    }
+                }
+            });
+            return Ok(());
+        }
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let child = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(TLS_SCRIPT)
+                .arg("sh")
+                .arg(std::process::id().to_string())
+                .arg("-quiet")
+                .arg("-naccept")
+                .arg("1")
+                .arg("-accept")
+                .arg(&config.listen)
+                .arg("-cert")
+                .arg(&tls.cert)
+                .arg("-key")
+                .arg(&tls.key)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    handle
+                        .lock()
+                        .signal_error(&format!("running openssl s_server: {}", e));
+                    tokio::time::sleep(TLS_RESTART_DELAY).await;
+                    continue;
+                }
+            };
+            if let (Some(write), Some(read)) = (child.stdin.take(), child.stdout.take()) {
+                let token = Some(config.token.clone());
+                serve_client(read, write, handle.clone(), token, None).await;
+            }
+            match child.wait_with_output().await {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => {
+                    let message = format!(
+                        "openssl s_server failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                    handle.lock().signal_error(&message);
+                    tokio::time::sleep(TLS_RESTART_DELAY).await;
+                }
+                Err(e) => {
+                    handle
+                        .lock()
+                        .signal_error(&format!("running openssl s_server: {}", e));
+                    tokio::time::sleep(TLS_RESTART_DELAY).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Serve a single client, one request per line, until it disconnects
+///
+/// If `token` is set, every call but `auth` is refused until `auth` has been
+/// called with it. `uid` is the client's user ID, if known, for access control.
+async fn serve_client<R, W>(
+    read: R,
+    mut write: W,
+    handle: Handle,
+    token: Option<String>,
+    uid: Option<u32>,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut authenticated = token.is_none();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = respond(&handle, &line, token.as_deref(), uid, &mut authenticated);
+        if let Some(response) = response {
+            let mut response = response.to_string();
+            response.push('\n');
+            if write.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Handle a single request, returning the response to send (if any)
+fn respond(
+    handle: &Handle,
+    line: &str,
+    token: Option<&str>,
+    uid: Option<u32>,
+    authenticated: &mut bool,
+) -> Option<Value> {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, &e.to_string())),
+    };
+    let result = match (request.method.as_str(), token) {
+        ("auth", None) => Ok(Value::Bool(true)),
+        ("auth", Some(expected)) => {
+            let given = request.params.get("token").and_then(Value::as_str);
+            *authenticated = given.is_some_and(|given| secrets_equal(given, expected));
+            match *authenticated {
+                true => Ok(Value::Bool(true)),
+                false => Err((REFUSED, "incorrect token".to_owned())),
+            }
+        }
+        _ if !*authenticated => Err((REFUSED, "call auth first".to_owned())),
+        (method, _) => call(handle, method, &request.params, uid),
+    };
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err((code, message)) => error(id, code, &message),
+    })
+}
+
+/// Call a method on the server
+///
+/// Parameters are passed by name, e.g. `{"time_s": 1500, "label": "review"}`
+/// for `start`. Destructive methods take the access control token, if one is
+/// configured, as `token`.
+fn call(
+    handle: &Handle,
+    method: &str,
+    params: &Value,
+    uid: Option<u32>,
+) -> Result<Value, (i64, String)> {
+    let mutating = matches!(method, "start" | "pause" | "cancel");
+    if mutating && !handle.lock().allow_call(method) {
+        return Err((REFUSED, format!("{} called too often", method)));
+    }
+    match method {
+        "start" => {
+            let time_s = params
+                .get("time_s")
+                .and_then(Value::as_u64)
+                .ok_or((INVALID_PARAMS, "expected integer param time_s".to_owned()))?;
+            let label = params
+                .get("label")
+                .and_then(Value::as_str)
+                .map(str::to_owned);
+            let limit = handle.with_server(|server| {
+                let limit = server.daily_limit_reached();
+                server.start_block(time_s, label);
+                limit
+            });
+            match limit {
+                Some(reason) => Err((REFUSED, reason)),
+                None => Ok(Value::Null),
+            }
+        }
+        "pause" => {
+            handle.with_server(|server| server.pause_block());
+            Ok(Value::Null)
+        }
+        "cancel" => {
+            let force = params
+                .get("force")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            let token = params.get("token").and_then(Value::as_str);
+            handle
+                .with_server(|server| {
+                    server.check_access(uid, token)?;
+                    server.try_cancel_block(force)
+                })
+                .map(|()| Value::Null)
+                .map_err(|reason| (REFUSED, reason))
+        }
+        "status" => Ok(json!(handle.lock().status())),
+        "dump" => {
+            let name = params
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or((INVALID_PARAMS, "expected string param name".to_owned()))?;
+            Ok(json!(
+                handle.with_server(|server| server.task_log_dump(name))
+            ))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    }
+}
+
+/// Construct a JSON-RPC error response
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn test_cancel_needs_token() {
+        let mut config = serde_json::to_value(Server::new()).unwrap();
+        config["access_control"] = json!({ "token": "sesame" });
+        let handle = Handle::new(serde_json::from_value(config).unwrap());
+        let mut authenticated = true;
+        let mut call = |request: Value| {
+            let line = request.to_string();
+            respond(&handle, &line, None, None, &mut authenticated).unwrap()
+        };
+
+        call(json!({ "method": "start", "params": { "time_s": 60 }, "id": 1 }));
+        let refused = call(json!({ "method": "cancel", "params": { "force": true }, "id": 2 }));
+        assert_eq!(
+            refused["error"]["message"],
+            "a confirmation token is required"
+        );
+        let params = json!({ "force": true, "token": "open" });
+        let refused = call(json!({ "method": "cancel", "params": params, "id": 3 }));
+        assert_eq!(refused["error"]["message"], "incorrect confirmation token");
+        let status = call(json!({ "method": "status", "id": 4 }));
+        assert_eq!(status["result"]["state"], "block");
+
+        let params = json!({ "force": true, "token": "sesame" });
+        let cancelled = call(json!({ "method": "cancel", "params": params, "id": 5 }));
+        assert_eq!(cancelled["result"], Value::Null);
+        let status = call(json!({ "method": "status", "id": 6 }));
+        assert_eq!(status["result"]["state"], "idle");
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let path = std::env::temp_dir().join(format!("pomotoshi-{}-bind.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = bind(&path).unwrap();
+        // A live socket is not stolen
+        let err = bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        // but a stale one is replaced
+        drop(listener);
+        assert!(path.exists());
+        bind(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
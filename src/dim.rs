@@ -0,0 +1,256 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Screen Dimming
+//!
+//! Dimming the screen, or shifting it towards red, during cooldowns, as a
+//! stronger nudge than the status bar color to actually take the break
+//!
+
+use crate::handle::Handle;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::{env, fs, io};
+
+/// How often the timer state is checked
+const POLL_FREQ: std::time::Duration = std::time::Duration::from_secs(1);
+
+fn default_brightness() -> f64 { 0.5 }
+fn default_gamma() -> String { "1:1:1".into() }
+fn default_temperature() -> u32 { 3000 }
+
+/// How the screen is dimmed
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Config {
+    /// Using `xrandr`, on the given outputs (e.g. "eDP-1")
+    Xrandr {
+        outputs: Vec<String>,
+        #[serde(default = "default_brightness")]
+        brightness: f64,
+        /// Red, green and blue gamma, e.g. "1:0.8:0.6"
+        #[serde(default = "default_gamma")]
+        gamma: String,
+    },
+    /// Using `gammastep`, at the given color temperature in Kelvin
+    Gammastep {
+        #[serde(default = "default_temperature")]
+        temperature: u32,
+        #[serde(default = "default_brightness")]
+        brightness: f64,
+    },
+}
+
+impl Config {
+    /// The commands which dim the screen, or restore it if `dim` is false
+    fn commands(&self, dim: bool) -> Vec<Vec<String>> {
+        match *self {
+            Config::Xrandr {
+                ref outputs,
+                brightness,
+                ref gamma,
+            } => outputs
+                .iter()
+                .map(|output| {
+                    let (brightness, gamma) = if dim {
+                        (brightness.to_string(), gamma.clone())
+                    } else {
+                        ("1".into(), "1:1:1".into())
+                    };
+                    vec![
+                        "xrandr".into(),
+                        "--output".into(),
+                        output.clone(),
+                        "--brightness".into(),
+                        brightness,
+                        "--gamma".into(),
+                        gamma,
+                    ]
+                })
+                .collect(),
+            Config::Gammastep {
+                temperature,
+                brightness,
+            } if dim => vec![vec![
+                "gammastep".into(),
+                "-P".into(),
+                "-O".into(),
+                temperature.to_string(),
+                "-b".into(),
+                brightness.to_string(),
+            ]],
+            Config::Gammastep { .. } => vec![vec!["gammastep".into(), "-x".into()]],
+        }
+    }
+
+    /// Dim the screen, or restore it if `dim` is false
+    async fn apply(&self, dim: bool) -> io::Result<()> {
+        for command in self.commands(dim) {
+            let output = tokio::process::Command::new(&command[0])
+                .args(&command[1..])
+                .stdin(Stdio::null())
+                .output()
+                .await?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed: {}",
+                    command.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim(),
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// File recording how the screen was dimmed, while it is, so that a later
+/// run can restore it if this one exits during a cooldown
+///
+/// Without a runtime directory there is no marker, and the screen is not
+/// restored at startup.
+fn marker_file() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("pomotoshi-dim"))
+}
+
+/// Record how the screen is about to be dimmed
+fn write_marker(path: &Path, config: &Config) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec(config)?)
+}
+
+/// How the screen was dimmed, according to the marker, if there is one
+fn read_marker(path: &Path) -> io::Result<Option<Config>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove the marker, once the screen has been restored
+fn remove_marker(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Restore the screen, and remove the marker if that worked
+async fn restore(handle: &Handle, config: &Config, marker: Option<&Path>) {
+    let result = config.apply(false).await;
+    if let Err(e) = result.and_then(|_| marker.map_or(Ok(()), remove_marker)) {
+        handle
+            .lock()
+            .signal_error(&format!("restoring screen: {}", e));
+    }
+}
+
+/// Dim the screen during cooldowns, forever
+///
+/// The config is looked up each second, so that it can be changed by
+/// reloading. If a previous run exited while the screen was dimmed, as
+/// recorded by the marker file, the screen is restored at startup.
+pub async fn run(handle: Handle) {
+    let marker = marker_file();
+    if let Some(ref path) = marker {
+        let config = match read_marker(path) {
+            Ok(config) => config,
+            Err(e) => {
+                handle
+                    .lock()
+                    .signal_error(&format!("reading {}: {}", path.display(), e));
+                // The screen was dimmed somehow, so restore it as best we can
+                handle.lock().screen_dimming().cloned()
+            }
+        };
+        match config {
+            Some(config) => {
+                tracing::info!("restoring screen dimmed by a previous run");
+                restore(&handle, &config, Some(path)).await;
+            }
+            // A bad marker with nothing to restore the screen with is no use
+            None => {
+                let _ = remove_marker(path);
+            }
+        }
+    }
+
+    // The config used to dim the screen, which is also used to restore it
+    // in case it has since changed
+    let mut dimmed: Option<Config> = None;
+    let mut interval = tokio::time::interval(POLL_FREQ);
+    loop {
+        interval.tick().await;
+        let (in_cooldown, config) = {
+            let lock = handle.lock();
+            (lock.in_cooldown(), lock.screen_dimming().cloned())
+        };
+        match (&dimmed, in_cooldown, config) {
+            (None, true, Some(config)) => {
+                tracing::info!("dimming screen");
+                // The marker is written first, so that the screen is restored
+                // even if we exit part way through dimming it
+                if let Some(ref path) = marker {
+                    if let Err(e) = write_marker(path, &config) {
+                        handle
+                            .lock()
+                            .signal_error(&format!("writing {}: {}", path.display(), e));
+                    }
+                }
+                if let Err(e) = config.apply(true).await {
+                    handle
+                        .lock()
+                        .signal_error(&format!("dimming screen: {}", e));
+                }
+                dimmed = Some(config);
+            }
+            (Some(config), false, _) | (Some(config), _, None) => {
+                tracing::info!("restoring screen");
+                restore(&handle, config, marker.as_deref()).await;
+                dimmed = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker() {
+        let path = env::temp_dir().join(format!("pomotoshi-{}-dim", std::process::id()));
+        let config = Config::Gammastep {
+            temperature: 3000,
+            brightness: 0.5,
+        };
+
+        // Without a marker, there is nothing to restore
+        remove_marker(&path).unwrap();
+        assert_eq!(read_marker(&path).unwrap(), None);
+
+        write_marker(&path, &config).unwrap();
+        assert_eq!(read_marker(&path).unwrap(), Some(config));
+        remove_marker(&path).unwrap();
+        assert_eq!(read_marker(&path).unwrap(), None);
+
+        // A marker which cannot be read still needs restoring somehow
+        fs::write(&path, "garbage").unwrap();
+        assert!(read_marker(&path).is_err());
+        remove_marker(&path).unwrap();
+    }
+}
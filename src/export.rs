@@ -0,0 +1,137 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Time Export
+//!
+//! Periodic export of completed blocks to other time-tracking tools, namely
+//! Timewarrior and ledger's timeclock format
+//!
+
+use crate::history::Block;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Command;
+
+fn default_interval_s() -> u64 { 3600 }
+
+/// Where to export blocks to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Run `timew track` for each block
+    Timewarrior,
+    /// Append `i`/`o` entries to a timeclock file, as read by ledger
+    Timeclock(PathBuf),
+}
+
+/// Configuration of the exporter
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    format: Format,
+    /// How often to export newly-completed blocks, in seconds
+    #[serde(default = "default_interval_s")]
+    interval_s: u64,
+}
+
+impl Config {
+    /// How often to export newly-completed blocks, which is at most once a
+    /// second
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval_s.max(1))
+    }
+
+    /// Export a set of completed blocks
+    ///
+    /// This is blocking, since it runs external commands and writes files.
+    pub fn export(&self, blocks: &[Block]) -> io::Result<()> {
+        match self.format {
+            Format::Timewarrior => {
+                for block in blocks {
+                    timewarrior_track(block)?;
+                }
+                Ok(())
+            }
+            Format::Timeclock(ref path) => {
+                let mut entries = String::new();
+                for block in blocks {
+                    entries += &timeclock_entry(block)?;
+                }
+                let mut fh = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                fh.write_all(entries.as_bytes())
+            }
+        }
+    }
+}
+
+/// The label of a block, or a placeholder if it has none
+fn label(block: &Block) -> &str {
+    block.label.as_deref().unwrap_or("pomodoro")
+}
+
+/// Run `timew track` for a block, tagging it with its label and top task
+fn timewarrior_track(block: &Block) -> io::Result<()> {
+    let mut command = Command::new("timew");
+    command
+        .arg("track")
+        .arg(utc_time(block.start_s)?)
+        .arg("-")
+        .arg(utc_time(block.end_s)?)
+        .arg(label(block));
+    if let Some(root) = block.tasks.top_path().first() {
+        command.arg(root);
+    }
+    command.arg(":quiet");
+    let status = command.output()?.status;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("timew exited with {}", status)))
+    }
+}
+
+/// Format a timeclock entry for a block, with its label as the account and
+/// its top task path as the payee
+fn timeclock_entry(block: &Block) -> io::Result<String> {
+    let account = label(block).replace(char::is_whitespace, "_");
+    let payee = block.tasks.top_path().join(" / ");
+    let clock_in = format!("i {} {}  {}", local_time(block.start_s)?, account, payee);
+    Ok(format!(
+        "{}\no {}\n",
+        clock_in.trim_end(),
+        local_time(block.end_s)?
+    ))
+}
+
+/// Format a UNIX timestamp in UTC, as Timewarrior stores times
+fn utc_time(unix_s: u64) -> io::Result<String> {
+    let format = time::macros::format_description!("[year][month][day]T[hour][minute][second]Z");
+    time::OffsetDateTime::from_unix_timestamp(unix_s as i64)
+        .map_err(io::Error::other)?
+        .format(&format)
+        .map_err(io::Error::other)
+}
+
+/// Format a UNIX timestamp in local time, as timeclock expects
+fn local_time(unix_s: u64) -> io::Result<String> {
+    let output = Command::new("date")
+        .arg("-d")
+        .arg(format!("@{}", unix_s))
+        .arg("+%Y/%m/%d %H:%M:%S")
+        .output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
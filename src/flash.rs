@@ -0,0 +1,79 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Flashing
+//!
+//! Configuration of the background flashes used to signal warnings and errors
+//!
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How a flash looks
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Style {
+    /// Background color while the flash is lit, in any format xmobar accepts
+    pub color: String,
+    /// How long the flash is lit for, and then unlit for, in each blink
+    blink_ms: u64,
+    /// How long the flash lasts
+    duration_ms: u64,
+}
+
+impl Style {
+    /// The default style of warnings
+    pub fn default_warn() -> Style {
+        Style {
+            color: "#FF0".into(),
+            blink_ms: 100,
+            duration_ms: 500,
+        }
+    }
+
+    /// The default style of errors
+    pub fn default_error() -> Style {
+        Style {
+            color: "#F00".into(),
+            blink_ms: 100,
+            duration_ms: 700,
+        }
+    }
+
+    /// How long the flash lasts
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// How long a single blink lasts
+    pub fn blink(&self) -> Duration {
+        Duration::from_millis(self.blink_ms)
+    }
+
+    /// Whether a flash which ends at `end_time` is lit at time `now`
+    ///
+    /// Flashes are lit for their final blink, and alternately unlit and lit
+    /// before that.
+    pub fn is_lit(&self, end_time: Instant, now: Instant) -> bool {
+        let remaining = end_time.saturating_duration_since(now);
+        let blinks = remaining.as_millis() / u128::from(self.blink_ms.max(1));
+        !remaining.is_zero() && blinks.is_multiple_of(2)
+    }
+
+    /// When a flash which ends at `end_time` next turns on or off, or `None`
+    /// if it is over
+    pub fn next_change(&self, end_time: Instant, now: Instant) -> Option<Instant> {
+        let blink = self.blink().max(Duration::from_millis(1));
+        (now < end_time).then(|| crate::clock::next_tick(end_time, now, blink))
+    }
+}
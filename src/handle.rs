@@ -0,0 +1,117 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Handle
+//!
+//! A shared handle to the server, used by the daemon's various tasks
+//!
+
+use crate::server::{Server, Status, Transition};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{broadcast, watch, Notify};
+
+/// Number of state transitions which may be queued for a slow subscriber
+const EVENT_QUEUE_SIZE: usize = 64;
+
+/// A cloneable handle to the server
+#[derive(Clone, Debug)]
+pub struct Handle {
+    server: Arc<Mutex<Server>>,
+    /// Notified whenever a client calls into the server
+    changed: Arc<Notify>,
+    /// The most recently published status
+    status: Arc<watch::Sender<Status>>,
+    /// The most recently rendered status line
+    line: Arc<watch::Sender<String>>,
+    /// State transitions, as they are taken from the server
+    events: broadcast::Sender<Transition>,
+}
+
+impl Handle {
+    /// Wrap a server in a new handle
+    pub fn new(server: Server) -> Handle {
+        let status = server.status();
+        Handle {
+            server: Arc::new(Mutex::new(server)),
+            changed: Arc::new(Notify::new()),
+            status: Arc::new(watch::channel(status).0),
+            line: Arc::new(watch::channel(String::new()).0),
+            events: broadcast::channel(EVENT_QUEUE_SIZE).0,
+        }
+    }
+
+    /// Lock the server, e.g. for rendering or bookkeeping
+    pub fn lock(&self) -> MutexGuard<'_, Server> {
+        self.server.lock().expect("server did not witness a panic")
+    }
+
+    /// Call a function on the server on behalf of a client, then wake up the
+    /// render task so that any change is shown immediately
+    pub fn with_server<T>(&self, f: impl FnOnce(&mut Server) -> T) -> T {
+        tracing::info!("client call");
+        let ret = f(&mut self.lock());
+        self.changed.notify_one();
+        ret
+    }
+
+    /// Wait until a client calls into the server
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// Publish the server's status to subscribers, if it has changed
+    pub fn publish_status(&self, status: Status) {
+        self.status.send_if_modified(|old| {
+            if *old == status {
+                false
+            } else {
+                *old = status;
+                true
+            }
+        });
+    }
+
+    /// Subscribe to changes in the server's status
+    pub fn subscribe_status(&self) -> watch::Receiver<Status> {
+        self.status.subscribe()
+    }
+
+    /// Publish the rendered status line to subscribers, if it has changed
+    pub fn publish_line(&self, line: &str) {
+        self.line.send_if_modified(|old| {
+            if old == line {
+                false
+            } else {
+                *old = line.to_owned();
+                true
+            }
+        });
+    }
+
+    /// Subscribe to changes in the rendered status line
+    pub fn subscribe_line(&self) -> watch::Receiver<String> {
+        self.line.subscribe()
+    }
+
+    /// Publish a state transition to subscribers
+    pub fn publish_event(&self, event: Transition) {
+        // It is fine for there to be no subscribers
+        let _ = self.events.send(event);
+    }
+
+    /// Subscribe to state transitions
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Transition> {
+        self.events.subscribe()
+    }
+}
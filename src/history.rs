@@ -0,0 +1,438 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! History
+//!
+//! Records of past blocks, including what was worked on during them
+//!
+
+use crate::task::Task;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// The current time, in seconds since the UNIX epoch
+pub fn unix_now() -> u64 {
+    crate::clock::unix_now()
+}
+
+/// Run `date` to format a time given in any form it understands, e.g.
+/// `@1700000000` or `2024-03-10 +1 day`, in the given timezone if any and
+/// otherwise the local one, returning `None` if it fails
+fn date(tz: Option<&str>, time: &str, format: &str) -> Option<String> {
+    let mut command = Command::new("date");
+    if let Some(tz) = tz {
+        command.env("TZ", tz);
+    }
+    let output = command
+        .arg("-d")
+        .arg(time)
+        .arg(format!("+{}", format))
+        .output()
+        .ok()?;
+    let formatted = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if formatted.is_empty() {
+        None
+    } else {
+        Some(formatted)
+    }
+}
+
+/// A time formatted in the local timezone by `date`, or `None` if it could
+/// not be
+fn local_format(unix_s: u64, format: &str) -> Option<String> {
+    date(None, &format!("@{}", unix_s), format)
+}
+
+/// The local date of a time, as `YYYY-MM-DD`, or `None` if it could not be
+/// determined
+pub fn local_date(unix_s: u64) -> Option<String> {
+    local_format(unix_s, "%F")
+}
+
+/// The local time of day of a time, as `HH:MM`, or `None` if it could not be
+/// determined
+pub fn local_clock(unix_s: u64) -> Option<String> {
+    local_format(unix_s, "%H:%M")
+}
+
+/// The start and end of the local day containing a time, in seconds since
+/// the UNIX epoch, or `None` if they could not be determined
+///
+/// Days start `start_minute` minutes after midnight, e.g. 240 for 4 AM so
+/// that late nights count towards the day before. On days when the clocks
+/// change, they are an hour shorter or longer.
+pub fn local_day(unix_s: u64, start_minute: u32) -> Option<(u64, u64)> {
+    local_day_in(None, unix_s, start_minute)
+}
+
+/// The start and end of a day, as for `local_day`, in the given timezone if any
+fn local_day_in(tz: Option<&str>, unix_s: u64, start_minute: u32) -> Option<(u64, u64)> {
+    let start = |day: &str| {
+        let at = |minute: u32| {
+            let time = format!("{} {:02}:{:02}", day, minute / 60, minute % 60);
+            date(tz, &time, "%s")?.parse::<u64>().ok()
+        };
+        // If the clocks went forward past the start of the day, start an hour later
+        at(start_minute).or_else(|| at(start_minute + 60))
+    };
+    let mut day = date(tz, &format!("@{}", unix_s), "%F")?;
+    let mut next_day = date(tz, &format!("{} +1 day", day), "%F")?;
+    // Before the day starts, it is still the day named for yesterday's date
+    if unix_s < start(&day)? {
+        next_day = day;
+        day = date(tz, &format!("{} -1 day", next_day), "%F")?;
+    }
+    Some((start(&day)?, start(&next_day)?))
+}
+
+/// The current local day of the week (1 for Monday through 7 for Sunday)
+/// and minute of the day, or `None` if they could not be determined
+pub fn local_weekday_minute() -> Option<(u32, u32)> {
+    local_weekday_minutes(&[unix_now()])?.pop()
+}
+
+/// The local day of the week and minute of the day of each of a set of
+/// times, or `None` if they could not be determined
+///
+/// All of the times are converted by a single run of `date`.
+pub fn local_weekday_minutes(unix_s: &[u64]) -> Option<Vec<(u32, u32)>> {
+    let mut child = Command::new("date")
+        .arg("-f")
+        .arg("-")
+        .arg("+%u %H %M")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let input: String = unix_s.iter().map(|s| format!("@{}\n", s)).collect();
+    // Write from another thread, so that a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take()?;
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().ok()?;
+    writer.join().ok()?.ok()?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    let ret: Option<Vec<_>> = output
+        .lines()
+        .map(|line| {
+            let mut fields = line.split_whitespace().map(|field| field.parse::<u32>());
+            let weekday = fields.next()?.ok()?;
+            let hour = fields.next()?.ok()?;
+            let minute = fields.next()?.ok()?;
+            Some((weekday, hour * 60 + minute))
+        })
+        .collect();
+    ret.filter(|ret| ret.len() == unix_s.len())
+}
+
+/// How a block ended
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The block ran to completion
+    Completed,
+    /// The block was cancelled
+    Cancelled,
+}
+
+/// A record of a single block
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Block {
+    /// When the block started, in seconds since the UNIX epoch
+    pub start_s: u64,
+    /// When the block ended, in seconds since the UNIX epoch
+    pub end_s: u64,
+    /// The planned length of the block
+    pub duration_s: u64,
+    pub label: Option<String>,
+    pub outcome: Outcome,
+    /// Windows focused during the block
+    pub tasks: Task,
+    /// Other windows visible full-screen, e.g. on another monitor, during the block
+    #[serde(default = "Task::new_root")]
+    pub unfocused: Task,
+    /// Time spent on blacklisted windows during the block
+    #[serde(default)]
+    pub distracted: Duration,
+    /// Number of times the focused task changed during the block
+    #[serde(default)]
+    pub switches: u64,
+    /// Longest time spent on a single task without switching
+    #[serde(default)]
+    pub longest_focus: Duration,
+    /// Score from 0 to 100 of how focused the block was, once it has finished
+    #[serde(default)]
+    pub focus_score: Option<u8>,
+    /// Free-form notes on the block, e.g. what was actually done in it
+    #[serde(default)]
+    pub notes: Vec<String>,
+    /// Number of times the block was paused
+    #[serde(default)]
+    pub pauses: u64,
+    /// Total time the block spent paused
+    #[serde(default)]
+    pub paused: Duration,
+    /// The machine the block was recorded on, if it was synced from another
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine: Option<String>,
+    /// How long the block lasted, including pauses, by the monotonic clock,
+    /// which unlike `end_s - start_s` is unaffected by changes to the wall
+    /// clock (zero for blocks recorded before this was)
+    #[serde(default)]
+    pub elapsed: Duration,
+    /// How far the wall clock jumped relative to the monotonic clock during
+    /// the block, in seconds, if it did, e.g. because it was set or the
+    /// machine was suspended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_jump_s: Option<i64>,
+    /// Intervals during which the monitors were off, and so the user away,
+    /// as start and end times in seconds since the UNIX epoch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub away: Vec<(u64, u64)>,
+    /// The tool the block was imported from, if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported: Option<String>,
+    /// When the block started, by the monotonic clock, while it is running
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::serde_instant::option"
+    )]
+    started: Option<Instant>,
+    /// When the block was paused, while it is paused
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::serde_instant::option"
+    )]
+    paused_since: Option<Instant>,
+    /// The task currently focused, and for how long, while the block is running
+    #[serde(skip)]
+    current_focus: Option<(Vec<String>, Duration)>,
+}
+
+impl Block {
+    /// Start recording a new block
+    pub fn start(duration_s: u64, label: Option<String>) -> Block {
+        let now = unix_now();
+        Block {
+            start_s: now,
+            end_s: now,
+            duration_s,
+            label,
+            outcome: Outcome::Cancelled,
+            tasks: Task::new_root(),
+            unfocused: Task::new_root(),
+            distracted: Duration::ZERO,
+            switches: 0,
+            longest_focus: Duration::ZERO,
+            focus_score: None,
+            notes: vec![],
+            pauses: 0,
+            paused: Duration::ZERO,
+            machine: None,
+            elapsed: Duration::ZERO,
+            clock_jump_s: None,
+            away: vec![],
+            imported: None,
+            started: Some(crate::clock::now()),
+            paused_since: None,
+            current_focus: None,
+        }
+    }
+
+    /// A finished block imported from another tool, which recorded only when
+    /// it ran, not what was worked on
+    pub fn imported(
+        source: &str,
+        start_s: u64,
+        end_s: u64,
+        label: Option<String>,
+        outcome: Outcome,
+    ) -> Block {
+        let elapsed_s = end_s.saturating_sub(start_s);
+        let mut block = Block::start(elapsed_s, label);
+        block.start_s = start_s;
+        block.end_s = end_s;
+        block.outcome = outcome;
+        block.elapsed = Duration::from_secs(elapsed_s);
+        block.imported = Some(source.to_owned());
+        block.started = None;
+        block
+    }
+
+    /// Record time spent on a task, given as a reversed path
+    pub fn record_focus(&mut self, path: Vec<String>, time: Duration) {
+        match self.current_focus {
+            Some((ref current, ref mut run)) if *current == path => *run += time,
+            Some(_) => {
+                self.switches += 1;
+                self.current_focus = Some((path.clone(), time));
+            }
+            None => self.current_focus = Some((path.clone(), time)),
+        }
+        if let Some((_, run)) = self.current_focus {
+            self.longest_focus = self.longest_focus.max(run);
+        }
+        self.tasks.add_time_path(path, time);
+    }
+
+    /// Record that the block was paused
+    pub fn pause(&mut self) {
+        self.pauses += 1;
+        self.paused_since = Some(crate::clock::now());
+    }
+
+    /// Record that the block was unpaused, adding to the time spent paused
+    pub fn unpause(&mut self) {
+        if let Some(since) = self.paused_since.take() {
+            self.paused += crate::clock::now().saturating_duration_since(since);
+        }
+    }
+
+    /// How long the block has lasted, including pauses, so far if it is still
+    /// running (by the wall clock, for blocks recorded before monotonic
+    /// times were)
+    pub fn actual(&self) -> Duration {
+        match self.started {
+            Some(started) => crate::clock::now().saturating_duration_since(started),
+            None if self.elapsed.is_zero() => {
+                Duration::from_secs(self.end_s.saturating_sub(self.start_s))
+            }
+            None => self.elapsed,
+        }
+    }
+
+    /// Record that the wall clock jumped, by a number of seconds (negative if
+    /// it went backwards), while the block was running
+    pub fn note_clock_jump(&mut self, jump_s: i64) {
+        *self.clock_jump_s.get_or_insert(0) += jump_s;
+    }
+
+    /// Record that the user was away between two times, in seconds since the
+    /// UNIX epoch, extending the last interval if this one follows on from it
+    pub fn note_away(&mut self, from_s: u64, to_s: u64) {
+        match self.away.last_mut() {
+            Some(last) if last.1 >= from_s => last.1 = last.1.max(to_s),
+            _ => self.away.push((from_s, to_s)),
+        }
+    }
+
+    /// Total time the user was away during the block, in seconds
+    pub fn away_s(&self) -> u64 {
+        self.away
+            .iter()
+            .map(|&(from, to)| to.saturating_sub(from))
+            .sum()
+    }
+
+    /// Compute the focus score, from the rate of task switches and the
+    /// fraction of the block spent in its longest uninterrupted stretch
+    fn compute_focus_score(&self) -> u8 {
+        let recorded = self.tasks.time_at_path(&[]).unwrap_or_default();
+        if recorded.is_zero() {
+            return 0;
+        }
+        let switches_per_min = self.switches as f64 * 60.0 / recorded.as_secs_f64();
+        let stability = 1.0 / (1.0 + switches_per_min);
+        let depth = self.longest_focus.as_secs_f64() / recorded.as_secs_f64();
+        (50.0 * stability + 50.0 * depth).round() as u8
+    }
+
+    /// Finish recording a block
+    pub fn finish(&mut self, outcome: Outcome) {
+        self.unpause();
+        self.end_s = unix_now();
+        self.elapsed = match self.started.take() {
+            Some(started) => crate::clock::now().saturating_duration_since(started),
+            None => Duration::from_secs(self.end_s.saturating_sub(self.start_s)),
+        };
+        self.outcome = outcome;
+        self.focus_score = Some(self.compute_focus_score());
+        self.current_focus = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_day() {
+        let tz = Some("America/New_York");
+        let hour = 3600;
+
+        // Midnight to midnight, or 4 AM to 4 AM, with the night before 4 AM
+        // counting towards the previous day, on the day the clocks go forward
+        assert_eq!(
+            local_day_in(tz, 1772955000, 0),
+            Some((1772946000, 1773028800)),
+        );
+        assert_eq!(
+            local_day_in(tz, 1772955000, 240),
+            Some((1772874000, 1772956800)),
+        );
+        assert_eq!(1772956800 - 1772874000, 23 * hour);
+        // and back
+        assert_eq!(
+            local_day_in(tz, 1793514600, 240),
+            Some((1793433600, 1793523600)),
+        );
+        assert_eq!(1793523600 - 1793433600, 25 * hour);
+
+        // A day starting at a time which is skipped starts an hour later
+        assert_eq!(
+            local_day_in(tz, 1772985600, 150),
+            Some((1772955000, 1773037800)),
+        );
+        assert_eq!(
+            local_day_in(tz, 1772955000 - 1, 150),
+            Some((1772868600, 1772955000)),
+        );
+    }
+
+    #[test]
+    fn test_focus_score() {
+        let a = vec!["a".to_owned()];
+        let b = vec!["b".to_owned()];
+
+        // One task throughout is perfect focus
+        let mut block = Block::start(60, None);
+        for _ in 0..60 {
+            block.record_focus(a.clone(), Duration::from_secs(1));
+        }
+        block.finish(Outcome::Completed);
+        assert_eq!(block.switches, 0);
+        assert_eq!(block.longest_focus, Duration::from_secs(60));
+        assert_eq!(block.focus_score, Some(100));
+
+        // Switching every ten seconds is not
+        let mut block = Block::start(60, None);
+        for i in 0..60 {
+            let path = if (i / 10) % 2 == 0 { &a } else { &b };
+            block.record_focus(path.clone(), Duration::from_secs(1));
+        }
+        block.finish(Outcome::Completed);
+        assert_eq!(block.switches, 5);
+        assert_eq!(block.longest_focus, Duration::from_secs(10));
+        assert_eq!(block.focus_score, Some(17));
+
+        // Nothing recorded scores zero
+        let mut block = Block::start(60, None);
+        block.finish(Outcome::Cancelled);
+        assert_eq!(block.focus_score, Some(0));
+    }
+}
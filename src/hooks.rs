@@ -0,0 +1,210 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Hooks
+//!
+//! User-configured shell commands which are run on state transitions
+//!
+
+use crate::handle::Handle;
+use crate::server::Transition;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+
+/// Most bytes of a command's stderr kept for the diagnostics
+const MAX_STDERR_LEN: usize = 500;
+
+/// Run the server's hooks, given as maps from transition names to shell
+/// commands (globally, and for each preset), forever
+///
+/// The hooks are looked up afresh for each transition, so that they can be
+/// changed by reloading the config. Details of the transition are passed in the environment variables
+/// `POMOTOSHI_EVENT`, `POMOTOSHI_LABEL`, `POMOTOSHI_DURATION_S`,
+/// `POMOTOSHI_DETAIL` and `POMOTOSHI_PRESET`, which are empty if not
+/// applicable, and, with more about the block and the server's state, as
+/// JSON on stdin.
+///
+/// Hooks are run one at a time, in order, and killed if they run for longer
+/// than the configured timeout, so that a hung one holds up the rest for at
+/// most that long.
+pub async fn run(handle: Handle, mut events: broadcast::Receiver<Transition>) {
+    loop {
+        let transition = match events.recv().await {
+            Ok(transition) => transition,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (script, payload) = {
+            let mut lock = handle.lock();
+            match lock.hook(&transition) {
+                Some(script) => (script.to_owned(), lock.hook_payload(&transition)),
+                None => continue,
+            }
+        };
+
+        let duration_s = transition.duration_s.map(|d| d.to_string());
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&script)
+            .env("POMOTOSHI_EVENT", transition.event.name())
+            .env("POMOTOSHI_LABEL", transition.label.as_deref().unwrap_or(""))
+            .env("POMOTOSHI_DURATION_S", duration_s.as_deref().unwrap_or(""))
+            .env(
+                "POMOTOSHI_DETAIL",
+                transition.detail.as_deref().unwrap_or(""),
+            )
+            .env(
+                "POMOTOSHI_PRESET",
+                transition.preset.as_deref().unwrap_or(""),
+            );
+        let mut payload = payload.to_string();
+        payload.push('\n');
+        run_reported(&handle, transition.event.name(), command, Some(payload)).await;
+    }
+}
+
+/// Run a hook's command, as `run_command` with the configured timeout,
+/// signalling an error if it fails, and otherwise noting anything it wrote
+/// to stderr in the diagnostics
+pub async fn run_reported(handle: &Handle, event: &str, command: Command, input: Option<String>) {
+    let timeout = handle.lock().hook_timeout();
+    report(
+        handle,
+        event,
+        run_command(command, input, Some(timeout)).await,
+    );
+}
+
+/// Start a command which is expected to run for a long time, such as a
+/// screen locker which only exits once the screen is unlocked, reporting
+/// how it went (as `run_reported` does) whenever it exits
+///
+/// There is no timeout, and the command is left running if the daemon
+/// exits first.
+pub fn spawn_reported(handle: &Handle, event: &str, command: Command) {
+    let (handle, event) = (handle.clone(), event.to_owned());
+    tokio::spawn(async move {
+        let result = run_command(command, None, None).await;
+        report(&handle, &event, result);
+    });
+}
+
+/// Signal an error if a hook failed, and otherwise note anything it wrote
+/// to stderr in the diagnostics
+fn report(handle: &Handle, event: &str, result: Result<String, String>) {
+    match result {
+        Ok(stderr) if stderr.is_empty() => {}
+        Ok(stderr) => handle
+            .lock()
+            .record_diagnostic(&format!("hook for {}: {}", event, stderr)),
+        Err(e) => handle
+            .lock()
+            .signal_error(&format!("hook for {} failed: {}", event, e)),
+    }
+}
+
+/// Run a command, writing `input` (if any) to its stdin, and killing it if
+/// it takes longer than `timeout` (if any)
+///
+/// Returns what the command wrote to stderr, or a description of how it
+/// failed, including that.
+pub async fn run_command(
+    mut command: Command,
+    input: Option<String>,
+    timeout: Option<Duration>,
+) -> Result<String, String> {
+    let stdin = match input {
+        Some(_) => Stdio::piped(),
+        None => Stdio::null(),
+    };
+    let mut child = command
+        .stdin(stdin)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(timeout.is_some())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let stdin = child.stdin.take();
+    let finish = async move {
+        // Commands need not read their input, so ignore their closing stdin
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+        child.wait_with_output().await
+    };
+    // On timeout the child is dropped, and so killed
+    let output = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, finish).await {
+            Ok(output) => output,
+            Err(_) => return Err(format!("timed out after {}s", timeout.as_secs())),
+        },
+        None => finish.await,
+    };
+    let output = output.map_err(|e| e.to_string())?;
+    let mut stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    if stderr.len() > MAX_STDERR_LEN {
+        let mut end = MAX_STDERR_LEN;
+        while !stderr.is_char_boundary(end) {
+            end -= 1;
+        }
+        stderr.truncate(end);
+        stderr.push_str("...");
+    }
+    match (output.status.success(), stderr.is_empty()) {
+        (true, _) => Ok(stderr),
+        (false, true) => Err(output.status.to_string()),
+        (false, false) => Err(format!("{}: {}", output.status, stderr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let second = Some(Duration::from_secs(1));
+        assert_eq!(run_command(sh("true"), None, second).await, Ok("".into()));
+        assert_eq!(
+            run_command(sh("cat >&2"), Some("payload\n".into()), second).await,
+            Ok("payload".into()),
+        );
+        assert_eq!(
+            run_command(sh("echo oops >&2; exit 3"), None, second).await,
+            Err("exit status: 3: oops".into()),
+        );
+        let start = std::time::Instant::now();
+        assert_eq!(
+            run_command(sh("exec sleep 10"), None, second).await,
+            Err("timed out after 1s".into()),
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Without a timeout, the command is waited for however long it takes
+        assert_eq!(
+            run_command(sh("sleep 1; echo done >&2"), None, None).await,
+            Ok("done".into()),
+        );
+    }
+}
@@ -0,0 +1,439 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! HTTP Server
+//!
+//! A minimal embedded HTTP server exposing a REST interface to the timer, and
+//! a WebSocket which streams status changes
+//!
+
+use crate::handle::Handle;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Maximum size of a request's head (request line plus headers)
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Maximum size of a request's body
+const MAX_BODY_SIZE: usize = 4 * 1024;
+/// How long a client has to send its whole request
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    path: String,
+    /// Header names and values, with names lowercased
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Look up a header by (lowercase) name
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An HTTP response
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: &'static str, body: serde_json::Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response::json(status, json!({ "error": message }))
+    }
+}
+
+/// Body of a `POST /block` request
+#[derive(Deserialize)]
+struct StartBlock {
+    time_s: u64,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+/// Bind the HTTP server to an address, e.g. `127.0.0.1:8080`
+pub async fn bind(addr: &str) -> io::Result<TcpListener> {
+    TcpListener::bind(addr).await
+}
+
+/// Accept and serve clients forever
+pub async fn serve(listener: TcpListener, handle: Handle) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_client(stream, handle.clone()));
+        }
+    }
+}
+
+/// Serve a single request, after which the connection is closed (unless it
+/// is upgraded to a WebSocket)
+async fn serve_client(mut stream: TcpStream, handle: Handle) {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => {
+            let response = Response::error("400 Bad Request", "malformed request");
+            let _ = write_response(&mut stream, response).await;
+            return;
+        }
+        Ok(Err(_)) => return,
+        Err(_) => {
+            let response = Response::error("408 Request Timeout", "request timed out");
+            let _ = write_response(&mut stream, response).await;
+            return;
+        }
+    };
+
+    if request.method == "GET" && request.path == "/ws" {
+        if let Some(key) = request.header("sec-websocket-key") {
+            if foreign_origin(&request) {
+                let response = Response::error("403 Forbidden", "foreign origin");
+                let _ = write_response(&mut stream, response).await;
+                return;
+            }
+            let accept = derive_accept_key(key.as_bytes());
+            let _ = serve_websocket(stream, &accept, handle).await;
+            return;
+        }
+    }
+
+    let response = route(&handle, &request);
+    let _ = write_response(&mut stream, response).await;
+}
+
+/// Whether a request was made by a page from some other site, which a
+/// browser may send on the page's behalf but which should not be obeyed
+///
+/// Requests from outside a browser (e.g. curl) have no origin, and are allowed.
+fn foreign_origin(request: &Request) -> bool {
+    match (request.header("origin"), request.header("host")) {
+        (None, _) => false,
+        (Some(origin), Some(host)) => origin.strip_prefix("http://") != Some(host),
+        (Some(_), None) => true,
+    }
+}
+
+/// Whether a request's body is declared to be JSON, which browsers will not
+/// send to another site without asking it first
+fn is_json(request: &Request) -> bool {
+    request
+        .header("content-type")
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Dispatch a (non-WebSocket) request
+fn route(handle: &Handle, request: &Request) -> Response {
+    if request.method == "POST" {
+        if foreign_origin(request) {
+            return Response::error("403 Forbidden", "foreign origin");
+        }
+        if !is_json(request) {
+            return Response::error(
+                "415 Unsupported Media Type",
+                "content type must be application/json",
+            );
+        }
+        // Only known routes are counted, so that made-up ones can't fill up
+        // the record of recent calls
+        let mutating = matches!(request.path.as_str(), "/block" | "/pause" | "/cancel");
+        if mutating && !handle.lock().allow_call(&request.path) {
+            return Response::error("429 Too Many Requests", "called too often");
+        }
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => Response::json("200 OK", json!(handle.lock().status())),
+        ("GET", "/bar") => Response {
+            status: "200 OK",
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", *handle.subscribe_line().borrow()),
+        },
+        ("GET", "/overlay") => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: include_str!("overlay.html").into(),
+        },
+        ("GET", "/overlay/fragment") => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: handle.lock().overlay(),
+        },
+        ("GET", "/metrics") => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: handle.lock().metrics(),
+        },
+        ("POST", "/block") => match serde_json::from_slice::<StartBlock>(&request.body) {
+            Ok(body) => {
+                let limit = handle.with_server(|server| {
+                    let limit = server.daily_limit_reached();
+                    server.start_block(body.time_s, body.label);
+                    limit
+                });
+                match limit {
+                    Some(reason) => Response::error("409 Conflict", &reason),
+                    None => Response::json("200 OK", json!(handle.lock().status())),
+                }
+            }
+            Err(e) => Response::error("400 Bad Request", &e.to_string()),
+        },
+        ("POST", "/pause") => {
+            handle.with_server(|server| server.pause_block());
+            Response::json("200 OK", json!(handle.lock().status()))
+        }
+        ("POST", "/cancel") => {
+            let token = request
+                .header("authorization")
+                .and_then(|auth| auth.strip_prefix("Bearer "));
+            let cancelled = handle.with_server(|server| {
+                server
+                    .check_access(None, token)
+                    .map_err(|e| Response::error("403 Forbidden", &e))?;
+                // A block near its end may need cancelling twice
+                server
+                    .try_cancel_block(false)
+                    .map_err(|e| Response::error("409 Conflict", &e))
+            });
+            match cancelled {
+                Ok(()) => Response::json("200 OK", json!(handle.lock().status())),
+                Err(response) => response,
+            }
+        }
+        (_, "/status")
+        | (_, "/bar")
+        | (_, "/overlay")
+        | (_, "/overlay/fragment")
+        | (_, "/metrics")
+        | (_, "/block")
+        | (_, "/pause")
+        | (_, "/cancel") => Response::error("405 Method Not Allowed", "method not allowed"),
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
+
+/// Complete a WebSocket handshake, then send the status whenever it changes
+async fn serve_websocket(mut stream: TcpStream, accept: &str, handle: Handle) -> io::Result<()> {
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    stream.write_all(handshake.as_bytes()).await?;
+
+    let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    let mut status = handle.subscribe_status();
+    loop {
+        let message = json!(*status.borrow_and_update()).to_string();
+        if ws.send(Message::text(message)).await.is_err() {
+            return Ok(());
+        }
+        tokio::select! {
+            changed = status.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+            // We ignore anything the client sends us, except for closing
+            incoming = ws.next() => match incoming {
+                Some(Ok(msg)) if !msg.is_close() => {}
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Read and parse a request, returning `None` if it is malformed
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    // Read until the end of the headers
+    let mut buf = Vec::with_capacity(1024);
+    let head_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = match std::str::from_utf8(&buf[..head_len]) {
+        Ok(head) => head,
+        Err(_) => return Ok(None),
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Ok(None),
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect();
+
+    // Read the body, if any
+    let mut body = buf[head_len..].to_vec();
+    let content_length = headers
+        .iter()
+        .find(|(key, _)| key == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    if content_length > MAX_BODY_SIZE {
+        return Ok(None);
+    }
+    while body.len() < content_length {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+/// Write a response and close the connection
+async fn write_response(stream: &mut TcpStream, response: Response) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: method.into(),
+            path: path.into(),
+            headers: headers
+                .iter()
+                .map(|&(key, value)| (key.into(), value.into()))
+                .collect(),
+            body: b"{}".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_post_checks() {
+        let handle = Handle::new(Server::new());
+        let json = ("content-type", "application/json; charset=utf-8");
+        let host = ("host", "127.0.0.1:8080");
+
+        let ok = request("POST", "/pause", &[json, host]);
+        assert_eq!(route(&handle, &ok).status, "200 OK");
+        let same_site = request(
+            "POST",
+            "/pause",
+            &[json, host, ("origin", "http://127.0.0.1:8080")],
+        );
+        assert_eq!(route(&handle, &same_site).status, "200 OK");
+
+        // Form posts, which browsers send anywhere, and requests from other
+        // sites are refused
+        let form = request("POST", "/pause", &[("content-type", "text/plain"), host]);
+        assert_eq!(route(&handle, &form).status, "415 Unsupported Media Type");
+        let untyped = request("POST", "/pause", &[host]);
+        assert_eq!(
+            route(&handle, &untyped).status,
+            "415 Unsupported Media Type"
+        );
+        let foreign = request(
+            "POST",
+            "/pause",
+            &[json, host, ("origin", "https://evil.example.com")],
+        );
+        assert_eq!(route(&handle, &foreign).status, "403 Forbidden");
+
+        // Only real routes are rate limited
+        for _ in 0..100 {
+            let unknown = request("POST", "/nonexistent", &[json, host]);
+            assert_eq!(route(&handle, &unknown).status, "404 Not Found");
+        }
+        let statuses: Vec<_> = (0..20).map(|_| route(&handle, &ok).status).collect();
+        assert!(statuses.contains(&"429 Too Many Requests"));
+
+        // Reading is allowed from anywhere
+        let get = request("GET", "/status", &[("origin", "https://evil.example.com")]);
+        assert_eq!(route(&handle, &get).status, "200 OK");
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut config = serde_json::to_value(Server::new()).unwrap();
+        config["confirm_cancel_s"] = json!(600);
+        let handle = Handle::new(serde_json::from_value(config).unwrap());
+        let headers = [
+            ("content-type", "application/json"),
+            ("host", "127.0.0.1:8080"),
+        ];
+        handle.with_server(|server| server.start_block(60, None));
+
+        // Cancelling near the end of a block must be confirmed, by cancelling
+        // again, and the first attempt says so
+        let cancel = request("POST", "/cancel", &headers);
+        let refused = route(&handle, &cancel);
+        assert_eq!(refused.status, "409 Conflict");
+        assert!(refused.body.contains("cancel again"), "{}", refused.body);
+        assert_eq!(route(&handle, &cancel).status, "200 OK");
+        assert_eq!(handle.lock().status().state, "idle");
+    }
+}
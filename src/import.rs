@@ -0,0 +1,446 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! History Import
+//!
+//! Conversion of the records kept by other tools into blocks, so that their
+//! history is not lost on switching: Pomobar-style block logs (as also
+//! returned by `blockLog`), Timewarrior's `timew export`, and CSV files of
+//! start times, end times and labels
+//!
+
+use crate::history::{Block, Outcome};
+use crate::store;
+use serde::Deserialize;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+/// A tool which history can be imported from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Lines of `YYYY-MM-DD HH:MM:SS+ZZZZ: message`, where the messages
+    /// record blocks starting, pausing and ending
+    Pomobar,
+    /// The JSON array output by `timew export`
+    Timewarrior,
+    /// Lines of `start,end,label`, with times as UNIX timestamps or in RFC
+    /// 3339 format, an optional label, and an optional header
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "pomobar" => Ok(Format::Pomobar),
+            "timewarrior" => Ok(Format::Timewarrior),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!(
+                "unknown format {} (expected pomobar, timewarrior or csv)",
+                s
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// The name of the format, recorded as where blocks were imported from
+    fn name(self) -> &'static str {
+        match self {
+            Format::Pomobar => "pomobar",
+            Format::Timewarrior => "timewarrior",
+            Format::Csv => "csv",
+        }
+    }
+
+    /// Convert a file in this format to blocks, in the order they appear
+    pub fn parse(self, input: &str) -> Result<Vec<Block>, String> {
+        match self {
+            Format::Pomobar => parse_pomobar(input),
+            Format::Timewarrior => parse_timewarrior(input),
+            Format::Csv => parse_csv(input),
+        }
+    }
+}
+
+/// Add the blocks in a file in the given format to a history store, skipping
+/// any which start at the same time as one already in it, e.g. from
+/// importing the same file twice, and describe what was imported
+///
+/// Imports go straight to the store, rather than the history kept in the
+/// config file, since they may span years.
+pub fn import(format: Format, path: &Path, config: &store::Config) -> Result<String, String> {
+    let blocks = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|input| format.parse(&input))
+        .map_err(|e| format!("importing {}: {}", path.display(), e))?;
+    let mut store = config
+        .open()
+        .map_err(|e| format!("opening history store: {}", e))?;
+    let total = blocks.len();
+    let mut imported = 0;
+    for block in &blocks {
+        if store
+            .append(block)
+            .map_err(|e| format!("storing history: {}", e))?
+        {
+            imported += 1;
+        }
+    }
+    Ok(format!(
+        "imported {} blocks from {} ({} already in history)",
+        imported,
+        path.display(),
+        total - imported
+    ))
+}
+
+/// A parsed time as seconds since the UNIX epoch, or `None` if it is before it
+fn unix_s(time: OffsetDateTime) -> Option<u64> {
+    u64::try_from(time.unix_timestamp()).ok()
+}
+
+/// Import a block log, pairing the starts of blocks with their ends
+fn parse_pomobar(input: &str) -> Result<Vec<Block>, String> {
+    let format = time::macros::format_description!(
+        "[year]-[month]-[day] [hour]:[minute]:[second][offset_hour sign:mandatory][offset_minute]"
+    );
+    let mut blocks = vec![];
+    // The start of the running block, when it was paused if it is, and the
+    // number of pauses and total time paused so far
+    let mut running: Option<(u64, Option<u64>, u64, u64)> = None;
+    for (n, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(": ").and_then(|(time, message)| {
+            let time = OffsetDateTime::parse(time, &format).ok()?;
+            Some((unix_s(time)?, message.trim()))
+        });
+        let (time_s, message) = parsed.ok_or_else(|| format!("line {}: no timestamp", n + 1))?;
+        let outcome = match message {
+            "started block" => {
+                running = Some((time_s, None, 0, 0));
+                continue;
+            }
+            "paused block" => {
+                if let Some((_, ref mut paused_since, ref mut pauses, _)) = running {
+                    *paused_since = Some(time_s);
+                    *pauses += 1;
+                }
+                continue;
+            }
+            "unpaused block" => {
+                if let Some((_, ref mut paused_since, _, ref mut paused_s)) = running {
+                    if let Some(since) = paused_since.take() {
+                        *paused_s += time_s.saturating_sub(since);
+                    }
+                }
+                continue;
+            }
+            "end block; start cooldown" => Outcome::Completed,
+            "canceled block" => Outcome::Cancelled,
+            _ => continue,
+        };
+        // An end without a start is from a log which was cut short
+        if let Some((start_s, paused_since, pauses, mut paused_s)) = running.take() {
+            if time_s < start_s {
+                return Err(format!("line {}: ends before it starts", n + 1));
+            }
+            if let Some(since) = paused_since {
+                paused_s += time_s.saturating_sub(since);
+            }
+            let mut block = Block::imported(Format::Pomobar.name(), start_s, time_s, None, outcome);
+            block.duration_s = (time_s - start_s).saturating_sub(paused_s);
+            block.pauses = pauses;
+            block.paused = Duration::from_secs(paused_s);
+            blocks.push(block);
+        }
+    }
+    Ok(blocks)
+}
+
+/// An interval, as output by `timew export`
+#[derive(Deserialize)]
+struct Interval {
+    start: String,
+    /// The end, unless the interval is still open
+    end: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    annotation: Option<String>,
+}
+
+/// Import the output of `timew export`, skipping any open interval
+///
+/// The first tag of each interval becomes the label of its block, and any
+/// others the path of the task its time is recorded under, which inverts
+/// how blocks are exported to Timewarrior.
+fn parse_timewarrior(input: &str) -> Result<Vec<Block>, String> {
+    let format = time::macros::format_description!("[year][month][day]T[hour][minute][second]Z");
+    let parse = |time: &str| {
+        PrimitiveDateTime::parse(time, &format)
+            .ok()
+            .and_then(|time| unix_s(time.assume_utc()))
+            .ok_or_else(|| format!("bad time {}", time))
+    };
+    let intervals: Vec<Interval> = serde_json::from_str(input).map_err(|e| e.to_string())?;
+    let mut blocks = vec![];
+    for interval in intervals {
+        let end = match interval.end {
+            Some(ref end) => end,
+            None => continue,
+        };
+        let (start_s, end_s) = (parse(&interval.start)?, parse(end)?);
+        if end_s < start_s {
+            return Err(format!("interval {} ends before it starts", interval.start));
+        }
+        let mut tags = interval.tags.into_iter();
+        let label = tags.next();
+        let source = Format::Timewarrior.name();
+        let mut block = Block::imported(source, start_s, end_s, label, Outcome::Completed);
+        let mut path: Vec<String> = tags.collect();
+        if !path.is_empty() {
+            path.reverse();
+            block
+                .tasks
+                .add_time_path(path, Duration::from_secs(block.duration_s));
+        }
+        block.notes.extend(interval.annotation);
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// Split a line of CSV into fields, which may be quoted (with `""` for a
+/// quote inside a quoted field)
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("there is always a field");
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields
+}
+
+/// Import a CSV file, whose first line is skipped if it is a header
+fn parse_csv(input: &str) -> Result<Vec<Block>, String> {
+    let parse = |time: &str| {
+        let time = time.trim();
+        time.parse::<u64>()
+            .ok()
+            .or_else(|| OffsetDateTime::parse(time, &Rfc3339).ok().and_then(unix_s))
+    };
+    let mut blocks = vec![];
+    for (n, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv(line);
+        let times = match fields[..] {
+            [ref start, ref end, ..] => parse(start).zip(parse(end)),
+            _ => None,
+        };
+        let (start_s, end_s) = match times {
+            Some(times) => times,
+            None if n == 0 => continue,
+            None => return Err(format!("line {}: expected start,end[,label]", n + 1)),
+        };
+        if end_s < start_s {
+            return Err(format!("line {}: ends before it starts", n + 1));
+        }
+        let label = fields
+            .get(2)
+            .map(|label| label.trim())
+            .filter(|label| !label.is_empty())
+            .map(str::to_owned);
+        blocks.push(Block::imported(
+            Format::Csv.name(),
+            start_s,
+            end_s,
+            label,
+            Outcome::Completed,
+        ));
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start and end times, label and outcome of each block
+    fn summarize(blocks: &[Block]) -> Vec<(u64, u64, u64, Option<&str>, Outcome)> {
+        blocks
+            .iter()
+            .map(|block| {
+                let label = block.label.as_deref();
+                (
+                    block.start_s,
+                    block.end_s,
+                    block.duration_s,
+                    label,
+                    block.outcome,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pomobar() {
+        let log = "\
+2024-03-10 09:00:00+0000: added/cleared task log work
+2024-03-10 09:00:00+0000: started block
+2024-03-10 09:10:00+0000: paused block
+2024-03-10 09:15:00+0000: unpaused block
+2024-03-10 09:30:00+0000: end block; start cooldown
+2024-03-10 09:35:00+0000: end cooldown
+2024-03-10 10:00:00+0100: started block
+2024-03-10 10:05:00+0100: canceled block
+2024-03-10 11:00:00+0000: end block; start cooldown
+";
+        let blocks = Format::Pomobar.parse(log).unwrap();
+        assert_eq!(
+            summarize(&blocks),
+            [
+                (1710061200, 1710063000, 1500, None, Outcome::Completed),
+                (1710061200, 1710061500, 300, None, Outcome::Cancelled),
+            ],
+        );
+        assert_eq!(blocks[0].pauses, 1);
+        assert_eq!(blocks[0].paused, Duration::from_secs(300));
+        assert_eq!(blocks[0].imported.as_deref(), Some("pomobar"));
+
+        let err = Format::Pomobar.parse("started block\n").unwrap_err();
+        assert_eq!(err, "line 1: no timestamp");
+        let log = "\
+2024-03-10 10:00:00+0000: started block
+2024-03-10 10:30:00+0100: end block; start cooldown
+";
+        let err = Format::Pomobar.parse(log).unwrap_err();
+        assert_eq!(err, "line 2: ends before it starts");
+    }
+
+    #[test]
+    fn test_timewarrior() {
+        let export = r#"[
+            {"id":3,"start":"20240310T090000Z","end":"20240310T092500Z","tags":["writing","pomotoshi","README"],"annotation":"first draft"},
+            {"id":2,"start":"20240310T100000Z","end":"20240310T101000Z"},
+            {"id":1,"start":"20240310T110000Z","tags":["open"]}
+        ]"#;
+        let blocks = Format::Timewarrior.parse(export).unwrap();
+        assert_eq!(
+            summarize(&blocks),
+            [
+                (
+                    1710061200,
+                    1710062700,
+                    1500,
+                    Some("writing"),
+                    Outcome::Completed
+                ),
+                (1710064800, 1710065400, 600, None, Outcome::Completed),
+            ],
+        );
+        assert_eq!(blocks[0].tasks.top_path(), ["pomotoshi", "README"]);
+        assert_eq!(blocks[0].notes, ["first draft"]);
+
+        assert!(Format::Timewarrior
+            .parse("[{\"start\":\"now\",\"end\":\"later\"}]")
+            .is_err());
+        let err = Format::Timewarrior
+            .parse("[{\"start\":\"20240310T100000Z\",\"end\":\"20240310T090000Z\"}]")
+            .unwrap_err();
+        assert_eq!(err, "interval 20240310T100000Z ends before it starts");
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = "\
+start,end,label
+1710061200,1710062700,writing
+2024-03-10T10:00:00Z,2024-03-10T10:10:00Z,\"review, \"\"final\"\"\"
+
+1710068400,1710069000,
+";
+        let blocks = Format::Csv.parse(csv).unwrap();
+        assert_eq!(
+            summarize(&blocks),
+            [
+                (
+                    1710061200,
+                    1710062700,
+                    1500,
+                    Some("writing"),
+                    Outcome::Completed
+                ),
+                (
+                    1710064800,
+                    1710065400,
+                    600,
+                    Some("review, \"final\""),
+                    Outcome::Completed
+                ),
+                (1710068400, 1710069000, 600, None, Outcome::Completed),
+            ],
+        );
+
+        let err = Format::Csv.parse("1,2\nnot,times\n").unwrap_err();
+        assert_eq!(err, "line 2: expected start,end[,label]");
+        let err = Format::Csv.parse("2,1\n").unwrap_err();
+        assert_eq!(err, "line 1: ends before it starts");
+    }
+
+    #[test]
+    #[cfg(feature = "jsonl")]
+    fn test_import() {
+        let dir = std::env::temp_dir().join(format!("pomotoshi-{}-import", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (csv, history) = (dir.join("history.csv"), dir.join("history.jsonl"));
+        let _ = std::fs::remove_file(&history);
+        let config = store::Config::Jsonl(history);
+
+        // Importing again only adds the blocks which are new
+        std::fs::write(&csv, "1000,2500,writing\n").unwrap();
+        let imported = import(Format::Csv, &csv, &config).unwrap();
+        assert!(imported.starts_with("imported 1 blocks"), "{}", imported);
+        std::fs::write(&csv, "1000,2500,writing\n3000,3600,review\n").unwrap();
+        let imported = import(Format::Csv, &csv, &config).unwrap();
+        assert!(imported.ends_with("(1 already in history)"), "{}", imported);
+        let stored = config.open().unwrap().range(0, u64::MAX).unwrap();
+        assert_eq!(
+            summarize(&stored),
+            [
+                (1000, 2500, 1500, Some("writing"), Outcome::Completed),
+                (3000, 3600, 600, Some("review"), Outcome::Completed),
+            ],
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Render Latency
+//!
+//! Measurements of how late the render loop wakes up, compared to when it
+//! meant to, and how long each render takes, so that hangs, e.g. from slow
+//! subprocesses or a client holding the server during a bus stall, can be
+//! quantified
+//!
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of recent renders which are summarized
+const WINDOW_LEN: usize = 100;
+/// How late a tick, or how long a render, must be to be noted as slow
+pub const SLOW: Duration = Duration::from_millis(250);
+
+/// Lateness and render times of recent renders
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Latency {
+    /// How late each recent render was, if it was a tick rather than a
+    /// response to a client, and how long it took, oldest first
+    recent: VecDeque<(Option<Duration>, Duration)>,
+    /// Number of slow renders ever
+    slow: u64,
+}
+
+impl Latency {
+    /// Record a render, returning whether it was slow
+    pub fn record(&mut self, late: Option<Duration>, render: Duration) -> bool {
+        if self.recent.len() == WINDOW_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((late, render));
+        let slow = late.is_some_and(|late| late >= SLOW) || render >= SLOW;
+        if slow {
+            self.slow += 1;
+        }
+        slow
+    }
+
+    /// A summary of recent renders, or `None` if there have been none
+    pub fn summary(&self) -> Option<String> {
+        let renders: Vec<Duration> = self.recent.iter().map(|&(_, render)| render).collect();
+        let late: Vec<Duration> = self.recent.iter().filter_map(|&(late, _)| late).collect();
+        let stats = |times: &[Duration]| {
+            let max = times.iter().max().copied().unwrap_or_default();
+            let mean = times.iter().sum::<Duration>() / times.len().max(1) as u32;
+            format!("mean {}ms, max {}ms", mean.as_millis(), max.as_millis())
+        };
+        if renders.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "render latency over the last {} renders: ticks late by {}; rendering took {}; {} slow renders in total",
+            renders.len(),
+            stats(&late),
+            stats(&renders),
+            self.slow,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let ms = Duration::from_millis;
+        let mut latency = Latency::default();
+        assert_eq!(latency.summary(), None);
+
+        assert!(!latency.record(Some(ms(2)), ms(1)));
+        assert!(!latency.record(None, ms(3)));
+        assert!(latency.record(Some(ms(400)), ms(2)));
+        assert!(latency.record(Some(ms(0)), ms(900)));
+        assert_eq!(
+            latency.summary().as_deref(),
+            Some(
+                "render latency over the last 4 renders: ticks late by mean 134ms, max 400ms; \
+                 rendering took mean 226ms, max 900ms; 2 slow renders in total"
+            ),
+        );
+
+        // Only recent renders are summarized, but slow ones are always counted
+        for _ in 0..WINDOW_LEN {
+            latency.record(Some(ms(1)), ms(1));
+        }
+        assert_eq!(
+            latency.summary().as_deref(),
+            Some(
+                "render latency over the last 100 renders: ticks late by mean 1ms, max 1ms; \
+                 rendering took mean 1ms, max 1ms; 2 slow renders in total"
+            ),
+        );
+    }
+}
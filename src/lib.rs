@@ -0,0 +1,84 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Pomotoshi
+//!
+//! Strongly inspired by [Pomobar](https://github.com/rlcintra/pomobar), this
+//! utility is a Pomodoro timer, designed to be controlled via DBus and to
+//! provide output via xmobar.
+//!
+//! This library contains the timer itself; the daemon which exposes it over
+//! D-Bus lives in `main.rs`.
+//!
+
+pub mod access;
+pub mod announce;
+pub mod audio;
+pub mod bar;
+pub mod calendar;
+pub mod chat;
+mod classifier;
+pub mod clock;
+mod color;
+pub mod control;
+pub mod dim;
+pub mod export;
+mod flash;
+pub mod handle;
+pub mod history;
+pub mod hooks;
+pub mod http;
+pub mod import;
+mod latency;
+pub mod lockout;
+pub mod logging;
+mod metrics;
+pub mod mqtt;
+pub mod peer;
+mod progress;
+pub mod record;
+mod redact;
+pub mod report;
+mod serde_instant;
+mod serde_regex;
+pub mod server;
+pub mod store;
+pub mod suggestions;
+pub mod sync;
+pub mod systemd;
+pub mod task;
+pub mod taskwarrior;
+mod theme;
+mod timer;
+pub mod tui;
+pub mod webhook;
+pub mod window;
+
+/// Version of the D-Bus API, which is bumped on breaking changes
+pub const DBUS_API_VERSION: u32 = 1;
+/// Name of the D-Bus org, and of the interface, which include the API version
+/// so that scripts written against an older version fail cleanly
+pub const DBUS_ORG: &str = "org.Pomotoshi1";
+/// Name of the D-Bus org, and of the interface, from before the API was
+/// versioned, which are still served (with the current API) for a while so
+/// that scripts can be moved over
+pub const DBUS_LEGACY_ORG: &str = "org.Pomotoshi";
+/// Name of the D-Bus path
+pub const DBUS_PATH: &str = "/org/pomotoshi";
+
+/// How long cooldown (period after a block when no new blocks are allowed) should last
+const COOLDOWN_DURATION: std::time::Duration = std::time::Duration::from_secs(300);
+/// How long an automatically-started meeting tracking session lasts after the
+/// last time a meeting window was focused
+const MEETING_GRACE_DURATION: std::time::Duration = std::time::Duration::from_secs(120);
@@ -0,0 +1,278 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Keyboard Lockout
+//!
+//! Disabling input devices (using `xinput`) during cooldowns, so that they
+//! are actually spent away from the computer
+//!
+
+use crate::handle::Handle;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::{env, io};
+use tokio::io::AsyncWriteExt;
+
+/// How often the timer state is checked, and the guard file touched
+const POLL_FREQ: std::time::Duration = std::time::Duration::from_secs(1);
+/// How long the guard file may go untouched before the guard process takes
+/// the daemon to have hung
+const HEARTBEAT_TIMEOUT_S: u64 = 10;
+
+/// Script for the guard process, which re-enables the devices once the
+/// daemon exits, the guard file is removed or times out, or the daemon stops
+/// touching it. Its arguments are the daemon's PID, the guard file, the
+/// timeout and heartbeat timeout in seconds, and the devices.
+const GUARD_SCRIPT: &str = r#"
+pid=$1 guard=$2 end=$(( $(date +%s) + $3 )) stale=$4
+shift 4
+while kill -0 "$pid" 2>/dev/null && [ -e "$guard" ] && [ "$(date +%s)" -lt "$end" ] \
+    && [ $(( $(date +%s) - $(stat -c %Y "$guard" 2>/dev/null || echo 0) )) -lt "$stale" ]; do
+    sleep 1
+done
+if [ -e "$guard" ]; then
+    for device in "$@"; do xinput enable "$device"; done
+    rm -f "$guard"
+fi
+"#;
+
+fn default_max_s() -> u64 { 3600 }
+
+/// Configuration for the keyboard lockout
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Names or IDs of the devices to disable, as listed by `xinput list`
+    devices: Vec<String>,
+    /// The longest time devices are kept disabled for, even if the daemon hangs
+    #[serde(default = "default_max_s")]
+    max_s: u64,
+}
+
+/// File recording which devices are disabled, so that they can be re-enabled
+/// by the guard process, or on startup if both it and the daemon died
+fn guard_file() -> io::Result<PathBuf> {
+    guard_file_in(env::var_os("XDG_RUNTIME_DIR"))
+}
+
+/// The guard file in a runtime directory, which must be given, since unlike
+/// `/tmp` it is private to the user
+fn guard_file_in(runtime_dir: Option<OsString>) -> io::Result<PathBuf> {
+    match runtime_dir {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir).join("pomotoshi-lockout")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "XDG_RUNTIME_DIR is not set",
+        )),
+    }
+}
+
+/// Run `xinput` to enable or disable a device
+async fn xinput(action: &str, device: &str) -> io::Result<()> {
+    let output = tokio::process::Command::new("xinput")
+        .arg(action)
+        .arg(device)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "xinput {} {} failed: {}",
+            action,
+            device,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+    Ok(())
+}
+
+/// Disable the configured devices, starting a guard process to re-enable them
+/// if we go away
+async fn disable(config: &Config) -> io::Result<()> {
+    let guard = guard_file()?;
+    // This fails rather than follow a link, or take over a file that is not ours
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&guard)
+        .await?;
+    file.write_all(config.devices.join("\n").as_bytes()).await?;
+    drop(file);
+    // The guard goes first, so that there is no window where the devices are
+    // disabled and nothing will re-enable them
+    tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(GUARD_SCRIPT)
+        .arg("sh")
+        .arg(std::process::id().to_string())
+        .arg(&guard)
+        .arg(config.max_s.to_string())
+        .arg(HEARTBEAT_TIMEOUT_S.to_string())
+        .args(&config.devices)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    for device in &config.devices {
+        xinput("disable", device).await?;
+    }
+    Ok(())
+}
+
+/// Touch the guard file, so that the guard process knows we are still running
+fn heartbeat() -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(guard_file()?)?
+        .set_modified(std::time::SystemTime::now())
+}
+
+/// Re-enable the devices listed in the guard file, if any, and remove it
+async fn enable() -> io::Result<()> {
+    // Nothing can have been disabled without a runtime directory
+    let guard = match guard_file() {
+        Ok(guard) => guard,
+        Err(_) => return Ok(()),
+    };
+    let devices = match tokio::fs::read_to_string(&guard).await {
+        Ok(devices) => devices,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // Remove the file first so the guard process doesn't also re-enable them
+    tokio::fs::remove_file(&guard).await?;
+    let mut result = Ok(());
+    for device in devices.lines().filter(|line| !line.is_empty()) {
+        if let Err(e) = xinput("enable", device).await {
+            result = Err(e);
+        }
+    }
+    result
+}
+
+/// Disable input devices during cooldowns, forever
+///
+/// The config is looked up each second, so that it can be changed by
+/// reloading. Devices left disabled by a previous run are re-enabled first.
+pub async fn run(handle: Handle) {
+    if let Err(e) = enable().await {
+        handle
+            .lock()
+            .signal_error(&format!("re-enabling input devices: {}", e));
+    }
+
+    let mut locked = false;
+    let mut interval = tokio::time::interval(POLL_FREQ);
+    loop {
+        interval.tick().await;
+        let (in_cooldown, config) = {
+            let lock = handle.lock();
+            (lock.in_cooldown(), lock.keyboard_lockout().cloned())
+        };
+        match (locked, in_cooldown, config) {
+            (false, true, Some(config)) => {
+                locked = true;
+                tracing::info!(devices = ?config.devices, "disabling input devices");
+                if let Err(e) = disable(&config).await {
+                    // Don't leave anything disabled if we couldn't do all of it
+                    let _ = enable().await;
+                    handle
+                        .lock()
+                        .signal_error(&format!("disabling input devices: {}", e));
+                }
+            }
+            (true, true, Some(_)) => {
+                if let Err(e) = heartbeat() {
+                    tracing::warn!(error = %e, "touching the keyboard lockout guard file");
+                }
+            }
+            (true, false, _) | (true, _, None) => {
+                locked = false;
+                tracing::info!("re-enabling input devices");
+                if let Err(e) = enable().await {
+                    handle
+                        .lock()
+                        .signal_error(&format!("re-enabling input devices: {}", e));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_guard_file() {
+        assert!(guard_file_in(None).is_err());
+        assert!(guard_file_in(Some("".into())).is_err());
+        assert_eq!(
+            guard_file_in(Some("/run/user/1000".into())).unwrap(),
+            PathBuf::from("/run/user/1000/pomotoshi-lockout"),
+        );
+    }
+
+    #[test]
+    fn test_guard_script() {
+        let dir = env::temp_dir().join(format!("pomotoshi-{}-lockout", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (log, guard) = (dir.join("log"), dir.join("guard"));
+        // A fake `xinput`, which records what it is asked to do
+        let script = format!(
+            "xinput() {{ echo \"$@\" >>'{}'; }}\n{}",
+            log.display(),
+            GUARD_SCRIPT
+        );
+        let guard_process = |stale_s: u64| {
+            Command::new("sh")
+                .arg("-c")
+                .arg(&script)
+                .arg("sh")
+                .arg(std::process::id().to_string())
+                .arg(&guard)
+                .arg("3600")
+                .arg(stale_s.to_string())
+                .arg("keyboard")
+                .arg("mouse")
+                .spawn()
+                .unwrap()
+        };
+
+        // The devices are re-enabled once the guard file is no longer touched
+        std::fs::write(&guard, "keyboard\nmouse").unwrap();
+        let start = Instant::now();
+        guard_process(2).wait().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "enable keyboard\nenable mouse\n"
+        );
+        assert!(!guard.exists());
+
+        // ...but not if the daemon removes it, having re-enabled them itself
+        std::fs::remove_file(&log).unwrap();
+        std::fs::write(&guard, "keyboard\nmouse").unwrap();
+        let mut child = guard_process(60);
+        std::thread::sleep(Duration::from_secs(1));
+        std::fs::remove_file(&guard).unwrap();
+        child.wait().unwrap();
+        assert!(!log.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
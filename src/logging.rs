@@ -0,0 +1,104 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Logging
+//!
+//! Structured logs of client calls, state transitions and failures, for
+//! working out after the fact what happened
+//!
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+fn default_level() -> String { "info".into() }
+fn default_prefix() -> String { "pomotoshi.log".into() }
+
+/// How often log files are rotated
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotate {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+/// Where logs are written
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    /// To the systemd journal
+    Journald,
+    /// To files in a directory, named with the given prefix and the date
+    File {
+        directory: PathBuf,
+        #[serde(default = "default_prefix")]
+        prefix: String,
+        #[serde(default)]
+        rotation: Rotate,
+    },
+}
+
+/// Configuration for logging
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    output: Output,
+    /// The most verbose level to log: "error", "warn", "info", "debug" or "trace"
+    #[serde(default = "default_level")]
+    level: String,
+}
+
+impl Config {
+    /// Install this configuration as the global logger
+    pub fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let level: LevelFilter = self.level.parse()?;
+        // Our dependencies (notably zbus) log a lot, so only include their warnings
+        let filter = Targets::new()
+            .with_default(LevelFilter::WARN)
+            .with_target("pomotoshi", level);
+        match self.output {
+            Output::Journald => {
+                let layer = tracing_journald::layer()?.with_syslog_identifier("pomotoshi".into());
+                tracing_subscriber::registry()
+                    .with(filter.clone())
+                    .with(layer)
+                    .try_init()?;
+            }
+            Output::File {
+                ref directory,
+                ref prefix,
+                rotation,
+            } => {
+                let rotation = match rotation {
+                    Rotate::Hourly => Rotation::HOURLY,
+                    Rotate::Daily => Rotation::DAILY,
+                    Rotate::Never => Rotation::NEVER,
+                };
+                let appender = RollingFileAppender::new(rotation, directory, prefix);
+                let layer = tracing_subscriber::fmt::layer()
+                    .with_writer(appender)
+                    .with_ansi(false);
+                tracing_subscriber::registry()
+                    .with(filter)
+                    .with(layer)
+                    .try_init()?;
+            }
+        }
+        Ok(())
+    }
+}
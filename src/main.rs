@@ -19,173 +19,1457 @@
 //! provide output via xmobar.
 //!
 
-mod color;
-mod server;
-mod task;
-
-use dbus::blocking::LocalConnection;
-use dbus::channel::MatchingReceiver;
-use dbus_crossroads::{Context, Crossroads};
-use std::process::Command;
-use std::sync::{Arc, Mutex};
+use pomotoshi::handle::Handle;
+use pomotoshi::{
+    announce, audio, bar, calendar, chat, clock, control, dim, export, hooks, http, import,
+    lockout, mqtt, peer, record, server, store, systemd, task, taskwarrior, tui, webhook, window,
+    DBUS_API_VERSION, DBUS_LEGACY_ORG, DBUS_ORG, DBUS_PATH,
+};
+use std::collections::HashMap;
+use std::io::Write;
 use std::{env, fs, io};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use zbus::fdo::{RequestNameFlags, RequestNameReply};
+use zbus::message::Header;
+use zbus::object_server::SignalEmitter;
 
-/// How long cooldown (period after a block when no new blocks are allowed) should last
-const COOLDOWN_DURATION: std::time::Duration = std::time::Duration::from_secs(300);
-
-/// Frequency with which to update xmobar
-///
-/// This should be less than a second to ensure that the clock/timer is updated
-/// every second, but is otherwise more-or-less arbitrary. It does define the
-/// flashing speed so it probably should not be super low.
+/// Frequency with which to sample the active window, while it is needed
 const UPDATE_FREQ: std::time::Duration = std::time::Duration::from_millis(100);
-/// How many `UPDATE_FREO` iterations go by between saving the state of the timer out to disk
-const SAVEOUT_FREQ: usize = 10;
-/// Name of the D-Bus org
-const DBUS_ORG: &str = "org.Pomotoshi";
-/// Name of the D-Bus path
-const DBUS_PATH: &str = "/org/pomotoshi";
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config_file = env::args().nth(1);
-    let server = if let Some(Ok(fh)) = config_file.as_ref().map(fs::File::open) {
+/// How often to check whether the active window is needed, while it is not,
+/// in case that changes without the state changing
+const IDLE_UPDATE_FREQ: std::time::Duration = std::time::Duration::from_secs(1);
+/// How often to save the state of the timer out to disk
+const SAVEOUT_FREQ: std::time::Duration = std::time::Duration::from_secs(1);
+/// How often to add finished blocks to the history store
+const STORE_FREQ: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The D-Bus interface, which forwards method calls to the server, and the
+/// config file, if any
+struct Interface(Handle, Option<String>);
+
+// Methods are handled in order, as they were received, rather than spawned.
+#[zbus::interface(name = "org.Pomotoshi1", spawn = false)]
+impl Interface {
+    /// The version of the daemon, and of the D-Bus API, which is also the
+    /// suffix of the interface name
+    #[zbus(name = "getVersion", out_args("version", "api_version"))]
+    fn get_version(&self) -> (String, u32) {
+        (env!("CARGO_PKG_VERSION").into(), DBUS_API_VERSION)
+    }
+
+    /// Start a block, which lasts the given number of seconds
+    #[zbus(name = "startBlock")]
+    #[tracing::instrument(skip(self))]
+    fn start_block(&self, time_s: u64) -> zbus::fdo::Result<()> {
+        self.limited("startBlock", |server| {
+            let limit = check_daily_limit(server);
+            server.start_block(time_s, None);
+            limit
+        })?
+    }
+
+    /// The current state ("idle", "block", "paused", "break", "cooldown" or
+    /// "tracking"), seconds remaining and total seconds (0 if not counting
+    /// down), and label (empty if none)
+    #[zbus(
+        name = "getStatus",
+        out_args("state", "remaining_s", "duration_s", "label")
+    )]
+    fn get_status(&self) -> (String, u64, u64, String) {
+        let status = self.0.lock().status();
+        (
+            status.state.to_owned(),
+            status.remaining_s.unwrap_or(0),
+            status.duration_s.unwrap_or(0),
+            status.label.unwrap_or_default(),
+        )
+    }
+
+    /// Start a block with a label, which lasts the given number of seconds
+    #[zbus(name = "startLabeledBlock")]
+    #[tracing::instrument(skip(self))]
+    fn start_labeled_block(&self, time_s: u64, label: String) -> zbus::fdo::Result<()> {
+        self.limited("startLabeledBlock", |server| {
+            let limit = check_daily_limit(server);
+            server.start_block(time_s, Some(label));
+            limit
+        })?
+    }
+
+    /// Cancel the running block. If `confirm_cancel_s` is set and the block is
+    /// nearly done, this fails unless it is called twice in quick succession.
+    #[zbus(name = "cancelBlock")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn cancel_block(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, None).await?;
+        self.limited("cancelBlock", |server| server.try_cancel_block(false))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Cancel the running block, without needing confirmation even if it is
+    /// nearly done
+    #[zbus(name = "forceCancelBlock")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn force_cancel_block(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, None).await?;
+        self.limited("cancelBlock", |server| server.try_cancel_block(true))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Cancel the running block, giving the confirmation token required by
+    /// `access_control`. This needs no further confirmation.
+    #[zbus(name = "cancelBlockConfirmed")]
+    #[tracing::instrument(skip(self, header, connection, token))]
+    async fn cancel_block_confirmed(
+        &self,
+        token: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, Some(&token)).await?;
+        self.limited("cancelBlock", |server| server.try_cancel_block(true))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Pause the running block, or unpause the paused one
+    #[zbus(name = "pauseBlock")]
+    #[tracing::instrument(skip(self))]
+    fn pause_block(&self) -> zbus::fdo::Result<()> {
+        self.limited("pauseBlock", |server| server.pause_block())
+    }
+
+    /// Start a block as described by a preset in the config file
+    #[zbus(name = "startPreset")]
+    #[tracing::instrument(skip(self))]
+    fn start_preset(&self, name: String) -> zbus::fdo::Result<()> {
+        self.limited("startPreset", |server| {
+            let limit = check_daily_limit(server);
+            server.start_preset(&name);
+            limit
+        })?
+    }
+
+    /// Start a routine of alternating work and rest, as named in the config file
+    #[zbus(name = "startRoutine")]
+    #[tracing::instrument(skip(self))]
+    fn start_routine(&self, name: String) -> zbus::fdo::Result<()> {
+        self.limited("startRoutine", |server| {
+            let limit = check_daily_limit(server);
+            server.start_routine(&name);
+            limit
+        })?
+    }
+
+    /// The current routine, as its name, phase ("work" or "rest"), round and
+    /// number of rounds (0 if unlimited); the name is empty if there is none
+    #[zbus(name = "routineStatus", out_args("name", "phase", "round", "rounds"))]
+    #[tracing::instrument(skip(self))]
+    fn routine_status(&self) -> (String, String, u32, u32) {
+        match self.0.lock().routine_status() {
+            Some((name, phase, round, rounds)) => (name, phase.to_owned(), round, rounds),
+            None => (String::new(), String::new(), 0, 0),
+        }
+    }
+
+    /// Start a named timer, which runs alongside any block, for the given
+    /// number of seconds
+    #[zbus(name = "startTimer")]
+    #[tracing::instrument(skip(self))]
+    fn start_timer(&self, name: String, time_s: u64) -> zbus::fdo::Result<()> {
+        self.limited("startTimer", |server| server.start_timer(name, time_s))
+    }
+
+    /// Pause a named timer, or resume it if it is paused
+    #[zbus(name = "pauseTimer")]
+    #[tracing::instrument(skip(self))]
+    fn pause_timer(&self, name: String) -> zbus::fdo::Result<()> {
+        self.limited("pauseTimer", |server| server.pause_timer(&name))
+    }
+
+    /// Cancel a named timer
+    #[zbus(name = "cancelTimer")]
+    #[tracing::instrument(skip(self))]
+    fn cancel_timer(&self, name: String) -> zbus::fdo::Result<()> {
+        self.limited("cancelTimer", |server| server.cancel_timer(&name))
+    }
+
+    /// The most recent internal errors, e.g. failed hooks or saves, as UNIX
+    /// timestamps and messages, oldest first, followed by a summary of how
+    /// late and slow rendering the status line has been
+    #[zbus(name = "getDiagnostics", out_args("errors"))]
+    #[tracing::instrument(skip(self))]
+    fn get_diagnostics(&self) -> Vec<(u64, String)> {
+        self.0.lock().diagnostics()
+    }
+
+    /// How long a block could be, in seconds, without running into a calendar event
+    #[zbus(name = "maxBlockUntilNextEvent", out_args("time_s"))]
+    #[tracing::instrument(skip(self))]
+    fn max_block_until_next_event(&self) -> u64 {
+        self.0.lock().max_block_until_next_event()
+    }
+
+    /// Progress towards the daily goal, in seconds of focus today and the goal
+    #[zbus(name = "goalStatus", out_args("focus_s", "goal_s"))]
+    #[tracing::instrument(skip(self))]
+    fn goal_status(&self) -> (u64, u64) {
+        self.0.lock().goal_status()
+    }
+
+    /// Current streaks, in days meeting the daily goal and consecutive completed blocks
+    #[zbus(name = "streaks", out_args("days", "blocks"))]
+    #[tracing::instrument(skip(self))]
+    fn streaks(&self) -> (u64, u64) {
+        self.0.lock().streaks()
+    }
+
+    /// The most recently broken streaks, as UNIX timestamps and messages, oldest first
+    #[zbus(name = "streakBreaks", out_args("breaks"))]
+    #[tracing::instrument(skip(self))]
+    fn streak_breaks(&self) -> Vec<(u64, String)> {
+        self.0.lock().streak_breaks()
+    }
+
+    /// Number of blocks ever completed
+    #[zbus(name = "getHeight", out_args("height"))]
+    #[tracing::instrument(skip(self))]
+    fn get_height(&self) -> u64 {
+        self.0.lock().height()
+    }
+
+    /// Totals over the blocks in the history store which ended between two
+    /// times, in seconds since the UNIX epoch: the numbers of completed and
+    /// cancelled blocks, and the planned length of the completed ones
+    #[zbus(name = "historyTotals", out_args("completed", "cancelled", "focus_s"))]
+    #[tracing::instrument(skip(self))]
+    async fn history_totals(&self, from_s: u64, to_s: u64) -> zbus::fdo::Result<(u64, u64, u64)> {
+        let config = self
+            .0
+            .lock()
+            .history_store()
+            .cloned()
+            .ok_or_else(|| zbus::fdo::Error::Failed("no history store is configured".into()))?;
+        // Stores are read from disk, so keep that off the main thread
+        let aggregate = tokio::task::spawn_blocking(move || config.open()?.aggregate(from_s, to_s))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(|e| zbus::fdo::Error::Failed(format!("reading history store: {}", e)))?;
+        Ok((aggregate.completed, aggregate.cancelled, aggregate.focus_s))
+    }
+
+    /// Attach a note to the current block, or if there is none, the most
+    /// recent one, e.g. to record what was actually done in it
+    #[zbus(name = "annotateBlock")]
+    #[tracing::instrument(skip(self))]
+    fn annotate_block(&self, text: String) -> zbus::fdo::Result<()> {
+        self.limited("annotateBlock", |server| server.annotate_block(text))
+    }
+
+    /// Report on a past block, as JSON, where 0 is the most recent block
+    #[zbus(name = "blockReport", out_args("report"))]
+    #[tracing::instrument(skip(self))]
+    fn block_report(&self, index: u32) -> String {
+        self.0.lock().block_report(index as usize)
+    }
+
+    /// The whole history rendered as a standalone HTML page with charts
+    #[zbus(name = "htmlReport", out_args("html"))]
+    #[tracing::instrument(skip(self))]
+    fn html_report(&self) -> String {
+        self.0.lock().html_report()
+    }
+
+    /// An SVG heatmap of focus time by day of the week and hour, over the
+    /// given number of weeks (or all of history, if 0)
+    #[zbus(name = "focusHeatmap", out_args("svg"))]
+    #[tracing::instrument(skip(self))]
+    fn focus_heatmap(&self, weeks: u32) -> zbus::fdo::Result<String> {
+        self.0.lock().focus_heatmap(weeks).ok_or_else(|| {
+            zbus::fdo::Error::Failed("could not determine local times of blocks".into())
+        })
+    }
+
+    /// Apply a named color theme
+    #[zbus(name = "setTheme")]
+    #[tracing::instrument(skip(self))]
+    fn set_theme(&self, name: String) -> zbus::fdo::Result<()> {
+        self.limited("setTheme", |server| server.set_theme(&name))
+    }
+
+    /// Set individual colors, e.g. `{"idle": "#888"}`
+    #[zbus(name = "setColors")]
+    #[tracing::instrument(skip(self))]
+    fn set_colors(&self, colors: HashMap<String, String>) -> zbus::fdo::Result<()> {
+        self.limited("setColors", |server| server.set_colors(&colors))
+    }
+
+    /// Output the log of the most recent block
+    #[zbus(name = "blockLog", out_args("log"))]
+    #[tracing::instrument(skip(self))]
+    fn block_log(&self) -> String {
+        self.0.with_server(|server| server.block_log())
+    }
+
+    /// Create (or clear) a task log
+    #[zbus(name = "taskLogAdd")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn task_log_add(
+        &self,
+        name: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_replace(&name, &header, connection).await?;
+        self.limited("taskLogAdd", |server| server.task_log_add(name))
+    }
+
+    /// Create (or clear) a task log which only records tasks whose paths,
+    /// joined with " / ", match `include` and don't match `exclude` (either
+    /// of which may be empty, to not filter on it)
+    #[zbus(name = "taskLogAddFiltered")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn task_log_add_filtered(
+        &self,
+        name: String,
+        include: String,
+        exclude: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        let parse = |re: &str| match re {
+            "" => Ok(vec![]),
+            re => regex::Regex::new(re)
+                .map(|re| vec![re])
+                .map_err(|e| zbus::fdo::Error::InvalidArgs(e.to_string())),
+        };
+        let filter = task::Filter {
+            include: parse(&include)?,
+            exclude: parse(&exclude)?,
+        };
+        self.check_replace(&name, &header, connection).await?;
+        self.limited("taskLogAddFiltered", |server| {
+            server.task_log_add_filtered(name, Some(filter))
+        })
+    }
+
+    /// Delete a task log
+    #[zbus(name = "taskLogRemove")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn task_log_remove(
+        &self,
+        name: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, None).await?;
+        self.limited("taskLogRemove", |server| server.task_log_remove(&name))
+    }
+
+    /// Delete a task log, giving the confirmation token required by
+    /// `access_control`
+    #[zbus(name = "taskLogRemoveConfirmed")]
+    #[tracing::instrument(skip(self, header, connection, token))]
+    async fn task_log_remove_confirmed(
+        &self,
+        name: String,
+        token: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, Some(&token)).await?;
+        self.limited("taskLogRemove", |server| server.task_log_remove(&name))
+    }
+
+    /// Manually add time to a task (given as a root-first path) in a task log
+    #[zbus(name = "taskLogAddTime")]
+    #[tracing::instrument(skip(self))]
+    fn task_log_add_time(
+        &self,
+        name: String,
+        path: Vec<String>,
+        time_s: u64,
+    ) -> zbus::fdo::Result<()> {
+        self.limited("taskLogAddTime", |server| {
+            server.task_log_add_time(&name, path, time_s)
+        })
+    }
+
+    /// Manually move time between tasks (given as root-first paths) in a task log
+    #[zbus(name = "taskLogMoveTime")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn task_log_move_time(
+        &self,
+        name: String,
+        from_path: Vec<String>,
+        to_path: Vec<String>,
+        time_s: u64,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, None).await?;
+        self.limited("taskLogMoveTime", |server| {
+            server.task_log_move_time(&name, from_path, to_path, time_s)
+        })
+    }
+
+    /// Manually move time between tasks in a task log, giving the
+    /// confirmation token required by `access_control`
+    #[zbus(name = "taskLogMoveTimeConfirmed")]
+    #[tracing::instrument(skip(self, header, connection, token))]
+    #[allow(clippy::too_many_arguments)]
+    async fn task_log_move_time_confirmed(
+        &self,
+        name: String,
+        from_path: Vec<String>,
+        to_path: Vec<String>,
+        time_s: u64,
+        token: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, Some(&token)).await?;
+        self.limited("taskLogMoveTime", |server| {
+            server.task_log_move_time(&name, from_path, to_path, time_s)
+        })
+    }
+
+    /// Write a task log to a file, as JSON
+    #[zbus(name = "taskLogExport")]
+    #[tracing::instrument(skip(self))]
+    async fn task_log_export(&self, name: String, path: String) -> zbus::fdo::Result<()> {
+        let log = self
+            .limited("taskLogExport", |server| server.task_log_export(&name))?
+            .map_err(zbus::fdo::Error::Failed)?;
+        // Keep writing the file off the main thread, and outside the lock
+        let write_path = path.clone();
+        let result = tokio::task::spawn_blocking(move || log.write_file(write_path.as_ref()))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+        self.0
+            .lock()
+            .task_log_exported(&name, path.as_ref(), result)
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Add the blocks recorded by another tool to the history store, from a
+    /// file in the given format (`pomobar`, `timewarrior` or `csv`),
+    /// returning a description of what was imported
+    #[zbus(name = "importHistory", out_args("imported"))]
+    #[tracing::instrument(skip(self))]
+    async fn import_history(&self, format: String, path: String) -> zbus::fdo::Result<String> {
+        let format: import::Format = format.parse().map_err(zbus::fdo::Error::Failed)?;
+        let config = self
+            .limited("importHistory", |server| server.history_store().cloned())?
+            .ok_or_else(|| zbus::fdo::Error::Failed("no history store is configured".into()))?;
+        // Files and stores are read from disk, so keep that off the main thread
+        let description =
+            tokio::task::spawn_blocking(move || import::import(format, path.as_ref(), &config))
+                .await
+                .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+                .map_err(zbus::fdo::Error::Failed)?;
+        self.0.lock().history_imported(&description);
+        Ok(description)
+    }
+
+    /// Read a task log from a file written by `taskLogExport`, replacing any existing log
+    #[zbus(name = "taskLogImport")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn task_log_import(
+        &self,
+        name: String,
+        path: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_replace(&name, &header, connection).await?;
+        self.limited("taskLogImport", |_| ())?;
+        // Keep reading the file off the main thread, and outside the lock
+        let read_path = path.clone();
+        let result = tokio::task::spawn_blocking(move || task::Task::read_file(read_path.as_ref()))
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+        self.0
+            .lock()
+            .task_log_import(name, path.as_ref(), result)
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Output the contents of a task log
+    #[zbus(name = "taskLogOutput", out_args("log"))]
+    #[tracing::instrument(skip(self))]
+    fn task_log_output(&self, name: String) -> String {
+        self.0.with_server(|server| server.task_log_dump(&name))
+    }
+
+    /// Fold small tasks which have not been worked on lately into "(other)"
+    #[zbus(name = "taskLogCompact")]
+    #[tracing::instrument(skip(self, header, connection))]
+    async fn task_log_compact(
+        &self,
+        name: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, None).await?;
+        self.limited("taskLogCompact", |server| server.task_log_compact(&name))
+    }
+
+    /// Fold small tasks into "(other)", giving the confirmation token
+    /// required by `access_control`
+    #[zbus(name = "taskLogCompactConfirmed")]
+    #[tracing::instrument(skip(self, header, connection, token))]
+    async fn task_log_compact_confirmed(
+        &self,
+        name: String,
+        token: String,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        self.check_access(&header, connection, Some(&token)).await?;
+        self.limited("taskLogCompact", |server| server.task_log_compact(&name))
+    }
+
+    /// Output the part of a task log recorded between two times, in seconds
+    /// since the UNIX epoch
+    #[zbus(name = "taskLogBetween", out_args("log"))]
+    #[tracing::instrument(skip(self))]
+    fn task_log_between(&self, name: String, start_s: u64, end_s: u64) -> String {
+        self.0
+            .with_server(|server| server.task_log_between(&name, start_s, end_s))
+    }
+
+    /// Output the contents of a task log as a markdown list
+    #[zbus(name = "taskLogMarkdown", out_args("log"))]
+    #[tracing::instrument(skip(self))]
+    fn task_log_markdown(&self, name: String) -> String {
+        self.0.with_server(|server| server.task_log_markdown(&name))
+    }
+
+    /// Output the contents of a task log as a Graphviz DOT graph
+    #[zbus(name = "taskLogDot", out_args("dot"))]
+    #[tracing::instrument(skip(self))]
+    fn task_log_dot(&self, name: String) -> String {
+        self.0.with_server(|server| server.task_log_dot(&name))
+    }
+
+    /// Undo the most recent destructive action (cancelling a block, or removing,
+    /// replacing or compacting a task log), returning a description of what was
+    /// undone
+    #[zbus(name = "undoLast", out_args("undone"))]
+    #[tracing::instrument(skip(self))]
+    fn undo_last(&self) -> zbus::fdo::Result<String> {
+        self.limited("undoLast", |server| server.undo_last())?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Time away during blocks (e.g. with the monitors off) waiting to be
+    /// resolved, oldest first, as start and end times in seconds since the
+    /// UNIX epoch and the task (as a root-first path) focused before it
+    #[zbus(name = "pendingAway", out_args("away"))]
+    #[tracing::instrument(skip(self))]
+    fn pending_away(&self) -> Vec<(u64, u64, Vec<String>)> {
+        self.0.lock().pending_away()
+    }
+
+    /// Decide whether the oldest time away counts: `keep` records it for the
+    /// task focused before, `assign:PATH` for the task with the given path
+    /// (separated by `/`), and `discard` leaves it unrecorded
+    #[zbus(name = "resolveAway", out_args("resolved"))]
+    #[tracing::instrument(skip(self))]
+    fn resolve_away(&self, choice: String) -> zbus::fdo::Result<String> {
+        self.limited("resolveAway", |server| server.resolve_away(&choice))?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Re-read the config file, applying any changes without interrupting the
+    /// running block
+    #[zbus(name = "reloadConfig")]
+    #[tracing::instrument(skip(self))]
+    fn reload_config(&self) -> zbus::fdo::Result<()> {
+        let file = self
+            .1
+            .as_ref()
+            .ok_or_else(|| zbus::fdo::Error::Failed("no config file".into()))?;
+        reload_config(&self.0, file).map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Sent on each state transition, with its name (as given to hooks), and
+    /// the label and planned length of the block it concerns (empty and 0 if
+    /// none)
+    #[zbus(signal, name = "transition")]
+    async fn transition(
+        emitter: &SignalEmitter<'_>,
+        event: &str,
+        label: &str,
+        duration_s: u64,
+    ) -> zbus::Result<()>;
+}
+
+impl Interface {
+    /// Check that the sender of a call to a destructive method is allowed to
+    /// make it, given the confirmation token it passed, if any
+    async fn check_access(
+        &self,
+        header: &Header<'_>,
+        connection: &zbus::Connection,
+        token: Option<&str>,
+    ) -> zbus::fdo::Result<()> {
+        let config = match self.0.lock().access_control() {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+        let uid = match header.sender() {
+            Some(sender) if config.checks_uid() => {
+                let proxy = zbus::fdo::DBusProxy::new(connection).await?;
+                Some(
+                    proxy
+                        .get_connection_unix_user(sender.clone().into())
+                        .await?,
+                )
+            }
+            _ => None,
+        };
+        config.check(uid, token).map_err(|e| {
+            tracing::warn!("refused call: {}", e);
+            zbus::fdo::Error::AccessDenied(e)
+        })
+    }
+
+    /// Check that the sender may replace the task log `name`, if there is
+    /// one, which needs the same access as removing it
+    async fn check_replace(
+        &self,
+        name: &str,
+        header: &Header<'_>,
+        connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<()> {
+        let exists = self.0.lock().has_task_log(name);
+        if exists {
+            self.check_access(header, connection, None).await?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` on the server, unless `method` has been called too often
+    /// recently, in which case the call is refused without touching the server
+    fn limited<T>(
+        &self,
+        method: &str,
+        f: impl FnOnce(&mut server::Server) -> T,
+    ) -> zbus::fdo::Result<T> {
+        self.0.with_server(|server| {
+            if server.allow_call(method) {
+                Ok(f(server))
+            } else {
+                Err(zbus::fdo::Error::LimitsExceeded(format!(
+                    "{} called too often",
+                    method
+                )))
+            }
+        })
+    }
+}
+
+/// The interface under its old name, `DBUS_LEGACY_ORG`, which has the same
+/// methods and signals as the current one
+struct LegacyInterface(Interface);
+
+#[async_trait::async_trait]
+impl zbus::object_server::Interface for LegacyInterface {
+    fn name() -> zbus::names::InterfaceName<'static> {
+        zbus::names::InterfaceName::from_static_str_unchecked(DBUS_LEGACY_ORG)
+    }
+
+    fn spawn_tasks_for_methods(&self) -> bool {
+        self.0.spawn_tasks_for_methods()
+    }
+
+    async fn get(
+        &self,
+        property_name: &str,
+        server: &zbus::ObjectServer,
+        connection: &zbus::Connection,
+        header: Option<&Header<'_>>,
+        emitter: &SignalEmitter<'_>,
+    ) -> Option<zbus::fdo::Result<zbus::zvariant::OwnedValue>> {
+        self.0
+            .get(property_name, server, connection, header, emitter)
+            .await
+    }
+
+    async fn get_all(
+        &self,
+        server: &zbus::ObjectServer,
+        connection: &zbus::Connection,
+        header: Option<&Header<'_>>,
+        emitter: &SignalEmitter<'_>,
+    ) -> zbus::fdo::Result<HashMap<String, zbus::zvariant::OwnedValue>> {
+        self.0.get_all(server, connection, header, emitter).await
+    }
+
+    async fn set_mut(
+        &mut self,
+        property_name: &str,
+        value: &zbus::zvariant::Value<'_>,
+        server: &zbus::ObjectServer,
+        connection: &zbus::Connection,
+        header: Option<&Header<'_>>,
+        emitter: &SignalEmitter<'_>,
+    ) -> Option<zbus::fdo::Result<()>> {
+        self.0
+            .set_mut(property_name, value, server, connection, header, emitter)
+            .await
+    }
+
+    fn call<'call>(
+        &'call self,
+        server: &'call zbus::ObjectServer,
+        connection: &'call zbus::Connection,
+        msg: &'call zbus::Message,
+        name: zbus::names::MemberName<'call>,
+    ) -> zbus::object_server::DispatchResult2<'call> {
+        tracing::warn!(
+            method = %name,
+            "called through the deprecated D-Bus interface {}",
+            DBUS_LEGACY_ORG
+        );
+        self.0.call(server, connection, msg, name)
+    }
+
+    fn call_mut<'call>(
+        &'call mut self,
+        server: &'call zbus::ObjectServer,
+        connection: &'call zbus::Connection,
+        msg: &'call zbus::Message,
+        name: zbus::names::MemberName<'call>,
+    ) -> zbus::object_server::DispatchResult2<'call> {
+        self.0.call_mut(server, connection, msg, name)
+    }
+
+    fn introspect_to_writer(&self, writer: &mut dyn std::fmt::Write, level: usize) {
+        let mut xml = String::new();
+        self.0.introspect_to_writer(&mut xml, level);
+        let from = format!("<interface name=\"{}\">", DBUS_ORG);
+        let to = format!("<interface name=\"{}\">", DBUS_LEGACY_ORG);
+        let _ = writer.write_str(&xml.replacen(&from, &to, 1));
+    }
+}
+
+/// Explain to D-Bus clients that a block cannot be started, if the daily
+/// limit has been reached
+fn check_daily_limit(server: &mut server::Server) -> zbus::fdo::Result<()> {
+    match server.daily_limit_reached() {
+        Some(reason) => Err(zbus::fdo::Error::Failed(reason)),
+        None => Ok(()),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Options may be given anywhere; the first other argument is the config file
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let mut tui_mode = false;
+    let mut no_output = false;
+    let mut time_scale = None;
+    let mut record_file = None;
+    let mut replay_file = None;
+    for flag in flags {
+        match flag.as_str() {
+            "--tui" => tui_mode = true,
+            "--no-output" => no_output = true,
+            // Also for watching fades and transitions while testing
+            _ if flag.starts_with("--time-scale=") => {
+                let scale = flag["--time-scale=".len()..]
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|scale| *scale > 0)
+                    .ok_or_else(|| format!("bad time scale in {}", flag))?;
+                time_scale = Some(scale);
+            }
+            _ if flag.starts_with("--record=") => {
+                record_file = Some(flag["--record=".len()..].to_owned());
+            }
+            _ if flag.starts_with("--replay=") => {
+                replay_file = Some(flag["--replay=".len()..].to_owned());
+            }
+            _ => return Err(format!("unknown option {}", flag).into()),
+        }
+    }
+    let replay = match replay_file {
+        Some(ref file) => Some(record::Replay::load(file.as_ref())?),
+        None => None,
+    };
+    // A replay runs from the time recording started, on a clock which is
+    // set before anything reads it
+    if time_scale.is_some() || replay.is_some() {
+        let start = replay.as_ref().map(|replay| replay.start_time());
+        clock::set(time_scale.unwrap_or(1), start)?;
+    }
+    let config_file = args.into_iter().next();
+    // A sped-up run or replay would fill the history with nonsense, so never
+    // save it
+    let state_file = config_file.clone().filter(|_| !clock::is_simulated());
+    let handle = if let Some(ref replay) = replay {
+        Handle::new(replay.server()?)
+    } else if let Some(Ok(fh)) = config_file.as_ref().map(fs::File::open) {
         let buf_reader = io::BufReader::new(fh);
-        Arc::new(Mutex::new(serde_json::from_reader(buf_reader)?))
+        Handle::new(serde_json::from_reader(buf_reader)?)
     } else {
-        Arc::new(Mutex::new(server::Server::new()))
+        Handle::new(server::Server::new())
     };
+    if let Some(ref file) = record_file {
+        record::start(file.as_ref(), &handle.lock())?;
+    }
 
-    // Start D-Bus connection
-    let c = LocalConnection::new_session()?;
-    // See https://dbus.freedesktop.org/doc/api/html/group__DBusBus.html for documentation
-    // of flags, which for some reason are exposed by the Rust API as undocumented booleans.
-    c.request_name(
-        // Name
-        DBUS_ORG,
-        // DBUS_NAME_FLAG_ALLOW_REPLACEMENT -- don't allow other instances to replace us
-        false,
-        // DBUS_NAME_FLAG_REPLACE_EXISTING -- don't try to replace other instances
-        false, // DBUS_NAME_FLAG_DO_NOT_QUEUE -- if another instance exists, just fail
-        true,
-    )?;
-
-    // Setup Crossroads instance
-    let mut cr = Crossroads::new();
-
-    let iface_token = cr.register(DBUS_ORG, |b| {
-        // startBlock method: takes an integer number of time, in seconds
-        b.method(
-            "startBlock", // name
-            ("time_s",),  // input args
-            (),           // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, (time_s,): (u64,)| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                lock.start_block(time_s);
-                Ok(())
-            },
-        );
-        b.method(
-            "cancelBlock", // name
-            (),            // input args
-            (),            // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, _: ()| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                lock.cancel_block();
-                Ok(())
-            },
-        );
-        b.method(
-            "pauseBlock", // name
-            (),           // input args
-            (),           // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, _: ()| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                lock.pause_block();
-                Ok(())
-            },
-        );
-        b.method(
-            "blockLog", // name
-            (),         // input args
-            ("log",),   // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, _: ()| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                Ok((lock.block_log(),))
-            },
-        );
-        b.method(
-            "taskLogAdd", // name
-            ("name",),    // input args
-            (),           // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, (name,): (String,)| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                lock.task_log_add(name);
-                Ok(())
-            },
-        );
-        b.method(
-            "taskLogRemove", // name
-            ("name",),       // input args
-            (),              // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, (name,): (String,)| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                lock.task_log_remove(&name);
-                Ok(())
-            },
-        );
-        b.method(
-            "taskLogOutput", // name
-            ("name",),       // input args
-            ("log",),        // output args
-            move |_: &mut Context, server: &mut Arc<Mutex<server::Server>>, (name,): (String,)| {
-                let mut lock = server.lock().expect("server did not witness a panic");
-                Ok((lock.task_log_dump(&name),))
-            },
-        );
-    });
-    cr.insert(DBUS_PATH, &[iface_token], Arc::clone(&server));
+    // Start logging, if configured
+    let logging_config = handle.lock().logging().cloned();
+    if let Some(config) = logging_config {
+        config.init()?;
+    }
 
-    // Serve clients forever.
-    // We add the Crossroads instance to the connection so that incoming method calls will be handled.
-    c.start_receive(
-        dbus::message::MatchRule::new_method_call(),
-        Box::new(move |msg, conn| {
-            cr.handle_message(msg, conn).unwrap();
-            true
-        }),
-    );
+    // Start the control socket, if configured
+    let control_socket = handle.lock().control_socket().map(|path| path.to_owned());
+    if let Some(ref path) = control_socket {
+        let listener = control::bind(path)?;
+        tokio::spawn(control::serve(listener, handle.clone()));
+    }
+    let control_tcp = handle.lock().control_tcp().cloned();
+    if let Some(ref config) = control_tcp {
+        control::serve_tcp(config.clone(), handle.clone()).await?;
+    }
+
+    // Start serving the status line to bars, if configured
+    let bar_socket = handle.lock().bar_socket().map(|path| path.to_owned());
+    if let Some(ref path) = bar_socket {
+        let listener = bar::bind(path)?;
+        tokio::spawn(bar::serve(listener, handle.clone()));
+    }
+
+    // Start the HTTP server, if configured
+    let http_listen = handle.lock().http_listen().map(|addr| addr.to_owned());
+    if let Some(ref addr) = http_listen {
+        let listener = http::bind(addr).await?;
+        tokio::spawn(http::serve(listener, handle.clone()));
+    }
+
+    // Start publishing to MQTT, if configured
+    let mqtt_config = handle.lock().mqtt().cloned();
+    if let Some(config) = mqtt_config {
+        mqtt::start(config, handle.clone())?;
+    }
+
+    // Start pairing with a partner's daemon, if configured
+    let peer_config = handle.lock().peer().cloned();
+    if let Some(config) = peer_config {
+        peer::start(config, handle.clone()).await?;
+    }
+
+    // Start running user-configured hooks, notifying webhooks, setting chat
+    // statuses and posting announcements. These are started even if there are
+    // none, in case some are added by reloading.
+    tokio::spawn(hooks::run(handle.clone(), handle.subscribe_events()));
+    tokio::spawn(webhook::run(handle.clone(), handle.subscribe_events()));
+    tokio::spawn(chat::run(handle.clone(), handle.subscribe_events()));
+    tokio::spawn(announce::run(handle.clone(), handle.subscribe_events()));
+
+    // Start the Taskwarrior integration, if configured
+    let taskwarrior_config = handle.lock().taskwarrior().cloned();
+    if let Some(config) = taskwarrior_config {
+        let events = handle.subscribe_events();
+        tokio::spawn(taskwarrior::run(config, handle.clone(), events));
+    }
+
+    // Start exporting completed blocks, if configured
+    let export_config = handle.lock().export().cloned();
+    if let Some(config) = export_config {
+        tokio::spawn(export_blocks(handle.clone(), config));
+    }
+
+    // Start adding finished blocks to the history store, if configured
+    let store_config = handle.lock().history_store().cloned();
+    if let Some(config) = store_config {
+        tokio::spawn(store_blocks(handle.clone(), config));
+    }
+
+    // Start syncing with other machines, if configured
+    let sync_interval = handle.lock().sync().map(|config| config.interval());
+    if let Some(interval) = sync_interval {
+        tokio::spawn(sync_machines(handle.clone(), interval));
+    }
+
+    // Start writing daily reports; the config is checked each time, so this
+    // is started even if there is none, in case one is added by reloading.
+    tokio::spawn(write_daily_reports(handle.clone()));
+
+    // Start reading the calendar, if configured
+    let calendar_config = handle.lock().calendar().cloned();
+    if let Some(config) = calendar_config {
+        tokio::spawn(refresh_calendar(handle.clone(), config));
+    }
+
+    // Start D-Bus connection, which is optional if we have another interface
+    // and there is no session bus; if there is one, but another instance is
+    // already on it, we exit rather than run a second timer
+    let connection = match connect_dbus(handle.clone(), config_file.clone()).await {
+        Ok(connection) => {
+            claim_dbus_name(&connection).await?;
+            Some(connection)
+        }
+        Err(e)
+            if tui_mode
+                || replay.is_some()
+                || control_socket.is_some()
+                || control_tcp.is_some()
+                || http_listen.is_some() =>
+        {
+            tracing::warn!(error = %e, "not using D-Bus");
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(ref connection) = connection {
+        let events = handle.subscribe_events();
+        tokio::spawn(emit_signals(connection.clone(), events));
+    }
+
+    // Start locking out input devices and dimming the screen during cooldowns.
+    // These are started even if unconfigured, and undo anything left by a
+    // previous run, so must wait until we know that no other instance is
+    // running, or they would undo its lockout or dimming.
+    tokio::spawn(lockout::run(handle.clone()));
+    tokio::spawn(dim::run(handle.clone()));
+
+    // When replaying, windows come from the recording rather than from X
+    match replay {
+        Some(replay) => {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                replay.run(handle).await;
+                tracing::info!("finished replaying");
+            });
+        }
+        None => {
+            tokio::spawn(track_windows(handle.clone()));
+        }
+    }
+    tokio::spawn(run_hooks(handle.clone(), handle.subscribe_events()));
+    tokio::spawn(save_state(handle.clone(), state_file.clone()));
+    tokio::spawn(reload_on_sighup(handle.clone(), config_file.clone()));
+
+    // Tell systemd we're up, now that clients can reach us, if it is supervising us
+    if let Err(e) = systemd::notify_ready() {
+        handle
+            .lock()
+            .signal_error(&format!("notifying systemd: {}", e));
+    }
+    tokio::spawn(systemd::watchdog(handle.clone()));
+
+    // The status line is rendered even if it is not printed, if bars may ask for it
+    let share_line = bar_socket.is_some() || http_listen.is_some();
+    if tui_mode {
+        // Keep stdout for the dashboard, and exit once the user quits it
+        tokio::spawn(render(handle.clone(), false, share_line));
+        let tui_handle = handle.clone();
+        tokio::task::spawn_blocking(move || tui::run(tui_handle)).await??;
+        if let Some(ref file) = state_file {
+            write_state(&handle, file);
+        }
+        return Ok(());
+    }
 
     // Serve clients forever.
-    let mut counter = 0;
+    render(handle, !no_output, share_line).await;
+    Ok(())
+}
+
+/// Connect to the session bus and serve our interface on it, under both its
+/// current and legacy names, failing if there is no session bus
+async fn connect_dbus(
+    handle: Handle,
+    config_file: Option<String>,
+) -> zbus::Result<zbus::Connection> {
+    let legacy = LegacyInterface(Interface(handle.clone(), config_file.clone()));
+    zbus::connection::Builder::session()?
+        .serve_at(DBUS_PATH, Interface(handle, config_file))?
+        .serve_at(DBUS_PATH, legacy)?
+        .build()
+        .await
+}
+
+/// Claim our names on the session bus, failing if another instance has
+/// either of them
+async fn claim_dbus_name(connection: &zbus::Connection) -> Result<(), Box<dyn std::error::Error>> {
+    // Don't allow other instances to replace us, don't try to replace other
+    // instances, and if another instance exists, just fail.
+    for name in [DBUS_ORG, DBUS_LEGACY_ORG] {
+        let reply = connection
+            .request_name_with_flags(name, RequestNameFlags::DoNotQueue.into())
+            .await?;
+        if reply != RequestNameReply::PrimaryOwner {
+            return Err(format!("could not acquire D-Bus name {} ({:?})", name, reply).into());
+        }
+    }
+    Ok(())
+}
+
+/// Send state transitions to D-Bus clients, as signals
+async fn emit_signals(
+    connection: zbus::Connection,
+    mut events: broadcast::Receiver<server::Transition>,
+) {
+    let emitter = SignalEmitter::new(&connection, DBUS_PATH).expect("DBUS_PATH is valid");
     loop {
-        // D-Bus updates
-        c.process(UPDATE_FREQ)?;
-
-        let mut lock = server.lock().expect("server did not witness a panic");
-
-        // Record currently-active window
-        let curr_win = Command::new("xdotool")
-            .arg("getwindowfocus")
-            .arg("getwindowname")
-            .output()
-            .expect("executing xdotool")
-            .stdout;
-        lock.record_current_window(String::from_utf8_lossy(&curr_win).as_ref());
-
-        // Output state to xmobar
-        println!("{}", lock.xmobar_update());
-
-        counter = (counter + 1) % SAVEOUT_FREQ;
-        if counter == 0 {
-            if let Some(fh) = config_file.as_ref().map(fs::File::create) {
-                if let Ok(fh) = fh {
-                    if serde_json::to_writer(fh, &*lock).is_err() {
-                        lock.signal_error();
+        let transition = match events.recv().await {
+            Ok(transition) => transition,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let label = transition.label.unwrap_or_default();
+        let duration_s = transition.duration_s.unwrap_or(0);
+        let result =
+            Interface::transition(&emitter, transition.event.name(), &label, duration_s).await;
+        if let Err(e) = result {
+            tracing::warn!(error = %e, "sending transition signal");
+        }
+        let body = (transition.event.name(), label.as_str(), duration_s);
+        if let Err(e) = emitter.emit(DBUS_LEGACY_ORG, "transition", &body).await {
+            tracing::warn!(error = %e, "sending legacy transition signal");
+        }
+    }
+}
+
+/// Output state to xmobar, whenever it changes, if `print_output` is set
+///
+/// Also advances the state machine, and publishes any state transitions, e.g.
+/// to the hook-running task. If `share_line` is set, the status line is also
+/// published for other bars. If neither is set, it is not rendered at all.
+///
+/// When started by D-Bus activation there may be nobody reading stdout, so if
+/// writing to it fails, we stop printing but otherwise carry on.
+async fn render(handle: Handle, mut print_output: bool, share_line: bool) {
+    let mut last_output = String::new();
+    // When the last tick was meant to happen, in real time rather than on the
+    // clock (which may be sped up), if this render is one
+    let mut intended = None;
+    loop {
+        let wakeup = {
+            let mut lock = handle.lock();
+            // Lateness includes waiting for the lock, e.g. behind a stalled client
+            let late = intended
+                .take()
+                .map(|intended| std::time::Instant::now().saturating_duration_since(intended));
+            let started = std::time::Instant::now();
+            if print_output || share_line {
+                let output = lock.xmobar_update();
+                if share_line {
+                    handle.publish_line(&output);
+                }
+                if print_output && output != last_output {
+                    let mut stdout = io::stdout().lock();
+                    if let Err(e) = writeln!(stdout, "{}", output).and_then(|_| stdout.flush()) {
+                        lock.signal_error(&format!("writing status to stdout: {}", e));
+                        print_output = false;
                     }
-                } else {
-                    lock.signal_error();
+                    last_output = output;
+                }
+            } else {
+                lock.update();
+            }
+            handle.publish_status(lock.status());
+            for event in lock.take_events() {
+                handle.publish_event(event);
+            }
+            lock.record_render(late, started.elapsed());
+            lock.next_wakeup()
+        };
+
+        // Sleep until the output next changes, or a client changes it
+        let wakeup = clock::real_time(wakeup);
+        tokio::select! {
+            _ = tokio::time::sleep_until(wakeup.into()) => intended = Some(wakeup),
+            _ = handle.changed() => {}
+        }
+    }
+}
+
+/// Record the active window, whenever the server wants it
+async fn track_windows(handle: Handle) {
+    let mut interval = tokio::time::interval(UPDATE_FREQ);
+    let mut status = handle.subscribe_status();
+    let mut failing = false;
+    let skip = || {
+        record::skip_window();
+        handle.lock().skip_current_window();
+    };
+    loop {
+        interval.tick().await;
+        if !handle.lock().wants_current_window() {
+            // Rather than waking up ten times a second, wait for the state to
+            // change (e.g. a block to start) before checking again, without
+            // recording the time waited against any window
+            skip();
+            tokio::select! {
+                _ = status.changed() => {}
+                _ = tokio::time::sleep(IDLE_UPDATE_FREQ) => {}
+            }
+            skip();
+            interval.reset();
+            continue;
+        }
+
+        // Querying X involves several subprocesses, so keep it off the main thread
+        let (with_monitor, with_class, with_desktop, with_fullscreen, with_microphone) = {
+            let lock = handle.lock();
+            (
+                lock.tracks_monitor(),
+                lock.tracks_class(),
+                lock.tracks_desktop(),
+                lock.tracks_fullscreen(),
+                lock.tracks_microphone(),
+            )
+        };
+        let query = move || -> io::Result<_> {
+            if with_monitor && window::monitor_off()? {
+                return Ok(None);
+            }
+            let win = window::Window::focused(with_class, with_desktop, with_fullscreen)?;
+            let microphone = if with_microphone {
+                audio::microphone_users()?
+            } else {
+                vec![]
+            };
+            Ok(Some((win, microphone)))
+        };
+        match tokio::task::spawn_blocking(query).await {
+            Ok(Ok(Some((win, microphone)))) => {
+                failing = false;
+                let mut lock = handle.lock();
+                record::window(&win, &microphone);
+                lock.record_current_window(&win, &microphone, clock::now());
+            }
+            Ok(Ok(None)) => {
+                failing = false;
+                record::monitor_off();
+                handle.lock().record_monitor_off(clock::now());
+            }
+            Ok(Err(e)) => {
+                // Only signal the first of a run of failures, which could go on
+                // indefinitely, e.g. if X is not running
+                if !failing {
+                    handle
+                        .lock()
+                        .signal_error(&format!("querying window: {}", e));
                 }
+                failing = true;
             }
+            Err(_) => {}
         }
     }
 }
+
+/// Run external commands in response to state transitions
+///
+/// Like user-configured hooks, these are killed if they run for longer than
+/// the hook timeout.
+async fn run_hooks(handle: Handle, mut events: broadcast::Receiver<server::Transition>) {
+    let notify = |message: String| {
+        let mut command = tokio::process::Command::new("notify-send");
+        command.arg("Pomotoshi").arg(message);
+        command
+    };
+    loop {
+        let transition = match events.recv().await {
+            Ok(transition) => transition,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let command = match transition.event {
+            server::Event::Halving => {
+                let height = handle.lock().height();
+                notify(format!("Halving! Block height {} reached.", height))
+            }
+            server::Event::WrapUp => {
+                if !handle
+                    .lock()
+                    .wrap_up()
+                    .is_some_and(|wrap_up| wrap_up.notify())
+                {
+                    continue;
+                }
+                let remaining_s = handle.lock().status().remaining_s.unwrap_or(0);
+                notify(format!(
+                    "{} minutes left; time to wrap up.",
+                    remaining_s.div_ceil(60)
+                ))
+            }
+            server::Event::BudgetExceeded => {
+                let task = transition.detail.unwrap_or_default();
+                notify(format!("Weekly budget for {} exceeded.", task))
+            }
+            server::Event::BreakStarted => {
+                if !handle
+                    .lock()
+                    .break_config()
+                    .is_some_and(|config| config.notify())
+                {
+                    continue;
+                }
+                notify("Time for a break: step away from the screen.".into())
+            }
+            server::Event::BreakSuggestion => {
+                if !handle
+                    .lock()
+                    .break_suggestions()
+                    .is_some_and(|config| config.notify())
+                {
+                    continue;
+                }
+                notify(transition.detail.unwrap_or_default())
+            }
+            server::Event::ScreenLocked => {
+                // Some lockers only exit once the screen is unlocked, so this
+                // is neither timed out nor waited for
+                let lock_command = handle.lock().lock_command().to_owned();
+                let mut command = tokio::process::Command::new("sh");
+                command.arg("-c").arg(lock_command);
+                hooks::spawn_reported(&handle, transition.event.name(), command);
+                continue;
+            }
+            server::Event::TimerFinished => {
+                let name = transition.detail.unwrap_or_default();
+                notify(format!("Timer {} finished.", name))
+            }
+            _ => continue,
+        };
+        hooks::run_reported(&handle, transition.event.name(), command, None).await;
+    }
+}
+
+/// Periodically re-read the calendar
+async fn refresh_calendar(handle: Handle, config: calendar::Config) {
+    let mut interval = tokio::time::interval(config.refresh_interval());
+    loop {
+        interval.tick().await;
+        let config = config.clone();
+        match tokio::task::spawn_blocking(move || config.load()).await {
+            Ok(Ok(events)) => handle.lock().set_calendar_events(events),
+            Ok(Err(e)) => handle
+                .lock()
+                .signal_error(&format!("reading calendar: {}", e)),
+            Err(e) => handle
+                .lock()
+                .signal_error(&format!("reading calendar: {}", e)),
+        }
+    }
+}
+
+/// Periodically export completed blocks to other time-tracking tools
+async fn export_blocks(handle: Handle, config: export::Config) {
+    let mut interval = tokio::time::interval(config.interval());
+    loop {
+        interval.tick().await;
+        let mut blocks = handle.lock().pending_exports();
+        if blocks.is_empty() {
+            continue;
+        }
+        blocks.sort_by_key(|block| block.end_s);
+
+        // Exporting runs external commands, so keep it off the main thread.
+        // Blocks are exported one at a time, and each is marked as exported
+        // as soon as it is, so that none are exported twice if a later one
+        // fails.
+        for block in blocks {
+            let config = config.clone();
+            let end_s = block.end_s;
+            let result = tokio::task::spawn_blocking(move || config.export(&[block])).await;
+            let mut lock = handle.lock();
+            match result {
+                Ok(Ok(())) => lock.mark_exported(end_s),
+                Ok(Err(e)) => {
+                    lock.signal_error(&format!("exporting blocks: {}", e));
+                    break;
+                }
+                Err(e) => {
+                    lock.signal_error(&format!("exporting blocks: {}", e));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Periodically add finished blocks to the history store
+async fn store_blocks(handle: Handle, config: store::Config) {
+    let mut interval = tokio::time::interval(STORE_FREQ);
+    let mut store = None;
+    loop {
+        interval.tick().await;
+        let blocks = handle.lock().pending_store();
+        let until_s = match blocks.iter().map(|block| block.end_s).max() {
+            Some(until_s) => until_s,
+            None => continue,
+        };
+
+        // Stores write to disk, so keep that off the main thread; the store
+        // is opened the first time there is something to add, and again
+        // after any failure
+        let config = config.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut store = match store {
+                Some(store) => store,
+                None => config.open()?,
+            };
+            for block in &blocks {
+                store.append(block)?;
+            }
+            Ok::<_, io::Error>(store)
+        })
+        .await;
+        let mut lock = handle.lock();
+        store = match result {
+            Ok(Ok(opened)) => {
+                lock.mark_stored(until_s);
+                Some(opened)
+            }
+            Ok(Err(e)) => {
+                lock.signal_error(&format!("storing history: {}", e));
+                None
+            }
+            Err(e) => {
+                lock.signal_error(&format!("storing history: {}", e));
+                None
+            }
+        };
+    }
+}
+
+/// Periodically share our history with other machines, and merge in theirs
+async fn sync_machines(handle: Handle, interval: std::time::Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let (config, ours) = match handle.lock().sync_snapshot() {
+            Some(snapshot) => snapshot,
+            None => continue,
+        };
+
+        let result = tokio::task::spawn_blocking(move || config.exchange(&ours)).await;
+        let mut lock = handle.lock();
+        match result {
+            Ok(Ok(theirs)) => lock.merge_synced(theirs),
+            Ok(Err(e)) => lock.signal_error(&format!("syncing: {}", e)),
+            Err(e) => lock.signal_error(&format!("syncing: {}", e)),
+        }
+    }
+}
+
+/// Write the daily report once it is due, checking every minute
+async fn write_daily_reports(handle: Handle) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let (config, report) = match handle.lock().take_daily_report() {
+            Some(due) => due,
+            None => continue,
+        };
+
+        let result = tokio::task::spawn_blocking(move || config.write(&report)).await;
+        let mut lock = handle.lock();
+        match result {
+            Ok(Ok(path)) => lock.daily_report_written(&path),
+            Ok(Err(e)) => lock.signal_error(&format!("writing daily report: {}", e)),
+            Err(e) => lock.signal_error(&format!("writing daily report: {}", e)),
+        }
+    }
+}
+
+/// Periodically save the state of the timer out to disk
+///
+/// If the file was changed by someone else since we last wrote it, it is
+/// reloaded first, so that edits to the config are applied rather than lost.
+async fn save_state(handle: Handle, config_file: Option<String>) {
+    let config_file = match config_file {
+        Some(file) => file,
+        None => return,
+    };
+    let modified = || {
+        fs::metadata(&config_file)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    let mut interval = tokio::time::interval(SAVEOUT_FREQ);
+    let mut written = modified();
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        if modified() != written {
+            // If the edited file can't be read, e.g. because it is half-written
+            // or has a typo, don't overwrite it until it is fixed
+            if let Err(e) = reload_config(&handle, &config_file) {
+                if !failing {
+                    handle
+                        .lock()
+                        .signal_error(&format!("reloading {}: {}", config_file, e));
+                }
+                failing = true;
+                continue;
+            }
+            failing = false;
+        }
+        write_state(&handle, &config_file);
+        written = modified();
+    }
+}
+
+/// Reload the config file whenever we receive SIGHUP
+async fn reload_on_sighup(handle: Handle, config_file: Option<String>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            handle
+                .lock()
+                .signal_error(&format!("listening for SIGHUP: {}", e));
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let result = match config_file {
+            Some(ref file) => reload_config(&handle, file),
+            None => Err("no config file".into()),
+        };
+        if let Err(e) = result {
+            handle
+                .lock()
+                .signal_error(&format!("reloading config: {}", e));
+        }
+    }
+}
+
+/// Read the config file and apply it, keeping the running block and other state
+fn reload_config(handle: &Handle, config_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let fh = fs::File::open(config_file)?;
+    let new = serde_json::from_reader(io::BufReader::new(fh))?;
+    handle.with_server(|server| server.reload_config(new));
+    Ok(())
+}
+
+/// Save the server's state to disk, flashing an error on failure
+fn write_state(handle: &Handle, config_file: &str) {
+    let mut lock = handle.lock();
+    let result = fs::File::create(config_file)
+        .map_err(serde_json::Error::io)
+        .and_then(|fh| serde_json::to_writer(fh, &*lock));
+    if let Err(e) = result {
+        lock.signal_error(&format!("saving state to {}: {}", config_file, e));
+    }
+}
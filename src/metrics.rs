@@ -0,0 +1,150 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Metrics
+//!
+//! Counters which are kept across restarts and exported in the Prometheus
+//! text format
+//!
+
+use crate::server::{Event, Status};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Counters tracked by the server
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    blocks_started: u64,
+    blocks_completed: u64,
+    blocks_cancelled: u64,
+    /// Focus time recorded against each top-level task
+    focus_time: BTreeMap<String, Duration>,
+}
+
+impl Metrics {
+    /// Update the counters in response to a state transition
+    pub fn record_event(&mut self, event: &Event) {
+        match *event {
+            Event::BlockStarted => self.blocks_started += 1,
+            Event::BlockCompleted => self.blocks_completed += 1,
+            Event::BlockCancelled => self.blocks_cancelled += 1,
+            _ => {}
+        }
+    }
+
+    /// Add focus time to a task, given as a reversed path as in `Task::add_time_path`
+    pub fn record_focus(&mut self, path: &[String], time: Duration) {
+        if let Some(root) = path.last() {
+            *self.focus_time.entry(root.clone()).or_default() += time;
+        }
+    }
+
+    /// Render the metrics, and the current status, in the Prometheus text format
+    pub fn render(&self, status: &Status) -> String {
+        let mut ret = String::new();
+        let counters = [
+            ("blocks_started", "Blocks started", self.blocks_started),
+            (
+                "blocks_completed",
+                "Blocks run to completion",
+                self.blocks_completed,
+            ),
+            (
+                "blocks_cancelled",
+                "Blocks cancelled",
+                self.blocks_cancelled,
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(ret, "# HELP pomotoshi_{}_total {}", name, help).unwrap();
+            writeln!(ret, "# TYPE pomotoshi_{}_total counter", name).unwrap();
+            writeln!(ret, "pomotoshi_{}_total {}", name, value).unwrap();
+        }
+
+        ret.push_str(
+            "# HELP pomotoshi_remaining_seconds Seconds left in the current block or cooldown\n",
+        );
+        ret.push_str("# TYPE pomotoshi_remaining_seconds gauge\n");
+        writeln!(
+            ret,
+            "pomotoshi_remaining_seconds {}",
+            status.remaining_s.unwrap_or(0)
+        )
+        .unwrap();
+
+        ret.push_str("# HELP pomotoshi_state Whether the timer is in the given state\n");
+        ret.push_str("# TYPE pomotoshi_state gauge\n");
+        for state in ["idle", "block", "paused", "break", "cooldown", "tracking"] {
+            let value = u8::from(status.state == state);
+            writeln!(ret, "pomotoshi_state{{state=\"{}\"}} {}", state, value).unwrap();
+        }
+
+        ret.push_str("# HELP pomotoshi_focus_seconds_total Focus time per top-level task\n");
+        ret.push_str("# TYPE pomotoshi_focus_seconds_total counter\n");
+        for (task, time) in &self.focus_time {
+            writeln!(
+                ret,
+                "pomotoshi_focus_seconds_total{{task=\"{}\"}} {:.3}",
+                escape_label(task),
+                time.as_secs_f64()
+            )
+            .unwrap();
+        }
+        ret
+    }
+}
+
+/// Escape a string for use as a Prometheus label value
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::default();
+        metrics.record_event(&Event::BlockStarted);
+        metrics.record_event(&Event::BlockCompleted);
+        metrics.record_event(&Event::BlockStarted);
+        metrics.record_focus(
+            &["leaf".into(), "Say \"hi\"".into()],
+            Duration::from_millis(1500),
+        );
+        metrics.record_focus(&[], Duration::from_secs(1));
+
+        let status = Status {
+            state: "block",
+            remaining_s: Some(42),
+            duration_s: Some(60),
+            label: None,
+        };
+        let output = metrics.render(&status);
+        assert!(output.contains("\npomotoshi_blocks_started_total 2\n"));
+        assert!(output.contains("\npomotoshi_blocks_completed_total 1\n"));
+        assert!(output.contains("\npomotoshi_blocks_cancelled_total 0\n"));
+        assert!(output.contains("\npomotoshi_remaining_seconds 42\n"));
+        assert!(output.contains("\npomotoshi_state{state=\"block\"} 1\n"));
+        assert!(output.contains("\npomotoshi_state{state=\"idle\"} 0\n"));
+        assert!(
+            output.ends_with("\npomotoshi_focus_seconds_total{task=\"Say \\\"hi\\\"\"} 1.500\n")
+        );
+    }
+}
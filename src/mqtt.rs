@@ -0,0 +1,120 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! MQTT
+//!
+//! Publishing of state transitions and status to an MQTT broker, e.g. for
+//! home automation
+//!
+
+use crate::handle::Handle;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How long to wait before reconnecting to a broker after an error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Number of messages which may be queued while the broker is unreachable
+const QUEUE_SIZE: usize = 16;
+
+fn default_port() -> u16 { 1883 }
+fn default_client_id() -> String { "pomotoshi".into() }
+fn default_topic() -> String { "pomotoshi".into() }
+
+/// Configuration of the MQTT broker, and what to publish to it
+///
+/// The status (as returned by the HTTP server) is published, retained, to
+/// `<topic>/status` whenever it changes, and the name of each state
+/// transition is published to `<topic>/event`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default = "default_client_id")]
+    client_id: String,
+    #[serde(default = "default_topic")]
+    topic: String,
+    /// Quality of service level: 0, 1 or 2
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+/// Connect to the broker and start publishing to it
+///
+/// Fails only if the configuration is invalid; connection errors are retried
+/// forever in the background.
+pub fn start(config: Config, handle: Handle) -> Result<(), Box<dyn std::error::Error>> {
+    let qos = rumqttc::qos(config.qos).map_err(|e| format!("invalid MQTT QoS: {}", e))?;
+
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    if let Some(ref username) = config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, QUEUE_SIZE);
+
+    // The event loop must be polled to make any progress, including reconnecting
+    tokio::spawn(async move {
+        loop {
+            if eventloop.poll().await.is_err() {
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    });
+
+    let status = handle.subscribe_status();
+    let events = handle.subscribe_events();
+    tokio::spawn(publish(client, config.topic, qos, status, events));
+    Ok(())
+}
+
+/// Publish status changes and state transitions forever
+async fn publish(
+    client: AsyncClient,
+    topic: String,
+    qos: QoS,
+    mut status: tokio::sync::watch::Receiver<crate::server::Status>,
+    mut events: broadcast::Receiver<crate::server::Transition>,
+) {
+    let status_topic = format!("{}/status", topic);
+    let event_topic = format!("{}/event", topic);
+    // If the queue is full (e.g. the broker is down) just drop messages, since
+    // stale ones are not of much use anyway.
+    let payload = serde_json::to_string(&*status.borrow_and_update()).unwrap();
+    let _ = client.try_publish(&status_topic, qos, true, payload);
+    loop {
+        tokio::select! {
+            changed = status.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let payload = serde_json::to_string(&*status.borrow_and_update()).unwrap();
+                let _ = client.try_publish(&status_topic, qos, true, payload);
+            }
+            event = events.recv() => match event {
+                Ok(transition) => {
+                    let name = transition.event.name();
+                    let _ = client.try_publish(&event_topic, qos, false, name);
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
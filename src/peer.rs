@@ -0,0 +1,506 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Paired Sessions
+//!
+//! Keeping blocks in lockstep with a coworking partner's daemon, over a TCP
+//! connection speaking newline-delimited JSON. Blocks started, paused,
+//! unpaused or cancelled on either side are mirrored on the other.
+//!
+//! Each side proves it knows the shared token with an HMAC over nonces
+//! chosen by both, so the token itself is never sent. The connection is
+//! otherwise unencrypted, and meant for trusted networks.
+//!
+
+use crate::access::secrets_equal;
+use crate::handle::Handle;
+use crate::history;
+use crate::server::{Event, Transition};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+/// How long to wait before reconnecting to the partner after an error
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// How long to wait for the partner to introduce itself
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest line accepted from the partner before it has authenticated
+const MAX_HELLO_LEN: usize = 1024;
+/// Longest line accepted from the partner once it has authenticated
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Length of the nonces each side chooses, in bytes
+const NONCE_LEN: usize = 32;
+
+fn default_name() -> String { "your partner".into() }
+
+/// Configuration for pairing with a partner's daemon
+///
+/// One side should `listen` and the other `connect`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Address to accept the partner's connection on, e.g. "192.168.1.10:7878"
+    #[serde(default)]
+    listen: Option<String>,
+    /// Address of the partner's daemon, e.g. "partner.example.com:7878"
+    #[serde(default)]
+    connect: Option<String>,
+    /// Secret shared with the partner, who must be configured with the same
+    token: String,
+    /// Our name, as shown in the partner's notifications
+    #[serde(default = "default_name")]
+    name: String,
+}
+
+/// A message exchanged with the partner
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Message {
+    /// Sent by the listening side on accepting a connection
+    Challenge {
+        nonce: String,
+    },
+    /// The connecting side's answer to the challenge, with its own nonce and
+    /// the MAC proving it knows the token
+    Hello {
+        name: String,
+        nonce: String,
+        mac: String,
+    },
+    /// The listening side's answer to the connecting side's nonce
+    Welcome {
+        name: String,
+        mac: String,
+    },
+    /// A block was started, which ends at the given UNIX time
+    Start {
+        end_s: u64,
+        label: Option<String>,
+    },
+    Pause,
+    Unpause,
+    Cancel,
+}
+
+/// Start listening for or connecting to the partner
+///
+/// Fails only if the configuration is invalid or the listening address
+/// cannot be bound; connection errors are retried forever in the background.
+pub async fn start(config: Config, handle: Handle) -> Result<(), Box<dyn std::error::Error>> {
+    match (config.listen.clone(), config.connect.clone()) {
+        (Some(addr), None) => {
+            let listener = TcpListener::bind(&addr).await?;
+            tokio::spawn(accept(listener, config, handle));
+        }
+        (None, Some(addr)) => {
+            tokio::spawn(async move {
+                loop {
+                    let result = match TcpStream::connect(&addr).await {
+                        Ok(stream) => session(stream, &config, &handle, false).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        tracing::warn!(error = %e, "connection to partner failed");
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            });
+        }
+        _ => return Err("exactly one of `listen` and `connect` must be set for `peer`".into()),
+    }
+    Ok(())
+}
+
+/// Accept connections from the partner forever
+async fn accept(listener: TcpListener, config: Config, handle: Handle) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let config = config.clone();
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(stream, &config, &handle, true).await {
+                tracing::warn!(error = %e, "connection from partner failed");
+            }
+        });
+    }
+}
+
+/// Send a message to the partner
+async fn send<W: AsyncWriteExt + Unpin>(writer: &mut W, message: &Message) -> io::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+/// Show a desktop notification about something the partner did
+fn notify(text: String) {
+    tokio::spawn(async move {
+        let _ = tokio::process::Command::new("notify-send")
+            .arg("Pomotoshi")
+            .arg(text)
+            .output()
+            .await;
+    });
+}
+
+/// A fresh nonce, in hex
+fn nonce() -> io::Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(hex(&nonce))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The MAC proving knowledge of the token, made by one side (`connect` or
+/// `listen`) over both sides' nonces, in hex
+fn mac(token: &str, side: &str, listen_nonce: &str, connect_nonce: &str) -> String {
+    use hmac::{KeyInit, Mac};
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(token.as_bytes())
+        .expect("HMAC takes keys of any length");
+    for part in [side, listen_nonce, connect_nonce] {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+    hex(&mac.finalize().into_bytes())
+}
+
+/// Read a line from the partner, failing if it is longer than `max_len`
+///
+/// Bytes read are kept in `partial` until the line is complete, so that this
+/// can be cancelled, e.g. by `select!`, and called again without losing any.
+async fn next_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    partial: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<Option<String>> {
+    let limit = (max_len + 1).saturating_sub(partial.len());
+    (&mut *reader)
+        .take(u64::try_from(limit).unwrap_or(u64::MAX))
+        .read_until(b'\n', partial)
+        .await?;
+    let mut line = std::mem::take(partial);
+    match line.pop() {
+        None => Ok(None),
+        Some(b'\n') => String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| io::Error::other("partner sent invalid UTF-8")),
+        Some(_) if line.len() >= max_len => Err(io::Error::other("partner sent too long a line")),
+        Some(_) => Err(io::Error::other("partner hung up")),
+    }
+}
+
+/// Read the next message of the handshake, which must come in time
+async fn handshake_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+    let mut partial = vec![];
+    let line = next_line(reader, &mut partial, MAX_HELLO_LEN);
+    match tokio::time::timeout(HELLO_TIMEOUT, line).await {
+        Ok(Ok(Some(line))) => serde_json::from_str(&line)
+            .map_err(|_| io::Error::other("partner did not authenticate")),
+        Ok(Ok(None)) => Err(io::Error::other("partner hung up")),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::other("partner did not introduce itself")),
+    }
+}
+
+/// Check that the partner knows the token, and prove that we do, returning
+/// the partner's name
+///
+/// The listening side checks the connecting side before revealing anything
+/// which depends on the token.
+async fn authenticate<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &Config,
+    listening: bool,
+) -> io::Result<String>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let refused = || io::Error::other("partner did not authenticate");
+    if listening {
+        let listen_nonce = nonce()?;
+        let challenge = Message::Challenge {
+            nonce: listen_nonce.clone(),
+        };
+        send(writer, &challenge).await?;
+        let (name, connect_nonce) = match handshake_message(reader).await? {
+            Message::Hello {
+                name,
+                nonce,
+                mac: theirs,
+            } if nonce.len() == 2 * NONCE_LEN
+                && secrets_equal(
+                    &theirs,
+                    &mac(&config.token, "connect", &listen_nonce, &nonce),
+                ) =>
+            {
+                (name, nonce)
+            }
+            _ => return Err(refused()),
+        };
+        let welcome = Message::Welcome {
+            name: config.name.clone(),
+            mac: mac(&config.token, "listen", &listen_nonce, &connect_nonce),
+        };
+        send(writer, &welcome).await?;
+        Ok(name)
+    } else {
+        let listen_nonce = match handshake_message(reader).await? {
+            Message::Challenge { nonce } if nonce.len() == 2 * NONCE_LEN => nonce,
+            _ => return Err(refused()),
+        };
+        let connect_nonce = nonce()?;
+        let hello = Message::Hello {
+            name: config.name.clone(),
+            nonce: connect_nonce.clone(),
+            mac: mac(&config.token, "connect", &listen_nonce, &connect_nonce),
+        };
+        send(writer, &hello).await?;
+        match handshake_message(reader).await? {
+            Message::Welcome { name, mac: theirs }
+                if secrets_equal(
+                    &theirs,
+                    &mac(&config.token, "listen", &listen_nonce, &connect_nonce),
+                ) =>
+            {
+                Ok(name)
+            }
+            _ => Err(refused()),
+        }
+    }
+}
+
+/// Exchange messages with the partner until the connection is lost
+async fn session(
+    stream: TcpStream,
+    config: &Config,
+    handle: &Handle,
+    listening: bool,
+) -> io::Result<()> {
+    // Subscribe before anything else, so that no transitions are missed
+    let mut events = handle.subscribe_events();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let partner = authenticate(&mut reader, &mut writer, config, listening).await?;
+    let mut partial = vec![];
+    tracing::info!(partner, "paired with partner");
+
+    // Bring the partner into any block which is already running
+    let status = handle.lock().status();
+    if let ("block", Some(remaining_s)) = (status.state, status.remaining_s) {
+        let end_s = history::unix_now() + remaining_s;
+        let label = status.label;
+        send(&mut writer, &Message::Start { end_s, label }).await?;
+    }
+
+    // Transitions caused by the partner, which should not be echoed back
+    let mut echoes = VecDeque::new();
+    loop {
+        tokio::select! {
+            line = next_line(&mut reader, &mut partial, MAX_LINE_LEN) => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => return Err(io::Error::other("partner hung up")),
+                };
+                match serde_json::from_str(&line) {
+                    Ok(message) => {
+                        echoes.extend(apply(handle, &partner, message));
+                    }
+                    Err(e) => tracing::warn!(error = %e, "bad message from partner"),
+                }
+            }
+            event = events.recv() => {
+                let transition = match event {
+                    Ok(transition) => transition,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                if echoes.front() == Some(&transition.event) {
+                    echoes.pop_front();
+                    continue;
+                }
+                if let Some(message) = forward(handle, transition) {
+                    send(&mut writer, &message).await?;
+                }
+            }
+        }
+    }
+}
+
+/// The message telling the partner about a local transition, if any
+fn forward(handle: &Handle, transition: Transition) -> Option<Message> {
+    match transition.event {
+        Event::BlockStarted => {
+            let remaining_s = handle.lock().status().remaining_s?;
+            Some(Message::Start {
+                end_s: history::unix_now() + remaining_s,
+                label: transition.label,
+            })
+        }
+        Event::BlockPaused => Some(Message::Pause),
+        Event::BlockUnpaused => Some(Message::Unpause),
+        Event::BlockCancelled => Some(Message::Cancel),
+        _ => None,
+    }
+}
+
+/// Mirror something the partner did, returning the resulting transitions,
+/// which should not be sent back to them
+fn apply(handle: &Handle, partner: &str, message: Message) -> Vec<Event> {
+    let mut lock = handle.lock();
+    let state = lock.status().state;
+    match message {
+        Message::Start { end_s, label } => {
+            let remaining_s = end_s.saturating_sub(history::unix_now());
+            if remaining_s == 0 || state == "block" || state == "paused" {
+                return vec![];
+            }
+            lock.start_block(remaining_s, label);
+            if lock.status().state == "block" {
+                notify(format!("{} started a block.", partner));
+                return vec![Event::BlockStarted];
+            }
+        }
+        Message::Pause if state == "block" => {
+            lock.pause_block();
+            if lock.status().state == "paused" {
+                return vec![Event::BlockPaused];
+            }
+        }
+        Message::Unpause if state == "paused" => {
+            lock.pause_block();
+            if lock.status().state == "block" {
+                return vec![Event::BlockUnpaused];
+            }
+        }
+        Message::Cancel if state == "block" || state == "paused" => {
+            notify(format!("{} cancelled the block.", partner));
+            // A paused block can't be cancelled, so is resumed first
+            let mut events = vec![];
+            if state == "paused" {
+                lock.pause_block();
+                if lock.status().state != "block" {
+                    return events;
+                }
+                events.push(Event::BlockUnpaused);
+            }
+            let _ = lock.try_cancel_block(true);
+            if lock.status().state != "block" {
+                events.push(Event::BlockCancelled);
+            }
+            return events;
+        }
+        _ => {}
+    }
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(token: &str) -> Config {
+        Config {
+            listen: None,
+            connect: None,
+            token: token.into(),
+            name: token.into(),
+        }
+    }
+
+    /// Run both sides of the handshake, returning what each made of it
+    async fn handshake(
+        listen_token: &str,
+        connect_token: &str,
+    ) -> (io::Result<String>, io::Result<String>) {
+        let (listen, connect) = tokio::io::duplex(4096);
+        let (listen_config, connect_config) = (config(listen_token), config(connect_token));
+        tokio::join!(
+            async move {
+                let (reader, mut writer) = tokio::io::split(listen);
+                let mut reader = BufReader::new(reader);
+                authenticate(&mut reader, &mut writer, &listen_config, true).await
+            },
+            async move {
+                let (reader, mut writer) = tokio::io::split(connect);
+                let mut reader = BufReader::new(reader);
+                authenticate(&mut reader, &mut writer, &connect_config, false).await
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let (listened, connected) = handshake("secret", "secret").await;
+        assert_eq!(listened.unwrap(), "secret");
+        assert_eq!(connected.unwrap(), "secret");
+
+        // Neither side accepts the other without the same token
+        let (listened, connected) = handshake("secret", "guess").await;
+        assert!(listened.is_err());
+        assert!(connected.is_err());
+
+        // The token is never sent, only MACs depending on it
+        let mac = mac("secret", "connect", "a", "b");
+        assert_eq!(mac.len(), 64);
+        assert!(!mac.contains("secret"));
+        assert_ne!(mac, super::mac("secret", "listen", "a", "b"));
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let handle = Handle::new(crate::server::Server::new());
+        let start = Message::Start {
+            end_s: history::unix_now() + 60,
+            label: None,
+        };
+        assert_eq!(apply(&handle, "bob", start), [Event::BlockStarted]);
+        assert_eq!(apply(&handle, "bob", Message::Pause), [Event::BlockPaused]);
+        assert_eq!(handle.lock().status().state, "paused");
+
+        // Cancelling a paused block resumes it first, and both transitions
+        // are returned so that neither is echoed back
+        assert_eq!(
+            apply(&handle, "bob", Message::Cancel),
+            [Event::BlockUnpaused, Event::BlockCancelled],
+        );
+        assert_eq!(handle.lock().status().state, "idle");
+        assert_eq!(apply(&handle, "bob", Message::Cancel), []);
+    }
+
+    #[tokio::test]
+    async fn test_next_line() {
+        let mut partial = vec![];
+        let mut input: &[u8] = b"short\nmuch too long\n";
+        assert_eq!(
+            next_line(&mut input, &mut partial, 8).await.unwrap(),
+            Some("short".into()),
+        );
+        assert!(next_line(&mut input, &mut partial, 8).await.is_err());
+        let mut input: &[u8] = b"cut sh";
+        assert!(next_line(&mut input, &mut partial, 8).await.is_err());
+        let mut input: &[u8] = b"";
+        assert_eq!(next_line(&mut input, &mut partial, 8).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,103 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Progress
+//!
+//! Unicode progress indicators shown alongside the countdown
+//!
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+fn default_width() -> usize { 8 }
+
+/// What the progress indicator looks like
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Style {
+    /// A single pie-clock character which fills up
+    Pie,
+    /// A bar of braille characters, `width` characters wide
+    Braille,
+    /// A bar of block characters, `width` characters wide
+    Blocks,
+}
+
+/// Configuration for the progress indicator
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    style: Style,
+    /// Width of bars, in characters
+    #[serde(default = "default_width")]
+    width: usize,
+}
+
+/// Draw a bar `width` characters wide which is `frac` full, where `steps`
+/// lists the characters for an empty cell through a full one
+fn bar(frac: f64, width: usize, steps: &[char]) -> String {
+    let per_cell = steps.len() - 1;
+    let filled = (frac * (width * per_cell) as f64).round() as usize;
+    (0..width)
+        .map(|i| steps[filled.saturating_sub(i * per_cell).min(per_cell)])
+        .collect()
+}
+
+impl Config {
+    /// Render the indicator, given how much of `total` remains
+    pub fn render(&self, remaining: Duration, total: Duration) -> String {
+        let frac = if total.is_zero() {
+            1.0
+        } else {
+            1.0 - remaining.as_secs_f64() / total.as_secs_f64()
+        };
+        let frac = frac.clamp(0.0, 1.0);
+        match self.style {
+            Style::Pie => bar(frac, 1, &['○', '◔', '◑', '◕', '●']),
+            Style::Braille => bar(frac, self.width, &['⠀', '⡇', '⣿']),
+            Style::Blocks => bar(
+                frac,
+                self.width,
+                &[' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let total = Duration::from_secs(100);
+        let config = |style, width| Config { style, width };
+
+        let pie = config(Style::Pie, 8);
+        assert_eq!(pie.render(total, total), "○");
+        assert_eq!(pie.render(Duration::from_secs(50), total), "◑");
+        assert_eq!(pie.render(Duration::from_secs(30), total), "◕");
+        assert_eq!(pie.render(Duration::ZERO, total), "●");
+
+        let braille = config(Style::Braille, 4);
+        assert_eq!(braille.render(total, total), "⠀⠀⠀⠀");
+        assert_eq!(braille.render(Duration::from_secs(50), total), "⣿⣿⠀⠀");
+        assert_eq!(braille.render(Duration::from_secs(25), total), "⣿⣿⣿⠀");
+        assert_eq!(braille.render(Duration::from_secs(37), total), "⣿⣿⡇⠀");
+
+        let blocks = config(Style::Blocks, 2);
+        assert_eq!(blocks.render(Duration::from_secs(75), total), "▌ ");
+        assert_eq!(blocks.render(Duration::from_secs(25), total), "█▌");
+        assert_eq!(blocks.render(Duration::ZERO, total), "██");
+    }
+}
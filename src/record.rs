@@ -0,0 +1,449 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Record and Replay
+//!
+//! Recording the calls and window samples which drive the state machine, as
+//! newline-delimited JSON, so that a real workday can be fed back through it
+//! later (at any speed) to reproduce a bug.
+//!
+
+use crate::clock;
+use crate::handle::Handle;
+use crate::server::Server;
+use crate::task::{self, Task};
+use crate::window::Window;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+/// A call which changes the server's state, made by a client e.g. over D-Bus
+///
+/// Calls are recorded by the server methods they are made to, so that calls
+/// from every client are recorded, but those the server makes to itself are
+/// not (and so are not made twice on replay).
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Call {
+    StartBlock {
+        time_s: u64,
+        label: Option<String>,
+    },
+    CancelBlock {
+        force: bool,
+    },
+    PauseBlock,
+    StartPreset {
+        name: String,
+    },
+    StartRoutine {
+        name: String,
+    },
+    StartTimer {
+        name: String,
+        time_s: u64,
+    },
+    PauseTimer {
+        name: String,
+    },
+    CancelTimer {
+        name: String,
+    },
+    AnnotateBlock {
+        text: String,
+    },
+    TaskLogAdd {
+        name: String,
+    },
+    /// Adding a filtered task log, with the filter's regexes (either of
+    /// which may be empty, to not filter on it)
+    TaskLogAddFiltered {
+        name: String,
+        include: String,
+        exclude: String,
+    },
+    TaskLogRemove {
+        name: String,
+    },
+    TaskLogAddTime {
+        name: String,
+        path: Vec<String>,
+        time_s: u64,
+    },
+    TaskLogMoveTime {
+        name: String,
+        from_path: Vec<String>,
+        to_path: Vec<String>,
+        time_s: u64,
+    },
+    TaskLogCompact {
+        name: String,
+    },
+    UndoLast,
+    ResolveAway {
+        choice: String,
+    },
+    SetTheme {
+        name: String,
+    },
+    SetColors {
+        colors: HashMap<String, String>,
+    },
+    HistoryImported {
+        description: String,
+    },
+    /// Importing a task log, with the log which was read from the file
+    TaskLogImport {
+        name: String,
+        path: PathBuf,
+        log: Box<Task>,
+    },
+}
+
+impl Call {
+    /// Make the call on a server, ignoring any error
+    pub fn apply(&self, server: &mut Server) {
+        match *self {
+            Call::StartBlock { time_s, ref label } => server.start_block(time_s, label.clone()),
+            Call::CancelBlock { force } => {
+                let _ = server.try_cancel_block(force);
+            }
+            Call::PauseBlock => server.pause_block(),
+            Call::StartPreset { ref name } => server.start_preset(name),
+            Call::StartRoutine { ref name } => server.start_routine(name),
+            Call::StartTimer { ref name, time_s } => server.start_timer(name.clone(), time_s),
+            Call::PauseTimer { ref name } => server.pause_timer(name),
+            Call::CancelTimer { ref name } => server.cancel_timer(name),
+            Call::AnnotateBlock { ref text } => server.annotate_block(text.clone()),
+            Call::TaskLogAdd { ref name } => server.task_log_add(name.clone()),
+            Call::TaskLogAddFiltered {
+                ref name,
+                ref include,
+                ref exclude,
+            } => {
+                // The regexes were checked when the call was first made
+                let parse = |re: &str| match re {
+                    "" => vec![],
+                    re => regex::Regex::new(re).into_iter().collect(),
+                };
+                let filter = task::Filter {
+                    include: parse(include),
+                    exclude: parse(exclude),
+                };
+                server.task_log_add_filtered(name.clone(), Some(filter));
+            }
+            Call::TaskLogRemove { ref name } => server.task_log_remove(name),
+            Call::TaskLogAddTime {
+                ref name,
+                ref path,
+                time_s,
+            } => server.task_log_add_time(name, path.clone(), time_s),
+            Call::TaskLogMoveTime {
+                ref name,
+                ref from_path,
+                ref to_path,
+                time_s,
+            } => server.task_log_move_time(name, from_path.clone(), to_path.clone(), time_s),
+            Call::TaskLogCompact { ref name } => server.task_log_compact(name),
+            Call::UndoLast => {
+                let _ = server.undo_last();
+            }
+            Call::ResolveAway { ref choice } => {
+                let _ = server.resolve_away(choice);
+            }
+            Call::SetTheme { ref name } => server.set_theme(name),
+            Call::SetColors { ref colors } => server.set_colors(colors),
+            Call::HistoryImported { ref description } => server.history_imported(description),
+            Call::TaskLogImport {
+                ref name,
+                ref path,
+                ref log,
+            } => server.task_log_imported(name.clone(), path, (**log).clone()),
+        }
+    }
+}
+
+/// A line of a recording
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    /// The first line: when recording started, in milliseconds since the UNIX
+    /// epoch, and the server's state (as saved to the state file) at the time
+    Start {
+        unix_ms: u64,
+        server: serde_json::Value,
+    },
+    /// A call, made the given number of milliseconds after recording started
+    Call { at_ms: u64, call: Call },
+    /// A sample of the focused window, and of the applications using the
+    /// microphone
+    Window {
+        at_ms: u64,
+        window: Window,
+        microphone: Vec<String>,
+    },
+    /// Time passing without the window being sampled
+    SkipWindow { at_ms: u64 },
+    /// A sample finding the monitors off
+    MonitorOff { at_ms: u64 },
+}
+
+/// A recording in progress
+struct Recorder {
+    file: Mutex<fs::File>,
+    /// When recording started
+    start: Instant,
+    /// The most recent of a run of window skips, which is only written out
+    /// when something else happens, since only the last one matters
+    pending_skip: Mutex<Option<u64>>,
+    /// The thread which started recording, which in tests is the only one
+    /// recorded, so that other tests running alongside are not
+    #[cfg(test)]
+    thread: std::thread::ThreadId,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// Start recording to a file, which is overwritten, given the server's state
+pub fn start(path: &Path, server: &Server) -> io::Result<()> {
+    let unix_ms = clock::system_now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let server = serde_json::to_value(server)?;
+    let recorder = Recorder {
+        file: Mutex::new(fs::File::create(path)?),
+        start: clock::now(),
+        pending_skip: Mutex::new(None),
+        #[cfg(test)]
+        thread: std::thread::current().id(),
+    };
+    write_entry(&recorder.file, &Entry::Start { unix_ms, server })?;
+    RECORDER
+        .set(recorder)
+        .map_err(|_| io::Error::other("already recording"))
+}
+
+/// Write a line to a recording
+fn write_entry(file: &Mutex<fs::File>, entry: &Entry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    file.lock()
+        .expect("recording did not witness a panic")
+        .write_all(line.as_bytes())
+}
+
+/// Record something, if recording, by making an entry given the current time
+fn record(entry: impl FnOnce(u64) -> Entry) {
+    if let Some(recorder) = RECORDER.get() {
+        #[cfg(test)]
+        if recorder.thread != std::thread::current().id() {
+            return;
+        }
+        let at_ms = clock::now()
+            .saturating_duration_since(recorder.start)
+            .as_millis() as u64;
+        let entry = entry(at_ms);
+        let mut pending_skip = recorder
+            .pending_skip
+            .lock()
+            .expect("recording did not witness a panic");
+        if let Entry::SkipWindow { at_ms } = entry {
+            *pending_skip = Some(at_ms);
+            return;
+        }
+        let mut result = Ok(());
+        if let Some(at_ms) = pending_skip.take() {
+            result = write_entry(&recorder.file, &Entry::SkipWindow { at_ms });
+        }
+        if let Err(e) = result.and_then(|_| write_entry(&recorder.file, &entry)) {
+            tracing::warn!(error = %e, "writing recording");
+        }
+    }
+}
+
+/// Record a call, if recording
+pub fn call(call: &Call) {
+    record(|at_ms| Entry::Call {
+        at_ms,
+        call: call.clone(),
+    });
+}
+
+/// Record a sample of the focused window, if recording
+pub fn window(window: &Window, microphone: &[String]) {
+    record(|at_ms| Entry::Window {
+        at_ms,
+        window: window.clone(),
+        microphone: microphone.to_vec(),
+    });
+}
+
+/// Record that the focused window was not sampled, if recording
+pub fn skip_window() {
+    record(|at_ms| Entry::SkipWindow { at_ms });
+}
+
+/// Record that the monitors were found to be off, if recording
+pub fn monitor_off() {
+    record(|at_ms| Entry::MonitorOff { at_ms });
+}
+
+/// A recording, loaded to be replayed
+pub struct Replay {
+    /// When recording started
+    start: SystemTime,
+    /// The server's state when recording started
+    server: serde_json::Value,
+    entries: Vec<Entry>,
+}
+
+impl Replay {
+    /// Load a recording from a file
+    pub fn load(path: &Path) -> io::Result<Replay> {
+        let mut lines = io::BufReader::new(fs::File::open(path)?).lines();
+        let (unix_ms, server) = match lines.next() {
+            Some(line) => match serde_json::from_str(&line?)? {
+                Entry::Start { unix_ms, server } => (unix_ms, server),
+                _ => return Err(io::Error::other("recording does not start with `start`")),
+            },
+            None => return Err(io::Error::other("recording is empty")),
+        };
+        let mut entries = vec![];
+        for line in lines {
+            let line = line?;
+            // The last line may be cut short if the recording daemon was killed
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => tracing::warn!(error = %e, line, "skipping bad line of recording"),
+            }
+        }
+        Ok(Replay {
+            start: UNIX_EPOCH + Duration::from_millis(unix_ms),
+            server,
+            entries,
+        })
+    }
+
+    /// The wall-clock time at which recording started, which the clock should
+    /// be set to before replaying
+    pub fn start_time(&self) -> SystemTime {
+        self.start
+    }
+
+    /// The server as it was when recording started, with anything which
+    /// reaches outside the daemon removed
+    pub fn server(&self) -> Result<Server, serde_json::Error> {
+        let mut server: Server = serde_json::from_value(self.server.clone())?;
+        server.disconnect();
+        Ok(server)
+    }
+
+    /// Feed the recorded calls and window samples to the server, at the times
+    /// they were recorded (relative to now, on the clock)
+    pub async fn run(self, handle: Handle) {
+        let start = clock::now();
+        for entry in self.entries {
+            let at_ms = match entry {
+                Entry::Start { .. } => continue,
+                Entry::Call { at_ms, .. }
+                | Entry::Window { at_ms, .. }
+                | Entry::SkipWindow { at_ms }
+                | Entry::MonitorOff { at_ms } => at_ms,
+            };
+            let at = start + Duration::from_millis(at_ms);
+            clock::sleep_until(at).await;
+            match entry {
+                Entry::Start { .. } => {}
+                Entry::Call { call, .. } => {
+                    tracing::info!(?call, "replaying call");
+                    handle.with_server(|server| call.apply(server));
+                }
+                Entry::Window {
+                    window, microphone, ..
+                } => handle
+                    .lock()
+                    .record_current_window(&window, &microphone, at),
+                Entry::SkipWindow { .. } => handle.lock().skip_current_window(),
+                Entry::MonitorOff { .. } => handle.lock().record_monitor_off(at),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        clock::set_manual(UNIX_EPOCH + Duration::from_secs(clock::unix_now()));
+        let path = std::env::temp_dir().join(format!("pomotoshi-{}-record", std::process::id()));
+        let mut server = Server::new();
+        start(&path, &server).unwrap();
+
+        // Calls are recorded by the server itself, whichever client makes them
+        server.start_block(600, Some("work".into()));
+        server.pause_block();
+        server.task_log_add("log".into());
+        server.task_log_add_time("log", vec!["Github".into()], 30);
+        server.start_timer("tea".into(), 180);
+        server.set_theme("gruvbox");
+        let replay = Replay::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let calls: Vec<_> = replay
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Call { call, .. } => Some(call.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                Call::StartBlock {
+                    time_s: 600,
+                    label: Some("work".into()),
+                },
+                Call::PauseBlock,
+                Call::TaskLogAdd { name: "log".into() },
+                Call::TaskLogAddTime {
+                    name: "log".into(),
+                    path: vec!["Github".into()],
+                    time_s: 30,
+                },
+                Call::StartTimer {
+                    name: "tea".into(),
+                    time_s: 180,
+                },
+                Call::SetTheme {
+                    name: "gruvbox".into(),
+                },
+            ],
+        );
+
+        // and replaying them gets the server back to where it was
+        clock::set_manual(replay.start_time());
+        let handle = Handle::new(replay.server().unwrap());
+        replay.run(handle.clone()).await;
+        let mut replayed = handle.lock();
+        assert_eq!(replayed.status(), server.status());
+        assert_eq!(replayed.task_log_dump("log"), server.task_log_dump("log"));
+    }
+}
@@ -0,0 +1,124 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Redaction
+//!
+//! User-configured rules which scrub confidential text out of window titles
+//! before they are recorded in any task log
+//!
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// A single redaction rule
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rule {
+    /// Titles matching this regex are redacted
+    #[serde(with = "crate::serde_regex")]
+    pattern: Regex,
+    /// What to do with matching titles
+    action: Action,
+}
+
+/// What a redaction rule does to a matching title
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Replace every match with this string (which may use `$1`-style captures)
+    Replace(String),
+    /// Classify the title as usual, but replace the leaf of the resulting
+    /// task path with a hash, so that distinct windows remain distinct
+    HashLeaf,
+}
+
+/// Apply a list of redaction rules, in order, to a window title
+///
+/// Returns the redacted title and whether the leaf of the title's task path
+/// should be hashed (see `hash_leaf`).
+pub fn redact_title<'t>(rules: &[Rule], title: &'t str) -> (Cow<'t, str>, bool) {
+    let mut title = Cow::Borrowed(title);
+    let mut hash = false;
+    for rule in rules {
+        match rule.action {
+            Action::Replace(ref replacement) => {
+                if let Cow::Owned(s) = rule.pattern.replace_all(&title, replacement.as_str()) {
+                    title = Cow::Owned(s);
+                }
+            }
+            Action::HashLeaf => hash |= rule.pattern.is_match(&title),
+        }
+    }
+    (title, hash)
+}
+
+/// Replace the leaf of a (reversed) task path with a hash of itself
+pub fn hash_leaf(path: &mut [String]) {
+    if let Some(leaf) = path.first_mut() {
+        // 32-bit FNV-1a, which is stable across compiler versions, unlike
+        // std's `DefaultHasher`, so hashes stay consistent in saved logs.
+        let hash = leaf.bytes().fold(0x811c9dc5u32, |acc, b| {
+            (acc ^ u32::from(b)).wrapping_mul(0x01000193)
+        });
+        *leaf = format!("[redacted {:08x}]", hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: serde_json::Value) -> Vec<Rule> {
+        serde_json::from_value(rules).unwrap()
+    }
+
+    #[test]
+    fn test_redact_title() {
+        let rules = rules(serde_json::json!([
+            { "pattern": r"\d{4}-\d{4}", "action": { "replace": "[card]" } },
+            { "pattern": r"(?i)confidential", "action": "hash_leaf" },
+        ]));
+
+        // Titles matching no rule are borrowed, untouched
+        let (title, hash) = redact_title(&rules, "notes.md - NVIM");
+        assert!(matches!(title, Cow::Borrowed("notes.md - NVIM")));
+        assert!(!hash);
+
+        // Every match of a replacing rule is replaced
+        let (title, hash) = redact_title(&rules, "1234-5678 and 8765-4321 - Firefox");
+        assert_eq!(title, "[card] and [card] - Firefox");
+        assert!(!hash);
+
+        // Rules apply in order, and a hashing rule only marks the title
+        let (title, hash) = redact_title(&rules, "CONFIDENTIAL 1234-5678.pdf");
+        assert_eq!(title, "CONFIDENTIAL [card].pdf");
+        assert!(hash);
+    }
+
+    #[test]
+    fn test_hash_leaf() {
+        // Only the leaf, which comes first, is hashed, and always the same way
+        let mut path = vec!["Q3 layoffs.pdf".to_owned(), "Documents".to_owned()];
+        hash_leaf(&mut path);
+        assert_eq!(path, ["[redacted 3bf138dd]", "Documents"]);
+        let mut other = vec!["Q4 layoffs.pdf".to_owned(), "Documents".to_owned()];
+        hash_leaf(&mut other);
+        assert_ne!(other[0], path[0]);
+        assert_eq!(other[1], "Documents");
+
+        let mut empty: Vec<String> = vec![];
+        hash_leaf(&mut empty);
+        assert!(empty.is_empty());
+    }
+}
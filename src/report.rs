@@ -0,0 +1,339 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Daily Reports
+//!
+//! Summaries of each day's blocks, task time and goal progress, written out
+//! automatically at a configured time
+//!
+
+use crate::history::{self, Block, Outcome};
+use crate::task::Task;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn default_at() -> String { "23:55".into() }
+
+/// What format reports are written in
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Markdown,
+    Json,
+}
+
+/// Configuration of daily reports
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Directory reports are written to, named by date
+    directory: PathBuf,
+    /// Local time at which to write the day's report, as `HH:MM`
+    #[serde(default = "default_at")]
+    at: String,
+    #[serde(default)]
+    format: Format,
+}
+
+impl Config {
+    /// The minute of the day at which to write the report, if it parses
+    pub fn minute(&self) -> Option<u32> {
+        let (h, m) = self.at.split_once(':')?;
+        Some(h.parse::<u32>().ok()? * 60 + m.parse::<u32>().ok()?)
+    }
+
+    /// Write a report, returning the path it was written to
+    ///
+    /// This is blocking, since it writes files.
+    pub fn write(&self, report: &Daily) -> io::Result<PathBuf> {
+        let (extension, contents) = match self.format {
+            Format::Markdown => ("md", report.to_markdown()),
+            Format::Json => ("json", serde_json::to_string_pretty(report)?),
+        };
+        std::fs::create_dir_all(&self.directory)?;
+        let path = self
+            .directory
+            .join(format!("{}.{}", report.date, extension));
+        std::fs::write(&path, contents)?;
+        Ok(path)
+    }
+}
+
+/// A summary of a single block in a daily report
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct BlockSummary {
+    /// When the block started, in seconds since the start of the day
+    pub start_s: u64,
+    pub label: Option<String>,
+    pub duration_s: u64,
+    pub outcome: Outcome,
+    pub focus_score: Option<u8>,
+}
+
+/// A summary of a single day
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct Daily {
+    /// The local date, as `YYYY-MM-DD`
+    pub date: String,
+    pub blocks: Vec<BlockSummary>,
+    /// Time spent in completed blocks, in seconds
+    pub focus_s: u64,
+    /// The daily goal, in seconds, if one is set
+    pub goal_s: Option<u64>,
+    /// Time recorded against each task (as a root-first path), most first
+    pub tasks: Vec<(Vec<String>, u64)>,
+}
+
+impl Daily {
+    /// Summarize the blocks which ended during a day
+    pub fn new(date: String, day_start_s: u64, blocks: &[&Block], goal_s: Option<u64>) -> Daily {
+        let mut tasks = BTreeMap::<Vec<String>, Duration>::new();
+        for block in blocks {
+            for (path, time) in block.tasks.leaf_paths() {
+                *tasks.entry(path).or_default() += time;
+            }
+        }
+        let mut tasks: Vec<_> = tasks
+            .into_iter()
+            .map(|(path, time)| (path, time.as_secs()))
+            .collect();
+        tasks.sort_by(|(p1, t1), (p2, t2)| t2.cmp(t1).then(p1.cmp(p2)));
+
+        Daily {
+            date,
+            blocks: blocks
+                .iter()
+                .map(|block| BlockSummary {
+                    start_s: block.start_s.saturating_sub(day_start_s),
+                    label: block.label.clone(),
+                    duration_s: block.duration_s,
+                    outcome: block.outcome,
+                    focus_score: block.focus_score,
+                })
+                .collect(),
+            focus_s: blocks
+                .iter()
+                .filter(|block| block.outcome == Outcome::Completed)
+                .map(|block| block.duration_s)
+                .sum(),
+            goal_s,
+            tasks,
+        }
+    }
+
+    /// Render the report as markdown
+    pub fn to_markdown(&self) -> String {
+        let completed = self
+            .blocks
+            .iter()
+            .filter(|block| block.outcome == Outcome::Completed)
+            .count();
+        let mut ret = format!("# Pomotoshi report for {}\n\n", self.date);
+        ret += &format!(
+            "Focused for {} in {} completed blocks ({} cancelled).",
+            hours_minutes(self.focus_s),
+            completed,
+            self.blocks.len() - completed,
+        );
+        if let Some(goal_s) = self.goal_s {
+            let met = if self.focus_s >= goal_s {
+                "met"
+            } else {
+                "not met"
+            };
+            ret += &format!(" Daily goal of {} {}.", hours_minutes(goal_s), met);
+        }
+        ret += "\n";
+
+        if !self.blocks.is_empty() {
+            ret += "\n## Blocks\n\n| Start | Label | Length | Outcome | Focus score |\n";
+            ret += "|---|---|---|---|---|\n";
+            for block in &self.blocks {
+                let outcome = match block.outcome {
+                    Outcome::Completed => "completed",
+                    Outcome::Cancelled => "cancelled",
+                };
+                ret += &format!(
+                    "| {:02}:{:02} | {} | {} | {} | {} |\n",
+                    block.start_s / 3600,
+                    block.start_s / 60 % 60,
+                    block.label.as_deref().unwrap_or(""),
+                    hours_minutes(block.duration_s),
+                    outcome,
+                    block.focus_score.map(|s| s.to_string()).unwrap_or_default(),
+                );
+            }
+        }
+
+        if !self.tasks.is_empty() {
+            ret += "\n## Tasks\n\n";
+            for (path, time_s) in &self.tasks {
+                ret += &format!("* {} {}\n", hours_minutes(*time_s), path.join(" / "));
+            }
+        }
+        ret
+    }
+}
+
+/// Render a set of blocks as a standalone HTML page, with a timeline of the
+/// blocks, totals for each day and a treemap of the time spent on each task
+pub fn html(blocks: &[Block], goal_s: Option<u64>, day_start_minute: u32) -> String {
+    let mut tasks = Task::new_root();
+    for block in blocks {
+        tasks.merge(&block.tasks);
+    }
+    let data = serde_json::json!({
+        "blocks": blocks
+            .iter()
+            .map(|block| serde_json::json!({
+                "start_s": block.start_s,
+                "end_s": block.end_s,
+                "duration_s": block.duration_s,
+                "label": block.label,
+                "outcome": block.outcome,
+            }))
+            .collect::<Vec<_>>(),
+        "goal_s": goal_s,
+        "day_start_minute": day_start_minute,
+        "tasks": tasks,
+    });
+    // Window titles could contain `</script>`, which must not end the script early
+    let data = data.to_string().replace("</", "<\\/");
+    include_str!("report.html").replace("/*DATA*/", &data)
+}
+
+/// Minutes of focus in each hour of each day of the week, Monday first
+pub type Heatmap = [[u64; 24]; 7];
+
+/// Add a block's focus time to a heatmap, given the local day of the week (1
+/// for Monday) and minute of the day when it started
+fn add_to_heatmap(heatmap: &mut Heatmap, weekday: u32, minute: u32, duration_s: u64) {
+    let mut day = (weekday as usize + 6) % 7;
+    let mut minute = minute as u64;
+    let mut remaining = duration_s / 60;
+    while remaining > 0 {
+        let hour = (minute / 60) as usize % 24;
+        let in_hour = (60 - minute % 60).min(remaining);
+        heatmap[day][hour] += in_hour;
+        remaining -= in_hour;
+        minute += in_hour;
+        if minute >= 24 * 60 {
+            minute = 0;
+            day = (day + 1) % 7;
+        }
+    }
+}
+
+/// Total the focus time of completed blocks by local day of the week and hour
+///
+/// This is blocking, since it runs `date` to find local times.
+pub fn heatmap(blocks: &[Block]) -> Option<Heatmap> {
+    let blocks: Vec<_> = blocks
+        .iter()
+        .filter(|block| block.outcome == Outcome::Completed)
+        .collect();
+    let starts: Vec<_> = blocks.iter().map(|block| block.start_s).collect();
+    let local = if starts.is_empty() {
+        vec![]
+    } else {
+        history::local_weekday_minutes(&starts)?
+    };
+    let mut heatmap = [[0; 24]; 7];
+    for (block, (weekday, minute)) in blocks.iter().zip(local) {
+        add_to_heatmap(&mut heatmap, weekday, minute, block.duration_s);
+    }
+    Some(heatmap)
+}
+
+/// Render a heatmap as SVG, shaded like a GitHub contribution graph
+pub fn heatmap_svg(heatmap: &Heatmap) -> String {
+    const CELL: u64 = 16;
+    const LEFT: u64 = 36;
+    const TOP: u64 = 20;
+    const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    const SHADES: [&str; 5] = ["#ebedf0", "#9be9a8", "#40c463", "#30a14e", "#216e39"];
+
+    let max = heatmap.iter().flatten().copied().max().unwrap_or(0).max(1);
+    let mut ret = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+         font-family=\"sans-serif\" font-size=\"10\">\n",
+        LEFT + 24 * CELL,
+        TOP + 7 * CELL,
+    );
+    for hour in (0..24).step_by(3) {
+        ret += &format!(
+            "<text x=\"{}\" y=\"{}\">{:02}</text>\n",
+            LEFT + hour * CELL,
+            TOP - 6,
+            hour
+        );
+    }
+    for (day, hours) in heatmap.iter().enumerate() {
+        let y = TOP + day as u64 * CELL;
+        ret += &format!(
+            "<text x=\"0\" y=\"{}\">{}</text>\n",
+            y + CELL - 4,
+            DAYS[day]
+        );
+        for (hour, &minutes) in hours.iter().enumerate() {
+            // Any focus at all gets at least the lightest shade of green
+            let shade = if minutes == 0 {
+                0
+            } else {
+                1 + (3 * minutes / max) as usize
+            };
+            ret += &format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"2\" fill=\"{}\">\
+                 <title>{} {:02}:00 {}m</title></rect>\n",
+                LEFT + hour as u64 * CELL,
+                y,
+                CELL - 2,
+                CELL - 2,
+                SHADES[shade.min(4)],
+                DAYS[day],
+                hour,
+                minutes,
+            );
+        }
+    }
+    ret += "</svg>\n";
+    ret
+}
+
+/// Format a number of seconds as hours and minutes
+fn hours_minutes(s: u64) -> String {
+    format!("{}h{:02}m", s / 3600, s / 60 % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_to_heatmap() {
+        let mut heatmap = [[0; 24]; 7];
+        // 25 minutes from Monday 09:50 spills into the next hour
+        add_to_heatmap(&mut heatmap, 1, 9 * 60 + 50, 25 * 60);
+        assert_eq!(heatmap[0][9], 10);
+        assert_eq!(heatmap[0][10], 15);
+        // An hour from Sunday 23:30 wraps around to Monday
+        add_to_heatmap(&mut heatmap, 7, 23 * 60 + 30, 3600);
+        assert_eq!(heatmap[6][23], 30);
+        assert_eq!(heatmap[0][0], 30);
+        assert_eq!(heatmap.iter().flatten().sum::<u64>(), 85);
+    }
+}
@@ -0,0 +1,69 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Serde Instant
+//!
+//! Helpers to store monotonic instants in the state file as UNIX timestamps,
+//! so that they survive restarts. Use with `#[serde(with = "crate::serde_instant")]`.
+//!
+
+use crate::history;
+use serde::{Deserialize, Deserializer, Serializer};
+use std::time::{Duration, Instant};
+
+pub fn serialize<S: Serializer>(instant: &Instant, s: S) -> Result<S::Ok, S::Error> {
+    let now = crate::clock::now();
+    let unix_now = history::unix_now();
+    let unix_s = if *instant > now {
+        unix_now + instant.duration_since(now).as_secs()
+    } else {
+        unix_now.saturating_sub(now.duration_since(*instant).as_secs())
+    };
+    s.serialize_u64(unix_s)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Instant, D::Error> {
+    u64::deserialize(d).map(from_unix_s)
+}
+
+/// The instant at the given UNIX time, by the current clock
+fn from_unix_s(unix_s: u64) -> Instant {
+    let now = crate::clock::now();
+    let unix_now = history::unix_now();
+    if unix_s > unix_now {
+        now + Duration::from_secs(unix_s - unix_now)
+    } else {
+        // Instants can't be earlier than boot, so clamp to that
+        now.checked_sub(Duration::from_secs(unix_now - unix_s))
+            .unwrap_or(now)
+    }
+}
+
+/// The same, for optional instants, with `#[serde(with = "crate::serde_instant::option")]`
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Instant;
+
+    pub fn serialize<S: Serializer>(instant: &Option<Instant>, s: S) -> Result<S::Ok, S::Error> {
+        match *instant {
+            Some(ref instant) => super::serialize(instant, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Instant>, D::Error> {
+        let unix_s = Option::<u64>::deserialize(d)?;
+        Ok(unix_s.map(super::from_unix_s))
+    }
+}
@@ -0,0 +1,52 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Serde Regex
+//!
+//! Helpers to store compiled regexes in the config file as plain strings.
+//! Use with `#[serde(with = "crate::serde_regex")]`.
+//!
+
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer>(re: &Regex, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(re.as_str())
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Regex, D::Error> {
+    let s = String::deserialize(d)?;
+    Regex::new(&s).map_err(D::Error::custom)
+}
+
+/// Helpers for lists of regexes, for use with `#[serde(with = "crate::serde_regex::vec")]`
+pub mod vec {
+    use regex::Regex;
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(res: &[Regex], s: S) -> Result<S::Ok, S::Error> {
+        let mut seq = s.serialize_seq(Some(res.len()))?;
+        for re in res {
+            seq.serialize_element(re.as_str())?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Regex>, D::Error> {
+        let strs = Vec::<String>::deserialize(d)?;
+        strs.iter()
+            .map(|s| Regex::new(s).map_err(D::Error::custom))
+            .collect()
+    }
+}
@@ -17,34 +17,209 @@
 //! The data managed by the actual timer
 //!
 
-use crate::task::Task;
+use crate::access;
+use crate::announce;
+use crate::audio;
+use crate::calendar;
+use crate::chat;
+use crate::classifier;
+use crate::color;
+use crate::control;
+use crate::dim;
+use crate::export;
+use crate::flash;
+use crate::history;
+use crate::latency;
+use crate::lockout;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::mqtt;
+use crate::peer;
+use crate::progress;
+use crate::record::{self, Call};
+use crate::redact;
+use crate::report;
+use crate::store;
+use crate::suggestions;
+use crate::sync;
+use crate::task::{self, Task};
+use crate::taskwarrior;
+use crate::theme;
+use crate::timer::Timer;
+use crate::webhook;
+use crate::window::Window;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// Number of internal errors kept for `diagnostics`
+const DIAGNOSTICS_LEN: usize = 50;
+/// Number of broken streaks kept for `streak_breaks`
+const STREAK_BREAKS_LEN: usize = 100;
+/// How long after a first call to `cancel_block` a second one confirms it
+const CANCEL_CONFIRM_DURATION: std::time::Duration = std::time::Duration::from_secs(5);
+/// Number of destructive actions which can be undone
+const UNDO_LEN: usize = 10;
+/// Number of tasks listed in a `block_report`
+const REPORT_TOP_TASKS: usize = 5;
+/// Number of tasks given in the payload passed to hooks
+const HOOK_TOP_TASKS: usize = 3;
+/// How long after a flash further flashes of the same kind are dropped, so
+/// that repeated warnings coalesce rather than strobing the bar
+const FLASH_COALESCE_DURATION: std::time::Duration = std::time::Duration::from_secs(2);
+/// How far the wall clock must move relative to the monotonic clock between
+/// updates to count as a jump, rather than e.g. being slewed by NTP
+const CLOCK_JUMP_THRESHOLD: std::time::Duration = std::time::Duration::from_secs(30);
+/// How often the status line is rendered while nothing is counting down or
+/// flashing
+const IDLE_TICK: std::time::Duration = std::time::Duration::from_secs(5);
 
 fn default_color_block_start() -> (u8, u8, u8) { (0, 255, 0) }
 fn default_color_block_end() -> (u8, u8, u8) { (255, 192, 0) }
 fn default_color_cooldown_start() -> (u8, u8, u8) { (255, 0, 0) }
 fn default_color_cooldown_end() -> (u8, u8, u8) { (192, 44, 44) }
+fn default_color_idle() -> String { "#AAA".into() }
+fn default_color_cooldown_background() -> String { "#FF8".into() }
+fn default_lock_command() -> String { "loginctl lock-session".into() }
+fn default_day_start() -> String { "00:00".into() }
+fn default_hook_timeout_s() -> u64 { 30 }
 
 /// Main server structure
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Server {
     /// The current state
-    #[serde(skip, default = "State::idle")]
+    #[serde(default = "State::idle")]
     state: State,
-    /// Error flash countdown
+    /// When the current error flash ends
     #[serde(skip, default)]
-    flash_error: usize,
-    /// Warning flash countdown
+    flash_error_until: Option<std::time::Instant>,
+    /// When the current warning flash ends
     #[serde(skip, default)]
-    flash_warn: usize,
+    flash_warn_until: Option<std::time::Instant>,
     /// Last active-window-log update
-    #[serde(skip, default = "std::time::Instant::now")]
+    #[serde(skip, default = "crate::clock::now")]
     last_task_report: std::time::Instant,
+    /// Last time a meeting window was focused
+    #[serde(skip, default = "crate::clock::now")]
+    last_meeting_seen: std::time::Instant,
+    /// State transitions which have not yet been taken by `take_events`
+    #[serde(skip)]
+    events: Vec<Transition>,
+    /// Upcoming events, as most recently read from the calendar
+    #[serde(skip)]
+    calendar_events: Vec<calendar::Event>,
+    /// Whether the wrap-up warning has been given for the current block
+    #[serde(skip)]
+    wrapped_up: bool,
+    /// Task budgets which have been exceeded, and alerted about
+    #[serde(skip)]
+    budgets_exceeded: HashSet<Vec<String>>,
+    /// Bounds of the current local day, cached since computing them is expensive
+    #[serde(skip)]
+    today: Option<(u64, u64)>,
+    /// Number of consecutive days before today on which the daily goal was met,
+    /// cached since computing it is expensive
+    #[serde(skip)]
+    prior_day_streak: Option<u64>,
+    /// Record of the current block, which is added to `history` when it ends
+    #[serde(default)]
+    current_block: Option<history::Block>,
+    /// Named timers running alongside the main block
+    #[serde(skip)]
+    timers: BTreeMap<String, Timer>,
+    /// The routine being run, if any, and which round of it we're on
+    #[serde(default)]
+    routine: Option<(String, u32)>,
+    /// Whether the current block was started from a strict preset, and so
+    /// cannot be paused or cancelled
+    #[serde(default)]
+    strict: bool,
+    /// Whether the current block was started from a preset which locks the
+    /// screen when it ends
+    #[serde(default)]
+    lock_screen: bool,
+    /// The preset the current block was started from, if any, which applies
+    /// through the break or cooldown after it
+    #[serde(default)]
+    preset: Option<String>,
+    /// Whether it was outside working hours when last checked, and when that was
+    #[serde(skip)]
+    off_hours: Option<(std::time::Instant, bool)>,
+    /// Index of the most recent break suggestion
+    #[serde(skip)]
+    suggestion: Option<usize>,
+    /// When the current break suggestion was made, if we are resting
+    #[serde(skip)]
+    suggestion_shown: Option<std::time::Instant>,
+    /// The most recent internal errors, as UNIX timestamps and messages
+    #[serde(skip)]
+    diagnostics: VecDeque<(u64, String)>,
+    /// Number of flashes dropped since the last one shown
+    #[serde(skip)]
+    flashes_coalesced: u64,
+    /// How late, and how slow, recent renders of the status line were
+    #[serde(skip)]
+    latency: latency::Latency,
+    /// The monotonic and wall-clock times at the last update, to notice the
+    /// wall clock jumping
+    #[serde(skip)]
+    last_clock_check: Option<(std::time::Instant, std::time::SystemTime)>,
+    /// Whether the monitors were off when last checked
+    #[serde(skip)]
+    monitor_off: bool,
+    /// Start of the block in which time was last marked as away while the
+    /// monitors were off
+    #[serde(skip)]
+    away_block_s: Option<u64>,
+    /// The task most recently recorded during a block, as a reversed path
+    #[serde(skip)]
+    last_path: Vec<String>,
+    /// Time during blocks when the user was away, waiting for `resolveAway`
+    #[serde(default)]
+    pending_away: Vec<PendingAway>,
+    /// Recent calls to each client method, and the number refused since one
+    /// was last allowed
+    #[serde(skip)]
+    recent_calls: HashMap<String, (VecDeque<std::time::Instant>, u64)>,
+    /// Recent destructive actions, oldest first, which `undo_last` can undo
+    #[serde(skip)]
+    undo: VecDeque<Undo>,
+    /// When cancelling the current block was last requested, if it needed confirming
+    #[serde(skip)]
+    cancel_requested: Option<std::time::Instant>,
     /// Log of block start/stop/etc
     block_log: String,
+    /// Records of past blocks
+    #[serde(default)]
+    history: Vec<history::Block>,
+    /// Number of blocks ever completed
+    #[serde(default)]
+    height: u64,
+    /// End time of the most recent exported block, in seconds since the UNIX epoch
+    #[serde(default)]
+    exported_until_s: u64,
+    /// End time of the most recent block added to the history store, in
+    /// seconds since the UNIX epoch
+    #[serde(default)]
+    stored_until_s: u64,
+    /// Start of the most recent day a report was written for, in seconds since the UNIX epoch
+    #[serde(default)]
+    reported_day_s: u64,
     /// Log of active windows (which must be manually reset)
     task_logs: HashMap<String, Task>,
+    /// The same time as in each task log, split up by when it was recorded
+    #[serde(default)]
+    task_log_buckets: HashMap<String, task::Buckets>,
+    /// Which tasks each task log records, for those which don't record everything
+    #[serde(default)]
+    task_log_filters: HashMap<String, task::Filter>,
+    /// Counters exported to Prometheus
+    #[serde(default)]
+    metrics: Metrics,
+    /// The most recently broken streaks, as UNIX timestamps and messages,
+    /// which unlike the block log are kept across blocks and restarts
+    #[serde(default)]
+    streak_breaks: VecDeque<(u64, String)>,
     /// Initial color of text when blocks start
     #[serde(default = "default_color_block_start")]
     color_block_start: (u8, u8, u8),
@@ -54,6 +229,218 @@ pub struct Server {
     color_cooldown_start: (u8, u8, u8),
     #[serde(default = "default_color_cooldown_end")]
     color_cooldown_end: (u8, u8, u8),
+    /// Color of text when idle, paused or tracking
+    #[serde(default = "default_color_idle")]
+    color_idle: String,
+    /// Background color during cooldown
+    #[serde(default = "default_color_cooldown_background")]
+    color_cooldown_background: String,
+    /// How text fades from `color_block_start` to `color_block_end`
+    #[serde(default)]
+    block_fade: color::Fade,
+    /// How text fades from `color_cooldown_start` to `color_cooldown_end`
+    #[serde(default)]
+    cooldown_fade: color::Fade,
+    /// How warnings are flashed
+    #[serde(default = "flash::Style::default_warn")]
+    warn_flash: flash::Style,
+    /// How errors are flashed
+    #[serde(default = "flash::Style::default_error")]
+    error_flash: flash::Style,
+    /// Warning given shortly before the end of each block, if any
+    #[serde(default)]
+    wrap_up: Option<WrapUp>,
+    /// If set, cancelling a block with less than this many seconds left must
+    /// be confirmed by cancelling again
+    #[serde(default)]
+    confirm_cancel_s: Option<u64>,
+    /// Rules used to scrub window titles before they enter any task log
+    #[serde(default)]
+    redaction_rules: Vec<redact::Rule>,
+    /// Titles of distracting windows, which are flashed and counted during blocks
+    #[serde(default, with = "crate::serde_regex::vec")]
+    blacklist: Vec<Regex>,
+    /// Limit on time spent on blacklisted windows in a single block
+    #[serde(default)]
+    distraction_budget: Option<DistractionBudget>,
+    /// Whether to use the window's `WM_CLASS` as the root of its task path
+    #[serde(default)]
+    class_in_path: bool,
+    /// Whether to strip unread counts and extra whitespace from window titles
+    /// when recording task paths
+    #[serde(default)]
+    normalize_paths: bool,
+    /// Whether to also ignore case in window titles, if normalizing them
+    #[serde(default)]
+    fold_path_case: bool,
+    /// Whether to also record other windows visible full-screen, e.g. on
+    /// another monitor, and how much weight to give them
+    #[serde(default)]
+    fullscreen_windows: Option<FullscreenWindows>,
+    /// Task log which records windows outside of blocks (and only then), if any
+    #[serde(default)]
+    background_log: Option<String>,
+    /// Whether to record time as a call while the microphone is in use
+    #[serde(default)]
+    microphone: Option<audio::Config>,
+    /// Whether to stop recording tasks, and mark blocks as away, while the
+    /// monitors are off
+    #[serde(default)]
+    away_on_monitor_off: bool,
+    /// If set, time away of at least this many seconds during a block (with
+    /// the monitors off, or the machine suspended) is held for `resolveAway`
+    #[serde(default)]
+    reconcile_away_s: Option<u64>,
+    /// Rules moving tasks to other parts of the tree, e.g. to group by project
+    #[serde(default)]
+    path_aliases: Vec<task::Alias>,
+    /// Whether to use the current desktop as the root of every task path
+    #[serde(default)]
+    desktop_in_path: bool,
+    /// Whether to start a tracking session when a meeting window is focused while idle
+    #[serde(default)]
+    meeting_auto_track: bool,
+    /// External command used to classify window titles, if any
+    #[serde(default)]
+    classifier: Option<classifier::External>,
+    /// Path of a Unix socket on which to accept JSON-RPC control commands
+    #[serde(default)]
+    control_socket: Option<std::path::PathBuf>,
+    /// TCP address on which to accept the same commands, from other machines
+    #[serde(default)]
+    control_tcp: Option<control::TcpConfig>,
+    /// Path of a Unix socket on which to stream the status line to any number
+    /// of bars
+    #[serde(default)]
+    bar_socket: Option<std::path::PathBuf>,
+    /// Address on which to serve HTTP, e.g. `127.0.0.1:8080`
+    #[serde(default)]
+    http_listen: Option<String>,
+    /// MQTT broker to publish state transitions to, if any
+    #[serde(default)]
+    mqtt: Option<mqtt::Config>,
+    /// Partner's daemon to keep blocks in lockstep with, if any
+    #[serde(default)]
+    peer: Option<peer::Config>,
+    /// URLs to notify of state transitions
+    #[serde(default)]
+    webhooks: Vec<webhook::Webhook>,
+    /// Chat accounts whose status is set while blocks are running
+    #[serde(default)]
+    chat_status: Vec<chat::Account>,
+    /// Places to post summaries of blocks
+    #[serde(default)]
+    announce: Vec<announce::Channel>,
+    /// Taskwarrior integration, if any
+    #[serde(default)]
+    taskwarrior: Option<taskwarrior::Config>,
+    /// Periodic export of completed blocks, if any
+    #[serde(default)]
+    export: Option<export::Config>,
+    /// Shared directory through which history is merged with other machines
+    #[serde(default)]
+    sync: Option<sync::Config>,
+    /// Long-term storage of finished blocks, if any
+    #[serde(default)]
+    history_store: Option<store::Config>,
+    /// Task logs of other machines, by machine and then name, as of the last sync
+    #[serde(skip)]
+    synced_task_logs: HashMap<String, HashMap<String, Task>>,
+    /// Automatic daily reports, if any
+    #[serde(default)]
+    daily_report: Option<report::Config>,
+    /// Calendar of events which blocks should avoid, if any
+    #[serde(default)]
+    calendar: Option<calendar::Config>,
+    /// Target amount of focus time per day, in seconds
+    #[serde(default, deserialize_with = "deserialize_daily_goal_s")]
+    daily_goal_s: Option<u64>,
+    /// Local time at which each day starts, for daily goals, limits and
+    /// reports, as `HH:MM`
+    #[serde(default = "default_day_start")]
+    day_start: String,
+    /// If set, cooldowns last for a proportion of the preceding block rather
+    /// than five minutes
+    #[serde(default)]
+    proportional_cooldown: Option<ProportionalCooldown>,
+    /// If set, completed blocks are followed by a break and then an optional
+    /// lockout, rather than a cooldown
+    #[serde(default, rename = "break")]
+    break_config: Option<Break>,
+    /// Where to write structured logs, if anywhere
+    #[serde(default)]
+    logging: Option<logging::Config>,
+    /// Things to suggest doing during breaks and cooldowns
+    #[serde(default)]
+    break_suggestions: Option<suggestions::Config>,
+    /// Input devices to disable during cooldowns, if any
+    #[serde(default)]
+    keyboard_lockout: Option<lockout::Config>,
+    /// How to dim the screen during cooldowns, if at all
+    #[serde(default)]
+    screen_dimming: Option<dim::Config>,
+    /// Limits on blocks per day, beyond which no more may be started
+    #[serde(default)]
+    daily_limit: Option<DailyLimit>,
+    /// Limit on how often each client method may be called
+    #[serde(default)]
+    rate_limit: RateLimit,
+    /// How task logs are split up by when time was recorded
+    #[serde(default)]
+    task_buckets: TaskBuckets,
+    /// When small tasks are folded together in task logs, if automatically
+    #[serde(default)]
+    task_log_retention: Option<TaskLogRetention>,
+    /// Restrictions on destructive D-Bus methods, if any
+    #[serde(default)]
+    access_control: Option<access::Config>,
+    /// Whether to show streaks in the status line
+    #[serde(default)]
+    streak_badge: bool,
+    /// Limits on the time spent on tasks per week
+    #[serde(default)]
+    task_budgets: Vec<TaskBudget>,
+    /// Named routines of alternating work and rest, started with `start_routine`
+    #[serde(default)]
+    routines: HashMap<String, Routine>,
+    /// Working hours, outside of which starting blocks is discouraged
+    #[serde(default)]
+    work_hours: Option<WorkHours>,
+    /// Named block lengths and settings, started with `start_preset`
+    #[serde(default)]
+    presets: HashMap<String, Preset>,
+    /// Shell command to lock the screen, when blocks from a `lock_screen`
+    /// preset end
+    #[serde(default = "default_lock_command")]
+    lock_command: String,
+    /// Shell commands to run on state transitions, keyed by `Event::name`
+    #[serde(default)]
+    hooks: HashMap<String, String>,
+    /// How long hooks may run before they are killed
+    #[serde(default = "default_hook_timeout_s")]
+    hook_timeout_s: u64,
+    /// Whether to show the block height in the status line
+    #[serde(default)]
+    show_height: bool,
+    /// Number of blocks between "halvings", which are celebrated with a notification
+    #[serde(default)]
+    halving_interval: Option<u64>,
+    /// Progress indicator shown after the countdown, if any
+    #[serde(default)]
+    progress: Option<progress::Config>,
+    /// Icons shown before the status, keyed by state as in `Status::state`
+    #[serde(default)]
+    icons: HashMap<String, String>,
+    /// If set, show the most-focused task of the current block, truncated to
+    /// this many characters, after the countdown
+    #[serde(default)]
+    top_task_width: Option<usize>,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
 }
 
 impl Server {
@@ -61,247 +448,4528 @@ impl Server {
     pub fn new() -> Server {
         Server {
             state: State::Idle,
-            flash_error: 0,
-            flash_warn: 0,
-            last_task_report: std::time::Instant::now(),
+            flash_error_until: None,
+            flash_warn_until: None,
+            last_task_report: crate::clock::now(),
+            last_meeting_seen: crate::clock::now(),
             task_logs: HashMap::new(),
+            task_log_buckets: HashMap::new(),
+            task_log_filters: HashMap::new(),
+            metrics: Metrics::default(),
+            streak_breaks: VecDeque::new(),
+            events: vec![],
+            calendar_events: vec![],
+            wrapped_up: false,
+            budgets_exceeded: HashSet::new(),
+            today: None,
+            prior_day_streak: None,
+            current_block: None,
+            timers: BTreeMap::new(),
+            routine: None,
+            strict: false,
+            lock_screen: false,
+            preset: None,
+            off_hours: None,
+            suggestion: None,
+            suggestion_shown: None,
+            diagnostics: VecDeque::new(),
+            flashes_coalesced: 0,
+            latency: latency::Latency::default(),
+            last_clock_check: None,
+            monitor_off: false,
+            away_block_s: None,
+            last_path: vec![],
+            pending_away: vec![],
+            recent_calls: HashMap::new(),
+            undo: VecDeque::new(),
+            cancel_requested: None,
             block_log: String::new(),
+            history: vec![],
+            height: 0,
+            exported_until_s: 0,
+            stored_until_s: 0,
+            reported_day_s: 0,
             color_block_start: default_color_block_start(),
             color_block_end: default_color_block_end(),
             color_cooldown_start: default_color_cooldown_start(),
             color_cooldown_end: default_color_cooldown_end(),
+            color_idle: default_color_idle(),
+            color_cooldown_background: default_color_cooldown_background(),
+            block_fade: color::Fade::default(),
+            cooldown_fade: color::Fade::default(),
+            warn_flash: flash::Style::default_warn(),
+            error_flash: flash::Style::default_error(),
+            wrap_up: None,
+            confirm_cancel_s: None,
+            redaction_rules: vec![],
+            blacklist: vec![],
+            distraction_budget: None,
+            class_in_path: false,
+            normalize_paths: false,
+            fold_path_case: false,
+            fullscreen_windows: None,
+            microphone: None,
+            away_on_monitor_off: false,
+            reconcile_away_s: None,
+            background_log: None,
+            path_aliases: vec![],
+            desktop_in_path: false,
+            meeting_auto_track: false,
+            classifier: None,
+            control_socket: None,
+            control_tcp: None,
+            bar_socket: None,
+            http_listen: None,
+            mqtt: None,
+            peer: None,
+            webhooks: vec![],
+            chat_status: vec![],
+            announce: vec![],
+            taskwarrior: None,
+            export: None,
+            sync: None,
+            history_store: None,
+            synced_task_logs: HashMap::new(),
+            daily_report: None,
+            calendar: None,
+            daily_goal_s: None,
+            day_start: default_day_start(),
+            daily_limit: None,
+            rate_limit: RateLimit::default(),
+            task_buckets: TaskBuckets::default(),
+            task_log_retention: None,
+            access_control: None,
+            proportional_cooldown: None,
+            break_config: None,
+            break_suggestions: None,
+            keyboard_lockout: None,
+            screen_dimming: None,
+            logging: None,
+            streak_badge: false,
+            task_budgets: vec![],
+            routines: HashMap::new(),
+            presets: HashMap::new(),
+            lock_command: default_lock_command(),
+            work_hours: None,
+            hooks: HashMap::new(),
+            hook_timeout_s: default_hook_timeout_s(),
+            show_height: false,
+            halving_interval: None,
+            progress: None,
+            icons: HashMap::new(),
+            top_task_width: None,
         }
     }
 
-    /// We can't really signal fs/IO errors in any way so just use this
-    pub fn signal_error(&mut self) {
-        self.flash_error = 15;
+    /// Path of the JSON-RPC control socket, if one is configured
+    pub fn control_socket(&self) -> Option<&std::path::Path> {
+        self.control_socket.as_deref()
     }
 
-    /// Internal logging method
-    fn log(&mut self, log_str: &str) {
-        let date = std::process::Command::new("date")
-            .arg("+%F %T%z")
-            .output()
-            .expect("executing bash")
-            .stdout;
-        let date = String::from_utf8_lossy(&date);
-        self.block_log += &format!("{}: {}\n", date.trim(), log_str);
+    /// Configuration for accepting control commands over TCP, if any
+    pub fn control_tcp(&self) -> Option<&control::TcpConfig> {
+        self.control_tcp.as_ref()
     }
 
-    /// Record the current active window, for task-tracking purposes
-    ///
-    /// Adds the duration that this window has been active (current time
-    /// minus the last time this function was called) to every log.
-    pub fn record_current_window(&mut self, win: &str) {
-        let now = std::time::Instant::now();
-        // Only record things if we are currently in a block...
-        if let State::InBlock { .. } = self.state {
-            let duration = now - self.last_task_report;
-            for log in self.task_logs.values_mut() {
-                log.add_time(win, duration);
-            }
+    /// Path of the socket serving the status line to bars, if one is configured
+    pub fn bar_socket(&self) -> Option<&std::path::Path> {
+        self.bar_socket.as_deref()
+    }
+
+    /// Address of the HTTP server, if one is configured
+    pub fn http_listen(&self) -> Option<&str> {
+        self.http_listen.as_deref()
+    }
+
+    /// MQTT broker configuration, if any
+    pub fn mqtt(&self) -> Option<&mqtt::Config> {
+        self.mqtt.as_ref()
+    }
+
+    /// Paired session configuration, if any
+    pub fn peer(&self) -> Option<&peer::Config> {
+        self.peer.as_ref()
+    }
+
+    /// URLs to notify of state transitions
+    pub fn webhooks(&self) -> &[webhook::Webhook] {
+        &self.webhooks
+    }
+
+    /// Places to post summaries of blocks
+    pub fn announce(&self) -> &[announce::Channel] {
+        &self.announce
+    }
+
+    /// Chat accounts whose status is set while blocks are running
+    pub fn chat_status(&self) -> &[chat::Account] {
+        &self.chat_status
+    }
+
+    /// Taskwarrior integration configuration, if any
+    pub fn taskwarrior(&self) -> Option<&taskwarrior::Config> {
+        self.taskwarrior.as_ref()
+    }
+
+    /// Time export configuration, if any
+    pub fn export(&self) -> Option<&export::Config> {
+        self.export.as_ref()
+    }
+
+    /// History store configuration, if any
+    pub fn history_store(&self) -> Option<&store::Config> {
+        self.history_store.as_ref()
+    }
+
+    /// Multi-machine sync configuration, if any
+    pub fn sync(&self) -> Option<&sync::Config> {
+        self.sync.as_ref()
+    }
+
+    /// What to share with other machines: the blocks recorded here, and our
+    /// task logs
+    pub fn sync_snapshot(&self) -> Option<(sync::Config, sync::Snapshot)> {
+        let config = self.sync.clone()?;
+        let snapshot = sync::Snapshot {
+            machine: config.machine().to_owned(),
+            blocks: self
+                .history
+                .iter()
+                .filter(|block| block.machine.is_none())
+                .cloned()
+                .collect(),
+            task_logs: self.task_logs.clone(),
+        };
+        Some((config, snapshot))
+    }
+
+    /// Merge in what other machines have shared
+    pub fn merge_synced(&mut self, theirs: Vec<sync::Snapshot>) {
+        let before = self.history.len();
+        self.synced_task_logs.clear();
+        for snapshot in theirs {
+            sync::merge_blocks(&mut self.history, &snapshot);
+            self.synced_task_logs
+                .insert(snapshot.machine, snapshot.task_logs);
         }
-        // ..but update last task report time regardless
-        self.last_task_report = now;
+        if self.history.len() != before {
+            self.log(&format!(
+                "synced history now has {} blocks",
+                self.history.len()
+            ));
+        }
+        // Blocks from elsewhere may have met the goal on earlier days
+        self.prior_day_streak = None;
     }
 
-    /// Output the most recent block log
-    pub fn block_log(&mut self) -> String {
-        self.block_log.clone()
+    /// A task log merged with the logs of the same name on other machines,
+    /// or `None` if there are none
+    fn merged_task_log(&self, name: &str) -> Option<Task> {
+        let mut logs = std::iter::once(&self.task_logs)
+            .chain(self.synced_task_logs.values())
+            .filter_map(|logs| logs.get(name));
+        let mut merged = logs.next()?.clone();
+        for log in logs {
+            merged.merge(log);
+        }
+        Some(merged)
     }
 
-    /// Create a new task log. This will overwrite any existing log with this name!
-    pub fn task_log_add(&mut self, name: String) {
-        self.log(&format!("added/cleared task log {}", name));
-        self.task_logs.insert(name, Task::new_root());
+    /// Calendar configuration, if any
+    pub fn calendar(&self) -> Option<&calendar::Config> {
+        self.calendar.as_ref()
     }
 
-    /// Deletes a task log
-    pub fn task_log_remove(&mut self, name: &str) {
-        self.log(&format!("cleared task log {}", name));
-        self.task_logs.remove(name);
+    /// Replace the set of calendar events, after re-reading the calendar
+    pub fn set_calendar_events(&mut self, events: Vec<calendar::Event>) {
+        self.calendar_events = events;
     }
 
-    /// Dumps a task log
-    pub fn task_log_dump(&mut self, name: &str) -> String {
-        self.log(&format!("output task log {}", name));
-        if let Some(log) = self.task_logs.get(name) {
-            log.to_string()
-        } else {
-            format!("[log {} not found]", name)
+    /// The longest block which could be started now without running into a
+    /// calendar event, in seconds, or `u64::MAX` if there are no upcoming events
+    pub fn max_block_until_next_event(&self) -> u64 {
+        let now = history::unix_now();
+        self.calendar_events
+            .iter()
+            .filter(|event| event.end_s > now)
+            .map(|event| event.start_s.saturating_sub(now))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// The minute of the day at which each day starts, or midnight if
+    /// `day_start` does not parse
+    fn day_start_minute(&self) -> u32 {
+        let parse = |s: &str| {
+            let (h, m) = s.split_once(':')?;
+            let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        parse(&self.day_start).unwrap_or(0)
+    }
+
+    /// The bounds of the current local day, in seconds since the UNIX epoch
+    fn today(&mut self) -> (u64, u64) {
+        let now = history::unix_now();
+        match self.today {
+            Some((start, end)) if start <= now && now < end => (start, end),
+            old_today => {
+                // If `date` fails, fall back to UTC days
+                let today = history::local_day(now, self.day_start_minute()).unwrap_or_else(|| {
+                    let start = now - now % 86400;
+                    (start, start + 86400)
+                });
+                self.today = Some(today);
+                // Note if the day which just ended broke a streak
+                if let (Some((start, end)), Some(streak)) = (old_today, self.prior_day_streak) {
+                    if streak > 0 && !self.goal_met(start, end) {
+                        self.record_streak_break(&format!(
+                            "daily goal streak of {} days broken",
+                            streak
+                        ));
+                    }
+                }
+                self.prior_day_streak = None;
+                today
+            }
         }
     }
 
-    /// (Attempt to) start a new block
-    pub fn start_block(&mut self, duration_s: u64) {
-        self.block_log = String::new();
-        self.log("started block");
-        match self.state {
-            State::Idle => {
-                let duration = std::time::Duration::from_secs(duration_s);
-                self.state = State::InBlock {
-                    duration,
-                    end_time: std::time::Instant::now() + duration,
-                };
+    /// Time spent in completed blocks which ended between two times, in seconds
+    fn focus_between_s(&self, start: u64, end: u64) -> u64 {
+        self.history
+            .iter()
+            .filter(|block| block.outcome == history::Outcome::Completed)
+            .filter(|block| start <= block.end_s && block.end_s < end)
+            .map(|block| block.duration_s)
+            .sum()
+    }
+
+    /// Whether the daily goal was met between two times
+    fn goal_met(&self, start: u64, end: u64) -> bool {
+        match self.daily_goal_s {
+            Some(goal_s) => self.focus_between_s(start, end) >= goal_s,
+            None => false,
+        }
+    }
+
+    /// Time spent today in completed blocks, in seconds
+    fn focus_today_s(&mut self) -> u64 {
+        let (start, end) = self.today();
+        self.focus_between_s(start, end)
+    }
+
+    /// If the daily limit has been reached, an explanation of why
+    pub fn daily_limit_reached(&mut self) -> Option<String> {
+        let limit = self.daily_limit?;
+        let (start, end) = self.today();
+        if let Some(max_blocks) = limit.max_blocks {
+            let blocks = self
+                .history
+                .iter()
+                .filter(|block| block.outcome == history::Outcome::Completed)
+                .filter(|block| start <= block.end_s && block.end_s < end)
+                .count() as u64;
+            if blocks >= max_blocks {
+                return Some(format!(
+                    "daily limit of {} blocks reached; take a break",
+                    max_blocks
+                ));
             }
-            State::Paused { .. } | State::InBlock { .. } => {
-                // refuse te start a block when one is running; first cancel the running one
-                self.flash_warn = 5;
+        }
+        if let Some(max_focus_s) = limit.max_focus_s {
+            if self.focus_between_s(start, end) >= max_focus_s {
+                return Some(format!(
+                    "daily limit of {:.1} hours of focus reached; take a break",
+                    max_focus_s as f64 / 3600.0
+                ));
             }
-            State::InCooldown { .. } => {
-                // refuse te start a block during cooldown; cooldown cannot be cancelled.
-                self.flash_error = 7;
+        }
+        None
+    }
+
+    /// Number of consecutive completed blocks, up to and including the most recent one
+    fn block_streak(&self) -> u64 {
+        self.history
+            .iter()
+            .rev()
+            .take_while(|block| block.outcome == history::Outcome::Completed)
+            .count() as u64
+    }
+
+    /// Current streaks, as the number of consecutive days on which the daily
+    /// goal was met (including today, if it has been met already) and the
+    /// number of consecutive completed blocks
+    pub fn streaks(&mut self) -> (u64, u64) {
+        let (today_start, today_end) = self.today();
+        let prior = match self.prior_day_streak {
+            Some(prior) => prior,
+            None => {
+                // No day before the oldest block can have met the goal, so
+                // stop there rather than walking back forever
+                let oldest_s = self.history.iter().map(|block| block.end_s).min();
+                let mut prior = 0;
+                let mut day_start = today_start;
+                while self.daily_goal_s.is_some() && oldest_s.is_some_and(|s| s < day_start) {
+                    let (start, end) = history::local_day(day_start - 1, self.day_start_minute())
+                        .unwrap_or((day_start.saturating_sub(86400), day_start));
+                    if !self.goal_met(start, end) {
+                        break;
+                    }
+                    prior += 1;
+                    day_start = start;
+                }
+                self.prior_day_streak = Some(prior);
+                prior
             }
+        };
+        let days = prior + u64::from(self.goal_met(today_start, today_end));
+        (days, self.block_streak())
+    }
+
+    /// Note that a streak was broken, in the block log and in `streak_breaks`
+    fn record_streak_break(&mut self, message: &str) {
+        self.log(message);
+        if self.streak_breaks.len() == STREAK_BREAKS_LEN {
+            self.streak_breaks.pop_front();
         }
+        self.streak_breaks
+            .push_back((history::unix_now(), message.to_owned()));
     }
 
-    /// Attempt to cancel a currently-running block
-    pub fn cancel_block(&mut self) {
-        self.log("canceled block");
-        match self.state {
-            State::InBlock { .. } => self.state = State::Idle,
-            State::InCooldown { .. } => self.flash_error = 7,
-            _ => self.flash_warn = 5,
+    /// The most recently broken streaks, oldest first, as UNIX timestamps
+    /// and messages
+    pub fn streak_breaks(&self) -> Vec<(u64, String)> {
+        self.streak_breaks.iter().cloned().collect()
+    }
+
+    /// Progress towards the daily goal, as seconds of focus so far today and
+    /// the goal in seconds (which is 0 if no goal is set)
+    pub fn goal_status(&mut self) -> (u64, u64) {
+        (self.focus_today_s(), self.daily_goal_s.unwrap_or(0))
+    }
+
+    /// The most recently finished block on this machine, if any
+    pub fn last_block(&self) -> Option<&history::Block> {
+        self.history
+            .iter()
+            .rev()
+            .find(|block| block.machine.is_none())
+    }
+
+    /// A report on a past block, as JSON, counting back from the most recent
+    /// block (which has index 0)
+    pub fn block_report(&self, index: usize) -> String {
+        let block = match self.history.iter().rev().nth(index) {
+            Some(block) => block,
+            None => return format!("[block {} not found]", index),
+        };
+        let top_tasks: Vec<_> = block
+            .tasks
+            .leaf_paths()
+            .into_iter()
+            .take(REPORT_TOP_TASKS)
+            .map(|(path, time)| serde_json::json!({ "path": path, "time_s": time.as_secs() }))
+            .collect();
+        let unfocused_tasks: Vec<_> = block
+            .unfocused
+            .leaf_paths()
+            .into_iter()
+            .take(REPORT_TOP_TASKS)
+            .map(|(path, time)| serde_json::json!({ "path": path, "time_s": time.as_secs() }))
+            .collect();
+        let actual_s = block.actual().as_secs();
+        serde_json::json!({
+            "label": block.label,
+            "machine": block.machine,
+            "start_s": block.start_s,
+            "end_s": block.end_s,
+            "duration_s": block.duration_s,
+            "actual_s": actual_s,
+            "focused_s": actual_s
+                .saturating_sub(block.paused.as_secs())
+                .saturating_sub(block.away_s()),
+            "outcome": block.outcome,
+            "pauses": block.pauses,
+            "paused_s": block.paused.as_secs(),
+            "clock_jump_s": block.clock_jump_s,
+            "away": block.away,
+            "away_s": block.away_s(),
+            "top_tasks": top_tasks,
+            "distracted_s": block.distracted.as_secs(),
+            "unfocused_tasks": unfocused_tasks,
+            "switches": block.switches,
+            "longest_focus_s": block.longest_focus.as_secs(),
+            "focus_score": block.focus_score,
+            "notes": block.notes,
+        })
+        .to_string()
+    }
+
+    /// The history rendered as a standalone HTML page, with a timeline of
+    /// blocks, a treemap of tasks and daily totals
+    pub fn html_report(&self) -> String {
+        report::html(&self.history, self.daily_goal_s, self.day_start_minute())
+    }
+
+    /// An SVG heatmap of focus time by day of the week and hour, over the
+    /// given number of weeks (or all of history, if 0), or `None` if local
+    /// times could not be determined
+    pub fn focus_heatmap(&self, weeks: u32) -> Option<String> {
+        let since_s = match weeks {
+            0 => 0,
+            weeks => history::unix_now().saturating_sub(u64::from(weeks) * 7 * 86400),
+        };
+        let start = self
+            .history
+            .partition_point(|block| block.start_s < since_s);
+        let heatmap = report::heatmap(&self.history[start..])?;
+        Some(report::heatmap_svg(&heatmap))
+    }
+
+    /// Attach a note to the current block or, if there is none, the most
+    /// recent one
+    pub fn annotate_block(&mut self, text: String) {
+        record::call(&Call::AnnotateBlock { text: text.clone() });
+        let block = match self.current_block.as_mut() {
+            Some(block) => block,
+            None => match self
+                .history
+                .iter_mut()
+                .rev()
+                .find(|block| block.machine.is_none())
+            {
+                Some(block) => block,
+                None => {
+                    self.flash(FlashKind::Warn, 1);
+                    return;
+                }
+            },
+        };
+        block.notes.push(text);
+        self.log("annotated block");
+    }
+
+    /// All recorded blocks, oldest first
+    pub fn history(&self) -> &[history::Block] {
+        &self.history
+    }
+
+    /// The record of the block in progress, if any
+    pub fn current_block(&self) -> Option<&history::Block> {
+        self.current_block.as_ref()
+    }
+
+    /// Completed blocks which have not yet been exported
+    pub fn pending_exports(&self) -> Vec<history::Block> {
+        self.history
+            .iter()
+            .filter(|block| block.outcome == history::Outcome::Completed)
+            .filter(|block| block.machine.is_none())
+            // Imported blocks were likely exported from the same tool
+            .filter(|block| block.imported.is_none())
+            .filter(|block| block.end_s > self.exported_until_s)
+            .cloned()
+            .collect()
+    }
+
+    /// Note that all blocks ending up to a given time have been exported
+    pub fn mark_exported(&mut self, until_s: u64) {
+        self.exported_until_s = self.exported_until_s.max(until_s);
+    }
+
+    /// Finished blocks which have not yet been added to the history store
+    pub fn pending_store(&self) -> Vec<history::Block> {
+        self.history
+            .iter()
+            .filter(|block| block.machine.is_none())
+            .filter(|block| block.end_s > self.stored_until_s)
+            .cloned()
+            .collect()
+    }
+
+    /// Note that all blocks ending up to a given time have been stored
+    pub fn mark_stored(&mut self, until_s: u64) {
+        self.stored_until_s = self.stored_until_s.max(until_s);
+    }
+
+    /// If today's report is due, the configuration to write it with and its
+    /// content; it is then not due again until tomorrow
+    pub fn take_daily_report(&mut self) -> Option<(report::Config, report::Daily)> {
+        let config = self.daily_report.clone()?;
+        let (_, minute) = history::local_weekday_minute()?;
+        let (start, end) = self.today();
+        // Compare times of day counting from when the day starts, so that a
+        // report due after midnight is written before the day ends
+        let day_start = self.day_start_minute();
+        let since_day_start = |minute: u32| (minute + 24 * 60 - day_start) % (24 * 60);
+        if since_day_start(minute) < since_day_start(config.minute()?)
+            || self.reported_day_s >= start
+        {
+            return None;
         }
+        let date = history::local_date(start)?;
+        self.reported_day_s = start;
+        let blocks: Vec<_> = self
+            .history
+            .iter()
+            .filter(|block| start <= block.end_s && block.end_s < end)
+            .collect();
+        let report = report::Daily::new(date, start, &blocks, self.daily_goal_s);
+        Some((config, report))
     }
 
-    /// Attempt to pause a currently-running block
-    pub fn pause_block(&mut self) {
-        match self.state {
-            State::InBlock { duration, end_time } => {
-                self.log("paused block");
-                self.state = State::Paused {
-                    total_duration: duration,
-                    remaining_duration: end_time - std::time::Instant::now(),
-                };
+    /// Note that a daily report was written, running its hook
+    pub fn daily_report_written(&mut self, path: &std::path::Path) {
+        self.log(&format!("wrote daily report to {}", path.display()));
+        self.emit_with_detail(Event::DailyReport, Some(path.display().to_string()));
+    }
+
+    /// Number of blocks ever completed
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Remember a destructive action, so that it can be undone
+    fn push_undo(&mut self, undo: Undo) {
+        if self.undo.len() == UNDO_LEN {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(undo);
+    }
+
+    /// Undo the most recent destructive action, returning a description of
+    /// what was undone
+    ///
+    /// A cancelled block is restored paused, with the time it had left when
+    /// it was cancelled, so can only be undone if no block has been started
+    /// since.
+    pub fn undo_last(&mut self) -> Result<String, String> {
+        record::call(&Call::UndoLast);
+        let undo = match self.undo.pop_back() {
+            Some(undo) => undo,
+            None => {
+                self.flash(FlashKind::Warn, 1);
+                return Err("nothing to undo".into());
             }
-            State::Paused {
-                total_duration,
-                remaining_duration,
+        };
+        let description = match undo {
+            Undo::TaskLog {
+                name,
+                previous,
+                buckets,
+                filter,
             } => {
-                self.log("unpaused block");
-                self.state = State::InBlock {
-                    duration: total_duration,
-                    end_time: std::time::Instant::now() + remaining_duration,
+                let description = format!("restored task log {}", name);
+                // A log which had no buckets or filter must not keep those
+                // of the log which replaced it
+                match buckets {
+                    Some(buckets) => self.task_log_buckets.insert(name.clone(), buckets),
+                    None => self.task_log_buckets.remove(&name),
                 };
+                match filter {
+                    Some(filter) => self.task_log_filters.insert(name.clone(), filter),
+                    None => self.task_log_filters.remove(&name),
+                };
+                self.task_logs.insert(name, previous);
+                description
             }
-            _ => self.flash_warn = 5,
-        }
+            Undo::CancelBlock {
+                state,
+                block,
+                routine,
+                wrapped_up,
+            } => {
+                if self.state != State::Idle {
+                    self.undo.push_back(Undo::CancelBlock {
+                        state,
+                        block,
+                        routine,
+                        wrapped_up,
+                    });
+                    self.flash(FlashKind::Warn, 1);
+                    return Err("cannot restore the cancelled block while busy".into());
+                }
+                // Forget the cancelled block, so that it doesn't count against
+                // streaks, if it is still in the history
+                if let Some(ref block) = block {
+                    let cancelled = self.history.iter().rposition(|b| {
+                        b.machine.is_none()
+                            && b.start_s == block.start_s
+                            && b.outcome == history::Outcome::Cancelled
+                    });
+                    if let Some(cancelled) = cancelled {
+                        self.history.remove(cancelled);
+                    }
+                }
+                self.state = state;
+                self.current_block = block.map(|block| *block);
+                self.routine = routine;
+                self.wrapped_up = wrapped_up;
+                "restored cancelled block, paused".to_owned()
+            }
+        };
+        self.log(&format!("undo: {}", description));
+        Ok(description)
     }
 
-    /// Write a single line of output to xmobar
-    pub fn xmobar_update(&mut self) -> String {
-        let now = std::time::Instant::now();
-        let mut bg_col = "";
-        // Flash a warning, if one is happening
-        if self.flash_warn > 0 {
-            if self.flash_warn % 2 == 1 {
-                bg_col = ",#FF0";
+    /// Finish recording the current block, adding it to the history
+    fn finish_block(&mut self, outcome: history::Outcome) {
+        if outcome == history::Outcome::Completed {
+            self.height += 1;
+            if let Some(interval) = self.halving_interval {
+                if interval > 0 && self.height.is_multiple_of(interval) {
+                    self.log(&format!("halving at height {}", self.height));
+                    self.emit(Event::Halving);
+                }
             }
-            self.flash_warn -= 1;
         }
-        // Flash an error, if one is happening
-        if self.flash_error > 0 {
-            if self.flash_error % 2 == 1 {
-                bg_col = ",#F00";
+        if let Some(mut block) = self.current_block.take() {
+            block.finish(outcome);
+            if block.distracted > std::time::Duration::ZERO {
+                let distracted_s = block.distracted.as_secs_f64();
+                self.log(&format!("distracted for {:.1}s", distracted_s));
             }
-            self.flash_error -= 1;
+            self.history.push(block);
         }
-        // Actually display status
-        match self.state {
-            State::Idle => format!("<fc=#AAA{}>--</fc>", bg_col),
-            State::Paused {
-                remaining_duration, ..
-            } => {
-                let rem = remaining_duration.as_secs();
-                format!("<fc=#AAA{}>{:02}:{:02}</fc>", bg_col, rem / 60, rem % 60)
+    }
+
+    /// Apply a named color theme
+    pub fn set_theme(&mut self, name: &str) {
+        record::call(&Call::SetTheme {
+            name: name.to_owned(),
+        });
+        let theme = match theme::named(name) {
+            Some(theme) => theme,
+            None => {
+                self.flash(FlashKind::Warn, 1);
+                return;
             }
-            State::InBlock { end_time, duration } => {
-                if now > end_time {
-                    self.log("end block; start cooldown");
-                    self.state = State::InCooldown {
-                        end_time: now + crate::COOLDOWN_DURATION,
-                    };
-                };
-                let rem_duration = end_time - now;
-                let rem_s = rem_duration.as_secs();
-                if rem_s < 10 && rem_duration.as_millis() % 2000 > 1750 {
-                    self.flash_warn = 3;
-                }
-                format!(
-                    "<fc={}{}>{:02}:{:02}</fc>",
-                    crate::color::fade_between(
-                        self.color_block_end,
-                        self.color_block_start,
-                        rem_duration,
-                        duration
-                    ),
-                    bg_col,
-                    rem_s / 60,
-                    rem_s % 60,
-                )
+        };
+        self.color_block_start = theme.block_start;
+        self.color_block_end = theme.block_end;
+        self.color_cooldown_start = theme.cooldown_start;
+        self.color_cooldown_end = theme.cooldown_end;
+        self.color_idle = theme.idle.into();
+        self.color_cooldown_background = theme.cooldown_background.into();
+        self.warn_flash.color = theme.warn.into();
+        self.error_flash.color = theme.error.into();
+        self.log(&format!("set color theme {}", name));
+    }
+
+    /// Set individual colors, given as `#rgb` or `#rrggbb` and keyed by the
+    /// name of their config field (without the `color_` prefix)
+    ///
+    /// If any name or color is invalid, no colors are changed.
+    pub fn set_colors(&mut self, colors: &HashMap<String, String>) {
+        record::call(&Call::SetColors {
+            colors: colors.clone(),
+        });
+        const NAMES: [&str; 8] = [
+            "block_start",
+            "block_end",
+            "cooldown_start",
+            "cooldown_end",
+            "idle",
+            "cooldown_background",
+            "warn",
+            "error",
+        ];
+        let valid = colors.iter().all(|(name, value)| {
+            NAMES.contains(&name.as_str()) && crate::color::parse_hex(value).is_some()
+        });
+        if !valid {
+            self.flash(FlashKind::Warn, 1);
+            return;
+        }
+
+        for (name, value) in colors {
+            let rgb = crate::color::parse_hex(value).expect("checked above");
+            match name.as_str() {
+                "block_start" => self.color_block_start = rgb,
+                "block_end" => self.color_block_end = rgb,
+                "cooldown_start" => self.color_cooldown_start = rgb,
+                "cooldown_end" => self.color_cooldown_end = rgb,
+                "idle" => self.color_idle = value.clone(),
+                "cooldown_background" => self.color_cooldown_background = value.clone(),
+                "warn" => self.warn_flash.color = value.clone(),
+                "error" => self.error_flash.color = value.clone(),
+                _ => unreachable!("checked above"),
             }
-            State::InCooldown { end_time } => {
-                if now > end_time {
-                    self.log("end cooldown");
-                    // FIXME we probably shouldn't hardcode this
-                    std::process::Command::new("bash")
-                        .arg("-c")
-                        .arg("source ~/.bashrc && ~/bin/keyboard.sh")
-                        .output()
-                        .expect("executing bash");
-                    self.state = State::Idle;
-                };
-                if bg_col == "" {
-                    // by default, highlight cooldown visibly
-                    bg_col = ",#FF8";
-                }
+        }
+        self.log("set colors");
+    }
 
-                let rem_duration = end_time - now;
-                let rem_s = rem_duration.as_secs();
-                if rem_s < 10 && rem_duration.as_millis() % 2000 > 1750 {
-                    self.flash_warn = 3;
-                }
-                format!(
-                    "<fc={}{}>{:02}:{:02}</fc>",
-                    crate::color::fade_between(
-                        self.color_cooldown_end,
-                        self.color_cooldown_start,
-                        rem_duration,
-                        crate::COOLDOWN_DURATION
-                    ),
-                    bg_col,
-                    rem_s / 60,
-                    rem_s % 60,
-                )
+    /// Logging configuration, if any
+    pub fn logging(&self) -> Option<&logging::Config> {
+        self.logging.as_ref()
+    }
+
+    /// Access control configuration, if any
+    pub fn access_control(&self) -> Option<&access::Config> {
+        self.access_control.as_ref()
+    }
+
+    /// Check that a call to a destructive method is allowed, given the
+    /// caller's user ID (if known) and the token it gave (if any)
+    pub fn check_access(&self, uid: Option<u32>, token: Option<&str>) -> Result<(), String> {
+        match self.access_control {
+            Some(ref config) => config.check(uid, token),
+            None => Ok(()),
+        }
+    }
+
+    /// Break configuration, if any
+    pub fn break_config(&self) -> Option<&Break> {
+        self.break_config.as_ref()
+    }
+
+    /// Break suggestion configuration, if any
+    pub fn break_suggestions(&self) -> Option<&suggestions::Config> {
+        self.break_suggestions.as_ref()
+    }
+
+    /// Keyboard lockout configuration, if any
+    pub fn keyboard_lockout(&self) -> Option<&lockout::Config> {
+        self.keyboard_lockout.as_ref()
+    }
+
+    /// Screen dimming configuration, if any
+    pub fn screen_dimming(&self) -> Option<&dim::Config> {
+        self.screen_dimming.as_ref()
+    }
+
+    /// Whether we are in a cooldown, during which input may be locked out
+    pub fn in_cooldown(&self) -> bool {
+        matches!(self.state, State::InCooldown { .. })
+    }
+
+    /// Wrap-up warning configuration, if any
+    pub fn wrap_up(&self) -> Option<&WrapUp> {
+        self.wrap_up.as_ref()
+    }
+
+    /// Shell command which locks the screen
+    pub fn lock_command(&self) -> &str {
+        &self.lock_command
+    }
+
+    /// How long hooks may run before they are killed
+    pub fn hook_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.hook_timeout_s)
+    }
+
+    /// Details of a state transition, and of the server's state, passed to
+    /// hooks as JSON
+    ///
+    /// The block concerned is the current one, if any, and otherwise the
+    /// most recent one.
+    pub fn hook_payload(&mut self, transition: &Transition) -> serde_json::Value {
+        let block = self.current_block.as_ref().or_else(|| {
+            self.history
+                .iter()
+                .rev()
+                .find(|block| block.machine.is_none())
+        });
+        let actual_s = block.map(|block| block.actual().as_secs());
+        let top_tasks: Vec<_> = block
+            .map(|block| block.tasks.leaf_paths())
+            .unwrap_or_default()
+            .into_iter()
+            .take(HOOK_TOP_TASKS)
+            .map(|(path, time)| serde_json::json!({ "path": path, "time_s": time.as_secs() }))
+            .collect();
+        let timestamp_s = transition
+            .timestamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (streak_days, streak_blocks) = self.streaks();
+        serde_json::json!({
+            "event": transition.event.name(),
+            "timestamp_s": timestamp_s,
+            "state": self.status().state,
+            "label": transition.label,
+            "preset": transition.preset,
+            "detail": transition.detail,
+            "duration_s": transition.duration_s,
+            "actual_s": actual_s,
+            "top_tasks": top_tasks,
+            "height": self.height,
+            "streak_days": streak_days,
+            "streak_blocks": streak_blocks,
+        })
+    }
+
+    /// The shell command to run on a state transition, if any: the preset's
+    /// own hook, if the block concerned was started from a preset which
+    /// overrides it, and otherwise the global one
+    pub fn hook(&self, transition: &Transition) -> Option<&str> {
+        let name = transition.event.name();
+        let preset = transition
+            .preset
+            .as_ref()
+            .and_then(|preset| self.presets.get(preset));
+        let command = match preset.and_then(|preset| preset.hooks.get(name)) {
+            Some(command) => command,
+            None => self.hooks.get(name)?,
+        };
+        Some(command.as_str()).filter(|command| !command.is_empty())
+    }
+
+    /// Time spent in the past week on a task, given as a root-first path
+    fn weekly_usage(&self, path: &[String]) -> std::time::Duration {
+        let week_ago = history::unix_now().saturating_sub(7 * 86400);
+        let path: Vec<String> = path.iter().rev().cloned().collect();
+        self.history
+            .iter()
+            .filter(|block| block.end_s >= week_ago)
+            .chain(self.current_block.as_ref())
+            .filter_map(|block| block.tasks.time_at_path(&path))
+            .sum()
+    }
+
+    /// Check whether any budgets covering a (reversed) task path have been exceeded
+    fn check_task_budgets(&mut self, path: &[String]) {
+        for i in 0..self.task_budgets.len() {
+            let budget = &self.task_budgets[i];
+            let covers = budget.path.len() <= path.len()
+                && budget
+                    .path
+                    .iter()
+                    .zip(path.iter().rev())
+                    .all(|(a, b)| a == b);
+            if !covers {
+                continue;
+            }
+            let exceeded = self.weekly_usage(&budget.path).as_secs() > budget.limit_s;
+            let path = budget.path.clone();
+            if !exceeded {
+                self.budgets_exceeded.remove(&path);
+            } else if self.budgets_exceeded.insert(path.clone()) {
+                let path_str = path.join(" / ");
+                self.log(&format!("weekly budget for \"{}\" exceeded", path_str));
+                self.emit_with_detail(Event::BudgetExceeded, Some(path_str));
             }
         }
     }
-}
 
-/// The state machine
-#[derive(PartialEq, Eq, Clone, Debug)]
-enum State {
-    /// The server is idle (no current block)
-    Idle,
-    /// The server is counting down a given block
-    InBlock {
-        duration: std::time::Duration,
-        end_time: std::time::Instant,
-    },
-    /// Timer is paused
-    Paused {
-        total_duration: std::time::Duration,
-        remaining_duration: std::time::Duration,
-    },
-    /// The server is counting down the post-block cooldown
-    InCooldown { end_time: std::time::Instant },
-}
+    /// Record a state transition
+    ///
+    /// The transition's label and duration are taken from the current state,
+    /// so this should be called after entering a state, for transitions which
+    /// start something, and before leaving it, for transitions which end something.
+    fn emit(&mut self, event: Event) {
+        self.emit_with_detail(event, None)
+    }
 
-impl State {
-    /// Helper function needed by serde to "deserialize" the field as Idle
-    fn idle() -> State {
-        State::Idle
+    /// Record a state transition, with some event-specific detail
+    fn emit_with_detail(&mut self, event: Event, detail: Option<String>) {
+        let (label, duration_s) = match self.state {
+            State::Idle | State::InBreak { .. } | State::InCooldown { .. } => (None, None),
+            State::InBlock {
+                duration,
+                ref label,
+                ..
+            } => (label.clone(), Some(duration.as_secs())),
+            State::Paused {
+                total_duration,
+                ref label,
+                ..
+            } => (label.clone(), Some(total_duration.as_secs())),
+            State::Tracking {
+                ref label,
+                start_time,
+            } => (
+                Some(label.clone()),
+                Some(
+                    crate::clock::now()
+                        .saturating_duration_since(start_time)
+                        .as_secs(),
+                ),
+            ),
+        };
+        tracing::info!(
+            event = event.name(),
+            label = label.as_deref(),
+            detail = detail.as_deref(),
+            "transition"
+        );
+        let preset = match self.state {
+            State::Idle | State::Tracking { .. } => None,
+            _ => self.preset.clone(),
+        };
+        self.metrics.record_event(&event);
+        self.events.push(Transition {
+            event,
+            label,
+            duration_s,
+            detail,
+            preset,
+            timestamp: crate::clock::system_now(),
+        });
+    }
+
+    /// Output metrics in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        self.metrics.render(&self.status())
+    }
+
+    /// Take all state transitions which have happened since the last call
+    pub fn take_events(&mut self) -> Vec<Transition> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Signal an internal error, e.g. from the filesystem or an external
+    /// command, by flashing and recording it for `diagnostics`
+    pub fn signal_error(&mut self, message: &str) {
+        self.record_diagnostic(message);
+        tracing::error!("{}", message);
+        self.flash(FlashKind::Error, 2);
+    }
+
+    /// Add a message to the diagnostics, without flashing
+    pub fn record_diagnostic(&mut self, message: &str) {
+        if self.diagnostics.len() == DIAGNOSTICS_LEN {
+            self.diagnostics.pop_front();
+        }
+        self.diagnostics
+            .push_back((history::unix_now(), message.to_owned()));
+    }
+
+    /// Record a call to a client method, returning whether it is within the
+    /// rate limit
+    ///
+    /// Refusals are noted in the diagnostics when they start, and counted
+    /// once calls are allowed again.
+    pub fn allow_call(&mut self, method: &str) -> bool {
+        let now = crate::clock::now();
+        let interval = std::time::Duration::from_secs(self.rate_limit.interval_s);
+        let (calls, refused) = self.recent_calls.entry(method.to_owned()).or_default();
+        while calls
+            .front()
+            .is_some_and(|&call| now.duration_since(call) >= interval)
+        {
+            calls.pop_front();
+        }
+        if calls.len() >= self.rate_limit.calls {
+            *refused += 1;
+            if *refused == 1 {
+                tracing::warn!(method, "rate limited");
+                self.record_diagnostic(&format!("{} called too often; refusing calls", method));
+            }
+            return false;
+        }
+        calls.push_back(now);
+        let refused = std::mem::take(refused);
+        if refused > 0 {
+            self.record_diagnostic(&format!("refused {} calls to {}", refused, method));
+        }
+        true
+    }
+
+    /// The most recent internal errors, oldest first, as UNIX timestamps and
+    /// messages, followed by a summary of recent render latency
+    pub fn diagnostics(&self) -> Vec<(u64, String)> {
+        let mut diagnostics: Vec<_> = self.diagnostics.iter().cloned().collect();
+        if let Some(summary) = self.latency.summary() {
+            diagnostics.push((history::unix_now(), summary));
+        }
+        diagnostics
+    }
+
+    /// Record how late a render of the status line was, if it was a tick
+    /// rather than a response to a client, and how long it took, noting slow
+    /// ones in the diagnostics
+    pub fn record_render(
+        &mut self,
+        late: Option<std::time::Duration>,
+        render: std::time::Duration,
+    ) {
+        if self.latency.record(late, render) {
+            let message = match late {
+                Some(late) if late >= latency::SLOW => {
+                    format!("render tick {}ms late", late.as_millis())
+                }
+                _ => format!("rendering took {}ms", render.as_millis()),
+            };
+            self.record_diagnostic(&message);
+        }
+    }
+
+    /// Drop everything configured to reach outside the daemon, e.g. hooks,
+    /// webhooks and exports, so that replaying a recording doesn't repeat
+    /// its effects
+    pub fn disconnect(&mut self) {
+        self.control_socket = None;
+        self.control_tcp = None;
+        self.bar_socket = None;
+        self.http_listen = None;
+        self.mqtt = None;
+        self.peer = None;
+        self.webhooks.clear();
+        self.chat_status.clear();
+        self.announce.clear();
+        self.taskwarrior = None;
+        self.export = None;
+        self.sync = None;
+        self.history_store = None;
+        self.daily_report = None;
+        self.calendar = None;
+        self.logging = None;
+        self.keyboard_lockout = None;
+        self.screen_dimming = None;
+        self.hooks.clear();
+        for preset in self.presets.values_mut() {
+            preset.hooks.clear();
+        }
+        self.lock_command = "true".into();
+    }
+
+    /// Apply the configuration from `new`, e.g. freshly read from the config
+    /// file, while keeping the current state, including any running block
+    pub fn reload_config(&mut self, new: Server) {
+        let old = std::mem::replace(self, new);
+        self.state = old.state;
+        self.flash_error_until = old.flash_error_until;
+        self.flash_warn_until = old.flash_warn_until;
+        self.last_task_report = old.last_task_report;
+        self.last_meeting_seen = old.last_meeting_seen;
+        self.events = old.events;
+        self.calendar_events = old.calendar_events;
+        self.wrapped_up = old.wrapped_up;
+        self.budgets_exceeded = old.budgets_exceeded;
+        // Days are only the same if they start at the same time
+        if self.day_start == old.day_start {
+            self.today = old.today;
+            // and the streak only if the goal is too
+            if self.daily_goal_s == old.daily_goal_s {
+                self.prior_day_streak = old.prior_day_streak;
+            }
+        }
+        self.current_block = old.current_block;
+        self.timers = old.timers;
+        self.routine = old.routine;
+        self.strict = old.strict;
+        self.lock_screen = old.lock_screen;
+        self.preset = old.preset;
+        self.suggestion = old.suggestion;
+        self.suggestion_shown = old.suggestion_shown;
+        self.diagnostics = old.diagnostics;
+        self.flashes_coalesced = old.flashes_coalesced;
+        self.latency = old.latency;
+        self.last_clock_check = old.last_clock_check;
+        self.monitor_off = old.monitor_off;
+        self.away_block_s = old.away_block_s;
+        self.last_path = old.last_path;
+        self.pending_away = old.pending_away;
+        self.recent_calls = old.recent_calls;
+        self.undo = old.undo;
+        self.cancel_requested = old.cancel_requested;
+        self.block_log = old.block_log;
+        self.history = old.history;
+        self.height = old.height;
+        self.exported_until_s = old.exported_until_s;
+        self.stored_until_s = old.stored_until_s;
+        self.reported_day_s = old.reported_day_s;
+        self.task_logs = old.task_logs;
+        self.task_log_buckets = old.task_log_buckets;
+        self.task_log_filters = old.task_log_filters;
+        self.synced_task_logs = old.synced_task_logs;
+        self.metrics = old.metrics;
+        self.streak_breaks = old.streak_breaks;
+        // `off_hours` is deliberately not kept, since working hours may have changed
+        self.log("reloaded config");
+    }
+
+    /// Start flashing, for the given multiple of the flash's configured duration
+    fn flash(&mut self, kind: FlashKind, multiple: u32) {
+        let (style, until) = match kind {
+            FlashKind::Warn => (&self.warn_flash, &mut self.flash_warn_until),
+            FlashKind::Error => (&self.error_flash, &mut self.flash_error_until),
+        };
+        let now = crate::clock::now();
+        if until.is_some_and(|until| now < until + FLASH_COALESCE_DURATION) {
+            self.flashes_coalesced += 1;
+            return;
+        }
+        *until = Some(now + style.duration() * multiple);
+    }
+
+    /// Flash a warning briefly, for three blinks
+    fn pulse_warn(&mut self) {
+        let end_time = crate::clock::now() + self.warn_flash.blink() * 3;
+        let until = &mut self.flash_warn_until;
+        *until = Some(until.map_or(end_time, |until| until.max(end_time)));
+    }
+
+    /// Internal logging method
+    fn log(&mut self, log_str: &str) {
+        let date = std::process::Command::new("date")
+            .arg("+%F %T%z")
+            .output()
+            .expect("executing bash")
+            .stdout;
+        let date = String::from_utf8_lossy(&date);
+        self.block_log += &format!("{}: {}\n", date.trim(), log_str);
+        tracing::info!("{}", log_str);
+    }
+
+    /// Compute the (reversed) task path that time in a window is recorded under
+    fn window_path(&mut self, win: &Window) -> Vec<String> {
+        let (title, hash_leaf) = redact::redact_title(&self.redaction_rules, &win.title);
+        let mut path = self
+            .classifier
+            .as_mut()
+            .and_then(|classifier| classifier.classify(&title))
+            .unwrap_or_else(|| task::title_to_path(&title));
+        if self.normalize_paths {
+            task::normalize_path(&mut path, self.fold_path_case);
+        }
+        if hash_leaf {
+            redact::hash_leaf(&mut path);
+        }
+        task::apply_aliases(&self.path_aliases, &mut path);
+        if self.class_in_path && !win.class.is_empty() {
+            path.push(win.class.clone());
+        }
+        if self.desktop_in_path && !win.desktop.is_empty() {
+            path.push(win.desktop.clone());
+        }
+        path
+    }
+
+    /// Whether applications using the microphone should be queried along with
+    /// the active window
+    pub fn tracks_microphone(&self) -> bool {
+        self.microphone.is_some()
+    }
+
+    /// Whether the active window's class should be queried along with it
+    pub fn tracks_class(&self) -> bool {
+        self.class_in_path
+    }
+
+    /// Whether the current desktop should be queried along with the active
+    /// window
+    pub fn tracks_desktop(&self) -> bool {
+        self.desktop_in_path
+    }
+
+    /// Whether other windows visible full-screen should be queried along with
+    /// the active window
+    pub fn tracks_fullscreen(&self) -> bool {
+        self.fullscreen_windows.is_some()
+    }
+
+    /// Whether the monitors' power state should be queried before the active
+    /// window, and `record_monitor_off` called instead while they are off
+    pub fn tracks_monitor(&self) -> bool {
+        self.away_on_monitor_off
+    }
+
+    /// Whether the current active window is needed for anything
+    ///
+    /// Querying X for the active window is relatively expensive, so the
+    /// caller should only do so while this returns true, and otherwise call
+    /// `skip_current_window` so that idle time is not attributed to any window.
+    pub fn wants_current_window(&self) -> bool {
+        match self.state {
+            State::InBlock { .. } | State::Tracking { .. } => true,
+            State::Idle => self.meeting_auto_track || self.background_log.is_some(),
+            State::InBreak { .. } | State::InCooldown { .. } => self.background_log.is_some(),
+            State::Paused { .. } => false,
+        }
+    }
+
+    /// Note that time has passed without the active window being recorded
+    pub fn skip_current_window(&mut self) {
+        self.last_task_report = crate::clock::now();
+    }
+
+    /// Note that the monitors were off, as sampled at time `now`
+    ///
+    /// The user is taken to be away, so the time since the last sample is not
+    /// recorded for any task, but a running block carries on, with the time
+    /// marked as away in its record.
+    pub fn record_monitor_off(&mut self, now: std::time::Instant) {
+        if !self.monitor_off {
+            self.monitor_off = true;
+            self.log("monitors off; not recording tasks");
+        }
+        if let State::InBlock { .. } = self.state {
+            let away = now.saturating_duration_since(self.last_task_report);
+            let now_s = history::unix_now();
+            if let Some(ref mut block) = self.current_block {
+                block.note_away(now_s.saturating_sub(away.as_secs()), now_s);
+                self.away_block_s = Some(block.start_s);
+            }
+        }
+        self.last_task_report = now;
+    }
+
+    /// The record of a block recorded on this machine, given its start time,
+    /// whether it is still running or not
+    fn local_block_mut(&mut self, start_s: u64) -> Option<&mut history::Block> {
+        match self.current_block {
+            Some(ref mut block) if block.start_s == start_s => Some(block),
+            _ => self
+                .history
+                .iter_mut()
+                .rev()
+                .find(|block| block.machine.is_none() && block.start_s == start_s),
+        }
+    }
+
+    /// Hold time away during a block, between two times in seconds since the
+    /// UNIX epoch, for `resolveAway`, if configured to and it was long enough
+    fn hold_away(&mut self, block_start_s: u64, from_s: u64, to_s: u64) {
+        match self.reconcile_away_s {
+            Some(min_s) if to_s.saturating_sub(from_s) >= min_s => {}
+            _ => return,
+        }
+        self.log(&format!(
+            "away for {}s during block; waiting for resolveAway",
+            to_s - from_s
+        ));
+        self.flash(FlashKind::Warn, 1);
+        self.pending_away.push(PendingAway {
+            block_start_s,
+            from_s,
+            to_s,
+            path: self.last_path.clone(),
+        });
+    }
+
+    /// Time away during blocks waiting for `resolveAway`, oldest first, as
+    /// start and end times in seconds since the UNIX epoch and the task
+    /// (root-first) focused before it
+    pub fn pending_away(&self) -> Vec<(u64, u64, Vec<String>)> {
+        self.pending_away
+            .iter()
+            .map(|away| {
+                let path = away.path.iter().rev().cloned().collect();
+                (away.from_s, away.to_s, path)
+            })
+            .collect()
+    }
+
+    /// Decide whether the oldest time away waiting for this counts, returning
+    /// a description of what was done
+    ///
+    /// `keep` records it for the task focused before the user went away,
+    /// `assign:PATH` for the task with the given root-first path, separated
+    /// by `/`, and `discard` leaves it unrecorded.
+    pub fn resolve_away(&mut self, choice: &str) -> Result<String, String> {
+        record::call(&Call::ResolveAway {
+            choice: choice.to_owned(),
+        });
+        let pending = match self.pending_away.first() {
+            Some(pending) => pending.clone(),
+            None => {
+                self.flash(FlashKind::Warn, 1);
+                return Err("nothing to resolve".into());
+            }
+        };
+        let path: Option<Vec<String>> = match choice {
+            "keep" => Some(pending.path.clone()),
+            "discard" => None,
+            _ => match choice.strip_prefix("assign:") {
+                Some(path) => Some(
+                    path.split('/')
+                        .map(str::trim)
+                        .filter(|component| !component.is_empty())
+                        .rev()
+                        .map(String::from)
+                        .collect(),
+                ),
+                None => {
+                    self.flash(FlashKind::Warn, 1);
+                    return Err(format!(
+                        "unknown choice \"{}\"; expected keep, discard or assign:PATH",
+                        choice
+                    ));
+                }
+            },
+        };
+        if path.as_ref().is_some_and(Vec::is_empty) {
+            self.flash(FlashKind::Warn, 1);
+            return Err("no task to record the time for".into());
+        }
+        self.pending_away.remove(0);
+
+        let away_s = pending.to_s - pending.from_s;
+        let description = match path {
+            Some(path) => {
+                let duration = std::time::Duration::from_secs(away_s);
+                if let Some(block) = self.local_block_mut(pending.block_start_s) {
+                    block
+                        .away
+                        .retain(|&away| away != (pending.from_s, pending.to_s));
+                    block.tasks.add_time_path(path.clone(), duration);
+                }
+                self.record_task_time(&path, duration);
+                let path_str: Vec<_> = path.iter().rev().map(String::as_str).collect();
+                format!("recorded {}s away for \"{}\"", away_s, path_str.join(" / "))
+            }
+            None => format!("discarded {}s away", away_s),
+        };
+        self.log(&description);
+        Ok(description)
+    }
+
+    /// Record the active window, as sampled at time `now`, for task-tracking purposes
+    ///
+    /// Adds the duration that this window has been active (`now` minus the
+    /// last time this function was called) to every log. If any of the
+    /// applications in `microphone` (which is only queried if `tracks_microphone`)
+    /// are using the microphone, the time is recorded as a call instead.
+    pub fn record_current_window(
+        &mut self,
+        win: &Window,
+        microphone: &[String],
+        now: std::time::Instant,
+    ) {
+        if self.monitor_off {
+            self.monitor_off = false;
+            self.log("monitors on; recording tasks again");
+            if let Some(block_start_s) = self.away_block_s.take() {
+                let away = self
+                    .local_block_mut(block_start_s)
+                    .and_then(|block| block.away.last().copied());
+                if let Some((from_s, to_s)) = away {
+                    self.hold_away(block_start_s, from_s, to_s);
+                }
+            }
+        }
+        let call_path = self
+            .microphone
+            .as_ref()
+            .and_then(|config| config.call_path(microphone));
+        // Automatically track meetings, if configured to, including those
+        // visible full-screen on another monitor and calls
+        let meeting = call_path.is_some()
+            || std::iter::once(win)
+                .chain(&win.fullscreen)
+                .any(|win| task::is_meeting(&win.title));
+        if self.meeting_auto_track && meeting {
+            self.last_meeting_seen = now;
+            if self.state == State::Idle {
+                self.log("meeting window focused; started tracking session");
+                self.state = State::Tracking {
+                    label: "meeting".into(),
+                    start_time: now,
+                };
+                self.emit(Event::TrackingStarted);
+            }
+        }
+        if let State::Tracking { .. } = self.state {
+            let since_meeting = now.saturating_duration_since(self.last_meeting_seen);
+            if since_meeting > crate::MEETING_GRACE_DURATION {
+                self.log("no meeting window focused; ended tracking session");
+                self.emit(Event::TrackingEnded);
+                self.state = State::Idle;
+            }
+        }
+        // Only record things if we are currently in a block or session...
+        if let State::InBlock { .. } | State::Tracking { .. } = self.state {
+            let mut duration = now.saturating_duration_since(self.last_task_report);
+            // Give a share of the time to other visible full-screen windows,
+            // if configured to, and note them in the block in any case
+            let mut others = vec![];
+            if let Some(config) = self.fullscreen_windows {
+                for other in &win.fullscreen {
+                    others.push(self.window_path(other));
+                }
+                if let Some(ref mut block) = self.current_block {
+                    for path in &others {
+                        block.unfocused.add_time_path(path.clone(), duration);
+                    }
+                }
+                if !others.is_empty() {
+                    let share = duration * u32::from(config.weight_pcnt.min(100)) / 100;
+                    duration -= share;
+                    let share = share / others.len() as u32;
+                    for path in &others {
+                        if let Some(ref mut block) = self.current_block {
+                            block.tasks.add_time_path(path.clone(), share);
+                        }
+                        self.record_task_time(path, share);
+                    }
+                }
+            }
+
+            let path = call_path.unwrap_or_else(|| self.window_path(win));
+            let mut distracted = false;
+            let mut over_budget = None;
+            if let Some(ref mut block) = self.current_block {
+                block.record_focus(path.clone(), duration);
+                if self.blacklist.iter().any(|re| re.is_match(&win.title)) {
+                    distracted = true;
+                    block.distracted += duration;
+                    over_budget = self
+                        .distraction_budget
+                        .filter(|budget| block.distracted.as_secs() > budget.limit_s);
+                }
+            }
+            if distracted {
+                self.pulse_warn();
+            }
+            if let Some(budget) = over_budget {
+                self.log(&format!(
+                    "distraction budget of {}s exceeded",
+                    budget.limit_s
+                ));
+                // The distraction budget applies even to strict blocks
+                self.strict = false;
+                match budget.action {
+                    DistractionAction::Pause => self.toggle_pause(),
+                    // There is nobody to confirm the cancellation, so force it
+                    DistractionAction::Cancel => {
+                        let _ = self.cancel(true);
+                    }
+                }
+            }
+            self.record_task_time(&path, duration);
+            self.last_path = path;
+        } else if let Some(name) = self.background_log.clone() {
+            // ...or, if configured to, outside of blocks, to the background log only
+            if let State::Idle | State::InBreak { .. } | State::InCooldown { .. } = self.state {
+                let duration = now.saturating_duration_since(self.last_task_report);
+                let path = call_path.unwrap_or_else(|| self.window_path(win));
+                self.add_to_task_log(&name, &path, duration);
+            }
+        }
+        // ..but update last task report time regardless
+        self.last_task_report = now;
+    }
+
+    /// Add time spent on a task, given as a reversed path, to the metrics and
+    /// every task log, and check it against its budget
+    fn record_task_time(&mut self, path: &[String], duration: std::time::Duration) {
+        self.metrics.record_focus(path, duration);
+        let names: Vec<String> = self
+            .task_logs
+            .keys()
+            .filter(|name| Some(*name) != self.background_log.as_ref())
+            .cloned()
+            .collect();
+        for name in names {
+            self.add_to_task_log(&name, path, duration);
+        }
+        self.check_task_budgets(path);
+    }
+
+    /// Add time spent on a task, given as a reversed path, to a single task
+    /// log, creating it if necessary
+    fn add_to_task_log(&mut self, name: &str, path: &[String], duration: std::time::Duration) {
+        if let Some(filter) = self.task_log_filters.get(name) {
+            if !filter.matches(path) {
+                return;
+            }
+        }
+        let now_s = history::unix_now();
+        let config = self.task_buckets;
+        self.task_logs
+            .entry(name.to_owned())
+            .or_insert_with(Task::new_root)
+            .add_time_path(path.to_vec(), duration);
+        let buckets = self.task_log_buckets.entry(name.to_owned()).or_default();
+        if buckets.add_time_path(now_s, config.bucket_s, path.to_vec(), duration) {
+            let before_s = now_s.saturating_sub(config.compact_after_s);
+            buckets.compact(before_s, config.compacted_bucket_s);
+            buckets.forget(now_s.saturating_sub(config.keep_s));
+            // Fold small tasks together once per bucket, rather than every tick
+            if let Some(retention) = self.task_log_retention {
+                let folded = self.fold_task_log(name, retention);
+                if folded > 0 {
+                    self.log(&format!(
+                        "folded {} small tasks in task log {}",
+                        folded, name
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Output the most recent block log
+    pub fn block_log(&mut self) -> String {
+        self.block_log.clone()
+    }
+
+    /// Whether there is a task log with this name
+    pub fn has_task_log(&self, name: &str) -> bool {
+        self.task_logs.contains_key(name)
+    }
+
+    /// Create a new task log. This will overwrite any existing log with this name!
+    pub fn task_log_add(&mut self, name: String) {
+        record::call(&Call::TaskLogAdd { name: name.clone() });
+        self.add_task_log(name, None);
+    }
+
+    /// Create a new task log which only records the tasks allowed by a filter
+    ///
+    /// This will overwrite any existing log with this name!
+    pub fn task_log_add_filtered(&mut self, name: String, filter: Option<task::Filter>) {
+        // Filters are made with at most one regex of each kind
+        let regex = |regexes: Option<&Vec<Regex>>| {
+            regexes
+                .and_then(|regexes| regexes.first())
+                .map_or(String::new(), |re| re.as_str().to_owned())
+        };
+        record::call(&Call::TaskLogAddFiltered {
+            name: name.clone(),
+            include: regex(filter.as_ref().map(|filter| &filter.include)),
+            exclude: regex(filter.as_ref().map(|filter| &filter.exclude)),
+        });
+        self.add_task_log(name, filter);
+    }
+
+    /// Create a new task log, with an optional filter, without recording the call
+    fn add_task_log(&mut self, name: String, filter: Option<task::Filter>) {
+        self.log(&format!("added/cleared task log {}", name));
+        let buckets = self.task_log_buckets.remove(&name);
+        let previous_filter = match filter {
+            Some(filter) => self.task_log_filters.insert(name.clone(), filter),
+            None => self.task_log_filters.remove(&name),
+        };
+        if let Some(previous) = self.task_logs.insert(name.clone(), Task::new_root()) {
+            self.push_undo(Undo::TaskLog {
+                name,
+                previous,
+                buckets,
+                filter: previous_filter,
+            });
+        }
+    }
+
+    /// Deletes a task log
+    pub fn task_log_remove(&mut self, name: &str) {
+        record::call(&Call::TaskLogRemove {
+            name: name.to_owned(),
+        });
+        self.log(&format!("cleared task log {}", name));
+        let buckets = self.task_log_buckets.remove(name);
+        let filter = self.task_log_filters.remove(name);
+        if let Some(previous) = self.task_logs.remove(name) {
+            self.push_undo(Undo::TaskLog {
+                name: name.to_owned(),
+                previous,
+                buckets,
+                filter,
+            });
+        }
+    }
+
+    /// Dumps a task log
+    pub fn task_log_dump(&mut self, name: &str) -> String {
+        self.log(&format!("output task log {}", name));
+        if let Some(log) = self.merged_task_log(name) {
+            log.to_string_with_marks(&self.budget_marks())
+        } else {
+            format!("[log {} not found]", name)
+        }
+    }
+
+    /// Fold small tasks in a task log which have not had time recorded against
+    /// them lately into "(other)", using the configured thresholds or defaults
+    pub fn task_log_compact(&mut self, name: &str) {
+        record::call(&Call::TaskLogCompact {
+            name: name.to_owned(),
+        });
+        let previous = match self.task_logs.get(name) {
+            Some(log) => log.clone(),
+            None => {
+                self.flash(FlashKind::Warn, 1);
+                return;
+            }
+        };
+        let retention = self.task_log_retention.unwrap_or_default();
+        let folded = self.fold_task_log(name, retention);
+        self.log(&format!(
+            "folded {} small tasks in task log {}",
+            folded, name
+        ));
+        if folded > 0 {
+            self.push_undo(Undo::TaskLog {
+                name: name.to_owned(),
+                previous,
+                buckets: None,
+                filter: None,
+            });
+        }
+    }
+
+    /// Fold small tasks in a task log into "(other)", returning how many were folded
+    fn fold_task_log(&mut self, name: &str, retention: TaskLogRetention) -> usize {
+        let now_s = history::unix_now();
+        let recent = self
+            .task_log_buckets
+            .get(name)
+            .map(|buckets| buckets.between(now_s.saturating_sub(retention.unused_s), now_s + 1));
+        match self.task_logs.get_mut(name) {
+            Some(log) => {
+                let min = std::time::Duration::from_secs(retention.min_s);
+                log.fold_small_leaves(min, recent.as_ref())
+            }
+            None => 0,
+        }
+    }
+
+    /// Dumps the part of a task log recorded between two times, given in
+    /// seconds since the UNIX epoch
+    ///
+    /// Time is recorded in buckets, so this includes all of any bucket which
+    /// overlaps the interval. Time added by hand is not included.
+    pub fn task_log_between(&mut self, name: &str, start_s: u64, end_s: u64) -> String {
+        self.log(&format!(
+            "output task log {} between {} and {}",
+            name, start_s, end_s
+        ));
+        if !self.task_logs.contains_key(name) {
+            return format!("[log {} not found]", name);
+        }
+        match self.task_log_buckets.get(name) {
+            Some(buckets) => buckets.between(start_s, end_s).to_string(),
+            None => Task::new_root().to_string(),
+        }
+    }
+
+    /// Output a task log as a markdown list
+    pub fn task_log_markdown(&mut self, name: &str) -> String {
+        self.log(&format!("output task log {} as markdown", name));
+        if let Some(log) = self.merged_task_log(name) {
+            log.to_markdown_with_marks(name, &self.budget_marks())
+        } else {
+            format!("[log {} not found]", name)
+        }
+    }
+
+    /// Output a task log as a Graphviz DOT graph
+    pub fn task_log_dot(&mut self, name: &str) -> String {
+        self.log(&format!("output task log {} as DOT", name));
+        if let Some(log) = self.merged_task_log(name) {
+            log.to_dot_with_marks(name, &self.budget_marks())
+        } else {
+            format!("[log {} not found]", name)
+        }
+    }
+
+    /// Notes to add to tasks which are over their weekly budgets
+    fn budget_marks(&self) -> Vec<(Vec<String>, String)> {
+        self.task_budgets
+            .iter()
+            .filter(|budget| self.weekly_usage(&budget.path).as_secs() > budget.limit_s)
+            .map(|budget| (budget.path.clone(), "(over weekly budget)".to_owned()))
+            .collect()
+    }
+
+    /// A copy of a task log, to be written to a file, e.g. for archival or
+    /// moving between machines
+    ///
+    /// The file is written by the caller with `Task::write_file`, so that the
+    /// server is not locked meanwhile, and the result passed to
+    /// `task_log_exported`.
+    pub fn task_log_export(&mut self, name: &str) -> Result<Task, String> {
+        match self.task_logs.get(name) {
+            Some(log) => Ok(log.clone()),
+            None => {
+                self.flash(FlashKind::Warn, 1);
+                Err(format!("no task log named {}", name))
+            }
+        }
+    }
+
+    /// Note the result of writing a task log to a file, returning any error
+    pub fn task_log_exported(
+        &mut self,
+        name: &str,
+        path: &std::path::Path,
+        result: std::io::Result<()>,
+    ) -> Result<(), String> {
+        match result {
+            Ok(()) => {
+                self.log(&format!("exported task log {} to {}", name, path.display()));
+                Ok(())
+            }
+            Err(e) => {
+                let message = format!("exporting task log {} to {}: {}", name, path.display(), e);
+                self.signal_error(&message);
+                Err(message)
+            }
+        }
+    }
+
+    /// Note that blocks recorded by another tool were added to the history store
+    pub fn history_imported(&mut self, description: &str) {
+        record::call(&Call::HistoryImported {
+            description: description.to_owned(),
+        });
+        self.log(description);
+        self.prior_day_streak = None;
+    }
+
+    /// Replace a task log with one read from a file, given the result of
+    /// reading it with `Task::read_file`, returning any error
+    ///
+    /// The file is read by the caller, so that the server is not locked
+    /// meanwhile. This will overwrite any existing log with this name!
+    pub fn task_log_import(
+        &mut self,
+        name: String,
+        path: &std::path::Path,
+        result: std::io::Result<Task>,
+    ) -> Result<(), String> {
+        match result {
+            Ok(log) => {
+                // The log itself is recorded, since the file may be gone by
+                // the time the recording is replayed
+                record::call(&Call::TaskLogImport {
+                    name: name.clone(),
+                    path: path.to_owned(),
+                    log: Box::new(log.clone()),
+                });
+                self.task_log_imported(name, path, log);
+                Ok(())
+            }
+            Err(e) => {
+                let message = format!("importing task log {} from {}: {}", name, path.display(), e);
+                self.signal_error(&message);
+                Err(message)
+            }
+        }
+    }
+
+    /// Replace a task log with one read from a file, without recording the call
+    pub(crate) fn task_log_imported(&mut self, name: String, path: &std::path::Path, log: Task) {
+        self.log(&format!(
+            "imported task log {} from {}",
+            name,
+            path.display()
+        ));
+        // The imported log has no record of when its time was spent, but
+        // keeps any filter, so undoing must not remove that
+        let buckets = self.task_log_buckets.remove(&name);
+        let filter = self.task_log_filters.get(&name).cloned();
+        if let Some(previous) = self.task_logs.insert(name.clone(), log) {
+            self.push_undo(Undo::TaskLog {
+                name,
+                previous,
+                buckets,
+                filter,
+            });
+        }
+    }
+
+    /// Manually add time to a task in a task log
+    ///
+    /// The path is given root-first, e.g. `["Github", "Issue"]`.
+    pub fn task_log_add_time(&mut self, name: &str, path: Vec<String>, duration_s: u64) {
+        record::call(&Call::TaskLogAddTime {
+            name: name.to_owned(),
+            path: path.clone(),
+            time_s: duration_s,
+        });
+        let path_str = path.join(" / ");
+        if let Some(log) = self.task_logs.get_mut(name) {
+            let duration = std::time::Duration::from_secs(duration_s);
+            log.add_time_path(path.into_iter().rev().collect(), duration);
+            self.log(&format!(
+                "manually added {}s to \"{}\" in task log {}",
+                duration_s, path_str, name
+            ));
+        } else {
+            self.flash(FlashKind::Warn, 1);
+        }
+    }
+
+    /// Manually move time from one task to another in a task log
+    ///
+    /// Paths are given root-first. If the source task does not have enough time
+    /// recorded against it, nothing is moved and a warning is flashed.
+    pub fn task_log_move_time(
+        &mut self,
+        name: &str,
+        from_path: Vec<String>,
+        to_path: Vec<String>,
+        duration_s: u64,
+    ) {
+        record::call(&Call::TaskLogMoveTime {
+            name: name.to_owned(),
+            from_path: from_path.clone(),
+            to_path: to_path.clone(),
+            time_s: duration_s,
+        });
+        let from_str = from_path.join(" / ");
+        let to_str = to_path.join(" / ");
+        let duration = std::time::Duration::from_secs(duration_s);
+        let moved = match self.task_logs.get_mut(name) {
+            Some(log) => log.move_time_path(
+                from_path.into_iter().rev().collect(),
+                to_path.into_iter().rev().collect(),
+                duration,
+            ),
+            None => false,
+        };
+        if moved {
+            self.log(&format!(
+                "manually moved {}s from \"{}\" to \"{}\" in task log {}",
+                duration_s, from_str, to_str, name
+            ));
+        } else {
+            self.flash(FlashKind::Warn, 1);
+        }
+    }
+
+    /// Whether it is currently outside the configured working hours
+    ///
+    /// Since this requires running `date`, the answer is cached for a minute
+    /// unless `refresh` is set.
+    fn is_off_hours(&mut self, refresh: bool) -> bool {
+        let work_hours = match self.work_hours {
+            Some(ref work_hours) => work_hours,
+            None => return false,
+        };
+        let now = crate::clock::now();
+        if let Some((checked, off_hours)) = self.off_hours {
+            if !refresh && now < checked + std::time::Duration::from_secs(60) {
+                return off_hours;
+            }
+        }
+        let off_hours = match history::local_weekday_minute() {
+            Some((weekday, minute)) => !work_hours.contains(weekday, minute),
+            None => false,
+        };
+        self.off_hours = Some((now, off_hours));
+        off_hours
+    }
+
+    /// Finish a tracking session or break, before starting something else
+    fn end_idle_state(&mut self) {
+        match self.state {
+            State::Tracking { .. } => self.emit(Event::TrackingEnded),
+            State::InBreak { .. } => {
+                self.log("cut break short");
+                self.flash(FlashKind::Warn, 1);
+                self.emit(Event::BreakEnded);
+            }
+            _ => {}
+        }
+    }
+
+    /// Move on from a break, to the lockout if there is one and otherwise to
+    /// idle (or the next block of a routine)
+    fn end_break(&mut self, now: std::time::Instant) {
+        let lockout_s = self
+            .break_config
+            .as_ref()
+            .map_or(0, |config| config.lockout_s);
+        if lockout_s > 0 {
+            let duration = std::time::Duration::from_secs(lockout_s);
+            self.state = State::InCooldown {
+                duration,
+                end_time: now + duration,
+            };
+        } else {
+            self.state = State::Idle;
+            self.continue_routine();
+        }
+    }
+
+    /// (Attempt to) start a new block, optionally labelled
+    pub fn start_block(&mut self, duration_s: u64, label: Option<String>) {
+        record::call(&Call::StartBlock {
+            time_s: duration_s,
+            label: label.clone(),
+        });
+        self.start_block_from(duration_s, label, None)
+    }
+
+    /// (Attempt to) start a new block, noting the preset it is from, if any
+    fn start_block_from(&mut self, duration_s: u64, label: Option<String>, preset: Option<&str>) {
+        self.block_log = String::new();
+        self.log("started block");
+        match self.state {
+            State::Idle | State::Tracking { .. } | State::InBreak { .. } => {
+                if let Some(reason) = self.daily_limit_reached() {
+                    // Force a break, rather than just refusing
+                    self.log(&format!("refused block: {}", reason));
+                    self.flash(FlashKind::Error, 1);
+                    self.end_idle_state();
+                    let limit = self.daily_limit.expect("limit was reached");
+                    let duration = std::time::Duration::from_secs(limit.cooldown_s);
+                    self.state = State::InCooldown {
+                        duration,
+                        end_time: crate::clock::now() + duration,
+                    };
+                    return;
+                }
+                if self.is_off_hours(true) {
+                    if self.work_hours.as_ref().is_some_and(|hours| hours.strict) {
+                        self.log("refused block outside working hours");
+                        self.flash(FlashKind::Error, 1);
+                        return;
+                    }
+                    self.log("block started outside working hours");
+                    self.flash(FlashKind::Warn, 1);
+                }
+                if duration_s > self.max_block_until_next_event() {
+                    let action = self.calendar.as_ref().map(|cal| cal.action);
+                    if action == Some(calendar::Action::Refuse) {
+                        self.log("refused block which would overlap a calendar event");
+                        self.flash(FlashKind::Error, 1);
+                        return;
+                    }
+                    self.log("block will overlap a calendar event");
+                    self.flash(FlashKind::Warn, 1);
+                }
+                self.end_idle_state();
+                let duration = std::time::Duration::from_secs(duration_s);
+                self.current_block = Some(history::Block::start(duration_s, label.clone()));
+                self.wrapped_up = false;
+                self.strict = false;
+                self.lock_screen = false;
+                self.preset = preset.map(str::to_owned);
+                self.state = State::InBlock {
+                    duration,
+                    end_time: crate::clock::now() + duration,
+                    label,
+                };
+                self.emit(Event::BlockStarted);
+            }
+            State::Paused { .. } | State::InBlock { .. } => {
+                // refuse te start a block when one is running; first cancel the running one
+                self.flash(FlashKind::Warn, 1);
+            }
+            State::InCooldown { .. } => {
+                // refuse te start a block during cooldown; cooldown cannot be cancelled.
+                self.flash(FlashKind::Error, 1);
+            }
+        }
+    }
+
+    /// Start a block as described by a configured preset
+    pub fn start_preset(&mut self, name: &str) {
+        record::call(&Call::StartPreset {
+            name: name.to_owned(),
+        });
+        let preset = match self.presets.get(name) {
+            Some(preset) => preset.clone(),
+            None => {
+                self.flash(FlashKind::Error, 1);
+                return;
+            }
+        };
+        self.start_block_from(preset.duration_s, preset.label, Some(name));
+        if let State::InBlock { .. } = self.state {
+            self.log(&format!("started preset {}", name));
+            self.strict = preset.strict;
+            self.lock_screen = preset.lock_screen;
+        }
+    }
+
+    /// Refuse to pause or cancel a block from a strict preset, returning
+    /// whether we did so
+    fn refuse_if_strict(&mut self, action: &str) -> bool {
+        if self.strict && matches!(self.state, State::InBlock { .. }) {
+            self.log(&format!("refused to {} strict block", action));
+            self.flash(FlashKind::Error, 1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Start a configured routine, whose first block starts immediately
+    pub fn start_routine(&mut self, name: &str) {
+        record::call(&Call::StartRoutine {
+            name: name.to_owned(),
+        });
+        let work_s = match self.routines.get(name) {
+            Some(routine) => routine.work_s,
+            None => {
+                self.flash(FlashKind::Error, 1);
+                return;
+            }
+        };
+        self.start_block_from(work_s, Some(name.to_owned()), None);
+        if let State::InBlock { .. } = self.state {
+            self.log(&format!("started routine {}", name));
+            self.routine = Some((name.to_owned(), 1));
+        }
+    }
+
+    /// The current routine, if any, as its name, the current phase ("work"
+    /// or "rest"), the current round, and the number of rounds (0 if unlimited)
+    pub fn routine_status(&self) -> Option<(String, &'static str, u32, u32)> {
+        let (name, round) = self.routine.as_ref()?;
+        let rounds = self.routines.get(name).and_then(|routine| routine.rounds);
+        let phase = match self.state {
+            State::InBreak { .. } | State::InCooldown { .. } => "rest",
+            _ => "work",
+        };
+        Some((name.clone(), phase, *round, rounds.unwrap_or(0)))
+    }
+
+    /// Start the next block of the current routine, or finish the routine
+    fn continue_routine(&mut self) {
+        let (name, round) = match self.routine.take() {
+            Some(routine) => routine,
+            None => return,
+        };
+        let routine = match self.routines.get(&name) {
+            Some(routine) => routine.clone(),
+            None => return,
+        };
+        if routine.rounds.is_some_and(|rounds| round >= rounds) {
+            self.log(&format!("finished routine {}", name));
+            return;
+        }
+        self.start_block_from(routine.work_s, Some(name.clone()), None);
+        if let State::InBlock { .. } = self.state {
+            self.routine = Some((name, round + 1));
+        }
+    }
+
+    /// Attempt to cancel a currently-running block
+    pub fn cancel_block(&mut self) {
+        // Any need for confirmation is signalled by flashing
+        let _ = self.try_cancel_block(false);
+    }
+
+    /// Attempt to cancel a currently-running block, returning an error if the
+    /// cancellation must be confirmed by calling this again
+    ///
+    /// If `force` is set, no confirmation is needed.
+    pub fn try_cancel_block(&mut self, force: bool) -> Result<(), String> {
+        record::call(&Call::CancelBlock { force });
+        self.cancel(force)
+    }
+
+    /// Attempt to cancel a currently-running block, without recording the call
+    fn cancel(&mut self, force: bool) -> Result<(), String> {
+        // A block whose time is up was completed, even if no tick has
+        // noticed yet, so end it rather than cancelling it
+        self.update();
+        if self.refuse_if_strict("cancel") {
+            return Ok(());
+        }
+        if let (State::InBlock { end_time, .. }, Some(confirm_s), false) =
+            (&self.state, self.confirm_cancel_s, force)
+        {
+            let now = crate::clock::now();
+            let confirmed = self
+                .cancel_requested
+                .is_some_and(|requested| now < requested + CANCEL_CONFIRM_DURATION);
+            if end_time.saturating_duration_since(now).as_secs() < confirm_s && !confirmed {
+                self.log("cancel requested near the end of the block; awaiting confirmation");
+                self.cancel_requested = Some(now);
+                self.flash(FlashKind::Warn, 1);
+                return Err(format!(
+                    "block nearly done; cancel again within {}s to confirm",
+                    CANCEL_CONFIRM_DURATION.as_secs()
+                ));
+            }
+        }
+        self.cancel_requested = None;
+        self.log("canceled block");
+        match self.state {
+            State::InBlock {
+                duration,
+                end_time,
+                ref label,
+            } => {
+                // Undoing brings the block back paused, so that the time it
+                // spent cancelled counts as a pause rather than as focus
+                let paused = State::Paused {
+                    total_duration: duration,
+                    remaining_duration: end_time.saturating_duration_since(crate::clock::now()),
+                    label: label.clone(),
+                };
+                let block = self.current_block.clone().map(|mut block| {
+                    block.pause();
+                    Box::new(block)
+                });
+                self.push_undo(Undo::CancelBlock {
+                    state: paused,
+                    block,
+                    routine: self.routine.clone(),
+                    wrapped_up: self.wrapped_up,
+                });
+                let streak = self.block_streak();
+                if streak > 0 {
+                    self.record_streak_break(&format!("block streak of {} broken", streak));
+                }
+                self.emit(Event::BlockCancelled);
+                self.finish_block(history::Outcome::Cancelled);
+                self.state = State::Idle;
+                self.routine = None;
+            }
+            State::Tracking { .. } => {
+                self.emit(Event::TrackingEnded);
+                self.state = State::Idle;
+            }
+            // Cancelling a break ends it early, and stops any routine
+            State::InBreak { .. } => {
+                self.log("ended break early");
+                self.routine = None;
+                self.emit(Event::BreakEnded);
+                self.end_break(crate::clock::now());
+            }
+            // Cooldown cannot be cancelled, but a routine can be stopped during it
+            State::InCooldown { .. } if self.routine.is_some() => {
+                self.log("stopped routine");
+                self.routine = None;
+            }
+            State::InCooldown { .. } => self.flash(FlashKind::Error, 1),
+            _ => self.flash(FlashKind::Warn, 1),
+        }
+        Ok(())
+    }
+
+    /// Attempt to pause a currently-running block
+    pub fn pause_block(&mut self) {
+        record::call(&Call::PauseBlock);
+        self.toggle_pause();
+    }
+
+    /// Pause or unpause a block, without recording the call
+    fn toggle_pause(&mut self) {
+        // As with cancelling, a block whose time is up cannot be paused
+        self.update();
+        if self.refuse_if_strict("pause") {
+            return;
+        }
+        match self.state {
+            State::InBlock {
+                duration,
+                end_time,
+                ref mut label,
+            } => {
+                let label = label.take();
+                self.log("paused block");
+                if let Some(ref mut block) = self.current_block {
+                    block.pause();
+                }
+                self.state = State::Paused {
+                    total_duration: duration,
+                    remaining_duration: end_time.saturating_duration_since(crate::clock::now()),
+                    label,
+                };
+                self.emit(Event::BlockPaused);
+            }
+            State::Paused {
+                total_duration,
+                remaining_duration,
+                ref mut label,
+            } => {
+                let label = label.take();
+                self.log("unpaused block");
+                if let Some(ref mut block) = self.current_block {
+                    block.unpause();
+                }
+                self.state = State::InBlock {
+                    duration: total_duration,
+                    end_time: crate::clock::now() + remaining_duration,
+                    label,
+                };
+                self.emit(Event::BlockUnpaused);
+            }
+            _ => self.flash(FlashKind::Warn, 1),
+        }
+    }
+
+    /// Start (or restart) a named timer, which runs alongside any block
+    pub fn start_timer(&mut self, name: String, duration_s: u64) {
+        record::call(&Call::StartTimer {
+            name: name.clone(),
+            time_s: duration_s,
+        });
+        self.log(&format!("started timer {} ({}s)", name, duration_s));
+        let duration = std::time::Duration::from_secs(duration_s);
+        let timer = Timer::start(duration, crate::clock::now());
+        self.timers.insert(name, timer);
+    }
+
+    /// Pause a named timer, or resume it if it is paused
+    pub fn pause_timer(&mut self, name: &str) {
+        record::call(&Call::PauseTimer {
+            name: name.to_owned(),
+        });
+        match self.timers.get_mut(name) {
+            Some(timer) => timer.toggle_pause(crate::clock::now()),
+            None => self.flash(FlashKind::Warn, 1),
+        }
+    }
+
+    /// Cancel a named timer
+    pub fn cancel_timer(&mut self, name: &str) {
+        record::call(&Call::CancelTimer {
+            name: name.to_owned(),
+        });
+        if self.timers.remove(name).is_some() {
+            self.log(&format!("cancelled timer {}", name));
+        } else {
+            self.flash(FlashKind::Warn, 1);
+        }
+    }
+
+    /// Move on to the next break suggestion, if it is time to
+    fn update_suggestion(&mut self, now: std::time::Instant) {
+        let resting = matches!(self.state, State::InBreak { .. } | State::InCooldown { .. });
+        let config = match self.break_suggestions {
+            Some(ref config) if resting => config,
+            _ => {
+                self.suggestion_shown = None;
+                return;
+            }
+        };
+        let mut new_suggestion = None;
+        if self
+            .suggestion_shown
+            .is_none_or(|shown| now >= shown + config.interval())
+        {
+            self.suggestion = config.next(self.suggestion);
+            self.suggestion_shown = Some(now);
+            new_suggestion = self.suggestion.and_then(|index| config.get(index));
+        }
+        if let Some(text) = new_suggestion.map(str::to_owned) {
+            self.emit_with_detail(Event::BreakSuggestion, Some(text));
+        }
+    }
+
+    /// Render the current break suggestion as a status line segment
+    fn render_suggestion(&self) -> String {
+        let config = match self.break_suggestions {
+            Some(ref config) if self.suggestion_shown.is_some() => config,
+            _ => return String::new(),
+        };
+        match self.suggestion.and_then(|index| config.get(index)) {
+            Some(text) if config.status_line() => {
+                format!(" <fc={}>{}</fc>", self.color_idle, escape_xmobar(text))
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Advance all named timers, and drop those finished long enough ago
+    fn update_timers(&mut self, now: std::time::Instant) {
+        let mut finished = vec![];
+        for (name, timer) in &mut self.timers {
+            if timer.tick(now) {
+                finished.push(name.clone());
+            }
+        }
+        for name in finished {
+            self.log(&format!("timer {} finished", name));
+            self.flash(FlashKind::Warn, 2);
+            self.emit_with_detail(Event::TimerFinished, Some(name));
+        }
+        self.timers.retain(|_, timer| !timer.is_expired(now));
+    }
+
+    /// Render all named timers as status line segments
+    fn render_timers(&self, now: std::time::Instant) -> String {
+        let mut output = String::new();
+        for (name, timer) in &self.timers {
+            output += &format!(
+                " <fc={}>{} {}</fc>",
+                self.color_idle,
+                escape_xmobar(name),
+                timer.display(now)
+            );
+        }
+        output
+    }
+
+    /// A snapshot of the current state, for reporting to clients
+    pub fn status(&self) -> Status {
+        let now = crate::clock::now();
+        let mut status = Status {
+            state: "idle",
+            remaining_s: None,
+            duration_s: None,
+            label: None,
+        };
+        match self.state {
+            State::Idle => {}
+            State::InBlock {
+                duration,
+                end_time,
+                ref label,
+            } => {
+                status.state = "block";
+                status.remaining_s = Some(end_time.saturating_duration_since(now).as_secs());
+                status.duration_s = Some(duration.as_secs());
+                status.label = label.clone();
+            }
+            State::Paused {
+                total_duration,
+                remaining_duration,
+                ref label,
+            } => {
+                status.state = "paused";
+                status.remaining_s = Some(remaining_duration.as_secs());
+                status.duration_s = Some(total_duration.as_secs());
+                status.label = label.clone();
+            }
+            State::InBreak { duration, end_time } => {
+                status.state = "break";
+                status.remaining_s = Some(end_time.saturating_duration_since(now).as_secs());
+                status.duration_s = Some(duration.as_secs());
+            }
+            State::InCooldown { duration, end_time } => {
+                status.state = "cooldown";
+                status.remaining_s = Some(end_time.saturating_duration_since(now).as_secs());
+                status.duration_s = Some(duration.as_secs());
+            }
+            State::Tracking { ref label, .. } => {
+                status.state = "tracking";
+                status.label = Some(label.clone());
+            }
+        }
+        status
+    }
+
+    /// When the status line next needs rendering, if nothing calls into the
+    /// server before then: when the time shown next changes, a flash blinks,
+    /// or a block, break, cooldown or timer ends
+    ///
+    /// While nothing is counting or flashing, this is `IDLE_TICK` from now,
+    /// to catch slower changes such as the start of working hours.
+    pub fn next_wakeup(&self) -> std::time::Instant {
+        let now = crate::clock::now();
+        let second = std::time::Duration::from_secs(1);
+        let mut next = now + IDLE_TICK;
+        let mut wake_at = |at: Option<std::time::Instant>| {
+            if let Some(at) = at {
+                next = next.min(at);
+            }
+        };
+        wake_at(
+            self.flash_warn_until
+                .and_then(|until| self.warn_flash.next_change(until, now)),
+        );
+        wake_at(
+            self.flash_error_until
+                .and_then(|until| self.error_flash.next_change(until, now)),
+        );
+        // Coalesced flashes are counted once flashing has calmed down
+        if self.flashes_coalesced > 0 {
+            let calm = [self.flash_warn_until, self.flash_error_until]
+                .into_iter()
+                .flatten()
+                .max();
+            wake_at(calm.map(|until| until + FLASH_COALESCE_DURATION));
+        }
+        for timer in self.timers.values() {
+            wake_at(timer.next_change(now));
+        }
+        match self.state {
+            State::Idle | State::Paused { .. } => {}
+            State::InBlock { end_time, .. }
+            | State::InBreak { end_time, .. }
+            | State::InCooldown { end_time, .. } => {
+                wake_at(Some(crate::clock::next_tick(end_time, now, second)))
+            }
+            State::Tracking { start_time, .. } => {
+                let elapsed_s = now.saturating_duration_since(start_time).as_secs();
+                wake_at(Some(start_time + second * (elapsed_s as u32 + 1)));
+            }
+        }
+        next
+    }
+
+    /// How long the cooldown after a block of the given length lasts
+    fn cooldown_after(&self, block: std::time::Duration) -> std::time::Duration {
+        // During a routine, rest for as long as it says instead
+        if let Some((ref name, _)) = self.routine {
+            if let Some(routine) = self.routines.get(name) {
+                return std::time::Duration::from_secs(routine.rest_s);
+            }
+        }
+        match self.proportional_cooldown {
+            Some(cooldown) => {
+                let cooldown_s = block.as_secs().saturating_mul(cooldown.percent) / 100;
+                let cooldown_s =
+                    cooldown_s.clamp(cooldown.min_s, cooldown.max_s.max(cooldown.min_s));
+                std::time::Duration::from_secs(cooldown_s)
+            }
+            None => crate::COOLDOWN_DURATION,
+        }
+    }
+
+    /// The configured progress indicator, with a leading space, or nothing
+    fn progress_indicator(
+        &self,
+        remaining: std::time::Duration,
+        total: std::time::Duration,
+    ) -> String {
+        match self.progress {
+            Some(ref progress) => format!(" {}", progress.render(remaining, total)),
+            None => String::new(),
+        }
+    }
+
+    /// The most-focused task of the current block, with a leading space, or
+    /// nothing if this isn't configured or no task has been recorded yet
+    fn top_task(&self) -> String {
+        let (width, block) = match (self.top_task_width, &self.current_block) {
+            (Some(width), Some(block)) => (width, block),
+            _ => return String::new(),
+        };
+        let name = match block.tasks.top_path().pop() {
+            Some(name) => name,
+            None => return String::new(),
+        };
+        let name = if name.chars().count() > width {
+            let mut truncated: String = name.chars().take(width.saturating_sub(1)).collect();
+            truncated.push('…');
+            truncated
+        } else {
+            name
+        };
+        format!(" {}", escape_xmobar(&name))
+    }
+
+    /// Advance the state machine: end blocks, breaks and cooldowns whose time
+    /// is up, give warnings, and advance timers and suggestions
+    ///
+    /// This is done by `xmobar_update`, so only needs calling directly if the
+    /// status line is not being rendered.
+    pub fn update(&mut self) {
+        let now = crate::clock::now();
+        self.check_clock(now);
+        match self.state {
+            State::InBlock {
+                end_time, duration, ..
+            } => {
+                if now > end_time {
+                    self.log("end block; start cooldown");
+                    self.emit(Event::BlockCompleted);
+                    if self.lock_screen {
+                        self.lock_screen = false;
+                        self.emit(Event::ScreenLocked);
+                    }
+                    self.finish_block(history::Outcome::Completed);
+                    let duration = self.cooldown_after(duration);
+                    if self.break_config.is_some() {
+                        self.state = State::InBreak {
+                            duration,
+                            end_time: now + duration,
+                        };
+                        self.emit(Event::BreakStarted);
+                    } else {
+                        self.state = State::InCooldown {
+                            duration,
+                            end_time: now + duration,
+                        };
+                    }
+                } else {
+                    let rem_s = (end_time - now).as_secs();
+                    let wrap_up = self.wrap_up.filter(|wrap_up| rem_s < wrap_up.remaining_s);
+                    if wrap_up.is_some() && !self.wrapped_up {
+                        self.wrapped_up = true;
+                        self.log("wrap-up warning");
+                        self.flash(FlashKind::Warn, 1);
+                        self.emit(Event::WrapUp);
+                    }
+                }
+            }
+            State::InBreak { end_time, .. } if now > end_time => {
+                self.log("end break");
+                self.emit(Event::BreakEnded);
+                self.end_break(now);
+            }
+            State::InCooldown { end_time, .. } if now > end_time => {
+                self.log("end cooldown");
+                self.emit(Event::CooldownEnded);
+                self.state = State::Idle;
+                self.continue_routine();
+            }
+            _ => {}
+        }
+        // Suggest something to do while resting
+        self.update_suggestion(now);
+        self.update_timers(now);
+        // Once flashing has calmed down, note how many flashes were dropped
+        let calm = [self.flash_warn_until, self.flash_error_until]
+            .into_iter()
+            .flatten()
+            .all(|until| now >= until + FLASH_COALESCE_DURATION);
+        if calm && self.flashes_coalesced > 0 {
+            let message = format!("dropped {} repeated flashes", self.flashes_coalesced);
+            self.record_diagnostic(&message);
+            self.flashes_coalesced = 0;
+        }
+    }
+
+    /// Compare how far the wall clock and the monotonic clock have moved since
+    /// the last update, noting any jump in the wall clock, e.g. because it was
+    /// set or the machine was suspended, against the current block
+    fn check_clock(&mut self, now: std::time::Instant) {
+        let system_now = crate::clock::system_now();
+        let (then, system_then) = match self.last_clock_check.replace((now, system_now)) {
+            Some(last) => last,
+            None => return,
+        };
+        let monotonic_s = now.saturating_duration_since(then).as_secs_f64();
+        let wall_s = match system_now.duration_since(system_then) {
+            Ok(forward) => forward.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        };
+        let jump_s = (wall_s - monotonic_s).round() as i64;
+        if jump_s.unsigned_abs() < CLOCK_JUMP_THRESHOLD.as_secs() {
+            return;
+        }
+        let message = format!("wall clock jumped by {}s", jump_s);
+        self.log(&message);
+        self.record_diagnostic(&message);
+        if let Some(ref mut block) = self.current_block {
+            block.note_clock_jump(jump_s);
+        }
+        // The machine may have been suspended mid-block, with the user away
+        if let (State::InBlock { .. }, Some(block)) = (&self.state, &self.current_block) {
+            if jump_s > 0 {
+                let now_s = history::unix_now();
+                self.hold_away(block.start_s, now_s - jump_s as u64, now_s);
+            }
+        }
+    }
+
+    /// The color the timer is currently shown in
+    fn foreground_color(&self, now: std::time::Instant) -> String {
+        match self.state {
+            State::Idle | State::Paused { .. } | State::Tracking { .. } => self.color_idle.clone(),
+            State::InBlock {
+                end_time, duration, ..
+            } => {
+                let rem_duration = end_time.saturating_duration_since(now);
+                match self.wrap_up {
+                    Some(wrap_up) if rem_duration.as_secs() < wrap_up.remaining_s => {
+                        crate::color::hex(wrap_up.color)
+                    }
+                    _ => crate::color::fade_between(
+                        self.color_block_end,
+                        self.color_block_start,
+                        rem_duration,
+                        duration,
+                        &self.block_fade,
+                    ),
+                }
+            }
+            State::InBreak { duration, end_time } => {
+                let (start, end, fade) = match self.break_config {
+                    Some(ref config) => (config.color_start, config.color_end, &config.fade),
+                    None => (
+                        default_color_break_start(),
+                        default_color_break_end(),
+                        &self.cooldown_fade,
+                    ),
+                };
+                let rem_duration = end_time.saturating_duration_since(now);
+                crate::color::fade_between(end, start, rem_duration, duration, fade)
+            }
+            State::InCooldown { duration, end_time } => crate::color::fade_between(
+                self.color_cooldown_end,
+                self.color_cooldown_start,
+                end_time.saturating_duration_since(now),
+                duration,
+                &self.cooldown_fade,
+            ),
+        }
+    }
+
+    /// Render the timer as an HTML fragment, with big colored digits, e.g.
+    /// for a streaming overlay
+    pub fn overlay(&self) -> String {
+        let now = crate::clock::now();
+        let status = self.status();
+        // Like the status line, tracking sessions show the time elapsed
+        let secs = match self.state {
+            State::Tracking { start_time, .. } => Some((now - start_time).as_secs()),
+            _ => status.remaining_s,
+        };
+        let text = match secs {
+            Some(secs) => format!("{:02}:{:02}", secs / 60, secs % 60),
+            None => "--".to_owned(),
+        };
+        let mut html = format!(
+            "<div class=\"pomotoshi {}\" style=\"color: {}\"><div class=\"time\">{}</div>",
+            status.state,
+            escape_html(&self.foreground_color(now)),
+            text,
+        );
+        if let Some(label) = status.label {
+            html += &format!("<div class=\"label\">{}</div>", escape_html(&label));
+        }
+        html += "</div>";
+        html
+    }
+
+    /// Advance the state machine, and render the status line
+    pub fn xmobar_update(&mut self) -> String {
+        self.update();
+        let now = crate::clock::now();
+        let mut bg_col = String::new();
+        // Flash a warning, if one is happening
+        if let Some(until) = self.flash_warn_until {
+            if self.warn_flash.is_lit(until, now) {
+                bg_col = format!(",{}", self.warn_flash.color);
+            }
+        }
+        // Flash an error, if one is happening
+        if let Some(until) = self.flash_error_until {
+            if self.error_flash.is_lit(until, now) {
+                bg_col = format!(",{}", self.error_flash.color);
+            }
+        }
+        // Actually display status
+        let color = self.foreground_color(now);
+        let mut output = match self.state {
+            State::Idle => {
+                let text = if self.is_off_hours(false) {
+                    "off hours"
+                } else {
+                    "--"
+                };
+                format!("<fc={}{}>{}</fc>", color, bg_col, text)
+            }
+            State::Paused {
+                total_duration,
+                remaining_duration,
+                ..
+            } => {
+                let rem = remaining_duration.as_secs();
+                format!(
+                    "<fc={}{}>{:02}:{:02}{}</fc>",
+                    color,
+                    bg_col,
+                    rem / 60,
+                    rem % 60,
+                    self.progress_indicator(remaining_duration, total_duration),
+                )
+            }
+            State::Tracking {
+                ref label,
+                start_time,
+            } => {
+                let elapsed = (now - start_time).as_secs();
+                format!(
+                    "<fc={}{}>{} {:02}:{:02}</fc>",
+                    color,
+                    bg_col,
+                    label,
+                    elapsed / 60,
+                    elapsed % 60
+                )
+            }
+            State::InBlock {
+                end_time, duration, ..
+            } => {
+                let rem_duration = end_time - now;
+                let rem_s = rem_duration.as_secs();
+                if rem_s < 10 && rem_duration.as_millis() % 2000 > 1750 {
+                    self.pulse_warn();
+                }
+                format!(
+                    "<fc={}{}>{:02}:{:02}{}{}</fc>",
+                    color,
+                    bg_col,
+                    rem_s / 60,
+                    rem_s % 60,
+                    self.progress_indicator(rem_duration, duration),
+                    self.top_task(),
+                )
+            }
+            State::InBreak { duration, end_time } => {
+                let rem_duration = end_time - now;
+                let rem_s = rem_duration.as_secs();
+                format!(
+                    "<fc={}{}>{:02}:{:02}{}</fc>",
+                    color,
+                    bg_col,
+                    rem_s / 60,
+                    rem_s % 60,
+                    self.progress_indicator(rem_duration, duration),
+                )
+            }
+            State::InCooldown { duration, end_time } => {
+                if bg_col.is_empty() {
+                    // by default, highlight cooldown visibly
+                    bg_col = format!(",{}", self.color_cooldown_background);
+                }
+
+                let rem_duration = end_time - now;
+                let rem_s = rem_duration.as_secs();
+                if rem_s < 10 && rem_duration.as_millis() % 2000 > 1750 {
+                    self.pulse_warn();
+                }
+                format!(
+                    "<fc={}{}>{:02}:{:02}{}</fc>",
+                    color,
+                    bg_col,
+                    rem_s / 60,
+                    rem_s % 60,
+                    self.progress_indicator(rem_duration, duration),
+                )
+            }
+        };
+        // Display the current break suggestion, and any named timers
+        output += &self.render_suggestion();
+        output += &self.render_timers(now);
+        // Prepend the icon for the current state, if there is one
+        if let Some(icon) = self.icons.get(self.status().state) {
+            output = format!("{} {}", escape_xmobar(icon), output);
+        }
+        // Display progress towards the daily goal, if there is one
+        if let Some(goal_s) = self.daily_goal_s {
+            let focus_h = self.focus_today_s() as f64 / 3600.0;
+            output += &format!(" [{:.1}/{}h]", focus_h, goal_s as f64 / 3600.0);
+        }
+        // Note any time away which is waiting to be resolved
+        if !self.pending_away.is_empty() {
+            let away_s: u64 = self
+                .pending_away
+                .iter()
+                .map(|away| away.to_s - away.from_s)
+                .sum();
+            output += &format!(" [away {}m?]", away_s / 60);
+        }
+        // Display the block height, if configured to
+        if self.show_height {
+            output += &format!(" #{}", self.height);
+        }
+        // Display streaks, if configured to
+        if self.streak_badge {
+            let (days, blocks) = self.streaks();
+            output += &format!(" ({}d {}b)", days, blocks);
+        }
+        output
+    }
+}
+
+/// Escape text for inclusion in HTML, including in attribute values
+/// Deserialize the daily goal, refusing a goal of zero, which every day
+/// would meet
+fn deserialize_daily_goal_s<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<Option<u64>, D::Error> {
+    match Option::<u64>::deserialize(d)? {
+        Some(0) => Err(serde::de::Error::custom("daily_goal_s must not be zero")),
+        goal_s => Ok(goal_s),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escape text so that xmobar displays it literally rather than as markup
+fn escape_xmobar(text: &str) -> String {
+    if text.contains('<') {
+        format!("<raw={}:{}/>", text.chars().count(), text)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// A snapshot of the server's state, for reporting to clients
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct Status {
+    /// The current state: "idle", "block", "paused", "break", "cooldown" or "tracking"
+    pub state: &'static str,
+    /// Seconds remaining in the current block or cooldown
+    pub remaining_s: Option<u64>,
+    /// Total length of the current block or cooldown, in seconds
+    pub duration_s: Option<u64>,
+    /// Label of the current block or tracking session
+    pub label: Option<String>,
+}
+
+/// The kinds of flash
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum FlashKind {
+    Warn,
+    Error,
+}
+
+fn default_color_wrap_up() -> (u8, u8, u8) { (255, 96, 0) }
+fn default_color_break_start() -> (u8, u8, u8) { (0, 192, 255) }
+fn default_color_break_end() -> (u8, u8, u8) { (64, 96, 192) }
+fn default_proportional_cooldown_max_s() -> u64 { u64::MAX }
+fn default_daily_limit_cooldown_s() -> u64 { 1800 }
+fn default_work_days() -> Vec<String> {
+    ["mon", "tue", "wed", "thu", "fri"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_bucket_s() -> u64 { 300 }
+fn default_compact_after_s() -> u64 { 7 * 86400 }
+fn default_compacted_bucket_s() -> u64 { 3600 }
+fn default_bucket_keep_s() -> u64 { 90 * 86400 }
+
+/// How task logs are split up by when time was recorded
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct TaskBuckets {
+    /// Length of each bucket
+    #[serde(default = "default_bucket_s")]
+    bucket_s: u64,
+    /// Age after which buckets are merged into longer ones, to save space
+    #[serde(default = "default_compact_after_s")]
+    compact_after_s: u64,
+    /// Length of merged buckets
+    #[serde(default = "default_compacted_bucket_s")]
+    compacted_bucket_s: u64,
+    /// Age after which buckets are forgotten, so that they do not fill the
+    /// config file
+    #[serde(default = "default_bucket_keep_s")]
+    keep_s: u64,
+}
+
+impl Default for TaskBuckets {
+    fn default() -> TaskBuckets {
+        TaskBuckets {
+            bucket_s: default_bucket_s(),
+            compact_after_s: default_compact_after_s(),
+            compacted_bucket_s: default_compacted_bucket_s(),
+            keep_s: default_bucket_keep_s(),
+        }
+    }
+}
+
+fn default_retention_min_s() -> u64 { 60 }
+fn default_retention_unused_s() -> u64 { 7 * 86400 }
+
+/// Which tasks are folded together into "(other)" when compacting task logs
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct TaskLogRetention {
+    /// Tasks with less time than this may be folded
+    #[serde(default = "default_retention_min_s")]
+    min_s: u64,
+    /// ...if no time has been recorded against them for this long
+    #[serde(default = "default_retention_unused_s")]
+    unused_s: u64,
+}
+
+impl Default for TaskLogRetention {
+    fn default() -> TaskLogRetention {
+        TaskLogRetention {
+            min_s: default_retention_min_s(),
+            unused_s: default_retention_unused_s(),
+        }
+    }
+}
+
+/// Time during a block when the user was away, waiting to be resolved
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+struct PendingAway {
+    /// Start of the block, in seconds since the UNIX epoch, to find its record
+    block_start_s: u64,
+    /// When the user went away, in seconds since the UNIX epoch
+    from_s: u64,
+    /// When the user came back, in seconds since the UNIX epoch
+    to_s: u64,
+    /// The task focused before the user went away, as a reversed path
+    path: Vec<String>,
+}
+
+/// How windows visible full-screen, other than the active window, are recorded
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FullscreenWindows {
+    /// Percentage of time to attribute to them (shared equally) rather than
+    /// to the active window; if 0, they are only noted in block reports
+    #[serde(default)]
+    weight_pcnt: u8,
+}
+
+fn default_rate_limit_calls() -> usize { 10 }
+fn default_rate_limit_interval_s() -> u64 { 10 }
+
+/// A limit on how often each client method may be called, e.g. by a
+/// misbehaving script
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Number of calls allowed in any interval
+    #[serde(default = "default_rate_limit_calls")]
+    calls: usize,
+    /// Length of the interval
+    #[serde(default = "default_rate_limit_interval_s")]
+    interval_s: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit {
+            calls: default_rate_limit_calls(),
+            interval_s: default_rate_limit_interval_s(),
+        }
+    }
+}
+
+/// A warning given shortly before the end of each block
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct WrapUp {
+    /// How long before the end of the block to warn
+    remaining_s: u64,
+    /// Color of the timer after the warning, instead of fading
+    #[serde(default = "default_color_wrap_up")]
+    color: (u8, u8, u8),
+    /// Whether to show a notification
+    #[serde(default)]
+    notify: bool,
+}
+
+impl WrapUp {
+    /// Whether to show a notification
+    pub fn notify(&self) -> bool {
+        self.notify
+    }
+}
+
+/// A break after each block, encouraging rest, with an optional lockout after it
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Break {
+    /// Length of the lockout after the break, during which blocks cannot be started
+    #[serde(default)]
+    lockout_s: u64,
+    /// Initial color of text during breaks
+    #[serde(default = "default_color_break_start")]
+    color_start: (u8, u8, u8),
+    #[serde(default = "default_color_break_end")]
+    color_end: (u8, u8, u8),
+    #[serde(default)]
+    fade: color::Fade,
+    /// Whether to show a notification when the break starts
+    #[serde(default)]
+    notify: bool,
+}
+
+impl Break {
+    /// Whether to show a notification when the break starts
+    pub fn notify(&self) -> bool {
+        self.notify
+    }
+}
+
+/// A cooldown which scales with the length of the preceding block
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ProportionalCooldown {
+    /// Length of the cooldown, as a percentage of the block
+    percent: u64,
+    #[serde(default)]
+    min_s: u64,
+    #[serde(default = "default_proportional_cooldown_max_s")]
+    max_s: u64,
+}
+
+/// Limits on how much work may be done in a day
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct DailyLimit {
+    /// Maximum number of completed blocks per day
+    #[serde(default)]
+    max_blocks: Option<u64>,
+    /// Maximum time spent in completed blocks per day
+    #[serde(default)]
+    max_focus_s: Option<u64>,
+    /// Length of the cooldown forced on attempts to start more blocks
+    #[serde(default = "default_daily_limit_cooldown_s")]
+    cooldown_s: u64,
+}
+
+/// The hours during which blocks are expected to be started
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct WorkHours {
+    /// Local start of the working day, as `HH:MM`
+    start: String,
+    /// Local end of the working day, as `HH:MM`; if this is before `start`,
+    /// working hours run past midnight
+    end: String,
+    /// Working days, as `mon`, `tue`, etc.
+    #[serde(default = "default_work_days")]
+    days: Vec<String>,
+    /// Whether to refuse to start blocks outside working hours, rather than warn
+    #[serde(default)]
+    strict: bool,
+}
+
+impl WorkHours {
+    /// Whether a given day of the week (1 for Monday) and minute of the day
+    /// is within working hours; unparseable times are ignored
+    fn contains(&self, weekday: u32, minute: u32) -> bool {
+        const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+        let parse = |s: &str| {
+            let (h, m) = s.split_once(':')?;
+            Some(h.parse::<u32>().ok()? * 60 + m.parse::<u32>().ok()?)
+        };
+        let day = DAYS.get(weekday.wrapping_sub(1) as usize).copied();
+        if !self
+            .days
+            .iter()
+            .any(|d| Some(d.to_lowercase().as_str()) == day)
+        {
+            return false;
+        }
+        match (parse(&self.start), parse(&self.end)) {
+            (Some(start), Some(end)) if start <= end => start <= minute && minute < end,
+            (Some(start), Some(end)) => start <= minute || minute < end,
+            _ => true,
+        }
+    }
+}
+
+/// A limit on the time spent on a task per week
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct TaskBudget {
+    /// The task, root first; time on any of its subtasks also counts
+    path: Vec<String>,
+    limit_s: u64,
+}
+
+/// A named kind of block
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Preset {
+    duration_s: u64,
+    #[serde(default)]
+    label: Option<String>,
+    /// Whether blocks started from this preset refuse to be paused or cancelled
+    #[serde(default)]
+    strict: bool,
+    /// Whether to lock the screen when blocks started from this preset end
+    #[serde(default)]
+    lock_screen: bool,
+    /// Hooks which replace the global ones for blocks started from this
+    /// preset, and the breaks and cooldowns after them; an empty command
+    /// disables the global hook
+    #[serde(default)]
+    hooks: HashMap<String, String>,
+}
+
+/// A routine of alternating work and rest
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Routine {
+    /// Length of each work block
+    work_s: u64,
+    /// Length of the rest (in place of the usual cooldown) after each block
+    rest_s: u64,
+    /// Number of rounds of work and rest, or unlimited if unset
+    #[serde(default)]
+    rounds: Option<u32>,
+}
+
+/// What to do when the distraction budget is exceeded
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistractionAction {
+    #[default]
+    Pause,
+    Cancel,
+}
+
+/// A limit on time spent on blacklisted windows in a single block
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct DistractionBudget {
+    limit_s: u64,
+    #[serde(default)]
+    action: DistractionAction,
+}
+
+/// A state transition, with some context, as reported to the daemon
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Transition {
+    pub event: Event,
+    /// Label of the block or tracking session which the transition concerns
+    pub label: Option<String>,
+    /// Planned length of the block, or length so far of the tracking session
+    pub duration_s: Option<u64>,
+    /// Event-specific detail, e.g. the task whose budget was exceeded
+    pub detail: Option<String>,
+    /// Preset which the block concerned was started from, if any
+    pub preset: Option<String>,
+    /// When the transition happened
+    pub timestamp: std::time::SystemTime,
+}
+
+/// A state transition, reported to the daemon so that it can run hooks
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Event {
+    /// A block was started
+    BlockStarted,
+    /// The running block was paused
+    BlockPaused,
+    /// The paused block was resumed
+    BlockUnpaused,
+    /// The running block was cancelled
+    BlockCancelled,
+    /// The running block is about to end
+    WrapUp,
+    /// The running block ran to completion, and cooldown started
+    BlockCompleted,
+    /// Cooldown finished
+    CooldownEnded,
+    /// A multiple of the halving interval of blocks has been completed
+    Halving,
+    /// The weekly budget for a task was exceeded
+    BudgetExceeded,
+    /// A tracking-only session was started
+    TrackingStarted,
+    /// The tracking-only session finished
+    TrackingEnded,
+    /// A named timer ran to completion
+    TimerFinished,
+    /// A break started, after a block was completed
+    BreakStarted,
+    /// A break finished, or was cut short
+    BreakEnded,
+    /// Something to do during a break or cooldown was suggested
+    BreakSuggestion,
+    /// The daily report was written
+    DailyReport,
+    /// The screen was locked, after a block from a `lock_screen` preset
+    ScreenLocked,
+}
+
+/// A destructive action, and what is needed to undo it
+#[derive(Clone, Debug)]
+enum Undo {
+    /// A task log was removed or replaced; this was its previous content
+    TaskLog {
+        name: String,
+        previous: Task,
+        buckets: Option<task::Buckets>,
+        filter: Option<task::Filter>,
+    },
+    /// A block was cancelled; these were the state (paused, with the time
+    /// that remained), record of the block, routine and wrap-up warning
+    /// before it was
+    CancelBlock {
+        state: State,
+        block: Option<Box<history::Block>>,
+        routine: Option<(String, u32)>,
+        wrapped_up: bool,
+    },
+}
+
+/// The state machine
+///
+/// This is saved along with the config, so that a running block survives
+/// restarts of the daemon.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum State {
+    /// The server is idle (no current block)
+    Idle,
+    /// The server is counting down a given block
+    InBlock {
+        duration: std::time::Duration,
+        #[serde(with = "crate::serde_instant")]
+        end_time: std::time::Instant,
+        label: Option<String>,
+    },
+    /// Timer is paused
+    Paused {
+        total_duration: std::time::Duration,
+        remaining_duration: std::time::Duration,
+        label: Option<String>,
+    },
+    /// The server is counting down a post-block break, during which blocks
+    /// may still be started
+    InBreak {
+        duration: std::time::Duration,
+        #[serde(with = "crate::serde_instant")]
+        end_time: std::time::Instant,
+    },
+    /// The server is counting down the post-block cooldown (or lockout)
+    InCooldown {
+        duration: std::time::Duration,
+        #[serde(with = "crate::serde_instant")]
+        end_time: std::time::Instant,
+    },
+    /// Windows are being tracked, as in a block, but there is no countdown
+    Tracking {
+        label: String,
+        #[serde(with = "crate::serde_instant")]
+        start_time: std::time::Instant,
+    },
+}
+
+impl Event {
+    /// A name for the transition, for reporting to external services
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::BlockStarted => "block_started",
+            Event::BlockPaused => "block_paused",
+            Event::BlockUnpaused => "block_unpaused",
+            Event::BlockCancelled => "block_cancelled",
+            Event::WrapUp => "wrap_up",
+            Event::BlockCompleted => "block_completed",
+            Event::CooldownEnded => "cooldown_ended",
+            Event::Halving => "halving",
+            Event::BudgetExceeded => "budget_exceeded",
+            Event::TrackingStarted => "tracking_started",
+            Event::TrackingEnded => "tracking_ended",
+            Event::TimerFinished => "timer_finished",
+            Event::BreakStarted => "break_started",
+            Event::BreakEnded => "break_ended",
+            Event::BreakSuggestion => "break_suggestion",
+            Event::DailyReport => "daily_report",
+            Event::ScreenLocked => "screen_locked",
+        }
+    }
+}
+
+impl State {
+    /// Helper function needed by serde to "deserialize" the field as Idle
+    fn idle() -> State {
+        State::Idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use std::time::{Duration, SystemTime};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// A server with default settings, on a stopped clock
+    fn server_at(start: SystemTime) -> Server {
+        clock::set_manual(start);
+        Server::new()
+    }
+
+    fn server() -> Server {
+        server_at(SystemTime::now())
+    }
+
+    /// Move the clock forward and tick the server, as `xmobar_update` does
+    fn tick(server: &mut Server, by: Duration) {
+        clock::advance(by);
+        server.update();
+    }
+
+    fn events(server: &mut Server) -> Vec<Event> {
+        server.take_events().into_iter().map(|t| t.event).collect()
+    }
+
+    fn state(server: &Server) -> (&'static str, Option<u64>) {
+        let status = server.status();
+        (status.state, status.remaining_s)
+    }
+
+    fn outcomes(server: &Server) -> Vec<history::Outcome> {
+        server.history().iter().map(|block| block.outcome).collect()
+    }
+
+    fn meeting() -> Window {
+        Window {
+            title: "Zoom Meeting".into(),
+            class: "zoom".into(),
+            desktop: "1".into(),
+            fullscreen: vec![],
+        }
+    }
+
+    fn short_break(lockout_s: u64) -> Break {
+        Break {
+            lockout_s,
+            color_start: default_color_break_start(),
+            color_end: default_color_break_end(),
+            fade: color::Fade::default(),
+            notify: false,
+        }
+    }
+
+    #[test]
+    fn test_block_lifecycle() {
+        let mut server = server();
+        assert_eq!(state(&server), ("idle", None));
+
+        server.start_block(60, Some("work".into()));
+        assert_eq!(state(&server), ("block", Some(60)));
+        tick(&mut server, Duration::from_secs(20));
+        assert_eq!(state(&server), ("block", Some(40)));
+
+        // Time spent paused does not count
+        server.pause_block();
+        assert_eq!(state(&server), ("paused", Some(40)));
+        tick(&mut server, Duration::from_secs(600));
+        assert_eq!(state(&server), ("paused", Some(40)));
+        server.pause_block();
+        assert_eq!(state(&server), ("block", Some(40)));
+        assert_eq!(server.status().label.as_deref(), Some("work"));
+
+        // The block is not over until its end time has passed
+        tick(&mut server, Duration::from_secs(40));
+        assert_eq!(state(&server), ("block", Some(0)));
+        tick(&mut server, MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed]);
+        assert_eq!(server.history()[0].pauses, 1);
+        assert_eq!(server.height, 1);
+
+        // Cooldown cannot be escaped
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        tick(&mut server, crate::COOLDOWN_DURATION);
+        assert_eq!(state(&server), ("cooldown", Some(0)));
+        tick(&mut server, MS);
+        assert_eq!(state(&server), ("idle", None));
+
+        assert_eq!(
+            events(&mut server),
+            [
+                Event::BlockStarted,
+                Event::BlockPaused,
+                Event::BlockUnpaused,
+                Event::BlockCompleted,
+                Event::CooldownEnded,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_break_lifecycle() {
+        let mut server = server();
+        server.break_config = Some(short_break(30));
+
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(state(&server), ("break", Some(300)));
+
+        // A block may be started during a break, cutting it short
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("block", Some(60)));
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(state(&server), ("break", Some(300)));
+
+        // Letting the break run out, or cancelling it, leads to the lockout
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        assert_eq!(state(&server), ("cooldown", Some(30)));
+        tick(&mut server, Duration::from_secs(30) + MS);
+        assert_eq!(state(&server), ("idle", None));
+
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("cooldown", Some(30)));
+
+        assert_eq!(
+            events(&mut server),
+            [
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::BreakStarted,
+                Event::BreakEnded,
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::BreakStarted,
+                Event::BreakEnded,
+                Event::CooldownEnded,
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::BreakStarted,
+                Event::BreakEnded,
+            ],
+        );
+
+        // Without a lockout, the break leads straight back to idle
+        server.break_config = Some(short_break(0));
+        tick(&mut server, Duration::from_secs(30) + MS);
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        assert_eq!(state(&server), ("idle", None));
+    }
+
+    #[test]
+    fn test_tracking_lifecycle() {
+        let mut server = server();
+        server.meeting_auto_track = true;
+
+        server.record_current_window(&meeting(), &[], clock::now());
+        assert_eq!(state(&server), ("tracking", None));
+        // Tracking ends once no meeting has been seen for a while
+        clock::advance(crate::MEETING_GRACE_DURATION + MS);
+        let mut other = meeting();
+        other.title = "editor".into();
+        server.record_current_window(&other, &[], clock::now());
+        assert_eq!(state(&server), ("idle", None));
+
+        // It can also be cancelled, or replaced by a block
+        server.record_current_window(&meeting(), &[], clock::now());
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+        server.record_current_window(&meeting(), &[], clock::now());
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("block", Some(60)));
+
+        assert_eq!(
+            events(&mut server),
+            [
+                Event::TrackingStarted,
+                Event::TrackingEnded,
+                Event::TrackingStarted,
+                Event::TrackingEnded,
+                Event::TrackingStarted,
+                Event::TrackingEnded,
+                Event::BlockStarted,
+            ],
+        );
+        // Tracking sessions are not blocks
+        assert!(server.history().is_empty());
+    }
+
+    #[test]
+    fn test_cancel_in_each_state() {
+        let mut server = server();
+
+        // Nothing to cancel
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(10));
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(outcomes(&server), [history::Outcome::Cancelled]);
+        assert_eq!(server.height, 0);
+
+        // A paused block must be resumed before it can be cancelled
+        server.start_block(60, None);
+        server.pause_block();
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        assert_eq!(state(&server), ("paused", Some(60)));
+        server.pause_block();
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+
+        // Cooldown cannot be cancelled, even by force
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        assert_eq!(
+            outcomes(&server),
+            [
+                history::Outcome::Cancelled,
+                history::Outcome::Cancelled,
+                history::Outcome::Completed,
+            ],
+        );
+        assert!(!events(&mut server).contains(&Event::CooldownEnded));
+    }
+
+    #[test]
+    fn test_undo_cancel() {
+        let mut server = server();
+
+        // The block comes back paused with the time it had left, however long
+        // it spent cancelled, even past when it would have ended
+        server.start_block(60, Some("work".into()));
+        tick(&mut server, Duration::from_secs(20));
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        tick(&mut server, Duration::from_secs(600));
+        assert_eq!(
+            server.undo_last().as_deref(),
+            Ok("restored cancelled block, paused")
+        );
+        assert_eq!(state(&server), ("paused", Some(40)));
+        assert_eq!(server.status().label.as_deref(), Some("work"));
+        assert_eq!(outcomes(&server), []);
+
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(40));
+        assert_eq!(state(&server), ("block", Some(0)));
+        tick(&mut server, MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed]);
+        assert_eq!(server.history()[0].pauses, 1);
+
+        // Only the cancelled block is forgotten, not whatever was recorded
+        // after it
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(10));
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        let mut other = history::Block::start(60, None);
+        other.start_s += 1;
+        other.finish(history::Outcome::Cancelled);
+        server.history.push(other);
+        assert!(server.undo_last().is_ok());
+        assert_eq!(
+            outcomes(&server),
+            [history::Outcome::Completed, history::Outcome::Cancelled],
+        );
+        assert_eq!(state(&server), ("paused", Some(50)));
+    }
+
+    #[test]
+    fn test_undo_task_log() {
+        let mut server = server();
+
+        // Restoring a log which was replaced drops the buckets and filter of
+        // the log which replaced it, if it had none of its own
+        server.task_log_add("log".into());
+        let filter = task::Filter {
+            include: vec![regex::Regex::new("NVIM").unwrap()],
+            exclude: vec![],
+        };
+        server.task_log_add_filtered("log".into(), Some(filter));
+        server.add_to_task_log("log", &["NVIM".into()], Duration::from_secs(60));
+        assert!(server.task_log_buckets.contains_key("log"));
+        assert!(server.task_log_filters.contains_key("log"));
+        assert_eq!(server.undo_last().as_deref(), Ok("restored task log log"));
+        assert!(!server.task_log_buckets.contains_key("log"));
+        assert!(!server.task_log_filters.contains_key("log"));
+        assert!(server.has_task_log("log"));
+    }
+
+    #[test]
+    fn test_task_log_export() {
+        let mut server = server();
+        let path = std::env::temp_dir().join(format!("pomotoshi-{}-log.json", std::process::id()));
+        assert!(server.task_log_export("log").is_err());
+
+        // A log written to a file by the caller reads back the same
+        server.task_log_add("log".into());
+        server.task_log_add_time("log", vec!["Github".into()], 30);
+        let log = server.task_log_export("log").unwrap();
+        let written = log.write_file(&path);
+        assert_eq!(server.task_log_exported("log", &path, written), Ok(()));
+        let read = Task::read_file(&path);
+        assert_eq!(server.task_log_import("copy".into(), &path, read), Ok(()));
+        assert_eq!(server.task_logs["copy"], server.task_logs["log"]);
+
+        // Failures are returned to the caller, as well as signalled
+        let missing = path.with_extension("missing");
+        let read = Task::read_file(&missing);
+        let error = server.task_log_import("copy".into(), &missing, read);
+        assert!(error.unwrap_err().starts_with("importing task log copy"));
+        assert_eq!(server.task_logs["copy"], server.task_logs["log"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pause_in_last_second() {
+        let mut server = server();
+
+        // Pausing with under a second left keeps the fraction remaining
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_millis(59_500));
+        server.pause_block();
+        assert_eq!(state(&server), ("paused", Some(0)));
+        tick(&mut server, Duration::from_secs(60));
+        server.pause_block();
+        tick(&mut server, Duration::from_millis(500));
+        assert_eq!(state(&server), ("block", Some(0)));
+        tick(&mut server, MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed]);
+
+        // Pausing on the instant the block ends leaves nothing remaining, and
+        // resuming ends it on the next tick
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60));
+        server.pause_block();
+        assert_eq!(state(&server), ("paused", Some(0)));
+        server.pause_block();
+        tick(&mut server, MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        // Pausing after the block ended, but before a tick noticed, is too late
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        events(&mut server);
+        server.start_block(60, None);
+        clock::advance(Duration::from_secs(60) + MS);
+        server.pause_block();
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(
+            events(&mut server),
+            [Event::BlockStarted, Event::BlockCompleted],
+        );
+        assert_eq!(outcomes(&server), [history::Outcome::Completed; 3]);
+    }
+
+    #[test]
+    fn test_cancel_on_final_tick() {
+        let mut server = server();
+        server.confirm_cancel_s = Some(10);
+
+        // Cancelling on the tick the block ends completes it, rather than
+        // recording it as both completed and cancelled
+        server.start_block(60, None);
+        clock::advance(Duration::from_secs(60) + MS);
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        server.update();
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed]);
+        assert_eq!(
+            events(&mut server),
+            [Event::BlockStarted, Event::BlockCompleted],
+        );
+
+        // Likewise when the block ends between a request to cancel and its
+        // confirmation
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(59));
+        assert!(server.try_cancel_block(false).is_err());
+        clock::advance(Duration::from_secs(1) + MS);
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed; 2]);
+
+        // On the instant the block ends, it has not yet completed and so
+        // may still be cancelled
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        clock::advance(Duration::from_secs(60));
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        server.update();
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(
+            outcomes(&server),
+            [
+                history::Outcome::Completed,
+                history::Outcome::Completed,
+                history::Outcome::Cancelled,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_cooldown_expiry_next_to_pause() {
+        let mut server = server();
+        server.routines.insert(
+            "pomodoro".into(),
+            Routine {
+                work_s: 60,
+                rest_s: 30,
+                rounds: Some(2),
+            },
+        );
+
+        // Pausing does nothing during cooldown, and does not stop it expiring
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        server.pause_block();
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        clock::advance(crate::COOLDOWN_DURATION + MS);
+        server.pause_block();
+        assert_eq!(state(&server), ("idle", None));
+        server.pause_block();
+        assert_eq!(state(&server), ("idle", None));
+
+        // During a routine, pausing as the rest expires pauses the next block
+        server.start_routine("pomodoro");
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(30)));
+        clock::advance(Duration::from_secs(30) + MS);
+        server.pause_block();
+        assert_eq!(state(&server), ("paused", Some(60)));
+        assert_eq!(server.routine_status().map(|r| r.2), Some(2));
+
+        // and resuming it lets the routine finish
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, Duration::from_secs(30) + MS);
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(server.routine_status(), None);
+        assert_eq!(outcomes(&server), [history::Outcome::Completed; 3]);
+    }
+
+    #[test]
+    fn test_flash_counters() {
+        let mut server = server();
+
+        // Repeated warnings coalesce, and are counted
+        for _ in 0..5 {
+            server.pause_block();
+            tick(&mut server, Duration::from_millis(100));
+        }
+        assert_eq!(server.flashes_coalesced, 4);
+
+        // Once things are calm, the count is reported and reset, exactly once
+        tick(&mut server, FLASH_COALESCE_DURATION);
+        assert_eq!(server.flashes_coalesced, 0);
+        for _ in 0..10 {
+            tick(&mut server, FLASH_COALESCE_DURATION);
+            assert_eq!(server.flashes_coalesced, 0);
+        }
+        let reports: Vec<_> = server
+            .diagnostics
+            .iter()
+            .filter(|(_, message)| message.contains("repeated flashes"))
+            .collect();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].1.starts_with("dropped 4 "));
+        assert_eq!(server.next_wakeup(), clock::now() + IDLE_TICK);
+
+        // An error during a warning is not coalesced with it, but holds back
+        // the count until it has calmed down too
+        server.pause_block();
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(1));
+        server.signal_error("first");
+        assert_eq!(server.flashes_coalesced, 1);
+        server.signal_error("second");
+        tick(&mut server, FLASH_COALESCE_DURATION);
+        assert_eq!(server.flashes_coalesced, 2);
+        tick(&mut server, FLASH_COALESCE_DURATION);
+        assert_eq!(server.flashes_coalesced, 0);
+        assert!(server
+            .diagnostics
+            .back()
+            .unwrap()
+            .1
+            .starts_with("dropped 2 "));
+    }
+
+    #[test]
+    fn test_clock_jumps() {
+        // Start on a whole second, so that times in whole seconds are exact
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(now.unwrap().as_secs());
+        let mut server = server_at(start);
+
+        // The wall clock being set back an hour, and small adjustments, during
+        // a block do not change how long it lasted, but the jump is flagged
+        server.start_block(600, None);
+        tick(&mut server, Duration::from_secs(60));
+        clock::set_wall(clock::system_now() - Duration::from_secs(3600));
+        tick(&mut server, Duration::from_secs(60));
+        clock::set_wall(clock::system_now() + Duration::from_secs(5));
+        tick(&mut server, Duration::from_secs(480) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        let block = &server.history()[0];
+        assert_eq!(block.elapsed.as_secs(), 600);
+        assert_eq!(block.clock_jump_s, Some(-3600));
+        let report: serde_json::Value = serde_json::from_str(&server.block_report(0)).unwrap();
+        assert_eq!(report["actual_s"], 600);
+        assert_eq!(report["clock_jump_s"], -3600);
+        let jumps: Vec<_> = server
+            .diagnostics
+            .iter()
+            .filter(|(_, message)| message.starts_with("wall clock jumped"))
+            .collect();
+        assert_eq!(jumps.len(), 1);
+
+        // Suspending the machine stops the monotonic clock, but not the wall clock
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(600, None);
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(30));
+        clock::set_wall(clock::system_now() + Duration::from_secs(7200));
+        tick(&mut server, MS);
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(600) + MS);
+        let block = &server.history()[1];
+        assert_eq!(block.paused, Duration::from_secs(30) + MS);
+        assert_eq!(block.clock_jump_s, Some(7200));
+        assert_eq!(block.end_s - block.start_s, 7830);
+    }
+
+    #[test]
+    fn test_monitor_off() {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(now.unwrap().as_secs());
+        let mut server = server_at(start);
+        server.away_on_monitor_off = true;
+        let editor = Window {
+            title: "editor".into(),
+            ..Window::default()
+        };
+        let minute = |server: &mut Server, monitor_on: bool| {
+            clock::advance(Duration::from_secs(60));
+            if monitor_on {
+                server.record_current_window(&editor, &[], clock::now());
+            } else {
+                server.record_monitor_off(clock::now());
+            }
+            server.update();
+        };
+
+        // The block carries on while the monitors are off, but the time is
+        // marked as away rather than given to the focused window
+        server.start_block(600, None);
+        for monitor_on in [true, true, false, false, false, true] {
+            minute(&mut server, monitor_on);
+        }
+        assert_eq!(state(&server), ("block", Some(240)));
+        tick(&mut server, Duration::from_secs(240) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        let block = &server.history()[0];
+        let start_s = block.start_s;
+        assert_eq!(
+            block.tasks.time_at_path(&[]),
+            Some(Duration::from_secs(180))
+        );
+        assert_eq!(block.away, vec![(start_s + 120, start_s + 300)]);
+        let report: serde_json::Value = serde_json::from_str(&server.block_report(0)).unwrap();
+        assert_eq!(report["away_s"], 180);
+        assert_eq!(report["focused_s"], 420);
+        assert!(server.block_log.contains("monitors off"));
+    }
+
+    #[test]
+    fn test_reconcile_away() {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(now.unwrap().as_secs());
+        let mut server = server_at(start);
+        server.away_on_monitor_off = true;
+        server.reconcile_away_s = Some(120);
+        let editor = Window {
+            title: "editor".into(),
+            ..Window::default()
+        };
+        let minute = |server: &mut Server, monitor_on: bool| {
+            clock::advance(Duration::from_secs(60));
+            if monitor_on {
+                server.record_current_window(&editor, &[], clock::now());
+            } else {
+                server.record_monitor_off(clock::now());
+            }
+            server.update();
+        };
+
+        // A short time away is not held
+        server.start_block(1200, None);
+        let start_s = server.current_block().unwrap().start_s;
+        for monitor_on in [true, false, true] {
+            minute(&mut server, monitor_on);
+        }
+        assert!(server.pending_away().is_empty());
+
+        // A longer one is, until it is kept, for the task focused before
+        for monitor_on in [false, false, false, true] {
+            minute(&mut server, monitor_on);
+        }
+        let editor_path = vec!["editor".to_owned()];
+        assert_eq!(
+            server.pending_away(),
+            vec![(start_s + 180, start_s + 360, editor_path.clone())],
+        );
+        assert!(server.xmobar_update().contains("[away 3m?]"));
+        assert!(server.resolve_away("maybe").is_err());
+        assert!(server.resolve_away("assign:/").is_err());
+        assert_eq!(
+            server.resolve_away("keep").as_deref(),
+            Ok("recorded 180s away for \"editor\""),
+        );
+        let block = server.current_block().unwrap();
+        assert_eq!(block.away, vec![(start_s + 60, start_s + 120)]);
+        assert_eq!(
+            block.tasks.time_at_path(&[]),
+            Some(Duration::from_secs(360))
+        );
+        assert!(server.resolve_away("keep").is_err());
+
+        // Suspending the machine is held too, and can be discarded
+        clock::set_wall(clock::system_now() + Duration::from_secs(300));
+        tick(&mut server, MS);
+        assert_eq!(server.pending_away().len(), 1);
+        assert_eq!(
+            server.resolve_away("discard").as_deref(),
+            Ok("discarded 300s away"),
+        );
+
+        // Time away can be assigned to another task after the block ends
+        for monitor_on in [false, false, false, false, false, true] {
+            minute(&mut server, monitor_on);
+        }
+        tick(&mut server, Duration::from_secs(1200));
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+        assert_eq!(server.pending_away().len(), 1);
+        assert_eq!(
+            server.resolve_away("assign:admin / email").as_deref(),
+            Ok("recorded 300s away for \"admin / email\""),
+        );
+        let block = &server.history()[0];
+        let email = ["email".to_owned(), "admin".to_owned()];
+        assert_eq!(
+            block.tasks.time_at_path(&email),
+            Some(Duration::from_secs(300))
+        );
+        assert!(server.pending_away().is_empty());
+    }
+
+    #[test]
+    fn test_preset_hooks() {
+        let mut server = server();
+        server.hooks = serde_json::from_value(serde_json::json!({
+            "block_started": "dnd on",
+            "block_completed": "ding",
+            "cooldown_ended": "dnd off",
+        }))
+        .unwrap();
+        server.presets = serde_json::from_value(serde_json::json!({
+            "meeting-prep": { "duration_s": 600, "hooks": { "block_started": "" } },
+            "deep": { "duration_s": 600, "hooks": { "block_completed": "lock-keyboard" } },
+        }))
+        .unwrap();
+        let hooks = |server: &mut Server| -> Vec<(Event, Option<String>)> {
+            let transitions = server.take_events();
+            transitions
+                .iter()
+                .map(|t| (t.event, server.hook(t).map(str::to_owned)))
+                .collect()
+        };
+        let hook = |command: &str| Some(command.to_owned());
+
+        // A preset can disable a hook, and otherwise the global hooks apply
+        server.start_preset("meeting-prep");
+        tick(&mut server, Duration::from_secs(600) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        assert_eq!(
+            hooks(&mut server),
+            vec![
+                (Event::BlockStarted, None),
+                (Event::BlockCompleted, hook("ding")),
+                (Event::CooldownEnded, hook("dnd off")),
+            ],
+        );
+
+        // or replace one, including after the block has ended
+        server.start_preset("deep");
+        tick(&mut server, Duration::from_secs(600) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(600, None);
+        assert_eq!(
+            hooks(&mut server),
+            vec![
+                (Event::BlockStarted, hook("dnd on")),
+                (Event::BlockCompleted, hook("lock-keyboard")),
+                (Event::CooldownEnded, hook("dnd off")),
+                (Event::BlockStarted, hook("dnd on")),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_hook_payload() {
+        let mut server = server();
+        server.start_block(600, Some("writing".into()));
+        for title in ["editor", "browser", "editor", "terminal", "editor"] {
+            let win = Window {
+                title: title.into(),
+                ..Window::default()
+            };
+            clock::advance(Duration::from_secs(60));
+            server.record_current_window(&win, &[], clock::now());
+        }
+        tick(&mut server, Duration::from_secs(300) + MS);
+        let transitions = server.take_events();
+        assert_eq!(transitions[1].event, Event::BlockCompleted);
+
+        let payload = server.hook_payload(&transitions[1]);
+        assert_eq!(payload["event"], "block_completed");
+        assert_eq!(payload["state"], "cooldown");
+        assert_eq!(payload["label"], "writing");
+        assert_eq!(payload["duration_s"], 600);
+        assert_eq!(payload["actual_s"], 600);
+        assert_eq!(payload["top_tasks"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            payload["top_tasks"][0]["path"],
+            serde_json::json!(["editor"])
+        );
+        assert_eq!(payload["top_tasks"][0]["time_s"], 180);
+        assert_eq!(payload["height"], 1);
+        assert_eq!(payload["streak_blocks"], 1);
+    }
+
+    #[test]
+    fn test_next_wakeup() {
+        let mut server = server();
+        let ms = |ms: u64| clock::now() + Duration::from_millis(ms);
+        assert_eq!(server.next_wakeup(), clock::now() + IDLE_TICK);
+
+        // Counting down, the time shown changes just after each second
+        server.start_block(600, None);
+        assert_eq!(server.next_wakeup(), ms(1));
+        tick(&mut server, Duration::from_millis(300));
+        assert_eq!(server.next_wakeup(), ms(701));
+        // and the bar flashes every blink
+        server.signal_error("oops");
+        assert_eq!(server.next_wakeup(), ms(1));
+        tick(&mut server, Duration::from_millis(50));
+        assert_eq!(server.next_wakeup(), ms(51));
+        tick(&mut server, Duration::from_millis(1400));
+        assert_eq!(server.next_wakeup(), ms(251));
+        // Timers count down too
+        server.start_timer("tea".into(), 180);
+        tick(&mut server, Duration::from_millis(400));
+        assert_eq!(server.next_wakeup(), ms(601));
+
+        // Paused, nothing changes by itself
+        server.cancel_timer("tea");
+        server.pause_block();
+        assert_eq!(server.next_wakeup(), clock::now() + IDLE_TICK);
+
+        // and the block ends just after it is due to
+        server.pause_block();
+        tick(&mut server, Duration::from_millis(597_750));
+        assert_eq!(server.next_wakeup(), ms(101));
+        tick(&mut server, Duration::from_millis(101));
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+    }
+
+    #[test]
+    fn test_block_past_midnight() {
+        // Start ten minutes before the end of a local day
+        let (_, midnight) = history::local_day(clock::unix_now(), 0).unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(midnight - 600);
+        let mut server = server_at(start);
+        server.daily_goal_s = Some(1800);
+
+        server.start_block(1800, None);
+        assert_eq!(server.focus_today_s(), 0);
+        tick(&mut server, Duration::from_secs(1800) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        // The block counts towards the day on which it ended
+        let block = &server.history()[0];
+        assert!(block.start_s < midnight && midnight <= block.end_s);
+        assert_eq!(server.focus_between_s(midnight - 86400, midnight), 0);
+        assert_eq!(server.focus_today_s(), 1800);
+        assert_eq!(server.streaks(), (1, 1));
+    }
+
+    #[test]
+    fn test_streak_breaks() {
+        let (_, midnight) = history::local_day(clock::unix_now(), 0).unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(midnight + 3600);
+        let mut server = server_at(start);
+        server.daily_goal_s = Some(1500);
+
+        // Meet the goal today, then cancel a block
+        server.start_block(1500, None);
+        tick(&mut server, Duration::from_secs(1500) + MS);
+        assert_eq!(server.streaks(), (1, 1));
+        tick(&mut server, Duration::from_secs(300) + MS);
+        server.start_block(1500, None);
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        assert_eq!(server.streaks(), (1, 0));
+
+        // Miss the goal tomorrow, and see the day after that
+        tick(&mut server, Duration::from_secs(86400));
+        assert_eq!(server.streaks(), (1, 0));
+        tick(&mut server, Duration::from_secs(86400));
+        assert_eq!(server.streaks(), (0, 0));
+
+        // The breaks outlive the block log, reloads and restarts
+        let messages = |server: &Server| -> Vec<String> {
+            server
+                .streak_breaks()
+                .into_iter()
+                .map(|(_, message)| message)
+                .collect()
+        };
+        let expected = [
+            "block streak of 1 broken",
+            "daily goal streak of 1 days broken",
+        ];
+        server.start_block(1500, None);
+        assert!(!server.block_log.contains("broken"));
+        assert_eq!(messages(&server), expected);
+        server.reload_config(Server::new());
+        assert_eq!(messages(&server), expected);
+        let saved = serde_json::to_string(&server).unwrap();
+        let restored: Server = serde_json::from_str(&saved).unwrap();
+        assert_eq!(messages(&restored), expected);
+    }
+
+    #[test]
+    fn test_routine() {
+        let mut server = server();
+        server.routines = serde_json::from_value(serde_json::json!({
+            "pomodoro": { "work_s": 60, "rest_s": 30, "rounds": 2 },
+        }))
+        .unwrap();
+
+        // Unknown routines start nothing
+        server.start_routine("unknown");
+        assert_eq!(state(&server), ("idle", None));
+
+        // Work and rest alternate, with the rest in place of the cooldown
+        server.start_routine("pomodoro");
+        assert_eq!(state(&server), ("block", Some(60)));
+        assert_eq!(server.status().label.as_deref(), Some("pomodoro"));
+        assert_eq!(
+            server.routine_status(),
+            Some(("pomodoro".into(), "work", 1, 2)),
+        );
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(30)));
+        assert_eq!(
+            server.routine_status(),
+            Some(("pomodoro".into(), "rest", 1, 2)),
+        );
+        tick(&mut server, Duration::from_secs(30) + MS);
+        assert_eq!(state(&server), ("block", Some(60)));
+        assert_eq!(
+            server.routine_status(),
+            Some(("pomodoro".into(), "work", 2, 2)),
+        );
+
+        // and the routine finishes after its last rest
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, Duration::from_secs(30) + MS);
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(server.routine_status(), None);
+        assert_eq!(outcomes(&server), [history::Outcome::Completed; 2]);
+
+        // Cancelling a block ends the routine, and the usual cooldown applies
+        // to blocks started outside it
+        server.start_routine("pomodoro");
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(server.routine_status(), None);
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        assert_eq!(
+            events(&mut server),
+            [
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::CooldownEnded,
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::CooldownEnded,
+                Event::BlockStarted,
+                Event::BlockCancelled,
+                Event::BlockStarted,
+                Event::BlockCompleted,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_preset() {
+        let mut server = server();
+        server.presets = serde_json::from_value(serde_json::json!({
+            "deep": { "duration_s": 1500, "label": "deep work", "strict": true },
+        }))
+        .unwrap();
+
+        // Unknown presets start nothing
+        server.start_preset("unknown");
+        assert_eq!(state(&server), ("idle", None));
+
+        // A strict block can be neither paused nor cancelled
+        server.start_preset("deep");
+        assert_eq!(state(&server), ("block", Some(1500)));
+        assert_eq!(server.status().label.as_deref(), Some("deep work"));
+        server.pause_block();
+        assert_eq!(state(&server), ("block", Some(1500)));
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        assert_eq!(state(&server), ("block", Some(1500)));
+        tick(&mut server, Duration::from_secs(1500) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+
+        // Blocks started otherwise are not strict
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        server.pause_block();
+        assert_eq!(state(&server), ("paused", Some(60)));
+        server.pause_block();
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+
+        assert_eq!(
+            events(&mut server),
+            [
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::CooldownEnded,
+                Event::BlockStarted,
+                Event::BlockPaused,
+                Event::BlockUnpaused,
+                Event::BlockCancelled,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_work_hours() {
+        let mut server = server();
+        let hours = |start: &str, end: &str, strict: bool| {
+            serde_json::from_value(serde_json::json!({
+                "start": start,
+                "end": end,
+                "days": ["mon", "tue", "wed", "thu", "fri", "sat", "sun"],
+                "strict": strict,
+            }))
+            .unwrap()
+        };
+
+        // Outside working hours, blocks start with a warning
+        server.work_hours = Some(hours("00:00", "00:00", false));
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("block", Some(60)));
+        assert!(server.block_log.contains("outside working hours"));
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+
+        // or, if they are strict, are refused
+        server.work_hours = Some(hours("00:00", "00:00", true));
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("idle", None));
+        assert!(server.block_log.contains("refused block"));
+
+        // Inside them, blocks start as usual
+        server.work_hours = Some(hours("00:00", "24:00", true));
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("block", Some(60)));
+        assert!(!server.block_log.contains("working hours"));
+
+        assert_eq!(
+            events(&mut server),
+            [
+                Event::BlockStarted,
+                Event::BlockCompleted,
+                Event::CooldownEnded,
+                Event::BlockStarted,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_daily_limit() {
+        let (_, midnight) = history::local_day(clock::unix_now(), 0).unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(midnight + 3600);
+        let mut server = server_at(start);
+        server.daily_limit = Some(DailyLimit {
+            max_blocks: Some(1),
+            max_focus_s: None,
+            cooldown_s: 600,
+        });
+
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        assert_eq!(
+            server.daily_limit_reached(),
+            Some("daily limit of 1 blocks reached; take a break".into())
+        );
+
+        // Once the limit is reached, starting a block forces a cooldown
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("cooldown", Some(600)));
+        tick(&mut server, Duration::from_secs(600) + MS);
+        assert_eq!(state(&server), ("idle", None));
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("cooldown", Some(600)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed]);
+
+        // until the next day
+        tick(&mut server, Duration::from_secs(86400));
+        assert_eq!(server.daily_limit_reached(), None);
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("block", Some(60)));
+
+        // A limit on focus time works the same way
+        server.daily_limit = Some(DailyLimit {
+            max_blocks: None,
+            max_focus_s: Some(90),
+            cooldown_s: 600,
+        });
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("block", Some(60)));
+        tick(&mut server, Duration::from_secs(60) + MS);
+        tick(&mut server, crate::COOLDOWN_DURATION + MS);
+        server.start_block(60, None);
+        assert_eq!(state(&server), ("cooldown", Some(600)));
+        assert_eq!(outcomes(&server), [history::Outcome::Completed; 3]);
+    }
+
+    #[test]
+    fn test_proportional_cooldown() {
+        let mut server = server();
+        server.proportional_cooldown = Some(ProportionalCooldown {
+            percent: 20,
+            min_s: 60,
+            max_s: 600,
+        });
+        let cooldown_after = |server: &mut Server, block_s: u64| {
+            server.start_block(block_s, None);
+            tick(server, Duration::from_secs(block_s) + MS);
+            let cooldown = state(server);
+            tick(server, Duration::from_secs(cooldown.1.unwrap()) + MS);
+            assert_eq!(state(server), ("idle", None));
+            cooldown
+        };
+
+        // The cooldown scales with the block, within its bounds
+        assert_eq!(cooldown_after(&mut server, 1500), ("cooldown", Some(300)));
+        assert_eq!(cooldown_after(&mut server, 120), ("cooldown", Some(60)));
+        assert_eq!(cooldown_after(&mut server, 7200), ("cooldown", Some(600)));
+
+        // Time spent paused does not make it longer
+        server.start_block(1500, None);
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(3600));
+        server.pause_block();
+        tick(&mut server, Duration::from_secs(1500) + MS);
+        assert_eq!(state(&server), ("cooldown", Some(300)));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut server = server();
+        server.rate_limit = RateLimit {
+            calls: 3,
+            interval_s: 10,
+        };
+
+        // Each method may be called as often as the limit allows
+        for _ in 0..3 {
+            assert!(server.allow_call("startBlock"));
+            tick(&mut server, Duration::from_secs(1));
+        }
+        assert!(!server.allow_call("startBlock"));
+        assert!(!server.allow_call("startBlock"));
+        assert!(server.allow_call("pauseBlock"));
+
+        // and once the oldest call is out of the interval, one more is allowed
+        tick(&mut server, Duration::from_secs(7));
+        assert!(server.allow_call("startBlock"));
+        assert!(!server.allow_call("startBlock"));
+        tick(&mut server, Duration::from_secs(10));
+        assert!(server.allow_call("startBlock"));
+
+        // Refusals are noted when they start, and counted when they end
+        let messages: Vec<_> = server
+            .diagnostics
+            .iter()
+            .map(|(_, message)| message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "startBlock called too often; refusing calls",
+                "refused 2 calls to startBlock",
+                "startBlock called too often; refusing calls",
+                "refused 1 calls to startBlock",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_confirm_cancel() {
+        let mut server = server();
+        server.confirm_cancel_s = Some(10);
+
+        // Early in a block, cancelling needs no confirmation
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(30));
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+
+        // Near its end, a second cancel must follow the first promptly
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(55));
+        assert!(server.try_cancel_block(false).is_err());
+        assert_eq!(state(&server), ("block", Some(5)));
+        tick(&mut server, CANCEL_CONFIRM_DURATION - MS);
+        assert_eq!(server.try_cancel_block(false), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+
+        // and a late confirmation is only another request
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(51));
+        assert!(server.try_cancel_block(false).is_err());
+        tick(&mut server, CANCEL_CONFIRM_DURATION);
+        assert!(server.try_cancel_block(false).is_err());
+        assert_eq!(state(&server), ("block", Some(4)));
+
+        // Forcing it needs no confirmation
+        assert_eq!(server.try_cancel_block(true), Ok(()));
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(outcomes(&server), [history::Outcome::Cancelled; 3]);
+
+        // ..nor does exceeding the distraction budget, however near the end
+        server.blacklist = vec![Regex::new("reddit").unwrap()];
+        server.distraction_budget = Some(DistractionBudget {
+            limit_s: 30,
+            action: DistractionAction::Cancel,
+        });
+        let reddit = Window {
+            title: "reddit".into(),
+            ..Window::default()
+        };
+        server.start_block(60, None);
+        server.record_current_window(&Window::default(), &[], clock::now());
+        tick(&mut server, Duration::from_secs(55));
+        server.record_current_window(&reddit, &[], clock::now());
+        assert_eq!(state(&server), ("idle", None));
+        assert_eq!(outcomes(&server), [history::Outcome::Cancelled; 4]);
+    }
+
+    #[test]
+    fn test_normalize_paths() {
+        let mut server = server();
+        let inbox = Window {
+            title: "(3) Inbox".into(),
+            ..Window::default()
+        };
+
+        // Titles are recorded as they are, unless asked otherwise
+        assert_eq!(server.window_path(&inbox), ["(3) Inbox"]);
+        server.normalize_paths = true;
+        assert_eq!(server.window_path(&inbox), ["Inbox"]);
+        server.fold_path_case = true;
+        assert_eq!(server.window_path(&inbox), ["inbox"]);
+    }
+
+    #[test]
+    fn test_daily_goal_streak() {
+        // A goal of nothing would be met on every day there has ever been
+        let config = |goal_s: u64| {
+            let mut config = serde_json::to_value(Server::new()).unwrap();
+            config["daily_goal_s"] = goal_s.into();
+            serde_json::from_value::<Server>(config)
+        };
+        assert!(config(0).is_err());
+        assert_eq!(config(1).unwrap().daily_goal_s, Some(1));
+
+        // The walk back over earlier days stops at the oldest block, even
+        // if every day since then met the goal
+        let (_, midnight) = history::local_day(clock::unix_now(), 0).unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(midnight + 3600);
+        let mut server = server_at(start);
+        server.daily_goal_s = Some(1);
+        assert_eq!(server.streaks(), (0, 0));
+        server.start_block(60, None);
+        tick(&mut server, Duration::from_secs(60) + MS);
+        assert_eq!(server.streaks(), (1, 1));
+        tick(&mut server, Duration::from_secs(86400));
+        assert_eq!(server.streaks(), (1, 1));
+
+        // Blocks synced from elsewhere count towards it straight away
+        let mut synced = server.history()[0].clone();
+        synced.start_s -= 86400;
+        synced.end_s -= 86400;
+        synced.machine = Some("laptop".into());
+        server.merge_synced(vec![sync::Snapshot {
+            machine: "laptop".into(),
+            blocks: vec![synced],
+            task_logs: HashMap::new(),
+        }]);
+        assert_eq!(server.streaks(), (2, 2));
     }
 }
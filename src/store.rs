@@ -0,0 +1,423 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! History Storage
+//!
+//! Long-term storage of finished blocks outside the config file, either as
+//! a flat file of JSON lines or (with the `sqlite` feature) in an SQLite
+//! database, which can answer queries over long histories without reading
+//! all of it
+//!
+
+use crate::history::{Block, Outcome};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+
+/// Totals over the blocks in a range of time
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Aggregate {
+    /// Number of completed blocks
+    pub completed: u64,
+    /// Number of cancelled blocks
+    pub cancelled: u64,
+    /// Planned length of the completed blocks, in seconds
+    pub focus_s: u64,
+}
+
+impl Aggregate {
+    /// Add a block to the totals
+    fn add(&mut self, block: &Block) {
+        match block.outcome {
+            Outcome::Completed => {
+                self.completed += 1;
+                self.focus_s += block.duration_s;
+            }
+            Outcome::Cancelled => self.cancelled += 1,
+        }
+    }
+}
+
+/// Somewhere finished blocks are stored
+///
+/// Ranges include blocks which ended at or after their start, and before
+/// their end, in seconds since the UNIX epoch. Blocks are identified by
+/// when they started, so that no block is stored twice.
+///
+/// A cancelled block may be restored by `undoLast` and then completed, so
+/// a completed block replaces a cancelled one which started at the same time.
+pub trait HistoryStore: Send {
+    /// Add a finished block, unless one which started at the same time is
+    /// already stored (and is completed, or this one is not), returning
+    /// whether it was added
+    fn append(&mut self, block: &Block) -> io::Result<bool>;
+
+    /// The blocks which ended in a range, in the order they were added
+    fn range(&self, from_s: u64, to_s: u64) -> io::Result<Vec<Block>>;
+
+    /// Totals over the blocks which ended in a range
+    fn aggregate(&self, from_s: u64, to_s: u64) -> io::Result<Aggregate> {
+        let mut aggregate = Aggregate::default();
+        for block in self.range(from_s, to_s)? {
+            aggregate.add(&block);
+        }
+        Ok(aggregate)
+    }
+}
+
+/// Which backend to store history in, and where
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Config {
+    /// A file of blocks as JSON, one per line
+    Jsonl(PathBuf),
+    /// An SQLite database
+    Sqlite(PathBuf),
+}
+
+impl Config {
+    /// Open the store, creating it if it does not exist
+    ///
+    /// This fails if pomotoshi was built without the store's feature.
+    pub fn open(&self) -> io::Result<Box<dyn HistoryStore>> {
+        match *self {
+            #[cfg(feature = "jsonl")]
+            Config::Jsonl(ref path) => Ok(Box::new(jsonl::Store::open(path.clone())?)),
+            #[cfg(feature = "sqlite")]
+            Config::Sqlite(ref path) => Ok(Box::new(sqlite::Store::open(path)?)),
+            #[allow(unreachable_patterns)]
+            ref other => Err(io::Error::other(format!(
+                "pomotoshi was built without the `{}` feature",
+                other.feature()
+            ))),
+        }
+    }
+
+    /// The cargo feature needed for the store
+    fn feature(&self) -> &'static str {
+        match *self {
+            Config::Jsonl(..) => "jsonl",
+            Config::Sqlite(..) => "sqlite",
+        }
+    }
+}
+
+#[cfg(feature = "jsonl")]
+mod jsonl {
+    use super::HistoryStore;
+    use crate::history::{Block, Outcome};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, BufRead, Read, Seek, Write};
+    use std::path::PathBuf;
+
+    /// A file of blocks as JSON, one per line, which is read in full to
+    /// answer queries
+    pub struct Store {
+        path: PathBuf,
+        /// Start times of the blocks in the file, and whether each completed
+        starts: HashMap<u64, bool>,
+    }
+
+    impl Store {
+        pub fn open(path: PathBuf) -> io::Result<Store> {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            let mut store = Store {
+                path,
+                starts: HashMap::new(),
+            };
+            store.starts = store
+                .range(0, u64::MAX)?
+                .iter()
+                .map(|block| (block.start_s, block.outcome == Outcome::Completed))
+                .collect();
+            Ok(store)
+        }
+    }
+
+    impl HistoryStore for Store {
+        fn append(&mut self, block: &Block) -> io::Result<bool> {
+            let completed = block.outcome == Outcome::Completed;
+            match self.starts.get(&block.start_s) {
+                Some(&stored) if stored || !completed => return Ok(false),
+                _ => {}
+            }
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&self.path)?;
+            // If the last line was cut short, end it, so that it is the
+            // only one lost rather than this one too
+            let mut line = String::new();
+            if file.seek(io::SeekFrom::End(-1)).is_ok() {
+                let mut last = [0];
+                file.read_exact(&mut last)?;
+                if last[0] != b'\n' {
+                    line.push('\n');
+                }
+            }
+            line += &serde_json::to_string(block)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+            self.starts.insert(block.start_s, completed);
+            Ok(true)
+        }
+
+        fn range(&self, from_s: u64, to_s: u64) -> io::Result<Vec<Block>> {
+            let mut blocks: Vec<Block> = vec![];
+            // Index in `blocks` of the block which started at each time
+            let mut indices = HashMap::new();
+            for line in io::BufReader::new(fs::File::open(&self.path)?).lines() {
+                let line = line?;
+                // The last line may be cut short if the daemon was killed
+                let block = match serde_json::from_str::<Block>(&line) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        tracing::warn!(error = %e, line, "skipping bad line of history");
+                        continue;
+                    }
+                };
+                // A later line for the same block is its completion, after
+                // it was cancelled and restored
+                match indices.get(&block.start_s) {
+                    Some(&index) => blocks[index] = block,
+                    None => {
+                        indices.insert(block.start_s, blocks.len());
+                        blocks.push(block);
+                    }
+                }
+            }
+            blocks.retain(|block| from_s <= block.end_s && block.end_s < to_s);
+            Ok(blocks)
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Aggregate, HistoryStore};
+    use crate::history::{Block, Outcome};
+    use std::io;
+    use std::path::Path;
+
+    /// Convert an SQLite error to an I/O one
+    fn to_io(e: rusqlite::Error) -> io::Error {
+        io::Error::other(e)
+    }
+
+    /// An SQLite database of blocks, indexed by when they ended, with the
+    /// totals over them kept in columns of their own
+    pub struct Store {
+        connection: rusqlite::Connection,
+    }
+
+    impl Store {
+        pub fn open(path: &Path) -> io::Result<Store> {
+            let connection = rusqlite::Connection::open(path).map_err(to_io)?;
+            connection
+                .execute_batch(
+                    "CREATE TABLE IF NOT EXISTS blocks (
+                        id INTEGER PRIMARY KEY,
+                        start_s INTEGER NOT NULL UNIQUE,
+                        end_s INTEGER NOT NULL,
+                        completed INTEGER NOT NULL,
+                        duration_s INTEGER NOT NULL,
+                        block TEXT NOT NULL
+                    );
+                    CREATE INDEX IF NOT EXISTS blocks_end_s ON blocks (end_s);",
+                )
+                .map_err(to_io)?;
+            Ok(Store { connection })
+        }
+    }
+
+    /// A time as SQLite stores it
+    fn sql_time(unix_s: u64) -> i64 {
+        i64::try_from(unix_s).unwrap_or(i64::MAX)
+    }
+
+    impl HistoryStore for Store {
+        fn append(&mut self, block: &Block) -> io::Result<bool> {
+            let added = self
+                .connection
+                .execute(
+                    "INSERT INTO blocks (start_s, end_s, completed, duration_s, block)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (start_s) DO UPDATE SET
+                        end_s = excluded.end_s,
+                        completed = excluded.completed,
+                        duration_s = excluded.duration_s,
+                        block = excluded.block
+                    WHERE NOT blocks.completed AND excluded.completed",
+                    (
+                        sql_time(block.start_s),
+                        sql_time(block.end_s),
+                        block.outcome == Outcome::Completed,
+                        sql_time(block.duration_s),
+                        serde_json::to_string(block)?,
+                    ),
+                )
+                .map_err(to_io)?;
+            Ok(added > 0)
+        }
+
+        fn range(&self, from_s: u64, to_s: u64) -> io::Result<Vec<Block>> {
+            let mut statement = self
+                .connection
+                .prepare("SELECT block FROM blocks WHERE ?1 <= end_s AND end_s < ?2 ORDER BY id")
+                .map_err(to_io)?;
+            let rows = statement
+                .query_map((sql_time(from_s), sql_time(to_s)), |row| {
+                    row.get::<_, String>(0)
+                })
+                .map_err(to_io)?;
+            let mut blocks = vec![];
+            for row in rows {
+                blocks.push(serde_json::from_str(&row.map_err(to_io)?)?);
+            }
+            Ok(blocks)
+        }
+
+        fn aggregate(&self, from_s: u64, to_s: u64) -> io::Result<Aggregate> {
+            self.connection
+                .query_row(
+                    "SELECT
+                        COALESCE(SUM(completed), 0),
+                        COALESCE(SUM(1 - completed), 0),
+                        COALESCE(SUM(CASE WHEN completed THEN duration_s ELSE 0 END), 0)
+                    FROM blocks WHERE ?1 <= end_s AND end_s < ?2",
+                    (sql_time(from_s), sql_time(to_s)),
+                    |row| {
+                        Ok(Aggregate {
+                            completed: row.get::<_, i64>(0)? as u64,
+                            cancelled: row.get::<_, i64>(1)? as u64,
+                            focus_s: row.get::<_, i64>(2)? as u64,
+                        })
+                    },
+                )
+                .map_err(to_io)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that a store gives back what was put into it
+    #[allow(dead_code)]
+    fn check_store(config: Config) {
+        let mut store = config.open().unwrap();
+        let block = |end_s: u64, outcome: Outcome| {
+            let mut block = Block::start(60, Some(format!("ended at {}", end_s)));
+            block.finish(outcome);
+            block.start_s = end_s - 60;
+            block.end_s = end_s;
+            block
+        };
+        let blocks = [
+            block(1000, Outcome::Completed),
+            block(2000, Outcome::Cancelled),
+            block(3000, Outcome::Completed),
+        ];
+        for block in &blocks {
+            assert!(store.append(block).unwrap());
+        }
+        // Blocks already stored, e.g. after a batch which failed part way
+        // through is retried, are not stored again
+        assert!(!store.append(&blocks[1]).unwrap());
+
+        assert_eq!(store.range(0, u64::MAX).unwrap(), blocks);
+        assert_eq!(store.range(1000, 3000).unwrap(), blocks[..2]);
+        assert_eq!(store.range(1001, 2000).unwrap(), []);
+        assert_eq!(
+            store.aggregate(0, u64::MAX).unwrap(),
+            Aggregate {
+                completed: 2,
+                cancelled: 1,
+                focus_s: 120,
+            },
+        );
+        assert_eq!(store.aggregate(5000, 6000).unwrap(), Aggregate::default());
+
+        // Stored blocks are still there when the store is reopened
+        drop(store);
+        let mut store = config.open().unwrap();
+        assert_eq!(store.range(2000, 3001).unwrap(), blocks[1..]);
+        assert!(!store.append(&blocks[0]).unwrap());
+        assert_eq!(store.range(0, u64::MAX).unwrap(), blocks);
+
+        // A cancelled block which was restored and then completed replaces
+        // the record of its cancellation, but not the other way around
+        let mut restored = blocks[1].clone();
+        restored.outcome = Outcome::Completed;
+        restored.end_s = 2500;
+        assert!(store.append(&restored).unwrap());
+        assert!(!store.append(&blocks[1]).unwrap());
+        assert!(!store.append(&restored).unwrap());
+        let expected = [blocks[0].clone(), restored, blocks[2].clone()];
+        assert_eq!(store.range(0, u64::MAX).unwrap(), expected);
+        assert_eq!(store.range(2000, 2001).unwrap(), []);
+        assert_eq!(store.aggregate(0, u64::MAX).unwrap().completed, 3);
+        drop(store);
+        let store = config.open().unwrap();
+        assert_eq!(store.range(0, u64::MAX).unwrap(), expected);
+    }
+
+    /// A fresh path for a store to be created at
+    #[allow(dead_code)]
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pomotoshi-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    #[cfg(feature = "jsonl")]
+    fn test_jsonl() {
+        let path = temp_path("history.jsonl");
+        check_store(Config::Jsonl(path.clone()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "jsonl")]
+    fn test_jsonl_truncated() {
+        let path = temp_path("truncated.jsonl");
+        let mut block = Block::start(60, None);
+        block.finish(Outcome::Completed);
+        let line = serde_json::to_string(&block).unwrap();
+        std::fs::write(&path, &line[..line.len() / 2]).unwrap();
+
+        // Only the cut-short line is lost, not the one appended after it
+        let mut store = Config::Jsonl(path.clone()).open().unwrap();
+        assert_eq!(store.range(0, u64::MAX).unwrap(), []);
+        assert!(store.append(&block).unwrap());
+        assert_eq!(store.range(0, u64::MAX).unwrap(), [block.clone()]);
+        let store = Config::Jsonl(path.clone()).open().unwrap();
+        assert_eq!(store.range(0, u64::MAX).unwrap(), [block]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_sqlite() {
+        let path = temp_path("history.sqlite");
+        check_store(Config::Sqlite(path.clone()));
+        std::fs::remove_file(path).unwrap();
+    }
+}
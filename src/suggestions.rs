@@ -0,0 +1,100 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Break Suggestions
+//!
+//! Things to do during breaks and cooldowns, to make them restorative
+//!
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn default_true() -> bool { true }
+fn default_interval_s() -> u64 { 60 }
+
+/// How the next suggestion is chosen
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// Each suggestion in turn
+    #[default]
+    RoundRobin,
+    /// A random suggestion, other than the current one
+    Random,
+}
+
+/// Configuration for break suggestions
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// The suggestions, e.g. "stretch" or "drink some water"
+    items: Vec<String>,
+    #[serde(default)]
+    order: Order,
+    /// How long each suggestion is shown before moving on to the next
+    #[serde(default = "default_interval_s")]
+    interval_s: u64,
+    /// Whether to show the suggestion in the status line
+    #[serde(default = "default_true")]
+    status_line: bool,
+    /// Whether to show a notification for each suggestion
+    #[serde(default)]
+    notify: bool,
+}
+
+impl Config {
+    /// How long each suggestion is shown for
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_s)
+    }
+
+    /// Whether to show the suggestion in the status line
+    pub fn status_line(&self) -> bool {
+        self.status_line
+    }
+
+    /// Whether to show a notification for each suggestion
+    pub fn notify(&self) -> bool {
+        self.notify
+    }
+
+    /// The suggestion with a given index
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.items.get(index).map(String::as_str)
+    }
+
+    /// Choose the index of the suggestion to follow the one at `previous`
+    pub fn next(&self, previous: Option<usize>) -> Option<usize> {
+        let len = self.items.len();
+        if len == 0 {
+            return None;
+        }
+        let next = match (self.order, previous) {
+            (Order::RoundRobin, Some(previous)) => previous + 1,
+            (Order::RoundRobin, None) => 0,
+            (Order::Random, previous) => {
+                // No need for a real RNG; the clock is unpredictable enough
+                let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .subsec_nanos() as usize;
+                match previous {
+                    // Skip over the previous suggestion so it isn't repeated
+                    Some(previous) if len > 1 => previous + 1 + nanos % (len - 1),
+                    _ => nanos,
+                }
+            }
+        };
+        Some(next % len)
+    }
+}
@@ -0,0 +1,201 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Multi-Machine Sync
+//!
+//! Merging block history and task logs between machines through a shared
+//! directory (e.g. one synced by Syncthing or Dropbox). Each machine only
+//! ever writes its own file, so the sync tool never sees conflicting edits.
+//!
+
+use crate::history::Block;
+use crate::task::Task;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, io};
+
+fn default_machine() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_else(|_| "localhost".into())
+}
+fn default_interval_s() -> u64 { 60 }
+
+/// Configuration for syncing with other machines
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// The shared directory
+    directory: PathBuf,
+    /// The name of this machine, which must be unique among those syncing
+    #[serde(default = "default_machine")]
+    machine: String,
+    /// How often to write our file and read the others
+    #[serde(default = "default_interval_s")]
+    interval_s: u64,
+}
+
+/// What one machine shares with the others
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub machine: String,
+    /// Blocks recorded on the machine
+    pub blocks: Vec<Block>,
+    /// The machine's task logs, by name
+    pub task_logs: HashMap<String, Task>,
+}
+
+impl Config {
+    /// The name of this machine
+    pub fn machine(&self) -> &str {
+        &self.machine
+    }
+
+    /// How often to sync, which is at most once a second
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_s.max(1))
+    }
+
+    /// Write our snapshot to the shared directory, and read everybody else's
+    ///
+    /// Files which can't be parsed, e.g. because they are only partly synced,
+    /// are skipped, as are copies made by the sync tool (e.g. Syncthing's
+    /// `laptop.sync-conflict-....json`), which are recognized by not being
+    /// named after the machine they are from.
+    pub fn exchange(&self, ours: &Snapshot) -> io::Result<Vec<Snapshot>> {
+        fs::create_dir_all(&self.directory)?;
+        // Write to a hidden file and rename it, so that the sync tool never
+        // sees a partly-written file
+        let path = self.directory.join(format!("{}.json", self.machine));
+        let tmp = self.directory.join(format!(".{}.json.tmp", self.machine));
+        fs::write(&tmp, serde_json::to_vec(ours)?)?;
+        fs::rename(&tmp, &path)?;
+
+        let mut theirs = vec![];
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            let stem = match (path.file_stem(), path.extension()) {
+                (Some(stem), Some(ext)) if ext == "json" => stem.to_string_lossy().into_owned(),
+                _ => continue,
+            };
+            if stem == self.machine || stem.starts_with('.') {
+                continue;
+            }
+            let snapshot: Snapshot = match fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+            {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = e, "skipping sync file");
+                    continue;
+                }
+            };
+            if snapshot.machine == stem {
+                theirs.push(snapshot);
+            }
+        }
+        Ok(theirs)
+    }
+}
+
+/// Merge another machine's blocks into our history, which is kept in order
+/// of start time
+///
+/// Blocks are identified by their machine and start time. Blocks from the
+/// machine are replaced by the versions in the snapshot, e.g. so notes added
+/// since the last sync are picked up, and removed if they are no longer in
+/// it, e.g. because a cancellation was undone.
+pub fn merge_blocks(history: &mut Vec<Block>, theirs: &Snapshot) {
+    let machine = Some(&theirs.machine);
+    let starts: HashSet<u64> = theirs.blocks.iter().map(|block| block.start_s).collect();
+    history.retain(|block| block.machine.as_ref() != machine || starts.contains(&block.start_s));
+
+    let mut index: HashMap<u64, usize> = HashMap::new();
+    for (i, block) in history.iter().enumerate() {
+        if block.machine.as_ref() == machine {
+            index.insert(block.start_s, i);
+        }
+    }
+    for block in &theirs.blocks {
+        let mut block = block.clone();
+        block.machine = Some(theirs.machine.clone());
+        match index.get(&block.start_s) {
+            Some(&i) => history[i] = block,
+            None => history.push(block),
+        }
+    }
+    // This is stable, so blocks which started at the same time keep their order
+    history.sort_by_key(|block| block.start_s);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Outcome;
+
+    fn block(start_s: u64, machine: Option<&str>) -> Block {
+        let mut block = Block::start(1500, None);
+        block.start_s = start_s;
+        block.end_s = start_s + 1500;
+        block.outcome = Outcome::Completed;
+        block.machine = machine.map(String::from);
+        block
+    }
+
+    #[test]
+    fn test_merge_blocks() {
+        let mut history = vec![block(100, None), block(5000, None)];
+        let mut theirs = Snapshot {
+            machine: "laptop".into(),
+            blocks: vec![block(2000, None), block(9000, None)],
+            task_logs: HashMap::new(),
+        };
+        merge_blocks(&mut history, &theirs);
+        let order: Vec<_> = history
+            .iter()
+            .map(|b| (b.start_s, b.machine.as_deref()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (100, None),
+                (2000, Some("laptop")),
+                (5000, None),
+                (9000, Some("laptop"))
+            ],
+        );
+
+        // Merging again changes nothing, except to pick up edits and removals
+        theirs.blocks[0].notes.push("reviewed PR".into());
+        theirs.blocks.pop();
+        merge_blocks(&mut history, &theirs);
+        let order: Vec<_> = history
+            .iter()
+            .map(|b| (b.start_s, b.machine.as_deref()))
+            .collect();
+        assert_eq!(order, [(100, None), (2000, Some("laptop")), (5000, None)]);
+        assert_eq!(history[1].notes, ["reviewed PR"]);
+    }
+
+    #[tokio::test]
+    async fn test_interval() {
+        let config: Config =
+            serde_json::from_str(r#"{"directory": "/sync", "interval_s": 0}"#).unwrap();
+        assert_eq!(config.interval(), Duration::from_secs(1));
+        // This would panic if given a zero interval
+        drop(tokio::time::interval(config.interval()));
+    }
+}
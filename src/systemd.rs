@@ -0,0 +1,56 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Systemd
+//!
+//! Lets systemd supervise the daemon, when it is run as a `Type=notify`
+//! service. Outside of systemd these do nothing.
+//!
+
+use crate::handle::Handle;
+use sd_notify::NotifyState;
+use std::time::Duration;
+
+/// Tell systemd that startup has finished
+pub fn notify_ready() -> std::io::Result<()> {
+    sd_notify::notify(false, &[NotifyState::Ready])
+}
+
+/// Ping the systemd watchdog, if `WatchdogSec` is set in the unit, for as
+/// long as the daemon is responsive
+///
+/// The daemon runs on a single thread, so if anything hangs, including the
+/// lock on the server, this stops pinging and systemd restarts us.
+pub async fn watchdog(handle: Handle) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // Ping twice per timeout, as recommended by `sd_watchdog_enabled(3)`
+    let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        let mut lock = handle.lock();
+        match sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    lock.signal_error(&format!("pinging systemd watchdog: {}", e));
+                }
+                failing = true;
+            }
+        }
+    }
+}
@@ -18,11 +18,42 @@
 //! the title of the active window and used for time-tracking
 //!
 
+use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::iter;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::time::Duration;
+use std::{fmt, fs, io};
+
+// Regexes used to classify window titles, which are compiled only once since
+// classification happens on every tick.
+static QUTE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:\[\d{1,2}%\] )?(.*) - qutebrowser").unwrap());
+static FIREFOX_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(.*) — Mozilla Firefox(?: Private Browsing)?(?: — (.*))?").unwrap());
+static CHROME_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(.*) - Google Chrome(?: - (.*))?").unwrap());
+static GITHUB_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(.*) · (Pull Request|Issue|Discussion) (#\d*) · (.*)").unwrap());
+static GITLAB_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(.*) \(([!#]\d*)\) · (Merge requests|Issues) · (.*) · GitLab").unwrap()
+});
+static MEET_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^Meet [-–] (.*)").unwrap());
+static JITSI_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(?:(.*) \| )?Jitsi Meet$").unwrap());
+static JIRA_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[(([A-Z][A-Z0-9_]*)-\d+)\] (.*) - (?:.* )?(?:Jira|JIRA)").unwrap());
+static ZOOM_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^Zoom (Meeting|Webinar)").unwrap());
+static TMUX_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.*) \(tmux:(.*)/(.*)\)").unwrap());
+static VIM_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(.+?)(?: [-+=]+)? \((.*)\) - (?:N?VIM|Nvim)\s*$").unwrap());
+static COUNT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(?:[(\[]\d+\+?[)\]]\s+)?(.*?)(?:\s+[(\[]\d+\+?[)\]])?$").unwrap());
+static SHELL_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^([\w.-]+@[\w.-]+): (.*?)\s*$").unwrap());
+
+/// Name of the task which small tasks are folded into by `fold_small_leaves`
+pub const OTHER: &str = "(other)";
 
 /// Node in the tree of "units of work"
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -40,12 +71,24 @@ impl Task {
         }
     }
 
+    /// Read a task tree from a file written by `write_file`
+    pub fn read_file(path: &Path) -> io::Result<Task> {
+        let fh = fs::File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(fh))?)
+    }
+
+    /// Write the task tree to a file, as JSON
+    pub fn write_file(&self, path: &Path) -> io::Result<()> {
+        let fh = fs::File::create(path)?;
+        Ok(serde_json::to_writer(io::BufWriter::new(fh), self)?)
+    }
+
     /// Add time to a task, specified by a path made of string indices
     ///
     /// The string indices are given in reverse order so that we can efficiently
-    /// pop them off as a stack. In general, this function shouldn't be used
-    /// directly; it is easier to use `add_time` which parses a window title
-    /// into an appropriately formed path for you.
+    /// pop them off as a stack. Such paths are usually obtained from
+    /// `title_to_path`, which parses a window title into an appropriately
+    /// formed path.
     pub fn add_time_path(&mut self, mut path: Vec<String>, time: Duration) {
         self.focus_time += time;
         if let Some(child) = path.pop() {
@@ -56,77 +99,549 @@ impl Task {
         }
     }
 
-    /// Add time to a task, specified by its window title
-    pub fn add_time(&mut self, title: &str, time: Duration) {
-        // FIXME actually split up the title
-        self.add_time_path(title_to_path(title), time);
+    /// Add all the time recorded in another task tree to this one
+    pub fn merge(&mut self, other: &Task) {
+        self.focus_time += other.focus_time;
+        for (name, child) in &other.children {
+            self.children
+                .entry(name.clone())
+                .or_insert_with(Task::new_root)
+                .merge(child);
+        }
+    }
+
+    /// Fold leaf tasks with less than `min` time into an "(other)" task under
+    /// their parent, except for those which also appear in `recent`, e.g.
+    /// because time was recorded against them lately. Returns the number of
+    /// tasks folded.
+    pub fn fold_small_leaves(&mut self, min: Duration, recent: Option<&Task>) -> usize {
+        let mut count = 0;
+        let mut folded = Duration::ZERO;
+        let names: Vec<String> = self.children.keys().cloned().collect();
+        for name in names {
+            if name == OTHER {
+                continue;
+            }
+            let child_recent = recent.and_then(|recent| recent.children.get(&name));
+            let child = self.children.get_mut(&name).expect("name from keys");
+            if !child.children.is_empty() {
+                count += child.fold_small_leaves(min, child_recent);
+            } else if child.focus_time < min && child_recent.is_none() {
+                folded += child.focus_time;
+                self.children.remove(&name);
+                count += 1;
+            }
+        }
+        if !folded.is_zero() {
+            self.children
+                .entry(OTHER.to_owned())
+                .or_insert_with(Task::new_root)
+                .focus_time += folded;
+        }
+        count
+    }
+
+    /// Remove time from a task, specified by a path made of string indices
+    ///
+    /// As with `add_time_path`, the path is given in reverse order. Returns
+    /// false, without modifying anything, if the path does not exist or does
+    /// not have at least `time` recorded directly against it, rather than
+    /// against its subtasks, whose time it would otherwise be left with less
+    /// than. Any tasks whose time drops to zero are removed from the tree.
+    pub fn remove_time_path(&mut self, path: Vec<String>, time: Duration) -> bool {
+        match self.task_at_path(&path).map(Task::own_time) {
+            Some(existing) if existing >= time => {
+                self.remove_time_path_internal(path, time);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Helper for `remove_time_path` which assumes the path has enough time
+    fn remove_time_path_internal(&mut self, mut path: Vec<String>, time: Duration) {
+        self.focus_time -= time;
+        if let Some(child) = path.pop() {
+            let child_task = self
+                .children
+                .get_mut(&child)
+                .expect("path checked by caller");
+            child_task.remove_time_path_internal(path, time);
+            if child_task.focus_time == Duration::ZERO {
+                self.children.remove(&child);
+            }
+        }
+    }
+
+    /// Move time from one task to another, both specified by reversed paths
+    ///
+    /// Returns false, without modifying anything, if the source path does not
+    /// have at least `time` recorded directly against it.
+    pub fn move_time_path(&mut self, from: Vec<String>, to: Vec<String>, time: Duration) -> bool {
+        if self.remove_time_path(from, time) {
+            self.add_time_path(to, time);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The time recorded against a path (given in reverse order), if it exists
+    pub fn time_at_path(&self, path: &[String]) -> Option<Duration> {
+        self.task_at_path(path).map(|task| task.focus_time)
+    }
+
+    /// The task at a path (given in reverse order), if it exists
+    fn task_at_path(&self, path: &[String]) -> Option<&Task> {
+        match path.split_last() {
+            None => Some(self),
+            Some((child, rest)) => self.children.get(child)?.task_at_path(rest),
+        }
+    }
+
+    /// The time recorded directly against this task, rather than against
+    /// one of its subtasks
+    fn own_time(&self) -> Duration {
+        let children_time: Duration = self.children.values().map(|c| c.focus_time).sum();
+        self.focus_time.saturating_sub(children_time)
+    }
+
+    /// The path (root first) to the task with the most time, following the
+    /// child with the most time at each level
+    pub fn top_path(&self) -> Vec<String> {
+        let mut ret = vec![];
+        let mut task = self;
+        while let Some((name, child)) = task
+            .children
+            .iter()
+            .max_by(|(n1, c1), (n2, c2)| c1.focus_time.cmp(&c2.focus_time).then(n2.cmp(n1)))
+        {
+            ret.push(name.clone());
+            task = child;
+        }
+        ret
+    }
+
+    /// Every path (root first) with time recorded directly against it, rather
+    /// than against one of its subtasks, along with that time, most time first
+    pub fn leaf_paths(&self) -> Vec<(Vec<String>, Duration)> {
+        let mut ret = vec![];
+        self.leaf_paths_internal(&mut vec![], &mut ret);
+        ret.sort_by(|(p1, t1), (p2, t2)| t2.cmp(t1).then(p1.cmp(p2)));
+        ret
+    }
+
+    /// Helper for `leaf_paths` which collects paths below `prefix`
+    fn leaf_paths_internal(
+        &self,
+        prefix: &mut Vec<String>,
+        out: &mut Vec<(Vec<String>, Duration)>,
+    ) {
+        let own_time = self.own_time();
+        if !prefix.is_empty() && !own_time.is_zero() {
+            out.push((prefix.clone(), own_time));
+        }
+        for (name, child) in &self.children {
+            prefix.push(name.clone());
+            child.leaf_paths_internal(prefix, out);
+            prefix.pop();
+        }
+    }
+
+    /// Render the task and all its children as a nested markdown list, with
+    /// bold totals and percentage bars, appending notes to the tasks at the
+    /// given (root-first) paths
+    pub fn to_markdown_with_marks(&self, name: &str, marks: &[(Vec<String>, String)]) -> String {
+        let total_s = self.focus_time.as_secs_f64().max(1.0);
+        let mut ret = String::new();
+        self.to_markdown_internal(name, 0, &mut vec![], total_s, marks, &mut ret);
+        ret
+    }
+
+    /// Helper for `to_markdown_with_marks` which adds a list item for this
+    /// task and its children
+    fn to_markdown_internal(
+        &self,
+        name: &str,
+        indent: usize,
+        path: &mut Vec<String>,
+        total_s: f64,
+        marks: &[(Vec<String>, String)],
+        out: &mut String,
+    ) {
+        const BAR_WIDTH: usize = 10;
+        let escaped: String = name
+            .trim()
+            .chars()
+            .flat_map(|ch| {
+                let escape = "\\`*_[]<>#|".contains(ch);
+                escape.then_some('\\').into_iter().chain(Some(ch))
+            })
+            .collect();
+        let fraction = self.focus_time.as_secs_f64() / total_s;
+        let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+        let bar: String = std::iter::repeat_n('█', filled)
+            .chain(std::iter::repeat_n('░', BAR_WIDTH.saturating_sub(filled)))
+            .collect();
+
+        out.extend(std::iter::repeat_n(' ', indent));
+        out.push_str(&format!(
+            "- {} **{}** `{}` {:.1}%",
+            escaped,
+            hours_minutes_seconds(self.focus_time),
+            bar,
+            100.0 * fraction
+        ));
+        for (_, mark) in marks.iter().filter(|(p, _)| p == path) {
+            out.push(' ');
+            out.push_str(mark);
+        }
+        out.push('\n');
+
+        let mut sorted_children: Vec<_> = self.children.iter().collect();
+        sorted_children.sort_by_key(|(_, c)| -(c.focus_time.as_millis() as i64));
+        for (child_name, child) in sorted_children {
+            path.push(child_name.clone());
+            child.to_markdown_internal(child_name, indent + 2, path, total_s, marks, out);
+            path.pop();
+        }
+    }
+
+    /// Render the task and all its children as a Graphviz DOT graph, with
+    /// nodes sized by their time and those at the given (root-first) paths
+    /// outlined in red and labelled with their notes
+    pub fn to_dot_with_marks(&self, name: &str, marks: &[(Vec<String>, String)]) -> String {
+        let mut ret = String::from("digraph tasks {\n    node [shape=box];\n");
+        let total_s = self.focus_time.as_secs_f64().max(1.0);
+        let mut next_id = 0;
+        self.to_dot_internal(name, &mut vec![], total_s, marks, &mut next_id, &mut ret);
+        ret += "}\n";
+        ret
+    }
+
+    /// Helper for `to_dot_with_marks` which adds a node for this task and its
+    /// children, returning this task's node ID
+    fn to_dot_internal(
+        &self,
+        name: &str,
+        path: &mut Vec<String>,
+        total_s: f64,
+        marks: &[(Vec<String>, String)],
+        next_id: &mut usize,
+        out: &mut String,
+    ) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+
+        let escape = |s: &str| {
+            s.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        };
+        let focus_s = self.focus_time.as_secs_f64();
+        let pcnt = 100.0 * focus_s / total_s;
+        let mut label = format!("{}\\n{:.0}s ({:.1}%)", escape(name.trim()), focus_s, pcnt);
+        let mut style = String::new();
+        for (_, mark) in marks.iter().filter(|(p, _)| p == path) {
+            label += "\\n";
+            label += &escape(mark);
+            style = ", color=red".into();
+        }
+        // Scale the area of each node, rather than its width, with its time
+        let fontsize = 10.0 + 30.0 * (focus_s / total_s).sqrt();
+        out.push_str(&format!(
+            "    n{} [label=\"{}\", fontsize={:.1}{}];\n",
+            id, label, fontsize, style
+        ));
+
+        let mut sorted_children: Vec<_> = self.children.iter().collect();
+        sorted_children.sort_by_key(|(_, c)| -(c.focus_time.as_millis() as i64));
+        for (child_name, child) in sorted_children {
+            path.push(child_name.clone());
+            let child_id = child.to_dot_internal(child_name, path, total_s, marks, next_id, out);
+            path.pop();
+            out.push_str(&format!("    n{} -> n{};\n", id, child_id));
+        }
+        id
+    }
+
+    /// Stringify (as a multi-line string) the task and all its children,
+    /// appending notes to the tasks at the given (root-first) paths
+    pub fn to_string_with_marks(&self, marks: &[(Vec<String>, String)]) -> String {
+        let focus_s = self.focus_time.as_millis() as f64 / 1000.0;
+        let marks: Vec<(&[String], &str)> = marks
+            .iter()
+            .map(|(path, mark)| (&path[..], &mark[..]))
+            .collect();
+        self.to_string_internal("", 0, focus_s, &marks)
     }
 
     /// Stringify an individual task
-    fn to_string_internal(&self, name: &str, indent: usize, total_s: f64) -> String {
+    ///
+    /// Marks are given relative to this task.
+    fn to_string_internal(
+        &self,
+        name: &str,
+        indent: usize,
+        total_s: f64,
+        marks: &[(&[String], &str)],
+    ) -> String {
         let focus_s = self.focus_time.as_millis() as f64 / 1000.0;
         let focus_pcnt = 100.0 * focus_s / total_s;
 
         let mut ret = String::new();
-        ret.extend(iter::repeat(' ').take(indent));
-        ret += &format!("- [{:5.2}% {:6.2}s] {}\n", focus_pcnt, focus_s, name.trim());
+        ret.extend(std::iter::repeat_n(' ', indent));
+        ret += &format!("- [{:5.2}% {:6.2}s] {}", focus_pcnt, focus_s, name.trim());
+        for (_, mark) in marks.iter().filter(|(path, _)| path.is_empty()) {
+            ret += " ";
+            ret += mark;
+        }
+        ret += "\n";
         let mut sorted_children: Vec<_> = self.children.iter().collect();
         sorted_children.sort_by_key(|(_, c)| -(c.focus_time.as_millis() as i64));
         for (name, child) in sorted_children {
-            ret += &child.to_string_internal(name, indent + 4, total_s);
+            let child_marks: Vec<_> = marks
+                .iter()
+                .filter_map(|(path, mark)| match path.split_first() {
+                    Some((first, rest)) if first == name => Some((rest, *mark)),
+                    _ => None,
+                })
+                .collect();
+            ret += &child.to_string_internal(name, indent + 4, total_s, &child_marks);
         }
         ret
     }
 }
 
-impl ToString for Task {
+/// Time recorded against tasks during a single interval
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+struct Bucket {
+    /// Length of the interval, which starts at the bucket's key
+    len_s: u64,
+    tasks: Task,
+}
+
+/// Time recorded against tasks, split into buckets by when it was recorded,
+/// so that it can be asked what was being done at a given time
+#[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Buckets {
+    /// Buckets by start time, in seconds since the UNIX epoch
+    buckets: BTreeMap<u64, Bucket>,
+}
+
+impl Buckets {
+    /// Add time to a task, specified by a reversed path as in
+    /// `Task::add_time_path`, in the bucket of the given length containing
+    /// `now_s`. Returns whether a new bucket had to be started.
+    pub fn add_time_path(
+        &mut self,
+        now_s: u64,
+        len_s: u64,
+        path: Vec<String>,
+        time: Duration,
+    ) -> bool {
+        let len_s = len_s.max(1);
+        let start_s = now_s - now_s % len_s;
+        let mut new = false;
+        let bucket = self.buckets.entry(start_s).or_insert_with(|| {
+            new = true;
+            Bucket {
+                len_s,
+                tasks: Task::new_root(),
+            }
+        });
+        bucket.tasks.add_time_path(path, time);
+        new
+    }
+
+    /// Merge all buckets which start before `before_s` into buckets of the
+    /// given (larger) length
+    pub fn compact(&mut self, before_s: u64, len_s: u64) {
+        let len_s = len_s.max(1);
+        let recent = self.buckets.split_off(&before_s);
+        let old = std::mem::replace(&mut self.buckets, recent);
+        for (start_s, bucket) in old {
+            let merged = self
+                .buckets
+                .entry(start_s - start_s % len_s)
+                .or_insert_with(|| Bucket {
+                    len_s,
+                    tasks: Task::new_root(),
+                });
+            merged.len_s = merged.len_s.max(len_s);
+            merged.tasks.merge(&bucket.tasks);
+        }
+    }
+
+    /// Forget all buckets which end at or before `before_s`
+    pub fn forget(&mut self, before_s: u64) {
+        self.buckets
+            .retain(|start_s, bucket| start_s + bucket.len_s > before_s);
+    }
+
+    /// All time recorded in buckets overlapping the interval between two times
+    pub fn between(&self, start_s: u64, end_s: u64) -> Task {
+        let mut ret = Task::new_root();
+        for (_, bucket) in self
+            .buckets
+            .range(..end_s)
+            .filter(|(bucket_start_s, bucket)| *bucket_start_s + bucket.len_s > start_s)
+        {
+            ret.merge(&bucket.tasks);
+        }
+        ret
+    }
+}
+
+/// Format a duration as hours, minutes and seconds, omitting leading zero units
+fn hours_minutes_seconds(time: Duration) -> String {
+    let s = time.as_secs();
+    if s >= 3600 {
+        format!("{}h{:02}m{:02}s", s / 3600, s / 60 % 60, s % 60)
+    } else if s >= 60 {
+        format!("{}m{:02}s", s / 60, s % 60)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+impl fmt::Display for Task {
     /// Stringify (as a multi-line string) the task and all its children
-    fn to_string(&self) -> String {
-        let focus_s = self.focus_time.as_millis() as f64 / 1000.0;
-        self.to_string_internal("", 0, focus_s)
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_string_with_marks(&[]))
     }
 }
 
-fn title_to_path(title: &str) -> Vec<String> {
-    // Blockstream-specific qutebrowser
-    if title.contains(" - qutebrowser") {
-        if title.contains("Rocket.Chat") {
-            return vec!["Rocket.Chat".into(), "Blockstream".into()];
+/// A browser window title, split into its parts
+struct BrowserTitle<'t> {
+    /// The title of the page being viewed
+    page: &'t str,
+    /// The browser's name
+    browser: &'static str,
+    /// The browser profile, if the browser puts it in the title
+    profile: Option<&'t str>,
+}
+
+impl<'t> BrowserTitle<'t> {
+    /// Recognize a window title as belonging to a browser
+    ///
+    /// For Firefox and Chrome, a profile name appended after the browser
+    /// name (e.g. `Page — Mozilla Firefox — Work`) is extracted.
+    fn parse(title: &'t str) -> Option<BrowserTitle<'t>> {
+        if let Some(qute) = QUTE_REGEX.captures(title) {
+            return Some(BrowserTitle {
+                page: qute.get(1).unwrap().as_str(),
+                browser: "qutebrowser",
+                profile: None,
+            });
         }
-        if title.contains("Blockstream Mail") {
-            return vec!["Gmail".into(), "Blockstream".into()];
+
+        if let Some(firefox) = FIREFOX_REGEX.captures(title) {
+            return Some(BrowserTitle {
+                page: firefox.get(1).unwrap().as_str(),
+                browser: "Firefox",
+                profile: firefox.get(2).map(|m| m.as_str()),
+            });
         }
-        if title.contains("Blockstream - Calendar") {
-            return vec!["Calendar".into(), "Blockstream".into()];
+
+        if let Some(chrome) = CHROME_REGEX.captures(title) {
+            return Some(BrowserTitle {
+                page: chrome.get(1).unwrap().as_str(),
+                browser: "Chrome",
+                profile: chrome.get(2).map(|m| m.as_str()),
+            });
         }
+
+        None
     }
+}
 
-    // Github-specific qutebrowser
-    if title.contains("Notifications - qutebrowser") {
-        return vec!["Notifications".into(), "Github".into()];
+/// Parse the title of a web page into a (reversed) task path, if it is a
+/// page that we know how to organize
+fn page_to_path(page: &str) -> Option<Vec<String>> {
+    // Blockstream-specific
+    if page.contains("Rocket.Chat") {
+        return Some(vec!["Rocket.Chat".into(), "Blockstream".into()]);
     }
-    let github_regex = Regex::new(
-        r"(?:\[\d{1,2}%\] )?(.*) · (Pull Request|Issue|Discussion) (#\d*) · (.*) - qutebrowser",
-    )
-    .unwrap();
-    if let Some(github) = github_regex.captures(title) {
-        return vec![
+    if page.contains("Blockstream Mail") {
+        return Some(vec!["Gmail".into(), "Blockstream".into()]);
+    }
+    if page.contains("Blockstream - Calendar") {
+        return Some(vec!["Calendar".into(), "Blockstream".into()]);
+    }
+
+    // Github-specific
+    if page.ends_with("Notifications") {
+        return Some(vec!["Notifications".into(), "Github".into()]);
+    }
+    if let Some(github) = GITHUB_REGEX.captures(page) {
+        return Some(vec![
             format!("{} {}", &github[3], &github[1]),
             github[2].into(),
             github[4].into(),
             "Github".into(),
-        ];
+        ]);
     }
 
-    // General qutebrowser
-    let qute_regex = Regex::new(r"(?:\[\d{1,2}%\] )?(.*) - (qutebrowser)").unwrap();
-    if let Some(qute) = qute_regex.captures(title) {
-        return vec![qute[1].into(), qute[2].into()];
+    // Gitlab-specific
+    if let Some(gitlab) = GITLAB_REGEX.captures(page) {
+        let kind = match &gitlab[3] {
+            "Merge requests" => "Merge Request",
+            _ => "Issue",
+        };
+        return Some(vec![
+            format!("{} {}", &gitlab[2], &gitlab[1]),
+            kind.into(),
+            gitlab[4].replace(" / ", "/"),
+            "GitLab".into(),
+        ]);
+    }
+
+    // Meetings
+    if let Some(meet) = MEET_REGEX.captures(page) {
+        return Some(vec![
+            meet[1].into(),
+            "Google Meet".into(),
+            "Meetings".into(),
+        ]);
+    }
+    if let Some(jitsi) = JITSI_REGEX.captures(page) {
+        let room = jitsi.get(1).map(|m| m.as_str()).unwrap_or("Jitsi Meet");
+        return Some(vec![room.into(), "Jitsi".into(), "Meetings".into()]);
+    }
+
+    // Jira-specific
+    if let Some(jira) = JIRA_REGEX.captures(page) {
+        return Some(vec![
+            format!("{} {}", &jira[1], &jira[3]),
+            jira[2].into(),
+            "Jira".into(),
+        ]);
+    }
+
+    None
+}
+
+/// Parse a window title into a (reversed) task path
+pub fn title_to_path(title: &str) -> Vec<String> {
+    // Browsers
+    if let Some(browser) = BrowserTitle::parse(title) {
+        if let Some(path) = page_to_path(browser.page) {
+            return path;
+        }
+        let mut path = vec![browser.page.into()];
+        path.extend(browser.profile.map(String::from));
+        path.push(browser.browser.into());
+        return path;
+    }
+
+    // Zoom
+    if let Some(zoom) = ZOOM_REGEX.captures(title) {
+        return vec![zoom[1].into(), "Zoom".into(), "Meetings".into()];
     }
 
     // TMux
-    let tmux_regex = Regex::new(r"(.*) \(tmux:(.*)/(.*)\)").unwrap();
-    if let Some(tmux) = tmux_regex.captures(title) {
+    if let Some(tmux) = TMUX_REGEX.captures(title) {
         return vec![
             tmux[1].into(),
             tmux[3].into(),
@@ -135,13 +650,350 @@ fn title_to_path(title: &str) -> Vec<String> {
         ];
     }
 
+    // Vim, using its default `titlestring` of `file.rs + (~/src/project) - VIM`
+    if let Some(vim) = VIM_REGEX.captures(title) {
+        return vec![vim[1].into(), vim[2].into(), "Vim".into()];
+    }
+
+    // Shells which set the title to `user@host: directory`
+    if let Some(shell) = SHELL_REGEX.captures(title) {
+        return vec![shell[2].into(), shell[1].into(), "Terminal".into()];
+    }
+
     vec![title.into()]
 }
 
+/// Normalize each component of a task path, so that titles which differ only
+/// in unread counts (e.g. `(3) Inbox` or `Inbox (3)`) or whitespace, and
+/// optionally in case, are recorded as the same task
+///
+/// Normalizing an already-normalized path leaves it unchanged.
+pub fn normalize_path(path: &mut [String], fold_case: bool) {
+    for component in path {
+        // Strip counts until there are none left, e.g. from `(1) (2) Inbox`,
+        // so that doing this again would not strip any more
+        let mut stripped = component.trim().to_owned();
+        while let Some(inner) = COUNT_REGEX
+            .captures(&stripped)
+            .map(|caps| caps[1].to_owned())
+            .filter(|inner| !inner.is_empty() && *inner != stripped)
+        {
+            stripped = inner;
+        }
+        let mut normalized = stripped.split_whitespace().collect::<Vec<_>>().join(" ");
+        if fold_case {
+            normalized = normalized.to_lowercase();
+        }
+        *component = normalized;
+    }
+}
+
+/// Which tasks a task log records, as regexes matched against task paths
+/// joined root-first with " / ", e.g. `Github / rust-bitcoin / Pull Request`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Filter {
+    /// If non-empty, only tasks matching one of these are recorded
+    #[serde(default, with = "crate::serde_regex::vec")]
+    pub include: Vec<Regex>,
+    /// Tasks matching any of these are not recorded
+    #[serde(default, with = "crate::serde_regex::vec")]
+    pub exclude: Vec<Regex>,
+}
+
+impl Filter {
+    /// Whether a task, given as a reversed path, should be recorded
+    pub fn matches(&self, path: &[String]) -> bool {
+        let joined = path
+            .iter()
+            .rev()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" / ");
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(&joined)))
+            && !self.exclude.iter().any(|re| re.is_match(&joined))
+    }
+}
+
+/// A rule which moves tasks from one part of the tree to another, e.g. to
+/// group the same project together across different tools
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct Alias {
+    /// Root-first path prefix to match, where `*` matches any one component
+    from: Vec<String>,
+    /// Root-first path to replace the matched prefix with
+    to: Vec<String>,
+}
+
+impl Alias {
+    /// If a (reversed) path starts with this alias' prefix, replace the prefix
+    pub fn apply(&self, path: &mut Vec<String>) -> bool {
+        if self.from.len() > path.len() {
+            return false;
+        }
+        let matches = self
+            .from
+            .iter()
+            .zip(path.iter().rev())
+            .all(|(pattern, component)| pattern == "*" || pattern == component);
+        if matches {
+            path.truncate(path.len() - self.from.len());
+            path.extend(self.to.iter().rev().cloned());
+        }
+        matches
+    }
+}
+
+/// Apply the first matching alias, if any, to a (reversed) path
+pub fn apply_aliases(aliases: &[Alias], path: &mut Vec<String>) {
+    for alias in aliases {
+        if alias.apply(path) {
+            return;
+        }
+    }
+}
+
+/// Whether a window title is recognized as belonging to a meeting
+pub fn is_meeting(title: &str) -> bool {
+    title_to_path(title).last().map(String::as_str) == Some("Meetings")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_filter() {
+        let path = |p: &[&str]| p.iter().rev().map(|s| s.to_string()).collect::<Vec<_>>();
+        let pr = path(&["Github", "rust-bitcoin", "Pull Request", "#1 Fix"]);
+        let issue = path(&["Github", "rust-bitcoin", "Issue", "#2 Bug"]);
+        let draft = path(&["Github", "rust-bitcoin", "Pull Request", "#3 WIP: Draft"]);
+
+        assert!(Filter::default().matches(&pr));
+        let filter = Filter {
+            include: vec![Regex::new("^Github / .* / Pull Request").unwrap()],
+            exclude: vec![Regex::new("WIP").unwrap()],
+        };
+        assert!(filter.matches(&pr));
+        assert!(!filter.matches(&issue));
+        assert!(!filter.matches(&draft));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let strings = |path: &[&str]| path.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let aliases = [
+            Alias {
+                from: strings(&["Github", "rust-bitcoin"]),
+                to: strings(&["Projects", "rust-bitcoin"]),
+            },
+            Alias {
+                from: strings(&["tmux", "*", "work-rust-bitcoin"]),
+                to: strings(&["Projects", "rust-bitcoin"]),
+            },
+        ];
+        let alias = |path: &[&str]| {
+            let mut path = strings(path);
+            path.reverse();
+            apply_aliases(&aliases, &mut path);
+            path.reverse();
+            path
+        };
+        assert_eq!(
+            alias(&["Github", "rust-bitcoin", "#123", "Fix it"]),
+            ["Projects", "rust-bitcoin", "#123", "Fix it"],
+        );
+        assert_eq!(
+            alias(&["tmux", "0", "work-rust-bitcoin", "vim"]),
+            ["Projects", "rust-bitcoin", "vim"],
+        );
+        assert_eq!(
+            alias(&["Github", "rust-bitcoin"]),
+            ["Projects", "rust-bitcoin"]
+        );
+        assert_eq!(alias(&["Github", "bdk", "#1"]), ["Github", "bdk", "#1"]);
+        assert_eq!(alias(&["tmux", "0"]), ["tmux", "0"]);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let normalize = |path: &[&str], fold_case| {
+            let mut path: Vec<String> = path.iter().map(|s| s.to_string()).collect();
+            normalize_path(&mut path, fold_case);
+            path
+        };
+        assert_eq!(
+            normalize(&["(1) Inbox", "Gmail"], false),
+            ["Inbox", "Gmail"]
+        );
+        assert_eq!(
+            normalize(&["Inbox (23)", "Gmail"], false),
+            ["Inbox", "Gmail"]
+        );
+        assert_eq!(normalize(&["[99+] Chat"], false), ["Chat"]);
+        assert_eq!(normalize(&["(1) [2] Inbox (3)"], false), ["Inbox"]);
+        assert_eq!(normalize(&["  a \t  b  "], false), ["a b"]);
+        assert_eq!(
+            normalize(&["README.md  ", "Rust-Bitcoin"], true),
+            ["readme.md", "rust-bitcoin"]
+        );
+        // Counts are only stripped when they are separate from the rest of the title
+        assert_eq!(normalize(&["Fix it (#123)"], false), ["Fix it (#123)"]);
+        assert_eq!(normalize(&["f(3)"], false), ["f(3)"]);
+        assert_eq!(normalize(&["(3)"], false), ["(3)"]);
+    }
+
+    #[test]
+    fn test_fold_small_leaves() {
+        let path = |p: &[&str]| p.iter().rev().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut task = Task::new_root();
+        task.add_time_path(path(&["a", "big"]), Duration::from_secs(100));
+        task.add_time_path(path(&["a", "small"]), Duration::from_secs(5));
+        task.add_time_path(path(&["a", "tiny"]), Duration::from_secs(1));
+        task.add_time_path(path(&["a", "recent"]), Duration::from_secs(1));
+        task.add_time_path(path(&["b"]), Duration::from_secs(2));
+        let mut recent = Task::new_root();
+        recent.add_time_path(path(&["a", "recent"]), Duration::from_secs(1));
+
+        assert_eq!(
+            task.fold_small_leaves(Duration::from_secs(10), Some(&recent)),
+            3
+        );
+        assert_eq!(task.time_at_path(&[]), Some(Duration::from_secs(109)));
+        assert_eq!(task.time_at_path(&path(&["a", "small"])), None);
+        assert_eq!(
+            task.time_at_path(&path(&["a", OTHER])),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(
+            task.time_at_path(&path(&["a", "recent"])),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            task.time_at_path(&path(&[OTHER])),
+            Some(Duration::from_secs(2))
+        );
+
+        // Folding again without the recent log folds the leaf which was only
+        // kept for being recent, but not the "(other)" leaves themselves
+        assert_eq!(task.fold_small_leaves(Duration::from_secs(10), None), 1);
+        assert_eq!(
+            task.time_at_path(&path(&["a", OTHER])),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            task.time_at_path(&path(&[OTHER])),
+            Some(Duration::from_secs(2))
+        );
+        // after which folding again does nothing
+        assert_eq!(task.fold_small_leaves(Duration::from_secs(10), None), 0);
+        assert_eq!(task.time_at_path(&[]), Some(Duration::from_secs(109)));
+    }
+
+    #[test]
+    fn test_buckets() {
+        let a = || vec!["a".to_string()];
+        let b = || vec!["b".to_string()];
+        let mut buckets = Buckets::default();
+        assert!(buckets.add_time_path(1000, 300, a(), Duration::from_secs(10)));
+        assert!(!buckets.add_time_path(1100, 300, a(), Duration::from_secs(10)));
+        assert!(buckets.add_time_path(1300, 300, b(), Duration::from_secs(10)));
+        assert!(buckets.add_time_path(4000, 300, b(), Duration::from_secs(10)));
+
+        // 900-1200 and 1200-1500 overlap the second
+        let task = buckets.between(1199, 1201);
+        assert_eq!(task.time_at_path(&a()), Some(Duration::from_secs(20)));
+        assert_eq!(task.time_at_path(&b()), Some(Duration::from_secs(10)));
+        assert_eq!(buckets.between(1500, 3900), Task::new_root());
+
+        // Compacting into hours merges the first two buckets, but leaves the last
+        buckets.compact(3600, 3600);
+        assert_eq!(buckets.buckets.len(), 2);
+        let task = buckets.between(3500, 3599);
+        assert_eq!(task.time_at_path(&[]), Some(Duration::from_secs(30)));
+        let task = buckets.between(3600, 4000);
+        assert_eq!(task.time_at_path(&[]), Some(Duration::from_secs(10)));
+
+        // Forgetting drops whole buckets which have ended
+        buckets.forget(3601);
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.between(0, 3600), Task::new_root());
+        buckets.forget(7200);
+        assert_eq!(buckets, Buckets::default());
+    }
+
+    #[test]
+    fn test_to_markdown() {
+        let mut task = Task::new_root();
+        task.add_time_path(vec!["x".into(), "a_b".into()], Duration::from_secs(90));
+        task.add_time_path(vec!["a_b".into()], Duration::from_secs(30));
+        let marks = [(vec!["a_b".to_string()], "(over)".to_string())];
+        assert_eq!(
+            task.to_markdown_with_marks("log", &marks),
+            "- log **2m00s** `██████████` 100.0%\n  \
+             - a\\_b **2m00s** `██████████` 100.0% (over)\n    \
+             - x **1m30s** `████████░░` 75.0%\n",
+        );
+    }
+
+    #[test]
+    fn test_leaf_paths() {
+        let path = |p: &[&str]| p.iter().rev().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut task = Task::new_root();
+        task.add_time_path(path(&["a", "x"]), Duration::from_secs(10));
+        task.add_time_path(path(&["a"]), Duration::from_secs(3));
+        task.add_time_path(path(&["b"]), Duration::from_secs(20));
+        task.add_time_path(path(&["a", "y"]), Duration::from_secs(10));
+        assert_eq!(
+            task.leaf_paths(),
+            vec![
+                (vec!["b".to_string()], Duration::from_secs(20)),
+                (
+                    vec!["a".to_string(), "x".to_string()],
+                    Duration::from_secs(10)
+                ),
+                (
+                    vec!["a".to_string(), "y".to_string()],
+                    Duration::from_secs(10)
+                ),
+                (vec!["a".to_string()], Duration::from_secs(3)),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_move_time_path() {
+        let path = |p: &[&str]| p.iter().rev().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut task = Task::new_root();
+        task.add_time_path(path(&["a", "x"]), Duration::from_secs(10));
+        task.add_time_path(path(&["a"]), Duration::from_secs(3));
+
+        // Only the time recorded against "a" itself can be moved out of it,
+        // so that its subtasks never add up to more than it
+        let moved = |task: &mut Task, time_s| {
+            task.move_time_path(path(&["a"]), path(&["b"]), Duration::from_secs(time_s))
+        };
+        assert!(!moved(&mut task, 4));
+        assert_eq!(
+            task.time_at_path(&path(&["a"])),
+            Some(Duration::from_secs(13))
+        );
+        assert!(moved(&mut task, 3));
+        assert_eq!(
+            task.time_at_path(&path(&["a"])),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            task.time_at_path(&path(&["b"])),
+            Some(Duration::from_secs(3))
+        );
+        assert!(!moved(&mut task, 1));
+
+        // Moving all of a leaf's time removes it
+        assert!(task.move_time_path(path(&["a", "x"]), path(&["b"]), Duration::from_secs(10)));
+        assert_eq!(task.time_at_path(&path(&["a"])), None);
+        assert_eq!(task.time_at_path(&[]), Some(Duration::from_secs(13)));
+    }
+
     #[test]
     fn test_title_to_path() {
         assert_eq!(
@@ -238,5 +1090,117 @@ mod tests {
                 "Github".to_string(),
             ],
         );
+        assert_eq!(
+            title_to_path("Draft: Add new CI job (!2345) · Merge requests · gitlab-org / gitlab-runner · GitLab - qutebrowser"),
+            vec![
+                "!2345 Draft: Add new CI job".to_string(),
+                "Merge Request".to_string(),
+                "gitlab-org/gitlab-runner".to_string(),
+                "GitLab".to_string(),
+            ],
+        );
+        assert_eq!(
+            title_to_path("[45%] Runner crashes on startup (#310) · Issues · gitlab-org / ci / gitlab-runner · GitLab - qutebrowser"),
+            vec![
+                "#310 Runner crashes on startup".to_string(),
+                "Issue".to_string(),
+                "gitlab-org/ci/gitlab-runner".to_string(),
+                "GitLab".to_string(),
+            ],
+        );
+        assert_eq!(
+            title_to_path("[PROJ-123] Fix the frobnicator - Jira - qutebrowser"),
+            vec![
+                "PROJ-123 Fix the frobnicator".to_string(),
+                "PROJ".to_string(),
+                "Jira".to_string(),
+            ],
+        );
+        assert_eq!(
+            title_to_path("[3%] [OPS2-7] Rotate keys - Acme Corp JIRA - qutebrowser"),
+            vec![
+                "OPS2-7 Rotate keys".to_string(),
+                "OPS2".to_string(),
+                "Jira".to_string(),
+            ],
+        );
+        assert_eq!(
+            title_to_path("Where in the World: Tenaya and Climate Change — Mozilla Firefox"),
+            vec![
+                "Where in the World: Tenaya and Climate Change".to_string(),
+                "Firefox".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("Where in the World — Mozilla Firefox Private Browsing"),
+            vec!["Where in the World".to_string(), "Firefox".to_string()],
+        );
+        assert_eq!(
+            title_to_path("Where in the World - Google Chrome - Work\n"),
+            vec![
+                "Where in the World".to_string(),
+                "Work".to_string(),
+                "Chrome".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("Standardize derives on error types by tcharding · Pull Request #1466 · rust-bitcoin/rust-bitcoin — Mozilla Firefox — Personal"),
+            vec![
+                "#1466 Standardize derives on error types by tcharding".to_string(),
+                "Pull Request".to_string(),
+                "rust-bitcoin/rust-bitcoin".to_string(),
+                "Github".to_string(),
+            ],
+        );
+        assert_eq!(
+            title_to_path("Zoom Meeting\n"),
+            vec![
+                "Meeting".to_string(),
+                "Zoom".to_string(),
+                "Meetings".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("Meet – abc-defg-hij - Google Chrome"),
+            vec![
+                "abc-defg-hij".to_string(),
+                "Google Meet".to_string(),
+                "Meetings".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("WeeklySync | Jitsi Meet — Mozilla Firefox"),
+            vec![
+                "WeeklySync".to_string(),
+                "Jitsi".to_string(),
+                "Meetings".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("server.rs + (~/code/pomotoshi/src) - NVIM\n"),
+            vec![
+                "server.rs".to_string(),
+                "~/code/pomotoshi/src".to_string(),
+                "Vim".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("README.md (~/code/pomotoshi) - VIM"),
+            vec![
+                "README.md".to_string(),
+                "~/code/pomotoshi".to_string(),
+                "Vim".to_string()
+            ],
+        );
+        assert_eq!(
+            title_to_path("apoelstra@camus: ~/code/rust-bitcoin\n"),
+            vec![
+                "~/code/rust-bitcoin".to_string(),
+                "apoelstra@camus".to_string(),
+                "Terminal".to_string()
+            ],
+        );
+        assert!(is_meeting("Meet - abc-defg-hij - qutebrowser"));
+        assert!(!is_meeting("Meeting notes - qutebrowser"));
     }
 }
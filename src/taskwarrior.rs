@@ -0,0 +1,93 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Taskwarrior
+//!
+//! Integration with Taskwarrior, which starts and stops tasks alongside
+//! labelled blocks and annotates them with completed blocks
+//!
+
+use crate::handle::Handle;
+use crate::server::{Event, Transition};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::Stdio;
+use tokio::sync::broadcast;
+
+fn default_command() -> String { "task".into() }
+
+/// Configuration of the Taskwarrior integration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// The Taskwarrior executable
+    #[serde(default = "default_command")]
+    command: String,
+    /// Map from block labels to Taskwarrior filters, e.g. `"review": "project:review +next"`
+    ///
+    /// Blocks whose labels do not appear here (and unlabelled blocks) are ignored.
+    mapping: HashMap<String, String>,
+}
+
+impl Config {
+    /// Run a Taskwarrior command on the task matching a filter
+    async fn run(&self, filter: &str, args: &[&str]) -> bool {
+        let status = tokio::process::Command::new(&self.command)
+            .arg("rc.confirmation=off")
+            .arg("rc.verbose=nothing")
+            .args(filter.split_whitespace())
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        matches!(status, Ok(status) if status.success())
+    }
+}
+
+/// Start and stop Taskwarrior tasks in response to state transitions, forever
+pub async fn run(config: Config, handle: Handle, mut events: broadcast::Receiver<Transition>) {
+    loop {
+        let transition = match events.recv().await {
+            Ok(transition) => transition,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let filter = match transition.label {
+            Some(ref label) => match config.mapping.get(label) {
+                Some(filter) => filter,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        let success = match transition.event {
+            Event::BlockStarted | Event::BlockUnpaused => config.run(filter, &["start"]).await,
+            Event::BlockPaused | Event::BlockCancelled => config.run(filter, &["stop"]).await,
+            Event::BlockCompleted => {
+                let annotation = format!(
+                    "completed {}-minute pomodoro",
+                    transition.duration_s.unwrap_or(0) / 60
+                );
+                config.run(filter, &["stop"]).await
+                    && config.run(filter, &["annotate", &annotation]).await
+            }
+            _ => true,
+        };
+        if !success {
+            let message = format!("taskwarrior: {} failed", transition.event.name());
+            handle.lock().signal_error(&message);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Themes
+//!
+//! Named sets of colors which can be applied all at once
+//!
+
+/// A complete set of colors
+pub struct Theme {
+    pub block_start: (u8, u8, u8),
+    pub block_end: (u8, u8, u8),
+    pub cooldown_start: (u8, u8, u8),
+    pub cooldown_end: (u8, u8, u8),
+    pub idle: &'static str,
+    pub cooldown_background: &'static str,
+    pub warn: &'static str,
+    pub error: &'static str,
+}
+
+/// Look up a theme by name
+pub fn named(name: &str) -> Option<Theme> {
+    match name {
+        "default" => Some(Theme {
+            block_start: (0, 255, 0),
+            block_end: (255, 192, 0),
+            cooldown_start: (255, 0, 0),
+            cooldown_end: (192, 44, 44),
+            idle: "#AAA",
+            cooldown_background: "#FF8",
+            warn: "#FF0",
+            error: "#F00",
+        }),
+        "gruvbox" => Some(Theme {
+            block_start: (0xb8, 0xbb, 0x26),
+            block_end: (0xfa, 0xbd, 0x2f),
+            cooldown_start: (0xfb, 0x49, 0x34),
+            cooldown_end: (0xcc, 0x24, 0x1d),
+            idle: "#928374",
+            cooldown_background: "#3c3836",
+            warn: "#d79921",
+            error: "#cc241d",
+        }),
+        "solarized" => Some(Theme {
+            block_start: (0x85, 0x99, 0x00),
+            block_end: (0xb5, 0x89, 0x00),
+            cooldown_start: (0xdc, 0x32, 0x2f),
+            cooldown_end: (0xcb, 0x4b, 0x16),
+            idle: "#93a1a1",
+            cooldown_background: "#073642",
+            warn: "#b58900",
+            error: "#dc322f",
+        }),
+        "high-contrast" => Some(Theme {
+            block_start: (0, 255, 0),
+            block_end: (255, 255, 0),
+            cooldown_start: (255, 0, 255),
+            cooldown_end: (255, 0, 0),
+            idle: "#FFF",
+            cooldown_background: "#000",
+            warn: "#FF0",
+            error: "#F00",
+        }),
+        _ => None,
+    }
+}
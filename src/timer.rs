@@ -0,0 +1,104 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Timers
+//!
+//! Simple named countdowns which run alongside the main block
+//!
+
+use std::time::{Duration, Instant};
+
+/// How long a finished timer stays in the status line
+const FINISHED_DISPLAY: Duration = Duration::from_secs(10);
+
+/// The state machine of a single timer
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Timer {
+    /// Counting down
+    Running { end_time: Instant },
+    /// Paused, with some time left
+    Paused { remaining: Duration },
+    /// Ran to completion at the given time
+    Finished { at: Instant },
+}
+
+impl Timer {
+    /// Start a new timer
+    pub fn start(duration: Duration, now: Instant) -> Timer {
+        Timer::Running {
+            end_time: now + duration,
+        }
+    }
+
+    /// Pause a running timer, or resume a paused one
+    pub fn toggle_pause(&mut self, now: Instant) {
+        *self = match *self {
+            Timer::Running { end_time } => Timer::Paused {
+                remaining: end_time.saturating_duration_since(now),
+            },
+            Timer::Paused { remaining } => Timer::Running {
+                end_time: now + remaining,
+            },
+            Timer::Finished { at } => Timer::Finished { at },
+        };
+    }
+
+    /// Advance the timer, returning whether it just finished
+    pub fn tick(&mut self, now: Instant) -> bool {
+        match *self {
+            Timer::Running { end_time } if now >= end_time => {
+                *self = Timer::Finished { at: now };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the timer has finished and been displayed long enough to remove
+    pub fn is_expired(&self, now: Instant) -> bool {
+        match *self {
+            Timer::Finished { at } => now >= at + FINISHED_DISPLAY,
+            _ => false,
+        }
+    }
+
+    /// When the text displayed for the timer next changes, or it next needs
+    /// advancing, or `None` if it is paused
+    pub fn next_change(&self, now: Instant) -> Option<Instant> {
+        match *self {
+            Timer::Running { end_time } => Some(crate::clock::next_tick(
+                end_time,
+                now,
+                Duration::from_secs(1),
+            )),
+            Timer::Paused { .. } => None,
+            Timer::Finished { at } => Some(at + FINISHED_DISPLAY),
+        }
+    }
+
+    /// The text to display for the timer, without its name
+    pub fn display(&self, now: Instant) -> String {
+        let rem_s = match *self {
+            Timer::Running { end_time } => end_time.saturating_duration_since(now).as_secs(),
+            Timer::Paused { remaining } => remaining.as_secs(),
+            Timer::Finished { .. } => return "done".into(),
+        };
+        let paused = if matches!(*self, Timer::Paused { .. }) {
+            " (paused)"
+        } else {
+            ""
+        };
+        format!("{:02}:{:02}{}", rem_s / 60, rem_s % 60, paused)
+    }
+}
@@ -0,0 +1,186 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Terminal Dashboard
+//!
+//! An interactive dashboard, for use over SSH or where there is no bar
+//!
+
+use crate::handle::Handle;
+use crate::history;
+use crate::server::Server;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph};
+use ratatui::Frame;
+use std::io;
+use std::time::Duration;
+
+/// How often the dashboard is redrawn, if no key is pressed
+const REDRAW_FREQ: Duration = Duration::from_millis(250);
+/// Length of blocks started from the dashboard, unless adjusted
+const DEFAULT_BLOCK_S: u64 = 25 * 60;
+/// How much `+` and `-` adjust the block length by
+const BLOCK_STEP_S: u64 = 5 * 60;
+/// Number of past blocks shown
+const HISTORY_LEN: usize = 20;
+
+/// Run the dashboard until the user quits
+///
+/// This blocks, so should be run with `tokio::task::spawn_blocking`.
+pub fn run(handle: Handle) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut block_s = DEFAULT_BLOCK_S;
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| draw(frame, &handle.lock(), block_s)) {
+            break Err(e);
+        }
+        match event::poll(REDRAW_FREQ) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => break Err(e),
+        }
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(e) => break Err(e),
+        };
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+            KeyCode::Char('s') => handle.with_server(|server| server.start_block(block_s, None)),
+            KeyCode::Char('p') => handle.with_server(Server::pause_block),
+            KeyCode::Char('c') => handle.with_server(Server::cancel_block),
+            KeyCode::Char('+') => block_s += BLOCK_STEP_S,
+            KeyCode::Char('-') => block_s = block_s.saturating_sub(BLOCK_STEP_S).max(BLOCK_STEP_S),
+            _ => {}
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+/// Draw the whole dashboard
+fn draw(frame: &mut Frame, server: &Server, block_s: u64) {
+    let [timer, gauge, body, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [history, tasks] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+
+    let status = server.status();
+    let mut text = status.state.to_owned();
+    if let Some(rem) = status.remaining_s {
+        text += &format!("  {:02}:{:02}", rem / 60, rem % 60);
+    }
+    if let Some(ref label) = status.label {
+        text += &format!("  {}", label);
+    }
+    frame.render_widget(
+        Paragraph::new(text).block(Block::bordered().title(" pomotoshi ")),
+        timer,
+    );
+
+    let ratio = match (status.remaining_s, status.duration_s) {
+        (Some(rem), Some(total)) if total > 0 => 1.0 - rem as f64 / total as f64,
+        _ => 0.0,
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(" progress "))
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio(ratio.clamp(0.0, 1.0)),
+        gauge,
+    );
+
+    let now = history::unix_now();
+    let items: Vec<ListItem> = server
+        .history()
+        .iter()
+        .rev()
+        .take(HISTORY_LEN)
+        .map(|block| ListItem::new(history_line(block, now)))
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" history ")),
+        history,
+    );
+
+    // Show the block in progress, or failing that, the most recent one
+    let (title, block) = match server.current_block() {
+        Some(block) => (" current block ", Some(block)),
+        None => match server.history().last() {
+            Some(block) => (" last block ", Some(block)),
+            None => (" tasks ", None),
+        },
+    };
+    let task_log = match block {
+        Some(block) => {
+            let mut text = block.tasks.to_string();
+            for note in &block.notes {
+                text += &format!("\nNote: {}", note);
+            }
+            text
+        }
+        None => String::new(),
+    };
+    frame.render_widget(
+        Paragraph::new(task_log).block(Block::bordered().title(title)),
+        tasks,
+    );
+
+    frame.render_widget(
+        Paragraph::new(format!(
+            "s: start {}-minute block  +/-: adjust length  p: pause/unpause  c: cancel  q: quit",
+            block_s / 60
+        )),
+        help,
+    );
+}
+
+/// Summarize a past block on one line
+fn history_line(block: &history::Block, now: u64) -> Line<'static> {
+    let ago_m = now.saturating_sub(block.end_s) / 60;
+    let ago = if ago_m < 60 {
+        format!("{}m ago", ago_m)
+    } else if ago_m < 24 * 60 {
+        format!("{}h ago", ago_m / 60)
+    } else {
+        format!("{}d ago", ago_m / (24 * 60))
+    };
+    let (outcome, color) = match block.outcome {
+        history::Outcome::Completed => ("done", Color::Green),
+        history::Outcome::Cancelled => ("cancelled", Color::Red),
+    };
+    let score = match block.focus_score {
+        Some(score) => format!(" {}%", score),
+        None => String::new(),
+    };
+    Line::styled(
+        format!(
+            "{:>8}  {:>3}m  {:<9}{}  {}",
+            ago,
+            block.duration_s / 60,
+            outcome,
+            score,
+            block.label.as_deref().unwrap_or(""),
+        ),
+        Style::default().fg(color),
+    )
+}
@@ -0,0 +1,378 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Windows
+//!
+//! Querying X for information about the currently-focused window
+//!
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process::Command;
+
+/// Information about a window, used to classify the time spent in it
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Window {
+    /// The window title
+    pub title: String,
+    /// The class part of the window's `WM_CLASS` property, e.g. "firefox"
+    pub class: String,
+    /// The name (or, if unnamed, number) of the current EWMH desktop
+    pub desktop: String,
+    /// Other windows which are visible full-screen, e.g. on another monitor,
+    /// if they were asked for
+    pub fullscreen: Vec<Window>,
+}
+
+impl Window {
+    /// Query the currently-focused window, and optionally its class, the
+    /// current desktop and any other windows which are visible full-screen
+    ///
+    /// Each of these needs another `xprop` process, so is only queried if
+    /// asked for, and otherwise left blank.
+    pub fn focused(
+        with_class: bool,
+        with_desktop: bool,
+        with_fullscreen: bool,
+    ) -> io::Result<Window> {
+        let id = xdotool(&["getwindowfocus"])?;
+        let id = String::from_utf8_lossy(&id);
+        let id = id.trim();
+
+        let title = xdotool(&["getwindowname", id])?;
+
+        // The class is optional, so if xprop is not available just leave it blank
+        let class = if with_class {
+            Command::new("xprop")
+                .arg("-id")
+                .arg(id)
+                .arg("WM_CLASS")
+                .output()
+                .map(|out| parse_wm_class(&String::from_utf8_lossy(&out.stdout)))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        // Similarly for the desktop, which is a property of the root window
+        let desktop = if with_desktop {
+            Command::new("xprop")
+                .arg("-root")
+                .arg("_NET_CURRENT_DESKTOP")
+                .arg("_NET_DESKTOP_NAMES")
+                .output()
+                .map(|out| parse_desktop(&String::from_utf8_lossy(&out.stdout)))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let fullscreen = if with_fullscreen {
+            fullscreen_windows(id, &desktop)
+        } else {
+            vec![]
+        };
+
+        Ok(Window {
+            title: String::from_utf8_lossy(&title).into_owned(),
+            class,
+            desktop,
+            fullscreen,
+        })
+    }
+}
+
+/// Whether the monitors have been put to sleep by DPMS, according to `xset q`
+pub fn monitor_off() -> io::Result<bool> {
+    let output = Command::new("xset")
+        .arg("q")
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("running xset: {}", e)))?;
+    Ok(parse_dpms(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Whether xset output has a line like `Monitor is Off` (or `in Standby` or
+/// `in Suspend`), rather than `Monitor is On`
+///
+/// Servers without the DPMS extension have no such line, so their monitors
+/// are taken to be on.
+fn parse_dpms(xset: &str) -> bool {
+    xset.lines()
+        .filter_map(|line| line.trim().strip_prefix("Monitor is "))
+        .any(|state| state != "On")
+}
+
+/// Script which prints, for each window ID given as an argument, a line
+/// `WINDOW <id>` followed by the properties `parse_fullscreen_client` needs
+const CLIENTS_SCRIPT: &str = r#"for id; do
+    echo "WINDOW $id"
+    xprop -id "$id" _NET_WM_STATE WM_STATE _NET_WM_NAME WM_CLASS
+done"#;
+
+/// Find the windows, other than the one with the given ID, which are mapped,
+/// full-screen and not hidden, using the EWMH client list
+///
+/// The clients' properties are all queried by a single shell, rather than
+/// by spawning a process for each, but this is still only done if asked
+/// for. Any failures are ignored, since this is a best-effort addition to
+/// tracking.
+fn fullscreen_windows(focused_id: &str, desktop: &str) -> Vec<Window> {
+    let focused_id = focused_id.parse::<u64>().ok();
+    let clients = match Command::new("xprop")
+        .arg("-root")
+        .arg("_NET_CLIENT_LIST")
+        .output()
+    {
+        Ok(out) => parse_client_list(&String::from_utf8_lossy(&out.stdout)),
+        Err(_) => return vec![],
+    };
+    let ids: Vec<String> = clients
+        .into_iter()
+        .filter(|&id| Some(id) != focused_id)
+        .map(|id| id.to_string())
+        .collect();
+    if ids.is_empty() {
+        return vec![];
+    }
+    let out = match Command::new("sh")
+        .arg("-c")
+        .arg(CLIENTS_SCRIPT)
+        .arg("sh")
+        .args(&ids)
+        .output()
+    {
+        Ok(out) => out,
+        Err(_) => return vec![],
+    };
+    parse_fullscreen_clients(&String::from_utf8_lossy(&out.stdout))
+        .into_iter()
+        .map(|mut win| {
+            win.desktop = desktop.to_owned();
+            win
+        })
+        .collect()
+}
+
+/// Extract the full-screen windows from the output of `CLIENTS_SCRIPT`
+fn parse_fullscreen_clients(output: &str) -> Vec<Window> {
+    let mut windows = vec![];
+    let mut client = String::new();
+    // Each client's output ends where the next one starts, or at the end
+    for line in output.lines().chain(Some("WINDOW")) {
+        if line == "WINDOW" || line.starts_with("WINDOW ") {
+            windows.extend(parse_fullscreen_client(&client));
+            client.clear();
+        } else {
+            client.push_str(line);
+            client.push('\n');
+        }
+    }
+    windows
+}
+
+/// Extract the window IDs from xprop output like
+/// `_NET_CLIENT_LIST(WINDOW): window id # 0x1800003, 0x2200007`
+fn parse_client_list(xprop: &str) -> Vec<u64> {
+    let ids = match xprop.split_once('#') {
+        Some((_, ids)) => ids,
+        None => return vec![],
+    };
+    ids.split(',')
+        .filter_map(|id| u64::from_str_radix(id.trim().trim_start_matches("0x"), 16).ok())
+        .collect()
+}
+
+/// Extract a window's title and class from xprop output for its
+/// `_NET_WM_STATE`, `WM_STATE`, `_NET_WM_NAME` and `WM_CLASS` properties, if
+/// it is full-screen and not hidden
+///
+/// Windows are only taken to be visible if their `WM_STATE` is `Normal`,
+/// so that those which are unmapped (`Withdrawn`) or minimized (`Iconic`),
+/// or not managed at all, are skipped.
+fn parse_fullscreen_client(xprop: &str) -> Option<Window> {
+    let mut fullscreen = false;
+    let mut mapped = false;
+    let mut win = Window::default();
+    for line in xprop.lines() {
+        if line.starts_with("_NET_WM_STATE") {
+            fullscreen =
+                line.contains("_NET_WM_STATE_FULLSCREEN") && !line.contains("_NET_WM_STATE_HIDDEN");
+        } else if let Some(state) = line.trim().strip_prefix("window state:") {
+            mapped = state.trim() == "Normal";
+        } else if line.starts_with("_NET_WM_NAME") {
+            if let Some((_, value)) = line.split_once(" = ") {
+                win.title = value.trim().trim_matches('"').replace("\\\"", "\"");
+            }
+        } else if line.starts_with("WM_CLASS") {
+            win.class = parse_wm_class(line);
+        }
+    }
+    if fullscreen && mapped {
+        Some(win)
+    } else {
+        None
+    }
+}
+
+/// Run xdotool, returning its output, or an error if it failed
+fn xdotool(args: &[&str]) -> io::Result<Vec<u8>> {
+    let output = Command::new("xdotool")
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("running xdotool: {}", e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "xdotool {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Extract the class from xprop output like `WM_CLASS(STRING) = "Navigator", "firefox"`
+fn parse_wm_class(xprop: &str) -> String {
+    // The property is an instance name followed by a class name, both quoted
+    xprop.split('"').nth(3).unwrap_or("").to_owned()
+}
+
+/// Extract the current desktop's name from xprop output like
+///
+/// ```text
+/// _NET_CURRENT_DESKTOP(CARDINAL) = 2
+/// _NET_DESKTOP_NAMES(UTF8_STRING) = "1", "2", "web", "4"
+/// ```
+///
+/// falling back to its number if the desktops are not named.
+fn parse_desktop(xprop: &str) -> String {
+    let mut number = None;
+    let mut names = vec![];
+    for line in xprop.lines() {
+        if let Some((prop, value)) = line.split_once(" = ") {
+            if prop.starts_with("_NET_CURRENT_DESKTOP") {
+                number = value.trim().parse::<usize>().ok();
+            } else if prop.starts_with("_NET_DESKTOP_NAMES") {
+                names = value.split('"').skip(1).step_by(2).collect();
+            }
+        }
+    }
+    match number {
+        Some(n) => names.get(n).map(|s| s.to_string()).unwrap_or(n.to_string()),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_xprop() {
+        assert_eq!(
+            parse_wm_class("WM_CLASS(STRING) = \"Navigator\", \"firefox\"\n"),
+            "firefox",
+        );
+        assert_eq!(parse_wm_class("WM_CLASS:  not found.\n"), "");
+        assert_eq!(
+            parse_desktop(
+                "_NET_CURRENT_DESKTOP(CARDINAL) = 2\n_NET_DESKTOP_NAMES(UTF8_STRING) = \"1\", \"2\", \"web\", \"4\"\n"
+            ),
+            "web",
+        );
+        assert_eq!(
+            parse_desktop("_NET_CURRENT_DESKTOP(CARDINAL) = 2\n_NET_DESKTOP_NAMES:  not found.\n"),
+            "2",
+        );
+        assert_eq!(parse_desktop(""), "");
+    }
+
+    #[test]
+    fn test_parse_dpms() {
+        let xset = |state: &str| {
+            format!(
+                "DPMS (Energy Star):\n  Standby: 600    Suspend: 600    Off: 600\n  DPMS is Enabled\n  Monitor is {}\n",
+                state
+            )
+        };
+        assert!(!parse_dpms(&xset("On")));
+        assert!(parse_dpms(&xset("Off")));
+        assert!(parse_dpms(&xset("in Standby")));
+        assert!(parse_dpms(&xset("in Suspend")));
+        assert!(!parse_dpms("Server does not have the DPMS Extension\n"));
+    }
+
+    #[test]
+    fn test_parse_fullscreen() {
+        assert_eq!(
+            parse_client_list("_NET_CLIENT_LIST(WINDOW): window id # 0x1800003, 0x220000a\n"),
+            vec![0x1800003, 0x220000a],
+        );
+        assert!(parse_client_list("_NET_CLIENT_LIST:  not found.\n").is_empty());
+
+        let client = |net_wm_state: &str, window_state: &str, name: &str| {
+            format!(
+                "_NET_WM_STATE(ATOM) = {}\n\
+                 WM_STATE(WM_STATE):\n\
+                 \t\twindow state: {}\n\
+                 \t\ticon window: 0x0\n\
+                 _NET_WM_NAME(UTF8_STRING) = \"{}\"\n\
+                 WM_CLASS(STRING) = \"{}\", \"{}\"\n",
+                net_wm_state, window_state, name, name, name,
+            )
+        };
+        let zoom =
+            parse_fullscreen_client(&client("_NET_WM_STATE_FULLSCREEN", "Normal", "zoom")).unwrap();
+        assert_eq!(zoom.title, "zoom");
+        assert_eq!(zoom.class, "zoom");
+        assert_eq!(
+            parse_fullscreen_client(&client(
+                "_NET_WM_STATE_FULLSCREEN, _NET_WM_STATE_HIDDEN",
+                "Normal",
+                "zoom"
+            )),
+            None,
+        );
+        assert_eq!(parse_fullscreen_client(&client("", "Normal", "x")), None);
+        // Unmapped and minimized windows are not visible, whatever they say
+        assert_eq!(
+            parse_fullscreen_client(&client("_NET_WM_STATE_FULLSCREEN", "Withdrawn", "zoom")),
+            None,
+        );
+        assert_eq!(
+            parse_fullscreen_client(&client("_NET_WM_STATE_FULLSCREEN", "Iconic", "zoom")),
+            None,
+        );
+        assert_eq!(
+            parse_fullscreen_client(
+                "_NET_WM_STATE(ATOM) = _NET_WM_STATE_FULLSCREEN\n\
+                 WM_STATE:  not found.\n"
+            ),
+            None,
+        );
+
+        // Each client's properties follow its ID in the script's output
+        let output = format!(
+            "WINDOW 1\n{}WINDOW 2\n{}WINDOW 3\n{}",
+            client("_NET_WM_STATE_FULLSCREEN", "Normal", "zoom"),
+            client("_NET_WM_STATE_FULLSCREEN", "Withdrawn", "mpv"),
+            client("_NET_WM_STATE_FULLSCREEN", "Normal", "firefox"),
+        );
+        let classes: Vec<_> = parse_fullscreen_clients(&output)
+            .into_iter()
+            .map(|win| win.class)
+            .collect();
+        assert_eq!(classes, ["zoom", "firefox"]);
+        assert!(parse_fullscreen_clients("").is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! Property tests for window title classification, which runs on arbitrary
+//! titles ten times a second
+
+use pomotoshi::task::{apply_aliases, normalize_path, title_to_path, Alias};
+use proptest::prelude::*;
+
+/// The most components `title_to_path` ever returns (for GitHub, GitLab and tmux)
+const MAX_DEPTH: usize = 4;
+
+/// Titles which are arbitrary, or which look like those the classifier
+/// recognizes but with arbitrary parts
+fn title() -> impl Strategy<Value = String> {
+    let text = || ".{0,24}";
+    let number = || "[0-9]{0,6}";
+    prop_oneof![
+        any::<String>(),
+        (text(), "Pull Request|Issue|Discussion", number(), text()).prop_map(
+            |(t, kind, n, repo)| format!("{} · {} #{} · {} - qutebrowser", t, kind, n, repo)
+        ),
+        (text(), number(), text(), text()).prop_map(|(t, n, repo, profile)| format!(
+            "{} (!{}) · Merge requests · {} · GitLab — Mozilla Firefox — {}",
+            t, n, repo, profile
+        )),
+        ("[A-Z][A-Z0-9_]{0,5}", number(), text(), text()).prop_map(|(key, n, t, profile)| {
+            format!("[{}-{}] {} - Jira - Google Chrome - {}", key, n, t, profile)
+        }),
+        (text(), text())
+            .prop_map(|(t, profile)| format!("Meet – {} - Google Chrome - {}", t, profile)),
+        text().prop_map(|t| format!("{} | Jitsi Meet - qutebrowser", t)),
+        text().prop_map(|t| format!("Zoom Meeting{}", t)),
+        (text(), text(), text())
+            .prop_map(|(t, session, window)| format!("{} (tmux:{}/{})", t, session, window)),
+        (text(), text()).prop_map(|(file, dir)| format!("{} + ({}) - NVIM", file, dir)),
+        ("[a-z.-]{1,8}", "[a-z.-]{1,8}", text())
+            .prop_map(|(user, host, dir)| format!("{}@{}: {}", user, host, dir)),
+        (number(), text()).prop_map(|(n, t)| format!("({}) [{}+] {} ({})", n, n, t, n)),
+    ]
+}
+
+/// A path component, from a small alphabet so that aliases often match
+fn component() -> impl Strategy<Value = String> {
+    "[ab*]"
+}
+
+/// An alias from one path prefix to another
+fn alias() -> impl Strategy<Value = (Vec<String>, Vec<String>)> {
+    (
+        prop::collection::vec(component(), 1..4),
+        prop::collection::vec("[ab]", 0..4),
+    )
+}
+
+fn make_alias(from: &[String], to: &[String]) -> Alias {
+    serde_json::from_value(serde_json::json!({ "from": from, "to": to })).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn title_to_path_is_bounded(title in title()) {
+        let path = title_to_path(&title);
+        prop_assert!(!path.is_empty());
+        prop_assert!(path.len() <= MAX_DEPTH, "{:?} gave {:?}", title, path);
+    }
+
+    #[test]
+    fn normalize_path_is_idempotent(title in title(), fold_case: bool) {
+        let mut path = title_to_path(&title);
+        normalize_path(&mut path, fold_case);
+        let mut again = path.clone();
+        normalize_path(&mut again, fold_case);
+        prop_assert_eq!(path, again);
+    }
+
+    #[test]
+    fn normalize_path_is_idempotent_on_any_strings(
+        mut path in prop::collection::vec(any::<String>(), 0..6),
+        fold_case: bool,
+    ) {
+        normalize_path(&mut path, fold_case);
+        let mut again = path.clone();
+        normalize_path(&mut again, fold_case);
+        prop_assert_eq!(path, again);
+    }
+
+    #[test]
+    fn aliases_replace_only_the_prefix(
+        aliases in prop::collection::vec(alias(), 0..4),
+        root_first in prop::collection::vec("[ab]", 0..6),
+    ) {
+        let rules: Vec<Alias> = aliases.iter().map(|(from, to)| make_alias(from, to)).collect();
+        let mut path: Vec<String> = root_first.iter().rev().cloned().collect();
+        apply_aliases(&rules, &mut path);
+        path.reverse();
+
+        let matched = aliases.iter().find(|(from, _)| {
+            from.len() <= root_first.len()
+                && from.iter().zip(&root_first).all(|(pattern, c)| pattern == "*" || pattern == c)
+        });
+        match matched {
+            Some((from, to)) => {
+                prop_assert_eq!(&path[..to.len()], &to[..]);
+                prop_assert_eq!(&path[to.len()..], &root_first[from.len()..]);
+            }
+            None => prop_assert_eq!(path, root_first),
+        }
+    }
+}
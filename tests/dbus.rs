@@ -0,0 +1,623 @@
+// Pomotoshi
+// Written in 2022 by
+//   Andrew Poelstra <icboc@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! End-to-end tests of the D-Bus interface, against the daemon running on a
+//! private bus
+//!
+//! These need `dbus-daemon`, and are skipped (saying so) if it is not installed.
+
+use futures_util::StreamExt;
+use pomotoshi::server::Server;
+use pomotoshi::{DBUS_API_VERSION, DBUS_LEGACY_ORG, DBUS_ORG, DBUS_PATH};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use std::{fs, process};
+use zbus::fdo::Error;
+
+/// How long to wait for the daemon to start, or for a signal
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A private bus, with a daemon serving on it, which are both killed (and
+/// their files removed) when this is dropped
+struct Harness {
+    dir: PathBuf,
+    address: String,
+    bus: Child,
+    daemon: Child,
+}
+
+impl Harness {
+    /// Start a bus, and a daemon with the given config, or return `None` if
+    /// there is no `dbus-daemon` to run
+    fn start(name: &str, config: serde_json::Value) -> Option<Harness> {
+        let dir = std::env::temp_dir().join(format!("pomotoshi-dbus-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let bus = Command::new("dbus-daemon")
+            .arg("--session")
+            .arg("--nofork")
+            .arg("--print-address")
+            .arg(format!("--address=unix:path={}", dir.join("bus").display()))
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut bus = match bus {
+            Ok(bus) => bus,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("dbus-daemon is not installed; skipping");
+                return None;
+            }
+            Err(e) => panic!("starting dbus-daemon: {}", e),
+        };
+        let mut address = String::new();
+        io::BufReader::new(bus.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_owned();
+
+        let config_file = dir.join("config.json");
+        fs::write(&config_file, config.to_string()).unwrap();
+        let daemon = Harness::spawn_daemon(&address, &config_file);
+        Some(Harness {
+            dir,
+            address,
+            bus,
+            daemon,
+        })
+    }
+
+    /// Start a daemon on a bus
+    fn spawn_daemon(address: &str, config_file: &PathBuf) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_pomotoshi"))
+            .arg("--no-output")
+            .arg(config_file)
+            .env("DBUS_SESSION_BUS_ADDRESS", address)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    fn config_file(&self) -> PathBuf {
+        self.dir.join("config.json")
+    }
+
+    /// Change the config file (which the daemon also saves its state to)
+    fn edit_config(&self, edit: impl FnOnce(&mut serde_json::Value)) {
+        let mut config = serde_json::from_slice(&fs::read(self.config_file()).unwrap()).unwrap();
+        edit(&mut config);
+        let tmp = self.dir.join("config.json.tmp");
+        fs::write(&tmp, config.to_string()).unwrap();
+        fs::rename(tmp, self.config_file()).unwrap();
+    }
+
+    /// Connect to the bus, once the daemon has claimed its name on it
+    async fn client(&self) -> Client {
+        let connection = zbus::connection::Builder::address(self.address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let dbus = zbus::fdo::DBusProxy::new(&connection).await.unwrap();
+        let started = std::time::Instant::now();
+        while !dbus
+            .name_has_owner(DBUS_ORG.try_into().unwrap())
+            .await
+            .unwrap()
+        {
+            assert!(started.elapsed() < TIMEOUT, "daemon did not claim its name");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let proxy = zbus::Proxy::new(&connection, DBUS_ORG, DBUS_PATH, DBUS_ORG)
+            .await
+            .unwrap();
+        Client {
+            connection,
+            proxy,
+            called: BTreeSet::new(),
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = self.bus.kill();
+        let _ = self.bus.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A connection to the daemon, which remembers which methods it called
+struct Client {
+    connection: zbus::Connection,
+    proxy: zbus::Proxy<'static>,
+    called: BTreeSet<String>,
+}
+
+impl Client {
+    async fn call<B, R>(&mut self, method: &str, body: &B) -> Result<R, Error>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+        R: for<'d> zbus::zvariant::DynamicDeserialize<'d>,
+    {
+        self.called.insert(method.to_owned());
+        self.proxy.call(method, body).await.map_err(Error::from)
+    }
+
+    async fn status(&mut self) -> (String, u64, u64, String) {
+        self.call("getStatus", &()).await.unwrap()
+    }
+
+    async fn state(&mut self) -> String {
+        self.status().await.0
+    }
+
+    /// The names of the methods and signals the daemon exports
+    async fn exported(&self) -> (BTreeSet<String>, BTreeSet<String>) {
+        let xml = zbus::fdo::IntrospectableProxy::builder(&self.connection)
+            .destination(DBUS_ORG)
+            .unwrap()
+            .path(DBUS_PATH)
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+            .introspect()
+            .await
+            .unwrap();
+        let start = xml
+            .find(&format!("<interface name=\"{}\">", DBUS_ORG))
+            .unwrap();
+        let interface = &xml[start..];
+        let interface = &interface[..interface.find("</interface>").unwrap()];
+        let names = |kind: &str| {
+            let re = regex::Regex::new(&format!("<{} name=\"([^\"]+)\"", kind)).unwrap();
+            re.captures_iter(interface)
+                .map(|captures| captures[1].to_owned())
+                .collect()
+        };
+        (names("method"), names("signal"))
+    }
+}
+
+/// The config of a freshly-installed daemon, plus some presets and routines
+/// to call
+fn config() -> serde_json::Value {
+    let mut config = serde_json::to_value(Server::new()).unwrap();
+    config["presets"] = json!({ "short": { "duration_s": 60, "label": "preset" } });
+    config["routines"] = json!({ "pomodoro": { "work_s": 60, "rest_s": 30, "rounds": 2 } });
+    config["confirm_cancel_s"] = json!(3600);
+    // Calls are made faster than any person would
+    config["rate_limit"] = json!({ "calls": 1000 });
+    config
+}
+
+fn failed(result: Result<impl std::fmt::Debug, Error>) -> String {
+    match result {
+        Err(Error::Failed(message)) => message,
+        other => panic!("expected Failed, got {:?}", other),
+    }
+}
+
+fn access_denied(result: Result<impl std::fmt::Debug, Error>) -> String {
+    match result {
+        Err(Error::AccessDenied(message)) => message,
+        other => panic!("expected AccessDenied, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_every_method() {
+    let harness = match Harness::start("every_method", config()) {
+        Some(harness) => harness,
+        None => return,
+    };
+    let mut client = harness.client().await;
+
+    // Version and status
+    let version: (String, u32) = client.call("getVersion", &()).await.unwrap();
+    assert_eq!(
+        version,
+        (env!("CARGO_PKG_VERSION").into(), DBUS_API_VERSION)
+    );
+    assert_eq!(client.status().await, ("idle".into(), 0, 0, String::new()));
+
+    // The same methods are served under the name from before versioning
+    let legacy = zbus::Proxy::new(
+        &client.connection,
+        DBUS_LEGACY_ORG,
+        DBUS_PATH,
+        DBUS_LEGACY_ORG,
+    )
+    .await
+    .unwrap();
+    let legacy_version: (String, u32) = legacy.call("getVersion", &()).await.unwrap();
+    assert_eq!(legacy_version, version);
+    let xml = zbus::fdo::IntrospectableProxy::builder(&client.connection)
+        .destination(DBUS_LEGACY_ORG)
+        .unwrap()
+        .path(DBUS_PATH)
+        .unwrap()
+        .build()
+        .await
+        .unwrap()
+        .introspect()
+        .await
+        .unwrap();
+    assert!(xml.contains(&format!("<interface name=\"{}\">", DBUS_LEGACY_ORG)));
+
+    let nothing = client.call::<_, String>("undoLast", &()).await;
+    assert!(failed(nothing).contains("nothing to undo"));
+
+    // Blocks: starting, pausing and cancelling (with confirmation, since
+    // the block is "nearly done"), and undoing that, which leaves it paused
+    client.call::<_, ()>("startBlock", &(60u64,)).await.unwrap();
+    let (state, _, duration_s, _) = client.status().await;
+    assert_eq!((state.as_str(), duration_s), ("block", 60));
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    assert_eq!(client.state().await, "paused");
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    assert_eq!(client.state().await, "block");
+    let unconfirmed = client.call::<_, ()>("cancelBlock", &()).await;
+    assert!(failed(unconfirmed).contains("cancel again"));
+    assert_eq!(client.state().await, "block");
+    client.call::<_, ()>("cancelBlock", &()).await.unwrap();
+    assert_eq!(client.state().await, "idle");
+    let undone: String = client.call("undoLast", &()).await.unwrap();
+    assert_eq!(undone, "restored cancelled block, paused");
+    assert_eq!(client.state().await, "paused");
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    assert_eq!(client.state().await, "block");
+    client.call::<_, ()>("forceCancelBlock", &()).await.unwrap();
+    assert_eq!(client.state().await, "idle");
+
+    // Labels, notes and reports
+    client
+        .call::<_, ()>("startLabeledBlock", &(60u64, "writing"))
+        .await
+        .unwrap();
+    assert_eq!(client.status().await.3, "writing");
+    client
+        .call::<_, ()>("annotateBlock", &("first draft",))
+        .await
+        .unwrap();
+    client.call::<_, ()>("forceCancelBlock", &()).await.unwrap();
+    let report: String = client.call("blockReport", &(0u32,)).await.unwrap();
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["label"], "writing");
+    assert_eq!(report["notes"], json!(["first draft"]));
+    let missing: String = client.call("blockReport", &(5u32,)).await.unwrap();
+    assert_eq!(missing, "[block 5 not found]");
+    let log: String = client.call("blockLog", &()).await.unwrap();
+    assert!(log.contains("canceled block"), "{}", log);
+    let html: String = client.call("htmlReport", &()).await.unwrap();
+    assert!(html.contains("<html"));
+    let svg: String = client.call("focusHeatmap", &(0u32,)).await.unwrap();
+    assert!(svg.contains("<svg"));
+    let height: u64 = client.call("getHeight", &()).await.unwrap();
+    assert_eq!(height, 0);
+    let goal: (u64, u64) = client.call("goalStatus", &()).await.unwrap();
+    assert_eq!(goal, (0, 0));
+    let streaks: (u64, u64) = client.call("streaks", &()).await.unwrap();
+    assert_eq!(streaks, (0, 0));
+    let breaks: Vec<(u64, String)> = client.call("streakBreaks", &()).await.unwrap();
+    assert_eq!(breaks, []);
+    let max_s: u64 = client.call("maxBlockUntilNextEvent", &()).await.unwrap();
+    assert_eq!(max_s, u64::MAX);
+
+    // There is no history store to total, or import into, until one is
+    // configured
+    let totals = client
+        .call::<_, (u64, u64, u64)>("historyTotals", &(0u64, u64::MAX))
+        .await;
+    assert!(failed(totals).contains("no history store"));
+    let csv = harness.dir.join("history.csv");
+    fs::write(&csv, "start,end,label\n1000,2500,imported\n").unwrap();
+    let body = ("csv", csv.to_str().unwrap());
+    let imported = client.call::<_, String>("importHistory", &body).await;
+    assert!(failed(imported).contains("no history store"));
+
+    // Importing history, which skips blocks already imported
+    let store = harness.dir.join("history.jsonl");
+    harness.edit_config(|config| config["history_store"] = json!({ "jsonl": store }));
+    client.call::<_, ()>("reloadConfig", &()).await.unwrap();
+    let imported: String = client.call("importHistory", &body).await.unwrap();
+    assert!(imported.starts_with("imported 1 blocks"), "{}", imported);
+    let imported: String = client.call("importHistory", &body).await.unwrap();
+    assert!(imported.contains("(1 already in history)"), "{}", imported);
+    let totals: (u64, u64, u64) = client
+        .call("historyTotals", &(0u64, u64::MAX))
+        .await
+        .unwrap();
+    assert_eq!(totals, (1, 0, 1500));
+    assert!(failed(
+        client
+            .call::<_, String>("importHistory", &("pomodoro", "/"))
+            .await
+    )
+    .contains("unknown format"));
+
+    // Presets and routines
+    client
+        .call::<_, ()>("startPreset", &("short",))
+        .await
+        .unwrap();
+    assert_eq!(client.status().await.3, "preset");
+    client.call::<_, ()>("forceCancelBlock", &()).await.unwrap();
+    client
+        .call::<_, ()>("startRoutine", &("pomodoro",))
+        .await
+        .unwrap();
+    let routine: (String, String, u32, u32) = client.call("routineStatus", &()).await.unwrap();
+    assert_eq!(routine, ("pomodoro".into(), "work".into(), 1, 2));
+    client.call::<_, ()>("forceCancelBlock", &()).await.unwrap();
+    let routine: (String, String, u32, u32) = client.call("routineStatus", &()).await.unwrap();
+    assert_eq!(routine, (String::new(), String::new(), 0, 0));
+
+    // Timers
+    client
+        .call::<_, ()>("startTimer", &("tea", 300u64))
+        .await
+        .unwrap();
+    client.call::<_, ()>("pauseTimer", &("tea",)).await.unwrap();
+    client
+        .call::<_, ()>("cancelTimer", &("tea",))
+        .await
+        .unwrap();
+
+    // Colors
+    client
+        .call::<_, ()>("setTheme", &("gruvbox",))
+        .await
+        .unwrap();
+    let colors = HashMap::from([("idle".to_owned(), "#888".to_owned())]);
+    client.call::<_, ()>("setColors", &(colors,)).await.unwrap();
+
+    // Task logs
+    client
+        .call::<_, ()>("taskLogAdd", &("work",))
+        .await
+        .unwrap();
+    client
+        .call::<_, ()>("taskLogAddFiltered", &("code", "NVIM$", ""))
+        .await
+        .unwrap();
+    match client
+        .call::<_, ()>("taskLogAddFiltered", &("bad", "(", ""))
+        .await
+    {
+        Err(Error::InvalidArgs(_)) => {}
+        other => panic!("expected InvalidArgs, got {:?}", other),
+    }
+    client
+        .call::<_, ()>("taskLogAddTime", &("work", vec!["email", "inbox"], 600u64))
+        .await
+        .unwrap();
+    client
+        .call::<_, ()>(
+            "taskLogMoveTime",
+            &(
+                "work",
+                vec!["email", "inbox"],
+                vec!["email", "replies"],
+                240u64,
+            ),
+        )
+        .await
+        .unwrap();
+    let output: String = client.call("taskLogOutput", &("work",)).await.unwrap();
+    assert!(
+        output.contains("inbox") && output.contains("replies"),
+        "{}",
+        output
+    );
+    let between: String = client
+        .call("taskLogBetween", &("work", 0u64, u64::MAX))
+        .await
+        .unwrap();
+    // Time added by hand was not recorded at any particular time
+    assert!(!between.contains("not found") && !between.contains("replies"));
+    let markdown: String = client.call("taskLogMarkdown", &("work",)).await.unwrap();
+    assert!(markdown.contains("replies"), "{}", markdown);
+    let dot: String = client.call("taskLogDot", &("work",)).await.unwrap();
+    assert!(dot.contains("digraph"), "{}", dot);
+    let export = harness.dir.join("work.json");
+    client
+        .call::<_, ()>("taskLogExport", &("work", export.to_str().unwrap()))
+        .await
+        .unwrap();
+    client
+        .call::<_, ()>("taskLogImport", &("copy", export.to_str().unwrap()))
+        .await
+        .unwrap();
+    let copy: String = client.call("taskLogOutput", &("copy",)).await.unwrap();
+    assert_eq!(copy, output);
+    client
+        .call::<_, ()>("taskLogCompact", &("work",))
+        .await
+        .unwrap();
+    client
+        .call::<_, ()>("taskLogRemove", &("copy",))
+        .await
+        .unwrap();
+    let undone: String = client.call("undoLast", &()).await.unwrap();
+    assert!(undone.contains("copy"), "{}", undone);
+
+    // Time away, of which there is none without the monitors going off
+    let away: Vec<(u64, u64, Vec<String>)> = client.call("pendingAway", &()).await.unwrap();
+    assert!(away.is_empty());
+    let nothing = client.call::<_, String>("resolveAway", &("keep",)).await;
+    assert!(failed(nothing).contains("nothing to resolve"));
+
+    // Destructive methods need a token once access control is configured,
+    // which reloading the config applies
+    harness.edit_config(|config| config["access_control"] = json!({ "token": "hunter2" }));
+    client.call::<_, ()>("reloadConfig", &()).await.unwrap();
+    assert!(
+        access_denied(client.call::<_, ()>("taskLogRemove", &("work",)).await)
+            .contains("token is required")
+    );
+    assert!(access_denied(
+        client
+            .call::<_, ()>("taskLogRemoveConfirmed", &("work", "hunter3"))
+            .await
+    )
+    .contains("incorrect"));
+    // ..as do methods which overwrite a log, or move time around in it
+    access_denied(client.call::<_, ()>("taskLogAdd", &("work",)).await);
+    access_denied(client.call::<_, ()>("taskLogCompact", &("work",)).await);
+    access_denied(
+        client
+            .call::<_, ()>("taskLogMoveTime", &("work", vec!["a"], vec!["b"], 1u64))
+            .await,
+    );
+    client
+        .call::<_, ()>("taskLogCompactConfirmed", &("work", "hunter2"))
+        .await
+        .unwrap();
+    client
+        .call::<_, ()>(
+            "taskLogMoveTimeConfirmed",
+            &(
+                "work",
+                vec!["email", "replies"],
+                vec!["email", "inbox"],
+                60u64,
+                "hunter2",
+            ),
+        )
+        .await
+        .unwrap();
+    client
+        .call::<_, ()>("taskLogRemoveConfirmed", &("work", "hunter2"))
+        .await
+        .unwrap();
+    // ..though a new log may be added without one
+    client
+        .call::<_, ()>("taskLogAdd", &("work",))
+        .await
+        .unwrap();
+    client.call::<_, ()>("startBlock", &(60u64,)).await.unwrap();
+    access_denied(client.call::<_, ()>("cancelBlock", &()).await);
+    access_denied(client.call::<_, ()>("forceCancelBlock", &()).await);
+    assert_eq!(client.state().await, "block");
+    client
+        .call::<_, ()>("cancelBlockConfirmed", &("hunter2",))
+        .await
+        .unwrap();
+    assert_eq!(client.state().await, "idle");
+
+    // A broken config file is refused, rather than half-applied
+    fs::write(harness.config_file(), "{ not json").unwrap();
+    failed(client.call::<_, ()>("reloadConfig", &()).await);
+
+    // Nothing above went wrong inside the daemon, except perhaps failing to
+    // query windows, since there is no X server, or rendering slowly on a
+    // busy machine
+    let diagnostics: Vec<(u64, String)> = client.call("getDiagnostics", &()).await.unwrap();
+    let (_, summary) = diagnostics.last().unwrap();
+    assert!(summary.starts_with("render latency"), "{}", summary);
+    for (_, message) in diagnostics {
+        assert!(
+            message.starts_with("querying window")
+                || message.starts_with("reloading")
+                || message.starts_with("render"),
+            "{}",
+            message
+        );
+    }
+
+    // Every method has been called
+    let (methods, _) = client.exported().await;
+    assert_eq!(client.called, methods);
+}
+
+#[tokio::test]
+async fn test_transition_signals() {
+    let harness = match Harness::start("transition_signals", config()) {
+        Some(harness) => harness,
+        None => return,
+    };
+    let mut client = harness.client().await;
+    let (_, signals) = client.exported().await;
+    assert_eq!(signals, BTreeSet::from(["transition".to_owned()]));
+
+    let mut transitions = client.proxy.receive_signal("transition").await.unwrap();
+    let mut next = async || {
+        let message = tokio::time::timeout(TIMEOUT, transitions.next())
+            .await
+            .expect("signal was sent in time")
+            .unwrap();
+        message
+            .body()
+            .deserialize::<(String, String, u64)>()
+            .unwrap()
+    };
+
+    client
+        .call::<_, ()>("startLabeledBlock", &(1u64, "sprint"))
+        .await
+        .unwrap();
+    assert_eq!(next().await, ("block_started".into(), "sprint".into(), 1));
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    assert_eq!(next().await, ("block_paused".into(), "sprint".into(), 1));
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    assert_eq!(next().await, ("block_unpaused".into(), "sprint".into(), 1));
+    // The block then runs out by itself
+    assert_eq!(next().await, ("block_completed".into(), "sprint".into(), 1));
+    let (state, _, duration_s, _) = client.status().await;
+    assert_eq!((state.as_str(), duration_s), ("cooldown", 300));
+}
+
+#[tokio::test]
+async fn test_refusals() {
+    let mut config = config();
+    config["rate_limit"] = json!({ "calls": 2, "interval_s": 3600 });
+    config["daily_limit"] = json!({ "max_blocks": 0, "cooldown_s": 60 });
+    let harness = match Harness::start("refusals", config) {
+        Some(harness) => harness,
+        None => return,
+    };
+    let mut client = harness.client().await;
+
+    // The daily limit refuses blocks, with an explanation, and forces a cooldown
+    let refused = client.call::<_, ()>("startBlock", &(60u64,)).await;
+    assert!(failed(refused).contains("daily limit"));
+    let (state, _, duration_s, _) = client.status().await;
+    assert_eq!((state.as_str(), duration_s), ("cooldown", 60));
+
+    // Calling too often is refused without reaching the server
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    client.call::<_, ()>("pauseBlock", &()).await.unwrap();
+    match client.call::<_, ()>("pauseBlock", &()).await {
+        Err(Error::LimitsExceeded(message)) => assert!(message.contains("pauseBlock")),
+        other => panic!("expected LimitsExceeded, got {:?}", other),
+    }
+    // but other methods have limits of their own
+    client
+        .call::<_, ()>("startTimer", &("tea", 60u64))
+        .await
+        .unwrap();
+
+    // A second daemon cannot take over the bus name
+    let status = Harness::spawn_daemon(&harness.address, &harness.dir.join("other.json"))
+        .wait()
+        .unwrap();
+    assert!(!status.success());
+    assert_eq!(client.state().await, "cooldown");
+}